@@ -0,0 +1,21 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/credentials.rs"]
+mod credentials;
+
+fuzz_target!(|header: &str| {
+    let login = credentials::credentials_login(header);
+    let allowed = credentials::allowed_login(header, &["login:password".to_string()]);
+    if let Some(allowed) = allowed {
+        assert_eq!(allowed, "login");
+        assert_eq!(login.as_deref(), Some("login"));
+    }
+    if let Some(login) = login {
+        assert!(!login.contains(':'));
+    }
+    if let Some(params) = credentials::digest_params(header) {
+        assert!(params.iter().all(|(name, _)| !name.is_empty()));
+    }
+});
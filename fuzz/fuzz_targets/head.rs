@@ -0,0 +1,31 @@
+#![no_main]
+
+use futures_util::FutureExt;
+use hyper::header::{HeaderName, HeaderValue};
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/head.rs"]
+#[allow(dead_code)]
+mod head;
+
+fuzz_target!(|data: &[u8]| {
+    // Reads from a slice are always ready
+    let mut reader = data;
+    let mut buffer = Vec::new();
+    let head_len = head::read_head(&mut reader, &mut buffer)
+        .now_or_never()
+        .expect("reading a slice doesn't wait")
+        .expect("reading a slice doesn't fail");
+    if let Some(head_len) = head_len {
+        assert!(buffer[..head_len].ends_with(b"\r\n\r\n"));
+    }
+
+    // What the HTTPS listener parses, and the headers it rebuilds from that
+    let request = String::from_utf8_lossy(&buffer[..head_len.unwrap_or(buffer.len())]);
+    if let Ok((_, _, _, headers)) = head::parse_request(&request) {
+        for (name, value) in headers {
+            assert!(HeaderName::from_bytes(name.as_bytes()).is_ok());
+            assert!(HeaderValue::from_str(&value).is_ok());
+        }
+    }
+});
@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/hostmatch.rs"]
+mod hostmatch;
+
+// A pattern and a host on the first two lines
+fuzz_target!(|input: &str| {
+    let (pattern, host) = input.split_once('\n').unwrap_or((input, ""));
+    let compiles = hostmatch::check(pattern).is_ok();
+    let matches = hostmatch::matches(pattern, host);
+    // A pattern that doesn't compile matches nothing
+    assert!(compiles || !matches);
+});
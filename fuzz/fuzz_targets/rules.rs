@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/hostmatch.rs"]
+#[allow(dead_code)]
+mod hostmatch;
+#[path = "../../src/rules.rs"]
+#[allow(dead_code)]
+mod rules;
+#[path = "../invariants/rules.rs"]
+mod invariants;
+
+// A host or address on the first line, then one rule per line, deny rules after a `!`,
+// see `fuzz/invariants/rules.rs`
+fuzz_target!(|input: &str| invariants::check(input));
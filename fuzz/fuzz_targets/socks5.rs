@@ -0,0 +1,20 @@
+#![no_main]
+
+use futures_util::FutureExt;
+use libfuzzer_sys::fuzz_target;
+
+#[path = "../../src/socks5.rs"]
+#[allow(dead_code)]
+mod socks5;
+
+// A client's greeting, username/password and request, read in the order the listener does
+fuzz_target!(|data: &[u8]| {
+    let mut reader = data;
+    let _ = async {
+        socks5::read_methods(&mut reader).await?;
+        socks5::read_password(&mut reader).await?;
+        socks5::read_request(&mut reader).await
+    }
+    .now_or_never()
+    .expect("reading a slice doesn't wait");
+});
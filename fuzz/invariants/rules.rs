@@ -0,0 +1,86 @@
+//! Invariants of the access rules, shared by the `rules` fuzz target and `tests/rules.rs`.
+//! The crate including this file declares the `hostmatch` and `rules` modules of `src/`.
+
+use std::fmt::Debug;
+use std::net::IpAddr;
+
+use crate::hostmatch;
+use crate::rules::{evaluate, IpNet, Matched, PortList};
+
+/// Check `input`: a host or address on the first line, then one rule per line, deny rules
+/// after a `!`. Rules are tried as host patterns, and as networks and port lists where
+/// they parse.
+pub fn check(input: &str) {
+    let mut lines = input.lines();
+    let subject = lines.next().unwrap_or_default();
+    let (mut deny, mut allow) = (Vec::new(), Vec::new());
+    for line in lines {
+        match line.strip_prefix('!') {
+            Some(rule) => deny.push(rule.to_string()),
+            None => allow.push(line.to_string()),
+        }
+    }
+
+    check_evaluation(&deny, &allow, |pattern| {
+        hostmatch::matches(pattern, subject)
+    });
+
+    if let Ok(ip) = subject.parse::<IpAddr>() {
+        let nets = |rules: &[String]| {
+            rules
+                .iter()
+                .filter_map(|rule| rule.parse::<IpNet>().ok())
+                .collect::<Vec<IpNet>>()
+        };
+        check_evaluation(&nets(&deny), &nets(&allow), |net| net.contains(ip));
+    }
+
+    for rule in deny.iter().chain(&allow) {
+        // What's written back, in the admin API or the users file, parses to the same rule
+        if let Ok(net) = rule.parse::<IpNet>() {
+            assert_eq!(net.to_string().parse::<IpNet>(), Ok(net));
+            assert!(net.contains(net.addr));
+        }
+        if let Ok(ports) = rule.parse::<PortList>() {
+            let normalized = ports.to_string();
+            assert_eq!(normalized.parse::<PortList>().as_ref(), Ok(&ports));
+            for range in &ports.0 {
+                assert!(ports.find(range.start).is_some() && ports.find(range.end).is_some());
+            }
+            if let Ok(port) = subject.parse::<u16>() {
+                let listed = ports
+                    .0
+                    .iter()
+                    .any(|range| (range.start..=range.end).contains(&port));
+                assert_eq!(ports.find(port).is_some(), listed);
+            }
+        }
+    }
+}
+
+/// Invariants of deny-then-allow evaluation, whatever the rules and what they match.
+fn check_evaluation<T: Clone + PartialEq + Debug>(
+    deny: &[T],
+    allow: &[T],
+    matches: impl Fn(&T) -> bool,
+) {
+    let denied = deny.iter().find(|rule| matches(rule));
+    let allowed = allow.iter().find(|rule| matches(rule));
+
+    match evaluate(deny, allow, &matches) {
+        // Deny beats allow, and in each list the first matching rule decides
+        Matched::Deny(rule) => assert_eq!(Some(rule), denied),
+        Matched::Allow(rule) => assert!(denied.is_none() && Some(rule) == allowed),
+        Matched::Default { allowed: default } => {
+            assert!(denied.is_none() && allowed.is_none());
+            assert_eq!(default, allow.is_empty());
+        }
+    }
+
+    // Allowing what is denied changes nothing, and neither does denying it twice
+    if denied.is_some() {
+        let both = [deny, allow].concat();
+        assert!(matches!(evaluate(deny, &both, &matches), Matched::Deny(_)));
+        assert!(matches!(evaluate(&both, allow, &matches), Matched::Deny(_)));
+    }
+}
@@ -0,0 +1,358 @@
+use crate::geoip;
+use crate::json::object;
+use crate::options::Opt;
+use crate::tenant::parse_bytes;
+use crate::utils::{label_ip, label_user, log_time, loggable};
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::Instant;
+
+use chrono::{DateTime, FixedOffset, Local};
+use hyper::body::HttpBody;
+use hyper::{Body, Response};
+use log::warn;
+
+static ACCESS_LOG: OnceLock<Mutex<Option<AccessLog>>> = OnceLock::new();
+
+/// Layout of the `--access-log` lines.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    /// Common Log Format, with the bytes from the client and the duration appended
+    Common,
+    /// One JSON object per line
+    Json,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clf" => Ok(AccessLogFormat::Common),
+            "json" => Ok(AccessLogFormat::Json),
+            _ => Err(format!(
+                "Unknown access log format '{s}', expected clf or json"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for AccessLogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccessLogFormat::Common => write!(f, "clf"),
+            AccessLogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+/// When the access log is moved aside for a fresh file: once it reaches `size` bytes,
+/// and at the start of every hour or day. Moved files are numbered `.1` (the newest)
+/// up to `.keep`, older ones are deleted.
+#[derive(Debug, Clone)]
+pub struct Rotation {
+    pub size: Option<u64>,
+    pub every: Option<Interval>,
+    pub keep: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interval {
+    Hourly,
+    Daily,
+}
+
+impl Interval {
+    /// Number of the local hour or day `time` falls in.
+    fn period(self, time: DateTime<Local>) -> i64 {
+        let seconds = time.naive_local().and_utc().timestamp();
+        match self {
+            Interval::Hourly => seconds.div_euclid(3600),
+            Interval::Daily => seconds.div_euclid(86400),
+        }
+    }
+}
+
+impl FromStr for Rotation {
+    type Err = String;
+
+    /// Parse `size=100M;every=daily;keep=7`, with `size` or `every` required.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rotation = Rotation {
+            size: None,
+            every: None,
+            keep: 7,
+        };
+
+        for field in s
+            .split(';')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+        {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key=value, got '{field}'"))?;
+            let value = value.trim();
+
+            match key.trim() {
+                "size" => rotation.size = Some(parse_bytes(value)?),
+                "every" => {
+                    rotation.every = Some(match value {
+                        "hourly" => Interval::Hourly,
+                        "daily" => Interval::Daily,
+                        _ => {
+                            return Err(format!(
+                                "Invalid rotation interval '{value}', expected hourly or daily"
+                            ))
+                        }
+                    })
+                }
+                "keep" => {
+                    rotation.keep = value
+                        .parse()
+                        .map_err(|e| format!("Invalid rotation keep '{value}': {e}"))?
+                }
+                key => return Err(format!("Unknown rotation setting '{key}'")),
+            }
+        }
+
+        if rotation.size.is_none() && rotation.every.is_none() {
+            return Err("Rotation needs size or every".to_string());
+        }
+        Ok(rotation)
+    }
+}
+
+/// The open access log, with what rotation needs to know about it.
+struct AccessLog {
+    file: File,
+    size: u64,
+    /// Hour or day the file was started in, for rotation by time
+    period: Option<i64>,
+}
+
+impl AccessLog {
+    fn open(path: &str, rotation: Option<&Rotation>) -> io::Result<AccessLog> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let metadata = file.metadata()?;
+        // A file left over from before a restart belongs to the period it was written in
+        let period = rotation.and_then(|rotation| rotation.every).map(|every| {
+            let modified = metadata.modified().map(DateTime::<Local>::from);
+            every.period(modified.unwrap_or_else(|_| Local::now()))
+        });
+        Ok(AccessLog {
+            file,
+            size: metadata.len(),
+            period,
+        })
+    }
+
+    fn is_due(&self, rotation: &Rotation, line_len: u64, now: DateTime<Local>) -> bool {
+        let full = rotation
+            .size
+            .is_some_and(|size| self.size > 0 && self.size + line_len > size);
+        let expired = rotation
+            .every
+            .zip(self.period)
+            .is_some_and(|(every, period)| every.period(now) != period);
+        full || expired
+    }
+}
+
+fn access_log() -> &'static Mutex<Option<AccessLog>> {
+    ACCESS_LOG.get_or_init(Default::default)
+}
+
+/// Open `--access-log`, so a path that can't be written to is reported at startup.
+pub fn init() -> io::Result<()> {
+    let options = Opt::global();
+    if let Some(path) = &options.access_log {
+        let log = AccessLog::open(path, options.access_log_rotate.as_ref())?;
+        *access_log().lock().unwrap() = Some(log);
+    }
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    Opt::global().access_log.is_some()
+}
+
+/// One request answered or tunnel closed, as written to the access log.
+#[derive(Debug, Clone)]
+pub struct Access {
+    /// Client label, with the address and the login
+    pub client: String,
+    pub method: String,
+    /// `host:port` the request was for
+    pub target: String,
+    pub status: u16,
+    /// Client to target
+    pub bytes_up: u64,
+    /// Target to client
+    pub bytes_down: u64,
+    pub started: Instant,
+}
+
+impl Access {
+    pub fn new(client: &str, method: &str, target: &str) -> Access {
+        Access {
+            client: client.to_string(),
+            method: method.to_string(),
+            target: target.to_string(),
+            status: 0,
+            bytes_up: 0,
+            bytes_down: 0,
+            started: Instant::now(),
+        }
+    }
+
+    /// Log the request once `response` has been sent to the client, counting its body
+    /// on the way.
+    pub fn log_response(mut self, response: Response<Body>) -> Response<Body> {
+        self.status = response.status().as_u16();
+        response.map(|mut body| {
+            let (mut sender, counted) = Body::channel();
+            tokio::spawn(async move {
+                while let Some(chunk) = body.data().await {
+                    let Ok(chunk) = chunk else {
+                        sender.abort();
+                        break;
+                    };
+                    self.bytes_down += chunk.len() as u64;
+                    if sender.send_data(chunk).await.is_err() {
+                        break;
+                    }
+                }
+                record(&self);
+            });
+            counted
+        })
+    }
+
+    /// Country of the client from the `--geoip-db`.
+    fn country(&self) -> Option<String> {
+        label_ip(&self.client).and_then(geoip::country)
+    }
+
+    fn to_common(&self, now: DateTime<FixedOffset>) -> String {
+        // The client's country goes last, so lines stay readable by CLF parsers
+        let country = match geoip::enabled() {
+            true => format!(" {}", self.country().unwrap_or_else(|| "-".to_string())),
+            false => String::new(),
+        };
+        format!(
+            "{} - {} [{}] \"{} {}\" {} {} {} {:.3}{country}\n",
+            client_ip(&self.client),
+            label_user(&self.client),
+            now.format("%d/%b/%Y:%H:%M:%S %z"),
+            self.method,
+            loggable(&self.target, &self.client),
+            self.status,
+            self.bytes_down,
+            self.bytes_up,
+            self.started.elapsed().as_secs_f64()
+        )
+    }
+
+    fn to_json(&self, now: DateTime<FixedOffset>) -> String {
+        let line = object([
+            ("time", now.to_rfc3339().into()),
+            ("client", client_ip(&self.client).into()),
+            ("user", label_user(&self.client).into()),
+            ("method", self.method.as_str().into()),
+            ("target", loggable(&self.target, &self.client).into()),
+            ("status", u64::from(self.status).into()),
+            ("bytes_up", self.bytes_up.into()),
+            ("bytes_down", self.bytes_down.into()),
+            (
+                "duration_ms",
+                (self.started.elapsed().as_millis() as u64).into(),
+            ),
+            ("country", self.country().into()),
+        ]);
+        format!("{line}\n")
+    }
+}
+
+/// Client address without the port.
+fn client_ip(client: &str) -> String {
+    let addr = client.split_whitespace().next().unwrap_or("-");
+    match addr.parse::<SocketAddr>() {
+        Ok(addr) => addr.ip().to_string(),
+        Err(_) => addr.to_string(),
+    }
+}
+
+/// Append `access` to `--access-log`, rotating it first when it's due.
+pub fn record(access: &Access) {
+    let options = Opt::global();
+    let Some(path) = &options.access_log else {
+        return;
+    };
+
+    let now = Local::now();
+    let time = log_time(now.to_utc());
+    let line = match options.access_log_format {
+        AccessLogFormat::Common => access.to_common(time),
+        AccessLogFormat::Json => access.to_json(time),
+    };
+
+    let mut log = access_log().lock().unwrap();
+    if let Err(e) = write(
+        &mut log,
+        path,
+        options.access_log_rotate.as_ref(),
+        &line,
+        now,
+    ) {
+        warn!("Failed to write to the access log {path}: {e}");
+    }
+}
+
+fn write(
+    log: &mut Option<AccessLog>,
+    path: &str,
+    rotation: Option<&Rotation>,
+    line: &str,
+    now: DateTime<Local>,
+) -> io::Result<()> {
+    if let (Some(current), Some(rotation)) = (log.as_ref(), rotation) {
+        if current.is_due(rotation, line.len() as u64, now) {
+            *log = None;
+            rotate(path, rotation.keep)?;
+        }
+    }
+
+    let current = match log {
+        Some(current) => current,
+        None => log.insert(AccessLog::open(path, rotation)?),
+    };
+    current.file.write_all(line.as_bytes())?;
+    current.size += line.len() as u64;
+    Ok(())
+}
+
+/// Shift `path.1` .. `path.{keep - 1}` up by one and move `path` to `path.1`.
+fn rotate(path: &str, keep: usize) -> io::Result<()> {
+    if keep == 0 {
+        return fs::remove_file(path);
+    }
+
+    let numbered = |n: usize| format!("{path}.{n}");
+    match fs::remove_file(numbered(keep)) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    for n in (1..keep).rev() {
+        match fs::rename(numbered(n), numbered(n + 1)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    fs::rename(path, numbered(1))
+}
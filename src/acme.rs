@@ -0,0 +1,568 @@
+//! Certificates for the HTTPS server from an ACME CA such as Let's Encrypt. With
+//! `--acme-domain`, a certificate is obtained at startup through the HTTP-01 challenge,
+//! cached in `--acme-cache-dir` and renewed in the background before it expires.
+
+use crate::https::{read_certs, read_private_key};
+use crate::json::{self, object, Value};
+use crate::listener;
+use crate::options::Opt;
+use crate::secrets::certified_key;
+
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, OnceLock, RwLock};
+use std::time::Duration;
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as b64url, Engine};
+use hyper::client::HttpConnector;
+use hyper::header::{CONTENT_TYPE, LOCATION};
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Client, Method, Request, Response, Server, StatusCode};
+use hyper_tls::HttpsConnector;
+use log::{error, info, warn};
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, BigNumContext};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::sha::sha256;
+use openssl::stack::Stack;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509NameBuilder, X509Req, X509};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use tokio::time::sleep;
+
+const CHALLENGE_PATH: &str = "/.well-known/acme-challenge/";
+
+// Certificates are renewed this long before they expire, checked this often
+const RENEW_BEFORE_DAYS: u32 = 30;
+const RENEW_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+// The CA validates challenges and issues certificates asynchronously
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: usize = 30;
+
+static CERTIFIED_KEY: OnceLock<RwLock<Option<Arc<CertifiedKey>>>> = OnceLock::new();
+static CHALLENGES: OnceLock<Mutex<HashMap<String, String>>> = OnceLock::new();
+
+/// Whether the HTTPS server's certificate comes from ACME.
+pub fn enabled() -> bool {
+    Opt::global().acme_domain.is_some()
+}
+
+fn certified_key_slot() -> &'static RwLock<Option<Arc<CertifiedKey>>> {
+    CERTIFIED_KEY.get_or_init(Default::default)
+}
+
+fn challenges() -> &'static Mutex<HashMap<String, String>> {
+    CHALLENGES.get_or_init(Default::default)
+}
+
+/// Serves the latest ACME certificate, so a renewed one is used for new connections.
+pub struct CertResolver;
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        certified_key_slot().read().unwrap().clone()
+    }
+}
+
+/// Start answering challenges and make sure there is a certificate for `--acme-domain`,
+/// from the cache or else from the CA.
+pub async fn init() -> Result<(), String> {
+    let options = Opt::global();
+    let Some(domain) = &options.acme_domain else {
+        return Ok(());
+    };
+
+    let listener = listener::bind(options.acme_http_listen, "ACME challenge server")
+        .await
+        .map_err(|e| format!("cannot listen on {}: {e}", options.acme_http_listen))?;
+    tokio::spawn(serve_challenges(listener, options.acme_http_listen));
+
+    fs::create_dir_all(&options.acme_cache_dir)
+        .map_err(|e| format!("cannot create {}: {e}", options.acme_cache_dir))?;
+
+    match load_cached(domain) {
+        Ok(Some((pem, key))) if !expires_soon(&pem) => {
+            info!("Using the cached certificate for {domain}");
+            install(&pem, &key)
+        }
+        Ok(_) => renew(domain).await,
+        Err(e) => {
+            warn!("Ignoring the cached certificate for {domain}: {e}");
+            renew(domain).await
+        }
+    }
+}
+
+/// Renew the certificate before it expires, for as long as the proxy runs.
+pub async fn renew_periodically() {
+    let Some(domain) = &Opt::global().acme_domain else {
+        return;
+    };
+
+    loop {
+        sleep(RENEW_CHECK_INTERVAL).await;
+        let due = match load_cached(domain) {
+            Ok(Some((pem, _))) => expires_soon(&pem),
+            _ => true,
+        };
+        if due {
+            if let Err(e) = renew(domain).await {
+                warn!("Renewing the certificate for {domain} failed, retrying later: {e}");
+            }
+        }
+    }
+}
+
+async fn renew(domain: &str) -> Result<(), String> {
+    info!("Requesting a certificate for {domain} from the ACME CA");
+    let (pem, key) = obtain(domain).await?;
+
+    let key_pem = key.private_key_to_pem_pkcs8().map_err(|e| e.to_string())?;
+    let key_pem = String::from_utf8_lossy(&key_pem).into_owned();
+    write_private(&cache_path(&format!("{domain}.key")), &key_pem)?;
+    write_private(&cache_path(&format!("{domain}.crt")), &pem)?;
+
+    install(&pem, &key_pem)?;
+    info!("Certificate for {domain} issued and installed");
+    Ok(())
+}
+
+fn install(pem: &str, key_pem: &str) -> Result<(), String> {
+    let certs = read_certs(&mut pem.as_bytes()).map_err(|e| format!("certificate: {e}"))?;
+    let key = read_private_key(&mut key_pem.as_bytes()).map_err(|e| format!("key: {e}"))?;
+    *certified_key_slot().write().unwrap() = Some(certified_key(certs, key)?);
+    Ok(())
+}
+
+fn cache_path(name: &str) -> PathBuf {
+    PathBuf::from(&Opt::global().acme_cache_dir).join(name)
+}
+
+/// Certificate chain and key cached for `domain`, if both are there.
+fn load_cached(domain: &str) -> Result<Option<(String, String)>, String> {
+    let (cert_path, key_path) = (
+        cache_path(&format!("{domain}.crt")),
+        cache_path(&format!("{domain}.key")),
+    );
+    if !cert_path.exists() || !key_path.exists() {
+        return Ok(None);
+    }
+
+    let pem = fs::read_to_string(&cert_path).map_err(|e| e.to_string())?;
+    let key = fs::read_to_string(&key_path).map_err(|e| e.to_string())?;
+    Ok(Some((pem, key)))
+}
+
+fn expires_soon(pem: &str) -> bool {
+    let Ok(cert) = X509::from_pem(pem.as_bytes()) else {
+        return true;
+    };
+    let Ok(deadline) = Asn1Time::days_from_now(RENEW_BEFORE_DAYS) else {
+        return true;
+    };
+    cert.not_after() < deadline
+}
+
+/// Write a file only the proxy's user can read, it holds a private key or belongs with one.
+fn write_private(path: &PathBuf, contents: &str) -> Result<(), String> {
+    use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+    let tmp_path = path.with_extension("tmp");
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)
+        .map_err(|e| format!("cannot write {}: {e}", tmp_path.display()))?;
+    std::io::Write::write_all(&mut file, contents.as_bytes())
+        .map_err(|e| format!("cannot write {}: {e}", tmp_path.display()))?;
+    fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o600)).ok();
+    fs::rename(&tmp_path, path).map_err(|e| format!("cannot write {}: {e}", path.display()))
+}
+
+/// Answer the CA's HTTP-01 validation requests, and nothing else.
+async fn serve_challenges(listener: std::net::TcpListener, addr: SocketAddr) {
+    let make_service = make_service_fn(|_| async {
+        Ok::<_, Infallible>(service_fn(|req: Request<Body>| async move {
+            let key_authorization = req
+                .uri()
+                .path()
+                .strip_prefix(CHALLENGE_PATH)
+                .and_then(|token| challenges().lock().unwrap().get(token).cloned());
+
+            Ok::<_, Infallible>(match key_authorization {
+                Some(key_authorization) => Response::builder()
+                    .header(CONTENT_TYPE, "application/octet-stream")
+                    .body(Body::from(key_authorization))
+                    .unwrap(),
+                None => Response::builder()
+                    .status(StatusCode::NOT_FOUND)
+                    .body(Body::empty())
+                    .unwrap(),
+            })
+        }))
+    });
+
+    let server = match Server::from_tcp(listener) {
+        Ok(server) => server,
+        Err(e) => {
+            error!("ACME challenge server on {addr} failed: {e}");
+            return;
+        }
+    };
+    if let Err(e) = server.serve(make_service).await {
+        error!("ACME challenge server on {addr} failed: {e}");
+    }
+}
+
+/// Go through the ACME order flow (RFC 8555) for `domain`. Returns the PEM certificate
+/// chain and the certificate's new private key.
+async fn obtain(domain: &str) -> Result<(String, PKey<Private>), String> {
+    let mut acme = Acme::new(account_key()?).await?;
+    acme.register().await?;
+
+    let identifiers = Value::Array(vec![object([
+        ("type", "dns".into()),
+        ("value", domain.into()),
+    ])]);
+    let new_order = acme.url("newOrder")?;
+    let (order_url, order) = acme
+        .post(&new_order, Some(&object([("identifiers", identifiers)])))
+        .await?;
+    let order_url = order_url.ok_or("CA created the order without its URL")?;
+
+    let authorizations = order
+        .get("authorizations")
+        .and_then(Value::as_array)
+        .cloned()
+        .unwrap_or_default();
+    for authorization in authorizations.iter().filter_map(Value::as_str) {
+        acme.authorize(authorization).await?;
+    }
+
+    // The certificate key is new for every certificate, only the account key is kept
+    let key = generate_key()?;
+    let finalize = order
+        .get("finalize")
+        .and_then(Value::as_str)
+        .ok_or("Order without finalize URL")?;
+    let csr = b64url.encode(csr(domain, &key)?);
+    acme.post(finalize, Some(&object([("csr", csr.into())])))
+        .await?;
+
+    let order = acme.poll(&order_url, &["processing"]).await?;
+    let certificate = order
+        .get("certificate")
+        .and_then(Value::as_str)
+        .ok_or("Order is valid but has no certificate")?;
+    let pem = acme.post_raw(certificate, None).await?.2;
+    Ok((pem, key))
+}
+
+/// ACME account key, created on first use and kept in the cache so the account is reused.
+fn account_key() -> Result<PKey<Private>, String> {
+    let path = cache_path("account.key");
+    if let Ok(pem) = fs::read(&path) {
+        return PKey::private_key_from_pem(&pem).map_err(|e| format!("{}: {e}", path.display()));
+    }
+
+    let key = generate_key()?;
+    let pem = key.private_key_to_pem_pkcs8().map_err(|e| e.to_string())?;
+    write_private(&path, &String::from_utf8_lossy(&pem))?;
+    Ok(key)
+}
+
+fn generate_key() -> Result<PKey<Private>, String> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).map_err(|e| e.to_string())?;
+    EcKey::generate(&group)
+        .and_then(PKey::from_ec_key)
+        .map_err(|e| e.to_string())
+}
+
+fn csr(domain: &str, key: &PKey<Private>) -> Result<Vec<u8>, String> {
+    let build = || {
+        let mut name = X509NameBuilder::new()?;
+        name.append_entry_by_nid(Nid::COMMONNAME, domain)?;
+
+        let mut builder = X509Req::builder()?;
+        builder.set_subject_name(&name.build())?;
+        builder.set_pubkey(key)?;
+        let alt_names = SubjectAlternativeName::new()
+            .dns(domain)
+            .build(&builder.x509v3_context(None))?;
+        let mut extensions = Stack::new()?;
+        extensions.push(alt_names)?;
+        builder.add_extensions(&extensions)?;
+        builder.sign(key, MessageDigest::sha256())?;
+        builder.build().to_der()
+    };
+    build().map_err(|e: openssl::error::ErrorStack| e.to_string())
+}
+
+/// Client for one ACME CA, signing its requests with the account key.
+struct Acme {
+    client: Client<HttpsConnector<HttpConnector>>,
+    directory: Value,
+    key: PKey<Private>,
+    jwk: Value,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Acme {
+    async fn new(key: PKey<Private>) -> Result<Acme, String> {
+        let client = Client::builder().build::<_, Body>(HttpsConnector::new());
+        let directory_url = &Opt::global().acme_directory;
+        let request = Request::get(directory_url).body(Body::empty()).unwrap();
+        let response = client
+            .request(request)
+            .await
+            .map_err(|e| format!("{directory_url}: {e}"))?;
+        let body = hyper::body::to_bytes(response.into_body())
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(Acme {
+            client,
+            directory: json::parse(&String::from_utf8_lossy(&body))?,
+            jwk: jwk(&key)?,
+            key,
+            kid: None,
+            nonce: None,
+        })
+    }
+
+    fn url(&self, name: &str) -> Result<String, String> {
+        self.directory
+            .get(name)
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| format!("ACME directory has no {name}"))
+    }
+
+    /// Find or create the account, agreeing to the CA's terms.
+    async fn register(&mut self) -> Result<(), String> {
+        let contact = Opt::global()
+            .acme_email
+            .iter()
+            .map(|email| format!("mailto:{email}"))
+            .collect::<Vec<String>>();
+        let payload = object([
+            ("termsOfServiceAgreed", true.into()),
+            ("contact", contact.into()),
+        ]);
+        let new_account = self.url("newAccount")?;
+        let (location, _) = self.post(&new_account, Some(&payload)).await?;
+        self.kid = Some(location.ok_or("CA created the account without its URL")?);
+        Ok(())
+    }
+
+    /// Prove control of the domain of `authorization` with the HTTP-01 challenge.
+    async fn authorize(&mut self, authorization: &str) -> Result<(), String> {
+        let (_, authz) = self.post(authorization, None).await?;
+        if authz.get("status").and_then(Value::as_str) == Some("valid") {
+            return Ok(());
+        }
+
+        let challenge = authz
+            .get("challenges")
+            .and_then(Value::as_array)
+            .and_then(|challenges| {
+                challenges.iter().find(|challenge| {
+                    challenge.get("type").and_then(Value::as_str) == Some("http-01")
+                })
+            })
+            .ok_or("CA offered no http-01 challenge")?;
+        let (Some(token), Some(url)) = (
+            challenge.get("token").and_then(Value::as_str),
+            challenge.get("url").and_then(Value::as_str),
+        ) else {
+            return Err("Malformed http-01 challenge".to_string());
+        };
+
+        let thumbprint = b64url.encode(sha256(self.jwk.to_string().as_bytes()));
+        challenges()
+            .lock()
+            .unwrap()
+            .insert(token.to_string(), format!("{token}.{thumbprint}"));
+
+        let result = async {
+            self.post(url, Some(&object([]))).await?;
+            self.poll(authorization, &["pending"]).await
+        }
+        .await;
+        challenges().lock().unwrap().remove(token);
+        result.map(|_| ())
+    }
+
+    /// Fetch `url` until its status leaves `waiting`. Errors unless it ends up valid.
+    async fn poll(&mut self, url: &str, waiting: &[&str]) -> Result<Value, String> {
+        for _ in 0..POLL_ATTEMPTS {
+            let (_, resource) = self.post(url, None).await?;
+            match resource.get("status").and_then(Value::as_str) {
+                Some("valid") => return Ok(resource),
+                Some(status) if waiting.contains(&status) => sleep(POLL_INTERVAL).await,
+                status => {
+                    let error = resource
+                        .get("error")
+                        .map(Value::to_string)
+                        .unwrap_or_default();
+                    return Err(format!("{url} is {} {error}", status.unwrap_or("unknown")));
+                }
+            }
+        }
+        Err(format!("{url} didn't become valid in time"))
+    }
+
+    /// Signed POST with a JSON payload, or POST-as-GET without one. Returns the
+    /// Location header and the response document.
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> Result<(Option<String>, Value), String> {
+        let (location, _, body) = self.post_raw(url, payload).await?;
+        Ok((location, json::parse(&body)?))
+    }
+
+    async fn post_raw(
+        &mut self,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> Result<(Option<String>, StatusCode, String), String> {
+        // A stale nonce is rejected with badNonce, and the rejection carries a fresh one
+        let mut retried = false;
+        loop {
+            let body = self.sign(url, payload).await?;
+            let request = Request::builder()
+                .method(Method::POST)
+                .uri(url)
+                .header(CONTENT_TYPE, "application/jose+json")
+                .body(Body::from(body))
+                .unwrap();
+            let response = self
+                .client
+                .request(request)
+                .await
+                .map_err(|e| format!("{url}: {e}"))?;
+
+            let header = |name| {
+                response
+                    .headers()
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .map(str::to_string)
+            };
+            self.nonce = header("replay-nonce");
+            let location = header(LOCATION.as_str());
+            let status = response.status();
+            let body = hyper::body::to_bytes(response.into_body())
+                .await
+                .map_err(|e| e.to_string())?;
+            let body = String::from_utf8_lossy(&body).into_owned();
+
+            if status.is_success() {
+                return Ok((location, status, body));
+            }
+            if !retried && body.contains("urn:ietf:params:acme:error:badNonce") {
+                retried = true;
+                continue;
+            }
+            return Err(format!("{url} answered {status}: {}", body.trim()));
+        }
+    }
+
+    /// JWS in flattened JSON serialization, with the account URL once there is one.
+    async fn sign(&mut self, url: &str, payload: Option<&Value>) -> Result<String, String> {
+        let nonce = match self.nonce.take() {
+            Some(nonce) => nonce,
+            None => self.new_nonce().await?,
+        };
+        let protected = match &self.kid {
+            Some(kid) => object([
+                ("alg", "ES256".into()),
+                ("kid", kid.as_str().into()),
+                ("nonce", nonce.into()),
+                ("url", url.into()),
+            ]),
+            None => object([
+                ("alg", "ES256".into()),
+                ("jwk", self.jwk.clone()),
+                ("nonce", nonce.into()),
+                ("url", url.into()),
+            ]),
+        };
+
+        let protected = b64url.encode(protected.to_string());
+        let payload = payload
+            .map(|payload| b64url.encode(payload.to_string()))
+            .unwrap_or_default();
+        let signature = es256(&self.key, format!("{protected}.{payload}").as_bytes())?;
+
+        Ok(object([
+            ("protected", protected.into()),
+            ("payload", payload.into()),
+            ("signature", b64url.encode(signature).into()),
+        ])
+        .to_string())
+    }
+
+    async fn new_nonce(&self) -> Result<String, String> {
+        let url = self.url("newNonce")?;
+        let request = Request::head(&url).body(Body::empty()).unwrap();
+        let response = self
+            .client
+            .request(request)
+            .await
+            .map_err(|e| format!("{url}: {e}"))?;
+        response
+            .headers()
+            .get("replay-nonce")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+            .ok_or_else(|| format!("{url} answered without a nonce"))
+    }
+}
+
+/// Public part of a P-256 account key as a JWK, with its members in the order the
+/// thumbprint (RFC 7638) needs.
+fn jwk(key: &PKey<Private>) -> Result<Value, String> {
+    let build = || {
+        let ec_key = key.ec_key()?;
+        let mut context = BigNumContext::new()?;
+        let (mut x, mut y) = (BigNum::new()?, BigNum::new()?);
+        ec_key
+            .public_key()
+            .affine_coordinates(ec_key.group(), &mut x, &mut y, &mut context)?;
+        Ok((x.to_vec_padded(32)?, y.to_vec_padded(32)?))
+    };
+    let (x, y) = build().map_err(|e: openssl::error::ErrorStack| e.to_string())?;
+
+    Ok(object([
+        ("crv", "P-256".into()),
+        ("kty", "EC".into()),
+        ("x", b64url.encode(x).into()),
+        ("y", b64url.encode(y).into()),
+    ]))
+}
+
+/// ECDSA P-256 SHA-256 signature as JWS wants it: r and s, 32 bytes each.
+fn es256(key: &PKey<Private>, data: &[u8]) -> Result<Vec<u8>, String> {
+    let sign = || {
+        let signature = EcdsaSig::sign(&sha256(data), &*key.ec_key()?)?;
+        let mut raw = signature.r().to_vec_padded(32)?;
+        raw.extend(signature.s().to_vec_padded(32)?);
+        Ok(raw)
+    };
+    sign().map_err(|e: openssl::error::ErrorStack| e.to_string())
+}
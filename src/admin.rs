@@ -0,0 +1,681 @@
+use crate::bans;
+use crate::explain::{explain, Hypothetical};
+use crate::files;
+use crate::hostmatch;
+use crate::http::Proxy;
+use crate::https::{load_certs, load_private_key};
+use crate::journal;
+use crate::json::{self, object, Value};
+use crate::listener;
+use crate::maintenance;
+use crate::metrics;
+use crate::options::Opt;
+use crate::reload;
+use crate::sessions;
+use crate::stats;
+use crate::tunnel;
+use crate::usage;
+use crate::users::{UserError, UserStore};
+use crate::utils::{formatted_time, parse_query, to_sha256};
+
+use std::convert::Infallible;
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use hyper::body::HttpBody;
+use hyper::header::{AUTHORIZATION, CONTENT_DISPOSITION, CONTENT_TYPE, WWW_AUTHENTICATE};
+use hyper::server::conn::Http;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, Server, StatusCode};
+
+use log::{info, warn};
+use rustls::server::AllowAnyAnonymousOrAuthenticatedClient;
+use rustls::{RootCertStore, ServerConfig};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+
+// Admin requests are small JSON documents, anything bigger is refused unread
+const MAX_REQUEST_BODY: u64 = 64 * 1024;
+
+const LOG_VIEWER_PATH: &str = "/v1/ui/log";
+const LOG_VIEWER: &str = include_str!("log_viewer.html");
+
+/// What an admin API client may do. `Read` only sees state, `Admin` may change it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    Read,
+    Admin,
+}
+
+impl Role {
+    fn permits(self, method: &Method, path: &str) -> bool {
+        match self {
+            // Explaining a request changes nothing, even though it is a POST
+            Role::Read => {
+                method == Method::GET
+                    || method == Method::HEAD
+                    || (method == Method::POST && path.trim_end_matches('/') == "/v1/explain")
+            }
+            Role::Admin => true,
+        }
+    }
+}
+
+impl FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim() {
+            "read" => Ok(Role::Read),
+            "admin" => Ok(Role::Admin),
+            role => Err(format!(
+                "Unknown admin role '{role}', expected 'read' or 'admin'"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Role::Read => write!(f, "read"),
+            Role::Admin => write!(f, "admin"),
+        }
+    }
+}
+
+/// Bearer token for the admin API, given as `role:token`.
+#[derive(Debug, Clone)]
+pub struct AdminToken {
+    pub role: Role,
+    token_hash: String,
+}
+
+impl FromStr for AdminToken {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (role, token) = s
+            .split_once(':')
+            .ok_or_else(|| "Expected role:token, e.g. 'admin:mysecrettoken'".to_string())?;
+        let token = token.trim();
+        if token.is_empty() {
+            return Err("Admin token must not be empty".to_string());
+        }
+
+        Ok(AdminToken {
+            role: role.parse()?,
+            token_hash: to_sha256(token),
+        })
+    }
+}
+
+/// Serve the admin API, used to manage the proxy programmatically. Served over TLS when
+/// `--admin-cert` is set, and then also authenticates clients by certificate if
+/// `--admin-client-ca` is set.
+pub async fn start_admin(listen_addr: SocketAddr) -> Result<(), Box<dyn std::error::Error>> {
+    let options = Opt::global();
+    let listener = listener::bind(listen_addr, "Admin API").await?;
+
+    let (Some(cert), Some(key)) = (&options.admin_cert, &options.admin_key) else {
+        let make_service = make_service_fn(move |_| async move {
+            Ok::<_, Infallible>(service_fn(move |req| async move {
+                Ok::<_, Infallible>(handle(req, None).await)
+            }))
+        });
+
+        return Server::from_tcp(listener)?
+            .serve(make_service)
+            .await
+            .map_err(Into::into);
+    };
+
+    let acceptor = TlsAcceptor::from(Arc::new(tls_config(cert, key)?));
+    let listener = TcpListener::from_std(listener)?;
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        let acceptor = acceptor.clone();
+
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    warn!("[Admin API] TLS handshake with {addr} failed: {e}");
+                    return;
+                }
+            };
+
+            // Only certificates signed by --admin-client-ca get this far
+            let certificate_role = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .filter(|certificates| !certificates.is_empty())
+                .map(|_| Opt::global().admin_client_role);
+
+            let service = service_fn(move |req| async move {
+                Ok::<_, Infallible>(handle(req, certificate_role).await)
+            });
+            if let Err(e) = Http::new().serve_connection(stream, service).await {
+                warn!("[Admin API] Connection from {addr} failed: {e}");
+            }
+        });
+    }
+}
+
+fn tls_config(cert: &str, key: &str) -> std::io::Result<ServerConfig> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+
+    let builder = match &Opt::global().admin_client_ca {
+        Some(client_ca) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in load_certs(client_ca)? {
+                roots
+                    .add(&certificate)
+                    .map_err(|e| IoError::new(ErrorKind::InvalidInput, e.to_string()))?;
+            }
+            builder.with_client_cert_verifier(AllowAnyAnonymousOrAuthenticatedClient::new(roots))
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    builder
+        .with_single_cert(load_certs(cert)?, load_private_key(key)?)
+        .map_err(|e| IoError::new(ErrorKind::InvalidInput, e))
+}
+
+/// Role of the client, from its bearer token or else its client certificate.
+fn authenticate(req: &Request<Body>, certificate_role: Option<Role>) -> Option<Role> {
+    let token = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().strip_prefix("Bearer "))
+        .map(|token| to_sha256(token.trim()));
+
+    match token {
+        Some(token_hash) => Opt::global()
+            .admin_token
+            .iter()
+            .find(|admin_token| admin_token.token_hash == token_hash)
+            .map(|admin_token| admin_token.role),
+        None => certificate_role,
+    }
+}
+
+async fn handle(req: Request<Body>, certificate_role: Option<Role>) -> Response<Body> {
+    let method = req.method().clone();
+    let path = req.uri().path().to_string();
+    let role = authenticate(&req, certificate_role);
+
+    // Everything outside the API is the public static site, if one is configured
+    let static_dir = Opt::global().admin_static_dir.as_deref();
+    let response = match (role, static_dir) {
+        // The page holds no data, it asks for the token and fetches the log itself
+        _ if method == Method::GET && path == LOG_VIEWER_PATH => html_response(LOG_VIEWER),
+        (_, Some(static_dir)) if !is_api_path(&path) => files::serve(static_dir, &req).await,
+        (None, _) => {
+            let mut response = json_response(
+                StatusCode::UNAUTHORIZED,
+                object([("error", "Authentication required".into())]),
+            );
+            response
+                .headers_mut()
+                .insert(WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+            response
+        }
+        (Some(role), _) if !role.permits(&method, &path) => json_response(
+            StatusCode::FORBIDDEN,
+            object([("error", format!("Role '{role}' may not {method}").into())]),
+        ),
+        (Some(_), _) => match route(req).await {
+            Ok(response) => response,
+            Err((status, message)) => json_response(status, object([("error", message.into())])),
+        },
+    };
+
+    let time = formatted_time();
+    let role = role
+        .map(|role| role.to_string())
+        .unwrap_or_else(|| "-".to_string());
+    info!(
+        "[{time}] [Admin API] {method} {path} role={role} -> {}",
+        response.status().as_u16()
+    );
+    response
+}
+
+type ApiResult = Result<Response<Body>, (StatusCode, String)>;
+
+fn is_api_path(path: &str) -> bool {
+    matches!(
+        path.trim_start_matches('/').split('/').next(),
+        Some("v1" | "metrics")
+    )
+}
+
+async fn route(req: Request<Body>) -> ApiResult {
+    let segments = req
+        .uri()
+        .path()
+        .trim_matches('/')
+        .split('/')
+        .map(str::to_string)
+        .collect::<Vec<String>>();
+    let segments = segments.iter().map(String::as_str).collect::<Vec<&str>>();
+
+    match (req.method().clone(), segments.as_slice()) {
+        (Method::GET, ["v1", "users"]) => list_users(),
+        (Method::POST, ["v1", "users"]) => create_user(read_json(req).await?),
+        (Method::GET, ["v1", "users", login]) => get_user(login),
+        (Method::PATCH, ["v1", "users", login]) => update_user(login, read_json(req).await?),
+        (Method::DELETE, ["v1", "users", login]) => delete_user(login, req.uri().query()),
+        (Method::GET, ["v1", "usage"]) => Ok(json_response(StatusCode::OK, usage::to_json())),
+        (Method::GET, ["v1", "usage", login]) => get_usage(login),
+        (Method::DELETE, ["v1", "usage", login]) => reset_usage(login),
+        (Method::GET, ["v1", "bans"]) => Ok(json_response(StatusCode::OK, bans::to_json())),
+        (Method::DELETE, ["v1", "bans"]) => clear_bans(),
+        (Method::DELETE, ["v1", "bans", ip]) => clear_ban(ip),
+        (Method::GET, ["v1", "tunnels"]) => Ok(json_response(StatusCode::OK, tunnel::to_json())),
+        (Method::DELETE, ["v1", "tunnels", id]) => kill_tunnel(id),
+        (Method::POST, ["v1", "explain"]) => explain_request(read_json(req).await?).await,
+        (Method::GET, ["v1", "log"]) => query_log(req.uri().query()),
+        (Method::GET, ["v1", "hosts"]) => list_hosts(),
+        (Method::POST, ["v1", "hosts"]) => add_host(read_json(req).await?),
+        (Method::DELETE, ["v1", "hosts", host]) => remove_host(host),
+        (Method::GET, ["v1", "maintenance"]) => {
+            Ok(json_response(StatusCode::OK, maintenance::to_json()))
+        }
+        (Method::POST, ["v1", "maintenance"]) => start_maintenance(read_json(req).await?),
+        (Method::DELETE, ["v1", "maintenance"]) => end_maintenance(),
+        (Method::GET, ["v1", "stats"]) => Ok(json_response(StatusCode::OK, stats::to_json())),
+        (Method::GET, ["metrics"]) => Ok(Response::builder()
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(metrics::to_prometheus()))
+            .unwrap()),
+        (
+            _,
+            ["v1", "users"]
+            | ["v1", "users", _]
+            | ["v1", "hosts"]
+            | ["v1", "hosts", _]
+            | ["v1", "maintenance"]
+            | ["v1", "stats"]
+            | ["v1", "usage"]
+            | ["v1", "bans"]
+            | ["v1", "bans", _]
+            | ["v1", "usage", _]
+            | ["v1", "tunnels"]
+            | ["v1", "tunnels", _]
+            | ["v1", "explain"]
+            | ["v1", "log"]
+            | ["metrics"],
+        ) => Err((
+            StatusCode::METHOD_NOT_ALLOWED,
+            "Method not allowed".to_string(),
+        )),
+        _ => Err((StatusCode::NOT_FOUND, "Not found".to_string())),
+    }
+}
+
+fn user_store() -> Result<&'static UserStore, (StatusCode, String)> {
+    UserStore::global().ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "No user backend configured, start the proxy with --users-file".to_string(),
+    ))
+}
+
+fn list_users() -> ApiResult {
+    let users = user_store()?
+        .list()
+        .iter()
+        .map(|user| user.to_json())
+        .collect::<Vec<Value>>();
+    Ok(json_response(
+        StatusCode::OK,
+        object([("users", Value::Array(users))]),
+    ))
+}
+
+fn create_user(request: Value) -> ApiResult {
+    let (user, password) = user_store()?.create(&request).map_err(error_status)?;
+    info!("User {} created", user.login);
+
+    // The password is only ever shown here, the store keeps a hash
+    let mut body = user.to_json();
+    if let Value::Object(fields) = &mut body {
+        fields.insert("password".to_string(), password.into());
+    }
+    Ok(json_response(StatusCode::CREATED, body))
+}
+
+fn get_user(login: &str) -> ApiResult {
+    let user = user_store()?
+        .get(login)
+        .ok_or_else(|| error_status(UserError::NotFound))?;
+    Ok(json_response(StatusCode::OK, user.to_json()))
+}
+
+fn update_user(login: &str, request: Value) -> ApiResult {
+    let user = user_store()?
+        .update(login, &request)
+        .map_err(error_status)?;
+    info!("User {login} updated");
+
+    // A new password or a disabled account ends the sessions of the old one
+    if request.get("password").is_some() || !user.is_active() {
+        let sessions = sessions::revoke(login);
+        if sessions > 0 {
+            info!("Ended {sessions} sessions of user {login}");
+        }
+    }
+    Ok(json_response(StatusCode::OK, user.to_json()))
+}
+
+/// Delete a user and end its sessions. With `purge=true` its kept log entries and usage go too, leaving
+/// nothing about it in the proxy, also for logins that aren't managed users.
+fn delete_user(login: &str, query: Option<&str>) -> ApiResult {
+    let purge = parse_query(query).get("purge").map(String::as_str) == Some("true");
+    // Logins from --auth can be purged without a users file
+    let deleted = match UserStore::global() {
+        None if purge => Err(UserError::NotFound),
+        _ => user_store()?.delete(login),
+    };
+    match deleted {
+        Ok(()) => info!("User {login} deleted"),
+        Err(UserError::NotFound) if purge => {}
+        Err(e) => return Err(error_status(e)),
+    }
+    let sessions = sessions::revoke(login);
+
+    if purge {
+        let entries = journal::purge(login);
+        usage::purge(login).map_err(|e| {
+            (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to save usage: {e}"),
+            )
+        })?;
+        info!("User {login} purged: {sessions} sessions, {entries} log entries and its usage");
+    }
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap())
+}
+
+/// What a login transferred, with the quota of a managed user and how much of it is used.
+fn get_usage(login: &str) -> ApiResult {
+    let counters = usage::counters(login);
+    let mut body = counters.to_json();
+    if let (Value::Object(fields), Some(user)) = (
+        &mut body,
+        UserStore::global().and_then(|store| store.get(login)),
+    ) {
+        fields.insert("quota".to_string(), user.quota.into());
+        fields.insert(
+            "quota_period".to_string(),
+            user.quota_period.map(|period| period.to_string()).into(),
+        );
+        fields.insert(
+            "quota_used".to_string(),
+            user.quota.map(|_| counters.used(user.quota_period)).into(),
+        );
+    }
+    Ok(json_response(StatusCode::OK, body))
+}
+
+/// Start counting a login's traffic over, lifting a quota it used up.
+fn reset_usage(login: &str) -> ApiResult {
+    match usage::purge(login) {
+        Ok(true) => {
+            info!("Usage of {login} reset");
+            Ok(Response::builder()
+                .status(StatusCode::NO_CONTENT)
+                .body(Body::empty())
+                .unwrap())
+        }
+        Ok(false) => Err((StatusCode::NOT_FOUND, "No usage counted".to_string())),
+        Err(e) => Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Failed to save usage: {e}"),
+        )),
+    }
+}
+
+/// Lift the ban of a client address before it runs out.
+fn clear_ban(ip: &str) -> ApiResult {
+    let ip = ip.parse::<IpAddr>().map_err(|_| {
+        (
+            StatusCode::BAD_REQUEST,
+            format!("Invalid IP address '{ip}'"),
+        )
+    })?;
+    if !bans::clear(ip) {
+        return Err((StatusCode::NOT_FOUND, format!("{ip} is not banned")));
+    }
+    info!("Ban of {ip} lifted");
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap())
+}
+
+fn clear_bans() -> ApiResult {
+    let count = bans::clear_all();
+    info!("{count} bans lifted");
+    Ok(json_response(
+        StatusCode::OK,
+        object([("cleared", (count as u64).into())]),
+    ))
+}
+
+/// Hosts the main listeners allow, an empty list allows every host.
+fn list_hosts() -> ApiResult {
+    let hosts = reload::current()
+        .map(|proxy| proxy.allowed_hosts.clone())
+        .unwrap_or_else(|| Proxy::from_opt(Opt::global()).allowed_hosts);
+    Ok(json_response(
+        StatusCode::OK,
+        object([("hosts", hosts.into())]),
+    ))
+}
+
+fn add_host(request: Value) -> ApiResult {
+    let host = request
+        .get("host")
+        .and_then(Value::as_str)
+        .map(str::trim)
+        .filter(|host| !host.is_empty() && !host.contains(','))
+        .ok_or((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Expected a host pattern in 'host'".to_string(),
+        ))?
+        .to_string();
+    hostmatch::check(&host).map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e))?;
+
+    let mut added = false;
+    reload::update(|proxy| {
+        if !proxy.allowed_hosts.contains(&host) {
+            proxy.allowed_hosts.push(host.clone());
+            added = true;
+        }
+    });
+    if !added {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("Host {host} is already allowed"),
+        ));
+    }
+    info!("Allowed host {host} added");
+    Ok(json_response(
+        StatusCode::CREATED,
+        object([("host", host.into())]),
+    ))
+}
+
+/// Remove an allowed host. The last one stays, without it every host would be allowed.
+fn remove_host(host: &str) -> ApiResult {
+    let mut result = Err((StatusCode::NOT_FOUND, "Host not found".to_string()));
+    reload::update(|proxy| {
+        let Some(index) = proxy
+            .allowed_hosts
+            .iter()
+            .position(|allowed| allowed == host)
+        else {
+            return;
+        };
+        if proxy.allowed_hosts.len() == 1 {
+            result = Err((
+                StatusCode::CONFLICT,
+                "The last allowed host can't be removed, an empty list allows every host"
+                    .to_string(),
+            ));
+            return;
+        }
+        proxy.allowed_hosts.remove(index);
+        result = Ok(());
+    });
+    result?;
+
+    info!("Allowed host {host} removed");
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap())
+}
+
+/// Answer new requests with the maintenance page, tunnels already open are kept.
+fn start_maintenance(request: Value) -> ApiResult {
+    let retry_after = match request.get("retry_after") {
+        None => None,
+        Some(value) => Some(value.as_u64().ok_or((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Expected a number of seconds in 'retry_after'".to_string(),
+        ))?),
+    };
+    let window = maintenance::start(retry_after);
+    warn!(
+        "Maintenance on, new requests are answered with 503 and Retry-After: {}",
+        window.retry_after
+    );
+    Ok(json_response(StatusCode::OK, maintenance::to_json()))
+}
+
+fn end_maintenance() -> ApiResult {
+    if maintenance::end().is_none() {
+        return Err((StatusCode::NOT_FOUND, "Maintenance is off".to_string()));
+    }
+    warn!("Maintenance off, new requests are served again");
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap())
+}
+
+fn kill_tunnel(id: &str) -> ApiResult {
+    let killed = id.parse::<u64>().map(tunnel::kill).unwrap_or(false);
+    if !killed {
+        return Err((StatusCode::NOT_FOUND, "Tunnel not found".to_string()));
+    }
+    info!("Tunnel {id} killed");
+    Ok(Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .body(Body::empty())
+        .unwrap())
+}
+
+async fn explain_request(request: Value) -> ApiResult {
+    let request =
+        Hypothetical::from_json(&request).map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    let trace = explain(&request)
+        .await
+        .map_err(|e| (StatusCode::UNPROCESSABLE_ENTITY, e))?;
+    Ok(json_response(StatusCode::OK, trace))
+}
+
+fn query_log(query: Option<&str>) -> ApiResult {
+    let query = parse_query(query);
+    let filter = journal::Filter::from_query(&query).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+    let entries = journal::query(&filter);
+
+    match query.get("format").map(String::as_str) {
+        None | Some("json") => Ok(json_response(StatusCode::OK, journal::to_json(&entries))),
+        Some("csv") => Ok(Response::builder()
+            .header(CONTENT_TYPE, "text/csv; charset=utf-8")
+            .header(
+                CONTENT_DISPOSITION,
+                "attachment; filename=\"proxerver-log.csv\"",
+            )
+            .body(Body::from(journal::to_csv(&entries)))
+            .unwrap()),
+        Some(format) => Err((
+            StatusCode::BAD_REQUEST,
+            format!("Unknown format '{format}', expected 'json' or 'csv'"),
+        )),
+    }
+}
+
+fn error_status(error: UserError) -> (StatusCode, String) {
+    let status = match error {
+        UserError::NotFound => StatusCode::NOT_FOUND,
+        UserError::AlreadyExists => StatusCode::CONFLICT,
+        UserError::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+        UserError::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+    };
+    (status, error.to_string())
+}
+
+async fn read_json(req: Request<Body>) -> Result<Value, (StatusCode, String)> {
+    let too_large = || {
+        (
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Request body too large".to_string(),
+        )
+    };
+
+    let mut body = req.into_body();
+    if body.size_hint().lower() > MAX_REQUEST_BODY {
+        return Err(too_large());
+    }
+
+    let mut buffer = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+        if buffer.len() + chunk.len() > MAX_REQUEST_BODY as usize {
+            return Err(too_large());
+        }
+        buffer.extend_from_slice(&chunk);
+    }
+
+    let text = String::from_utf8(buffer)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Body is not UTF-8".to_string()))?;
+    let value = json::parse(&text).map_err(|e| (StatusCode::BAD_REQUEST, e))?;
+
+    match value {
+        Value::Object(_) => Ok(value),
+        _ => Err((
+            StatusCode::BAD_REQUEST,
+            "Expected a JSON object".to_string(),
+        )),
+    }
+}
+
+fn html_response(body: &'static str) -> Response<Body> {
+    Response::builder()
+        .header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .body(Body::from(body))
+        .unwrap()
+}
+
+fn json_response(status: StatusCode, body: Value) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(format!("{body}\n")))
+        .unwrap()
+}
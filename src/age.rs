@@ -0,0 +1,326 @@
+use base64::{engine::general_purpose::STANDARD_NO_PAD as b64, Engine};
+use openssl::derive::Deriver;
+use openssl::pkey::{Id, PKey};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305};
+use ring::{hkdf, hmac};
+
+const VERSION_LINE: &str = "age-encryption.org/v1";
+const IDENTITY_HRP: &str = "age-secret-key-";
+const BECH32_CHARSET: &[u8] = b"qpzry9x8gf2tvdw0s3jn54khce6mua7l";
+// Stanza bodies are wrapped at 64 columns of base64
+const BODY_LINE_LEN: usize = 64;
+const CHUNK_LEN: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+// 2^22 makes scrypt use 4 GiB, age itself defaults to 2^18
+const MAX_SCRYPT_LOG_N: u8 = 22;
+
+/// Whether `data` is an age encrypted file.
+pub fn is_encrypted(data: &[u8]) -> bool {
+    data.starts_with(VERSION_LINE.as_bytes())
+}
+
+/// X25519 secret keys from an age identity file, one `AGE-SECRET-KEY-1...` per line.
+pub fn parse_identities(text: &str) -> Result<Vec<[u8; 32]>, String> {
+    let identities = text
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(decode_identity)
+        .collect::<Result<Vec<[u8; 32]>, String>>()?;
+
+    if identities.is_empty() {
+        return Err("No identities found".to_string());
+    }
+    Ok(identities)
+}
+
+fn decode_identity(line: &str) -> Result<[u8; 32], String> {
+    let invalid = || "Invalid age identity".to_string();
+
+    let line = line.to_ascii_lowercase();
+    let (hrp, data) = line.rsplit_once('1').ok_or_else(invalid)?;
+    if hrp != IDENTITY_HRP {
+        return Err("Not an age X25519 identity".to_string());
+    }
+    let values = data
+        .bytes()
+        .map(|c| BECH32_CHARSET.iter().position(|&v| v == c).map(|v| v as u8))
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(invalid)?;
+    if values.len() < 6 || bech32_polymod(hrp, &values) != 1 {
+        return Err(invalid());
+    }
+
+    // Regroup the 5-bit values, without the checksum, into bytes
+    let mut bytes = Vec::new();
+    let (mut acc, mut bits) = (0u32, 0);
+    for value in &values[..values.len() - 6] {
+        acc = (acc << 5) | *value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((acc >> bits) as u8);
+        }
+    }
+    bytes.try_into().map_err(|_| invalid())
+}
+
+fn bech32_polymod(hrp: &str, values: &[u8]) -> u32 {
+    const GENERATOR: [u32; 5] = [0x3b6a57b2, 0x26508e6d, 0x1ea119fa, 0x3d4233dd, 0x2a1462b3];
+
+    let expanded = hrp
+        .bytes()
+        .map(|c| c >> 5)
+        .chain([0])
+        .chain(hrp.bytes().map(|c| c & 31))
+        .chain(values.iter().copied());
+
+    let mut checksum = 1u32;
+    for value in expanded {
+        let top = checksum >> 25;
+        checksum = ((checksum & 0x1ff_ffff) << 5) ^ value as u32;
+        for (i, generator) in GENERATOR.iter().enumerate() {
+            if (top >> i) & 1 == 1 {
+                checksum ^= generator;
+            }
+        }
+    }
+    checksum
+}
+
+struct Stanza {
+    kind: String,
+    args: Vec<String>,
+    body: Vec<u8>,
+}
+
+struct Header<'a> {
+    stanzas: Vec<Stanza>,
+    // Length of the header the MAC covers, up to and including "---"
+    mac_input_len: usize,
+    mac: Vec<u8>,
+    payload: &'a [u8],
+}
+
+/// Decrypt an age v1 file encrypted to one of `identities` or, for passphrase
+/// encrypted files, with the passphrase `passphrase` returns.
+pub fn decrypt(
+    data: &[u8],
+    identities: &[[u8; 32]],
+    passphrase: &dyn Fn() -> Result<String, String>,
+) -> Result<Vec<u8>, String> {
+    let Header {
+        stanzas,
+        mac_input_len,
+        mac,
+        payload,
+    } = parse_header(data)?;
+
+    let is_scrypt = stanzas.iter().any(|stanza| stanza.kind == "scrypt");
+    if is_scrypt && stanzas.len() != 1 {
+        return Err("A passphrase encrypted file must have a single recipient".to_string());
+    }
+
+    let file_key = if is_scrypt {
+        unwrap_scrypt(&stanzas[0], &passphrase()?)?
+    } else {
+        stanzas
+            .iter()
+            .filter(|stanza| stanza.kind == "X25519")
+            .find_map(|stanza| {
+                identities
+                    .iter()
+                    .find_map(|identity| unwrap_x25519(stanza, identity).ok())
+            })
+            .ok_or("The file isn't encrypted to any of the identities")?
+    };
+
+    let mac_key = hmac::Key::new(hmac::HMAC_SHA256, &hkdf_sha256(&[], &file_key, b"header"));
+    hmac::verify(&mac_key, &data[..mac_input_len], &mac)
+        .map_err(|_| "Header MAC mismatch, the file was altered or the key is wrong")?;
+
+    decrypt_payload(&file_key, payload)
+}
+
+fn parse_header(data: &[u8]) -> Result<Header<'_>, String> {
+    let malformed = || "Malformed age header".to_string();
+
+    let mut pos = 0;
+    let next_line = |pos: &mut usize| -> Result<String, String> {
+        let end = data[*pos..]
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or_else(malformed)?;
+        let line = std::str::from_utf8(&data[*pos..*pos + end])
+            .map_err(|_| malformed())?
+            .to_string();
+        *pos += end + 1;
+        Ok(line)
+    };
+
+    if next_line(&mut pos)? != VERSION_LINE {
+        return Err("Unsupported age version".to_string());
+    }
+
+    let mut stanzas = Vec::new();
+    loop {
+        let line_start = pos;
+        let line = next_line(&mut pos)?;
+
+        if let Some(mac) = line.strip_prefix("--- ") {
+            let mac = b64.decode(mac).map_err(|_| malformed())?;
+            if stanzas.is_empty() || mac.len() != 32 {
+                return Err(malformed());
+            }
+            return Ok(Header {
+                stanzas,
+                mac_input_len: line_start + 3,
+                mac,
+                payload: &data[pos..],
+            });
+        }
+
+        let mut parts = line.strip_prefix("-> ").ok_or_else(malformed)?.split(' ');
+        let kind = parts
+            .next()
+            .filter(|kind| !kind.is_empty())
+            .ok_or_else(malformed)?;
+        let args = parts.map(str::to_string).collect();
+
+        let mut body = String::new();
+        loop {
+            let body_line = next_line(&mut pos)?;
+            if body_line.len() > BODY_LINE_LEN {
+                return Err(malformed());
+            }
+            body.push_str(&body_line);
+            if body_line.len() < BODY_LINE_LEN {
+                break;
+            }
+        }
+
+        stanzas.push(Stanza {
+            kind: kind.to_string(),
+            args,
+            body: b64.decode(body).map_err(|_| malformed())?,
+        });
+    }
+}
+
+fn unwrap_x25519(stanza: &Stanza, identity: &[u8; 32]) -> Result<[u8; 16], String> {
+    let share = match stanza.args.as_slice() {
+        [share] => b64.decode(share).map_err(|e| e.to_string())?,
+        _ => return Err("Malformed X25519 stanza".to_string()),
+    };
+
+    let private =
+        PKey::private_key_from_raw_bytes(identity, Id::X25519).map_err(|e| e.to_string())?;
+    let peer = PKey::public_key_from_raw_bytes(&share, Id::X25519).map_err(|e| e.to_string())?;
+    let mut deriver = Deriver::new(&private).map_err(|e| e.to_string())?;
+    deriver.set_peer(&peer).map_err(|e| e.to_string())?;
+    let shared = deriver.derive_to_vec().map_err(|e| e.to_string())?;
+    if shared.iter().all(|&b| b == 0) {
+        return Err("Low order X25519 share".to_string());
+    }
+
+    let recipient = private.raw_public_key().map_err(|e| e.to_string())?;
+    let salt = [share.as_slice(), recipient.as_slice()].concat();
+    let wrap_key = hkdf_sha256(&salt, &shared, b"age-encryption.org/v1/X25519");
+    unwrap_file_key(&wrap_key, &stanza.body)
+}
+
+fn unwrap_scrypt(stanza: &Stanza, passphrase: &str) -> Result<[u8; 16], String> {
+    let (salt, log_n) = match stanza.args.as_slice() {
+        [salt, log_n] => (
+            b64.decode(salt).map_err(|e| e.to_string())?,
+            log_n.parse::<u8>().map_err(|e| e.to_string())?,
+        ),
+        _ => return Err("Malformed scrypt stanza".to_string()),
+    };
+    if salt.len() != 16 || log_n == 0 || log_n > MAX_SCRYPT_LOG_N {
+        return Err("Unsupported scrypt parameters".to_string());
+    }
+
+    let salt = [b"age-encryption.org/v1/scrypt".as_slice(), &salt].concat();
+    let n = 1u64 << log_n;
+    let mut wrap_key = [0u8; 32];
+    openssl::pkcs5::scrypt(
+        passphrase.as_bytes(),
+        &salt,
+        n,
+        8,
+        1,
+        // scrypt needs 128 * r * N bytes, plus a margin
+        1024 * (n + 2) + (1 << 20),
+        &mut wrap_key,
+    )
+    .map_err(|e| e.to_string())?;
+
+    unwrap_file_key(&wrap_key, &stanza.body).map_err(|_| "Wrong passphrase".to_string())
+}
+
+fn unwrap_file_key(wrap_key: &[u8; 32], body: &[u8]) -> Result<[u8; 16], String> {
+    if body.len() != 16 + TAG_LEN {
+        return Err("Malformed stanza body".to_string());
+    }
+    let mut body = body.to_vec();
+    let file_key = open(wrap_key, [0; 12], &mut body)?;
+    Ok(file_key.try_into().unwrap())
+}
+
+fn decrypt_payload(file_key: &[u8; 16], payload: &[u8]) -> Result<Vec<u8>, String> {
+    if payload.len() < 16 {
+        return Err("Truncated age payload".to_string());
+    }
+    let (nonce, mut ciphertext) = payload.split_at(16);
+    let payload_key = hkdf_sha256(nonce, file_key, b"payload");
+
+    let mut plaintext = Vec::with_capacity(ciphertext.len());
+    let mut counter = 0u128;
+    loop {
+        let is_last = ciphertext.len() <= CHUNK_LEN + TAG_LEN;
+        let (chunk, rest) = ciphertext.split_at(ciphertext.len().min(CHUNK_LEN + TAG_LEN));
+
+        // 11-byte big-endian chunk counter, then whether this is the last chunk
+        let mut nonce = [0u8; 12];
+        nonce[..11].copy_from_slice(&counter.to_be_bytes()[5..]);
+        nonce[11] = is_last as u8;
+
+        let mut chunk = chunk.to_vec();
+        let decrypted = open(&payload_key, nonce, &mut chunk)?;
+        if is_last && decrypted.is_empty() && counter > 0 {
+            return Err("Empty final age chunk".to_string());
+        }
+        plaintext.extend_from_slice(decrypted);
+
+        if is_last {
+            return Ok(plaintext);
+        }
+        ciphertext = rest;
+        counter += 1;
+    }
+}
+
+fn open<'a>(key: &[u8; 32], nonce: [u8; 12], data: &'a mut [u8]) -> Result<&'a [u8], String> {
+    let key = LessSafeKey::new(UnboundKey::new(&CHACHA20_POLY1305, key).unwrap());
+    key.open_in_place(Nonce::assume_unique_for_key(nonce), Aad::empty(), data)
+        .map(|plaintext| &*plaintext)
+        .map_err(|_| "Decryption failed".to_string())
+}
+
+struct OutputLen(usize);
+
+impl hkdf::KeyType for OutputLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+fn hkdf_sha256(salt: &[u8], ikm: &[u8], info: &[u8]) -> [u8; 32] {
+    let prk = hkdf::Salt::new(hkdf::HKDF_SHA256, salt).extract(ikm);
+    let mut key = [0u8; 32];
+    prk.expand(&[info], OutputLen(32))
+        .and_then(|okm| okm.fill(&mut key))
+        .expect("HKDF output of 32 bytes");
+    key
+}
@@ -0,0 +1,334 @@
+use crate::auth;
+use crate::credentials::credentials_login;
+use crate::json::{object, Value};
+use crate::options::Opt;
+use crate::users::UserStore;
+use crate::utils::{formatted_time, label_ip, label_user, log_time};
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use chrono::{SecondsFormat, Utc};
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Method, Request};
+use hyper_tls::HttpsConnector;
+use log::{info, warn};
+
+// Checks whose denials are attempts at destinations the proxy blocks
+const BLOCKED_DESTINATION_CHECKS: [&str; 5] = [
+    "hosts",
+    "deny-hosts",
+    "connect-ports",
+    "destination",
+    "dest-countries",
+];
+
+// Clients tracked for abuse reports at most, so a scan from many addresses can't grow
+// the table without bound
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+const DEFAULT_REPORT_TEMPLATE: &str = "\
+Abuse report for {client}
+
+Between {first} and {last}, {client} tried to reach destinations blocked by our proxy \
+{attempts} times within {window} seconds.
+
+Time (UTC), user, destination and the rule that blocked it:
+{evidence}
+";
+
+static FAILED_LOGINS: OnceLock<Mutex<HashMap<String, FailedLogins>>> = OnceLock::new();
+static BLOCKED_ATTEMPTS: OnceLock<Mutex<HashMap<IpAddr, BlockedAttempts>>> = OnceLock::new();
+static REPORT_TEMPLATE: OnceLock<String> = OnceLock::new();
+
+#[derive(Debug, Default)]
+struct FailedLogins {
+    attempts: VecDeque<(Instant, IpAddr)>,
+    last_alert: Option<Instant>,
+}
+
+#[derive(Debug, Default)]
+struct BlockedAttempts {
+    attempts: VecDeque<Attempt>,
+    last_report: Option<Instant>,
+}
+
+impl BlockedAttempts {
+    /// Whether the client tried or was reported within the last `window`.
+    fn is_recent(&self, now: Instant, window: Duration) -> bool {
+        let last_attempt = self.attempts.back().map(|attempt| attempt.at);
+        [last_attempt, self.last_report]
+            .into_iter()
+            .flatten()
+            .any(|at| now.duration_since(at) <= window)
+    }
+}
+
+/// One denied request for a blocked destination, the evidence of an abuse report.
+#[derive(Debug, Clone)]
+struct Attempt {
+    at: Instant,
+    time: String,
+    user: String,
+    target: String,
+    rule: String,
+}
+
+impl Attempt {
+    fn to_json(&self) -> Value {
+        object([
+            ("time", self.time.as_str().into()),
+            ("user", self.user.as_str().into()),
+            ("target", self.target.as_str().into()),
+            ("rule", self.rule.as_str().into()),
+        ])
+    }
+}
+
+/// Read the `--abuse-report-template`, so a missing file is reported at startup.
+pub fn init() -> io::Result<()> {
+    let template = match &Opt::global().abuse_report_template {
+        Some(path) => fs::read_to_string(path)?,
+        None => DEFAULT_REPORT_TEMPLATE.to_string(),
+    };
+    let _ = REPORT_TEMPLATE.set(template);
+    Ok(())
+}
+
+/// Record a request of `client` (a client label) for `target` that `rule` denied. Once a
+/// client has tried blocked destinations `--abuse-report-threshold` times within the
+/// window, it is reported with the attempts as evidence. Other denials, like failed
+/// authentication, don't count.
+pub fn record_blocked_attempt(client: &str, target: &str, rule: &str) {
+    let options = Opt::global();
+    if options.abuse_report_threshold == 0 {
+        return;
+    }
+    // Tenant rules count like the main listener's
+    let check = rule.split(':').next().unwrap_or(rule);
+    let check = check.rsplit('/').next().unwrap_or(check);
+    if !BLOCKED_DESTINATION_CHECKS.contains(&check) {
+        return;
+    }
+    let Some(ip) = label_ip(client) else {
+        return;
+    };
+
+    let window = Duration::from_secs(options.abuse_report_window);
+    let now = Instant::now();
+    let attempt = Attempt {
+        at: now,
+        time: log_time(Utc::now()).to_rfc3339_opts(SecondsFormat::Secs, true),
+        user: label_user(client).to_string(),
+        target: target.to_string(),
+        rule: rule.to_string(),
+    };
+
+    let attempts = {
+        let mut clients = BLOCKED_ATTEMPTS
+            .get_or_init(Default::default)
+            .lock()
+            .unwrap();
+        if clients.len() >= MAX_TRACKED_CLIENTS && !clients.contains_key(&ip) {
+            clients.retain(|_, blocked| blocked.is_recent(now, window));
+            if clients.len() >= MAX_TRACKED_CLIENTS {
+                return;
+            }
+        }
+        let blocked = clients.entry(ip).or_default();
+
+        blocked.attempts.push_back(attempt);
+        while let Some(attempt) = blocked.attempts.front() {
+            if now.duration_since(attempt.at) <= window {
+                break;
+            }
+            blocked.attempts.pop_front();
+        }
+
+        // At most one report per client and window, however long the attempts go on
+        let recently_reported = blocked
+            .last_report
+            .is_some_and(|at| now.duration_since(at) < window);
+        if blocked.attempts.len() < options.abuse_report_threshold || recently_reported {
+            return;
+        }
+        blocked.last_report = Some(now);
+        blocked.attempts.iter().cloned().collect::<Vec<Attempt>>()
+    };
+
+    let time = formatted_time();
+    warn!(
+        "\x1B[31m[{time}] ALERT Client {ip} tried {} blocked destinations in {}s\x1B[0m",
+        attempts.len(),
+        window.as_secs()
+    );
+
+    if let Some(webhook) = &options.abuse_report_webhook {
+        let event = object([
+            ("event", "abuse_report".into()),
+            ("client", ip.to_string().into()),
+            ("attempts", (attempts.len() as u64).into()),
+            ("window_secs", window.as_secs().into()),
+            (
+                "evidence",
+                attempts
+                    .iter()
+                    .map(Attempt::to_json)
+                    .collect::<Vec<Value>>()
+                    .into(),
+            ),
+            ("report", render_report(ip, &attempts, window).into()),
+            ("time", time.into()),
+        ]);
+        tokio::spawn(send_webhook(
+            "Abuse report",
+            webhook.clone(),
+            event.to_string(),
+        ));
+    }
+}
+
+/// Abuse report for the hosting provider of `ip`, from the `--abuse-report-template`.
+fn render_report(ip: IpAddr, attempts: &[Attempt], window: Duration) -> String {
+    let template = REPORT_TEMPLATE
+        .get()
+        .map_or(DEFAULT_REPORT_TEMPLATE, String::as_str);
+    let evidence = attempts
+        .iter()
+        .map(|attempt| {
+            format!(
+                "{}  user={}  {}  {}",
+                attempt.time, attempt.user, attempt.target, attempt.rule
+            )
+        })
+        .collect::<Vec<String>>()
+        .join("\n");
+    let first = attempts.first().map_or("", |attempt| attempt.time.as_str());
+    let last = attempts.last().map_or("", |attempt| attempt.time.as_str());
+
+    template
+        .replace("{client}", &ip.to_string())
+        .replace("{attempts}", &attempts.len().to_string())
+        .replace("{window}", &window.as_secs().to_string())
+        .replace("{first}", first)
+        .replace("{last}", last)
+        .replace("{evidence}", &evidence)
+}
+
+/// Record a rejected Proxy-Authorization header. Only logins that exist are tracked,
+/// so the owner of a credential can be told it is being guessed; unknown logins are
+/// just noise from scanners.
+pub fn record_failed_login(
+    credentials_header: &str,
+    allowed_credentials: &[String],
+    client_ip: IpAddr,
+) {
+    let options = Opt::global();
+    if options.login_alert_threshold == 0 {
+        return;
+    }
+    let Some(login) = credentials_login(credentials_header) else {
+        return;
+    };
+    if !is_known_login(&login, allowed_credentials) {
+        return;
+    }
+
+    let window = Duration::from_secs(options.login_alert_window);
+    let now = Instant::now();
+
+    let (attempts, clients) = {
+        let mut failed_logins = FAILED_LOGINS.get_or_init(Default::default).lock().unwrap();
+        let failures = failed_logins.entry(login.clone()).or_default();
+
+        failures.attempts.push_back((now, client_ip));
+        while let Some((at, _)) = failures.attempts.front() {
+            if now.duration_since(*at) <= window {
+                break;
+            }
+            failures.attempts.pop_front();
+        }
+
+        // At most one alert per login and window, however long the guessing goes on
+        let recently_alerted = failures
+            .last_alert
+            .map(|at| now.duration_since(at) < window)
+            .unwrap_or(false);
+        if failures.attempts.len() < options.login_alert_threshold || recently_alerted {
+            return;
+        }
+        failures.last_alert = Some(now);
+
+        let clients = failures
+            .attempts
+            .iter()
+            .map(|(_, ip)| ip.to_string())
+            .collect::<BTreeSet<String>>();
+        (failures.attempts.len(), clients)
+    };
+
+    let clients = clients.into_iter().collect::<Vec<String>>();
+    let time = formatted_time();
+
+    warn!(
+        "\x1B[31m[{time}] ALERT Credential of user={login} failed {attempts} times in {}s from [{}]\x1B[0m",
+        window.as_secs(),
+        clients.join(", ")
+    );
+
+    if let Some(webhook) = &options.login_alert_webhook {
+        let event = object([
+            ("event", "failed_logins".into()),
+            ("login", login.into()),
+            ("attempts", (attempts as u64).into()),
+            ("window_secs", window.as_secs().into()),
+            ("clients", clients.into()),
+            ("time", time.into()),
+        ]);
+        tokio::spawn(send_webhook(
+            "Login alert",
+            webhook.clone(),
+            event.to_string(),
+        ));
+    }
+}
+
+fn is_known_login(login: &str, allowed_credentials: &[String]) -> bool {
+    let in_credentials = allowed_credentials.iter().any(|credentials| {
+        credentials
+            .split_once(':')
+            .map(|(allowed_login, _)| allowed_login == login)
+            .unwrap_or(false)
+    });
+    let in_store = UserStore::global()
+        .map(|store| store.get(login).is_some())
+        .unwrap_or(false);
+
+    in_credentials || in_store || auth::knowing(login).is_some()
+}
+
+async fn send_webhook(alert: &'static str, url: String, payload: String) {
+    let request = match Request::builder()
+        .method(Method::POST)
+        .uri(&url)
+        .header(CONTENT_TYPE, "application/json")
+        .body(Body::from(payload))
+    {
+        Ok(request) => request,
+        Err(e) => {
+            warn!("Invalid {} webhook {url}: {e}", alert.to_lowercase());
+            return;
+        }
+    };
+
+    let client = Client::builder().build::<_, Body>(HttpsConnector::new());
+    match client.request(request).await {
+        Ok(response) if response.status().is_success() => {}
+        Ok(response) => info!("{alert} webhook {url} answered {}", response.status()),
+        Err(e) => warn!("{alert} webhook {url} failed: {e}"),
+    }
+}
@@ -0,0 +1,79 @@
+use std::fmt;
+use std::str::FromStr;
+
+use ::argon2::password_hash::rand_core::OsRng;
+use ::argon2::password_hash::{
+    PasswordHash, PasswordHashString, PasswordHasher, PasswordVerifier, SaltString,
+};
+use ::argon2::{Algorithm, Argon2, Params, Version};
+
+// Caps on what a hash may ask for, so a mistyped one can't exhaust the host's memory
+// or hold up every login. Libraries default to 19 MiB to 64 MiB and a few passes.
+const MAX_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+const MAX_PASSES: u32 = 64;
+const MAX_LANES: u32 = 64;
+
+// Parameters of new hashes, the first of OWASP's recommendations for Argon2id
+const DEFAULT_MEMORY_KIB: u32 = 19 * 1024;
+const DEFAULT_PASSES: u32 = 2;
+const HASH_LEN: usize = 32;
+
+/// An Argon2 hash in the PHC string format that `argon2` and most libraries write:
+/// `$argon2id$v=19$m=65536,t=3,p=4$<salt>$<hash>`, salt and hash in unpadded base64.
+/// Hashing is RustCrypto's `argon2`.
+#[derive(Debug)]
+pub struct Argon2Hash(PasswordHashString);
+
+impl FromStr for Argon2Hash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let hash = PasswordHash::new(s).map_err(|e| e.to_string())?;
+        Algorithm::try_from(hash.algorithm).map_err(|_| "not an Argon2 hash".to_string())?;
+        if let Some(version) = hash.version {
+            Version::try_from(version).map_err(|_| format!("unknown version {version}"))?;
+        }
+        if hash.salt.is_none() || hash.hash.is_none() {
+            return Err("expected $argon2id$v=19$m=..,t=..,p=..$<salt>$<hash>".to_string());
+        }
+
+        let params = Params::try_from(&hash).map_err(|e| e.to_string())?;
+        if params.p_cost() > MAX_LANES {
+            return Err(format!("p must be 1 to {MAX_LANES}"));
+        }
+        if params.t_cost() > MAX_PASSES {
+            return Err(format!("t must be 1 to {MAX_PASSES}"));
+        }
+        if params.m_cost() > MAX_MEMORY_KIB {
+            return Err(format!("m must be 8 KiB per lane to {MAX_MEMORY_KIB} KiB"));
+        }
+        Ok(Argon2Hash(hash.serialize()))
+    }
+}
+
+impl fmt::Display for Argon2Hash {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.0.as_str())
+    }
+}
+
+impl Argon2Hash {
+    /// Argon2id hash of `password` with a random salt.
+    pub fn new(password: &[u8]) -> Argon2Hash {
+        let params = Params::new(DEFAULT_MEMORY_KIB, DEFAULT_PASSES, 1, Some(HASH_LEN))
+            .expect("valid Argon2 parameters");
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password(password, &salt)
+            .expect("Argon2 hashes any password");
+        Argon2Hash(hash.serialize())
+    }
+
+    /// Whether `password` hashes to this hash with its salt and parameters.
+    pub fn verify(&self, password: &[u8]) -> bool {
+        let mut hash = self.0.password_hash();
+        // Hashes without a `v=` field predate version 1.3
+        hash.version = hash.version.or(Some(Version::V0x10 as u32));
+        Argon2::default().verify_password(password, &hash).is_ok()
+    }
+}
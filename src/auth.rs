@@ -0,0 +1,111 @@
+use crate::credentials::basic_credentials;
+use crate::htpasswd::Htpasswd;
+use crate::ldap::Ldap;
+use crate::options::Opt;
+use crate::pam::Pam;
+
+use std::sync::OnceLock;
+
+use log::warn;
+
+static PROVIDERS: OnceLock<Vec<Box<dyn AuthProvider>>> = OnceLock::new();
+
+/// External account database that Basic credentials are checked against when they
+/// match neither `--auth` nor the user store.
+pub trait AuthProvider: Send + Sync {
+    /// Name used in logs.
+    fn name(&self) -> &'static str;
+
+    /// Whether `password` is valid for `login`. May block on the network or the
+    /// system, so it is called off the async threads.
+    fn authenticate(&self, login: &str, password: &str) -> Result<bool, String>;
+
+    /// Whether `login` has an account, `None` for providers that can't tell without
+    /// a password.
+    fn knows(&self, _login: &str) -> Option<bool> {
+        None
+    }
+}
+
+/// Set up the configured providers at startup, so a missing library is reported right away.
+pub fn init() -> Result<(), String> {
+    let options = Opt::global();
+    let mut providers: Vec<Box<dyn AuthProvider>> = Vec::new();
+
+    // The local file first, it answers without a round trip
+    if let Some(path) = &options.auth_file {
+        providers.push(Box::new(Htpasswd::load(path)?));
+    }
+    if options.ldap_uri.is_some() {
+        providers.push(Box::new(Ldap::from_options()?));
+    }
+    if let Some(service) = &options.pam_service {
+        providers.push(Box::new(Pam::new(service)?));
+    }
+
+    PROVIDERS
+        .set(providers)
+        .map_err(|_| "Authentication providers are already set up".to_string())
+}
+
+fn providers() -> &'static [Box<dyn AuthProvider>] {
+    PROVIDERS.get().map(Vec::as_slice).unwrap_or_default()
+}
+
+/// Names of the configured providers, in the order they are tried.
+pub fn names() -> Vec<&'static str> {
+    providers().iter().map(|provider| provider.name()).collect()
+}
+
+/// Whether any provider is configured.
+pub fn enabled() -> bool {
+    !providers().is_empty()
+}
+
+/// Name of the provider that has an account for `login`, if one can tell.
+pub fn knowing(login: &str) -> Option<&'static str> {
+    providers()
+        .iter()
+        .find(|provider| provider.knows(login) == Some(true))
+        .map(|provider| provider.name())
+}
+
+/// Whether the provider `name` may still have an account for `login`: it's configured
+/// and doesn't know it to be gone.
+pub fn may_know(name: &str, login: &str) -> bool {
+    providers()
+        .iter()
+        .find(|provider| provider.name() == name)
+        .is_some_and(|provider| provider.knows(login) != Some(false))
+}
+
+/// Names of the providers that might accept `login`, not knowing without a password.
+pub fn undecided(login: &str) -> Vec<&'static str> {
+    providers()
+        .iter()
+        .filter(|provider| provider.knows(login).is_none())
+        .map(|provider| provider.name())
+        .collect()
+}
+
+/// Check a `Basic` Proxy-Authorization header against the providers in turn.
+/// Returns the name of the provider that accepted it and the login it accepted.
+pub async fn authenticate(credentials_header: &str) -> Option<(&'static str, String)> {
+    let (login, password) = basic_credentials(credentials_header)?;
+
+    tokio::task::spawn_blocking(move || {
+        providers()
+            .iter()
+            .find(|provider| match provider.authenticate(&login, &password) {
+                Ok(allowed) => allowed,
+                Err(e) => {
+                    warn!("{} authentication of {login} failed: {e}", provider.name());
+                    false
+                }
+            })
+            .map(|provider| (provider.name(), login))
+    })
+    .await
+    .ok()
+    .flatten()
+}
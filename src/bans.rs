@@ -0,0 +1,165 @@
+use crate::json::{object, Value};
+use crate::options::Opt;
+use crate::utils::{formatted_time, log_time};
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use log::warn;
+
+// Clients tracked for bans at most, so credential stuffing from many addresses can't
+// grow the table without bound. Bans in force are always kept.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+static CLIENTS: OnceLock<Mutex<HashMap<IpAddr, Failures>>> = OnceLock::new();
+
+#[derive(Debug, Default)]
+struct Failures {
+    attempts: VecDeque<Instant>,
+    ban: Option<Ban>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Ban {
+    since: DateTime<Utc>,
+    until: Instant,
+    failures: usize,
+}
+
+impl Failures {
+    fn is_banned(&self, now: Instant) -> bool {
+        self.ban.is_some_and(|ban| ban.until > now)
+    }
+
+    /// Whether the client failed or was banned within the last `window`.
+    fn is_recent(&self, now: Instant, window: Duration) -> bool {
+        self.is_banned(now)
+            || self
+                .attempts
+                .back()
+                .is_some_and(|at| now.duration_since(*at) <= window)
+    }
+}
+
+fn clients() -> &'static Mutex<HashMap<IpAddr, Failures>> {
+    CLIENTS.get_or_init(Default::default)
+}
+
+/// Record a failed authentication from `ip`, with any login or none that exists. Once a
+/// client fails `--ban-threshold` times within `--ban-window`, it's banned for
+/// `--ban-duration`. Returns whether the client is banned, so the listener can close
+/// the connection it failed on.
+pub fn record_failure(ip: IpAddr) -> bool {
+    let options = Opt::global();
+    if options.ban_threshold == 0 {
+        return false;
+    }
+
+    let window = Duration::from_secs(options.ban_window);
+    let now = Instant::now();
+    let failures = {
+        let mut clients = clients().lock().unwrap();
+        if clients.len() >= MAX_TRACKED_CLIENTS && !clients.contains_key(&ip) {
+            clients.retain(|_, failures| failures.is_recent(now, window));
+            if clients.len() >= MAX_TRACKED_CLIENTS {
+                return false;
+            }
+        }
+        let client = clients.entry(ip).or_default();
+        if client.is_banned(now) {
+            return true;
+        }
+
+        client.attempts.push_back(now);
+        while let Some(at) = client.attempts.front() {
+            if now.duration_since(*at) <= window {
+                break;
+            }
+            client.attempts.pop_front();
+        }
+        if client.attempts.len() < options.ban_threshold {
+            return false;
+        }
+
+        let failures = client.attempts.len();
+        client.attempts.clear();
+        client.ban = Some(Ban {
+            since: Utc::now(),
+            until: now + Duration::from_secs(options.ban_duration),
+            failures,
+        });
+        failures
+    };
+
+    let time = formatted_time();
+    warn!(
+        "\x1B[31m[{time}] Banned client {ip} for {}s after {failures} failed logins in {}s\x1B[0m",
+        options.ban_duration,
+        window.as_secs()
+    );
+    true
+}
+
+/// Whether `ip` is banned for failing to authenticate too often.
+pub fn is_banned(ip: IpAddr) -> bool {
+    let now = Instant::now();
+    clients()
+        .lock()
+        .unwrap()
+        .get(&ip)
+        .is_some_and(|failures| failures.is_banned(now))
+}
+
+/// Bans in force, for the admin API.
+pub fn to_json() -> Value {
+    let now = Instant::now();
+    let mut bans = clients()
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|(_, failures)| failures.is_banned(now))
+        .filter_map(|(ip, failures)| Some((*ip, failures.ban?)))
+        .collect::<Vec<(IpAddr, Ban)>>();
+    bans.sort_by_key(|(_, ban)| ban.since);
+
+    let time = |at: DateTime<Utc>| log_time(at).to_rfc3339_opts(SecondsFormat::Secs, true);
+    let bans = bans
+        .into_iter()
+        .map(|(ip, ban)| {
+            let remaining = ban.until.duration_since(now);
+            object([
+                ("ip", ip.to_string().into()),
+                ("failures", (ban.failures as u64).into()),
+                ("since", time(ban.since).into()),
+                ("until", time(Utc::now() + remaining).into()),
+                ("remaining_secs", remaining.as_secs().into()),
+            ])
+        })
+        .collect::<Vec<Value>>();
+    object([("bans", bans.into())])
+}
+
+/// Lift the ban of `ip` and forget its failures. Returns whether it was banned.
+pub fn clear(ip: IpAddr) -> bool {
+    let now = Instant::now();
+    clients()
+        .lock()
+        .unwrap()
+        .remove(&ip)
+        .is_some_and(|failures| failures.is_banned(now))
+}
+
+/// Lift every ban and forget all failures. Returns how many bans there were.
+pub fn clear_all() -> usize {
+    let now = Instant::now();
+    let mut clients = clients().lock().unwrap();
+    let banned = clients
+        .values()
+        .filter(|failures| failures.is_banned(now))
+        .count();
+    clients.clear();
+    banned
+}
@@ -0,0 +1,250 @@
+use crate::options::Opt;
+use crate::utils::formatted_time;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use hyper::{Body, Response, StatusCode};
+use log::{info, warn};
+
+static CIRCUITS: OnceLock<Mutex<HashMap<String, Circuit>>> = OnceLock::new();
+
+/// When to stop connecting to a destination host that keeps failing: after `failures`
+/// failed connects within `window` seconds, connects fail at once for `open` seconds.
+/// Then a single probe goes through, and `probes` successful ones in a row close the
+/// circuit again.
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    pub failures: u32,
+    pub window: u64,
+    pub open: u64,
+    pub probes: u32,
+}
+
+impl FromStr for CircuitBreaker {
+    type Err = String;
+
+    /// Parse `failures=5;window=60;open=30;probes=1`, every field is optional.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut breaker = CircuitBreaker {
+            failures: 5,
+            window: 60,
+            open: 30,
+            probes: 1,
+        };
+
+        for field in s
+            .split(';')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+        {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key=value, got '{field}'"))?;
+            let (key, value) = (key.trim(), value.trim());
+            let invalid = |e: std::num::ParseIntError| {
+                format!("Invalid circuit breaker {key} '{value}': {e}")
+            };
+
+            match key {
+                "failures" => breaker.failures = value.parse().map_err(invalid)?,
+                "window" => breaker.window = value.parse().map_err(invalid)?,
+                "open" => breaker.open = value.parse().map_err(invalid)?,
+                "probes" => breaker.probes = value.parse().map_err(invalid)?,
+                key => return Err(format!("Unknown circuit breaker setting '{key}'")),
+            }
+        }
+
+        if breaker.failures == 0 || breaker.window == 0 || breaker.open == 0 || breaker.probes == 0
+        {
+            return Err("Circuit breaker settings must be greater than 0".to_string());
+        }
+        Ok(breaker)
+    }
+}
+
+#[derive(Debug)]
+enum Circuit {
+    /// Connecting normally, `failures` since `since`
+    Closed { failures: u32, since: Instant },
+    /// Failing fast until `until`
+    Open { until: Instant },
+    /// Letting probes through one at a time, `successes` of them succeeded so far
+    HalfOpen {
+        successes: u32,
+        probe: Option<Instant>,
+    },
+}
+
+/// Connect refused because the destination's circuit is open.
+#[derive(Debug)]
+pub struct CircuitOpen {
+    host: String,
+    retry_after: u64,
+}
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Circuit to {} is open, retry in {}s",
+            self.host, self.retry_after
+        )
+    }
+}
+
+impl Error for CircuitOpen {}
+
+fn circuits() -> &'static Mutex<HashMap<String, Circuit>> {
+    CIRCUITS.get_or_init(Default::default)
+}
+
+fn key(host: &str) -> String {
+    host.trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_ascii_lowercase()
+}
+
+/// Whether a connect to `host` may be made. Refused while its circuit is open, and
+/// while a probe is already on its way.
+pub fn check(host: &str) -> io::Result<()> {
+    let Some(breaker) = &Opt::global().circuit_breaker else {
+        return Ok(());
+    };
+    let open = Duration::from_secs(breaker.open);
+
+    let now = Instant::now();
+    let mut circuits = circuits().lock().unwrap();
+    let Some(circuit) = circuits.get_mut(&key(host)) else {
+        return Ok(());
+    };
+
+    let retry_after = match circuit {
+        Circuit::Closed { .. } => return Ok(()),
+        Circuit::Open { until } if *until > now => until.duration_since(now),
+        Circuit::Open { .. } => {
+            *circuit = Circuit::HalfOpen {
+                successes: 0,
+                probe: Some(now),
+            };
+            return Ok(());
+        }
+        // A probe that never reported back doesn't hold the circuit forever
+        Circuit::HalfOpen { probe, .. } => match probe {
+            Some(started) if now.duration_since(*started) < open => {
+                open - now.duration_since(*started)
+            }
+            _ => {
+                *probe = Some(now);
+                return Ok(());
+            }
+        },
+    };
+
+    Err(io::Error::new(
+        ErrorKind::ConnectionRefused,
+        CircuitOpen {
+            host: host.to_string(),
+            retry_after: retry_after.as_secs_f64().ceil() as u64,
+        },
+    ))
+}
+
+/// Count a connect to `host` that succeeded or failed.
+pub fn record(host: &str, success: bool) {
+    let Some(breaker) = &Opt::global().circuit_breaker else {
+        return;
+    };
+
+    let now = Instant::now();
+    let key = key(host);
+    let mut circuits = circuits().lock().unwrap();
+    let time = formatted_time();
+
+    let circuit = match circuits.get_mut(&key) {
+        Some(circuit) => circuit,
+        // Healthy hosts aren't tracked
+        None if success => return,
+        None => circuits.entry(key.clone()).or_insert(Circuit::Closed {
+            failures: 0,
+            since: now,
+        }),
+    };
+
+    let close = match circuit {
+        Circuit::Closed { .. } if success => true,
+        Circuit::Closed { failures, since } => {
+            if now.duration_since(*since) > Duration::from_secs(breaker.window) {
+                (*failures, *since) = (0, now);
+            }
+            *failures += 1;
+            if *failures >= breaker.failures {
+                warn!(
+                    "[{time}] Circuit to {key} opened after {failures} failed connects, failing fast for {}s",
+                    breaker.open
+                );
+                *circuit = Circuit::Open {
+                    until: now + Duration::from_secs(breaker.open),
+                };
+            }
+            false
+        }
+        Circuit::HalfOpen { successes, probe } if success => {
+            *successes += 1;
+            *probe = None;
+            if *successes >= breaker.probes {
+                info!("[{time}] Circuit to {key} closed after {successes} successful probes");
+            }
+            *successes >= breaker.probes
+        }
+        Circuit::HalfOpen { .. } => {
+            warn!(
+                "[{time}] Circuit to {key} probe failed, failing fast for another {}s",
+                breaker.open
+            );
+            *circuit = Circuit::Open {
+                until: now + Duration::from_secs(breaker.open),
+            };
+            false
+        }
+        // Connects that started before the circuit opened
+        Circuit::Open { .. } => false,
+    };
+    if close {
+        circuits.remove(&key);
+    }
+}
+
+/// Seconds until the circuit that refused the connect lets a probe through, when `e`
+/// comes from an open circuit.
+pub fn retry_after(e: &(dyn Error + 'static)) -> Option<u64> {
+    let mut source = Some(e);
+    while let Some(e) = source {
+        if let Some(open) = e.downcast_ref::<CircuitOpen>() {
+            return Some(open.retry_after);
+        }
+        if let Some(open) = e
+            .downcast_ref::<io::Error>()
+            .and_then(|e| e.get_ref())
+            .and_then(|e| e.downcast_ref::<CircuitOpen>())
+        {
+            return Some(open.retry_after);
+        }
+        source = e.source();
+    }
+    None
+}
+
+/// 503 telling the client when the destination will be tried again.
+pub fn open_response(retry_after: u64) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header("Retry-After", retry_after.to_string())
+        .body(Body::empty())
+        .unwrap()
+}
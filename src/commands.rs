@@ -0,0 +1,209 @@
+use crate::argon2::Argon2Hash;
+use crate::json::{object, Value};
+use crate::options::{Command, Opt, UserCommand};
+use crate::signed_token;
+use crate::usage;
+use crate::users::{generate_password, UserError, UserStore};
+use crate::utils::to_sha256;
+
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::process::exit;
+
+/// Run a management subcommand instead of starting the servers.
+pub fn run(command: &Command) {
+    match command {
+        Command::User(UserCommand::Add {
+            login,
+            generate,
+            password,
+            quota,
+            quota_period,
+            expires_at,
+            scopes,
+            hosts,
+            deny_hosts,
+            rate_limit,
+            connect_ports,
+        }) => {
+            let Some(store) = UserStore::global() else {
+                eprintln!("Error: --users-file is required to manage users");
+                exit(1);
+            };
+
+            // Without a password in the request the store generates one
+            let password = password.clone().filter(|_| !generate);
+            let request = object([
+                ("login", login.as_str().into()),
+                ("quota", (*quota).into()),
+                (
+                    "quota_period",
+                    quota_period.map(|period| period.to_string()).into(),
+                ),
+                ("expires_at", expires_at.clone().into()),
+                ("scopes", split_list(scopes).into()),
+                ("hosts", split_list(hosts).into()),
+                ("deny_hosts", split_list(deny_hosts).into()),
+                ("rate_limit", rate_limit.map(|rate| rate.to_string()).into()),
+                (
+                    "connect_ports",
+                    connect_ports.as_ref().map(|ports| ports.to_string()).into(),
+                ),
+            ]);
+            let request = match (request, password) {
+                (Value::Object(mut fields), Some(password)) => {
+                    fields.insert("password".to_string(), password.into());
+                    Value::Object(fields)
+                }
+                (request, _) => request,
+            };
+
+            match store.create(&request) {
+                Ok((user, password)) => {
+                    println!(
+                        "User {} added to {}",
+                        user.login,
+                        Opt::global().users_file.as_deref().unwrap_or_default()
+                    );
+                    if *generate {
+                        println!("Password: {password}");
+                    }
+                }
+                Err(e) => {
+                    eprintln!("Error: {e}");
+                    exit(1);
+                }
+            }
+        }
+        Command::Purge { user } => purge(user),
+        Command::HashPassword { login } => hash_password(login.as_deref()),
+        Command::GenToken { token, expires_in } => gen_token(token.as_deref(), *expires_in),
+        // The servers start instead of a management subcommand
+        Command::Run => {}
+    }
+}
+
+fn split_list(list: &Option<String>) -> Vec<String> {
+    list.iter()
+        .flat_map(|list| list.split(','))
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Remove what is persisted about `login`. The users file and the usage file are the
+/// only storage the proxy writes to: logs go to stdout, and sessions and the log viewer's
+/// buffer only live in the running proxy, which purges them with
+/// `DELETE /v1/users/{login}?purge=true`.
+fn purge(login: &str) {
+    match usage::purge_files(login) {
+        Ok(paths) => {
+            for path in paths {
+                println!("Removed the usage of {login} from {path}");
+            }
+        }
+        Err(e) => {
+            eprintln!("Error: {e}");
+            exit(1);
+        }
+    }
+
+    let Some(store) = UserStore::global() else {
+        println!("Nothing stored about {login}: no --users-file is configured");
+        return;
+    };
+
+    let users_file = Opt::global().users_file.as_deref().unwrap_or_default();
+    match store.delete(login) {
+        Ok(()) => println!("Removed {login} from {users_file}"),
+        Err(UserError::NotFound) => println!("Nothing stored about {login} in {users_file}"),
+        Err(e) => {
+            eprintln!("Error: {e}");
+            exit(1);
+        }
+    }
+}
+
+/// Print the Argon2id hash of a password for `--auth-file`, so no plaintext password
+/// has to be written anywhere.
+fn hash_password(login: Option<&str>) {
+    if login.is_some_and(|login| login.contains(':')) {
+        eprintln!("Error: a login can't contain ':'");
+        exit(1);
+    }
+    let password = match io::stdin().is_terminal() {
+        true => {
+            let password = prompt("Password: ");
+            if prompt("Repeat the password: ") != password {
+                eprintln!("Error: the passwords don't match");
+                exit(1);
+            }
+            password
+        }
+        false => read_line(),
+    };
+    if password.is_empty() {
+        eprintln!("Error: the password is empty");
+        exit(1);
+    }
+
+    let hash = Argon2Hash::new(password.as_bytes());
+    match login {
+        Some(login) => println!("{login}:{hash}"),
+        None => println!("{hash}"),
+    }
+}
+
+/// Print a new secret token, or the one given, and the SHA-256 of it Proxer Client sends
+/// in the `x-http(s)-secret-token` header, and a signed value expiring in `expires_in`
+/// seconds if given.
+fn gen_token(token: Option<&str>, expires_in: Option<u64>) {
+    let token = token
+        .map(|token| token.trim().to_string())
+        .unwrap_or_else(generate_password);
+    if token.is_empty() {
+        eprintln!("Error: the token is empty");
+        exit(1);
+    }
+    println!("Token: {token}");
+    println!("Header value: {}", to_sha256(&token));
+    if let Some(expires_in) = expires_in {
+        let signed = signed_token::sign_for(&token, expires_in);
+        println!("Signed header value: {signed}");
+    }
+}
+
+/// Read a password from the terminal without echoing it.
+fn prompt(message: &str) -> String {
+    eprint!("{message}");
+    let _ = io::stderr().flush();
+
+    #[cfg(unix)]
+    {
+        // SAFETY: termios is plain data, filled in by tcgetattr before it's used
+        let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+        let echoing = unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } == 0;
+        if echoing {
+            let mut silent = termios;
+            silent.c_lflag &= !libc::ECHO;
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &silent) };
+        }
+        let password = read_line();
+        if echoing {
+            unsafe { libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, &termios) };
+        }
+        eprintln!();
+        password
+    }
+
+    #[cfg(not(unix))]
+    read_line()
+}
+
+fn read_line() -> String {
+    let mut line = String::new();
+    if let Err(e) = io::stdin().lock().read_line(&mut line) {
+        eprintln!("Error: {e}");
+        exit(1);
+    }
+    line.trim_end_matches(['\r', '\n']).to_string()
+}
@@ -0,0 +1,451 @@
+use crate::options::Opt;
+
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs;
+
+use chrono::{DateTime, FixedOffset, Utc};
+use clap::{ArgAction, CommandFactory};
+
+// Table whose sub-tables hold settings that take effect at their time
+const SCHEDULED_TABLE: &str = "scheduled";
+
+// Settings a reload applies, the only ones that can switch at a scheduled time
+const RELOADABLE: [&str; 3] = ["auth", "hosts", "token"];
+
+/// A value of the TOML subset config files are written in: the scalars and flat arrays
+/// that command-line options take.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    String(String),
+    Integer(i64),
+    Float(f64),
+    Bool(bool),
+    Array(Vec<Value>),
+}
+
+impl Value {
+    fn to_arg(&self) -> String {
+        match self {
+            Value::String(s) => s.clone(),
+            Value::Integer(n) => n.to_string(),
+            Value::Float(n) => n.to_string(),
+            Value::Bool(b) => b.to_string(),
+            Value::Array(items) => items
+                .iter()
+                .map(Value::to_arg)
+                .collect::<Vec<String>>()
+                .join(","),
+        }
+    }
+}
+
+/// Settings of a config file: those at the top level, and those of `[scheduled."<time>"]`
+/// tables, which replace them from that time on.
+#[derive(Debug, Default)]
+struct Document {
+    settings: Vec<(String, Value)>,
+    scheduled: Vec<Scheduled>,
+}
+
+#[derive(Debug)]
+struct Scheduled {
+    from: DateTime<FixedOffset>,
+    settings: Vec<(String, Value)>,
+}
+
+impl Document {
+    /// The settings in effect at `now`, scheduled ones applied in the order of their times.
+    fn effective(mut self, now: DateTime<Utc>) -> Vec<(String, Value)> {
+        self.scheduled.sort_by_key(|scheduled| scheduled.from);
+        let mut settings = self.settings;
+        for scheduled in self.scheduled {
+            if scheduled.from > now {
+                break;
+            }
+            for (key, value) in scheduled.settings {
+                let name = key.replace('_', "-");
+                match settings
+                    .iter_mut()
+                    .find(|(other, _)| other.replace('_', "-") == name)
+                {
+                    Some(setting) => *setting = (key, value),
+                    None => settings.push((key, value)),
+                }
+            }
+        }
+        settings
+    }
+}
+
+/// Command line with the settings of `--config` put in front of the given arguments.
+/// Settings the command line also sets are left out, so flags take precedence over the
+/// file. Keys are option names, with `_` or `-`: `http_port = 8080` is `--http-port 8080`.
+/// Scheduled settings whose time has come replace the top-level ones.
+pub fn args() -> Vec<OsString> {
+    match try_args() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("Error: {e}");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Like [`args`], with a config file that can't be read or parsed as an error.
+pub fn try_args() -> Result<Vec<OsString>, String> {
+    let mut args = std::env::args_os().collect::<Vec<OsString>>();
+    // `run` is what proxerver does without a subcommand, its options are the top-level ones
+    if args.get(1).is_some_and(|arg| arg == "run") {
+        args.remove(1);
+    }
+    let Some(path) = config_path(&args) else {
+        return Ok(args);
+    };
+
+    let document = read(&path)?;
+    // Scheduled settings are checked now rather than when they take effect
+    for scheduled in &document.scheduled {
+        for (key, _) in &scheduled.settings {
+            if !RELOADABLE.contains(&key.replace('_', "-").as_str()) {
+                return Err(format!(
+                    "failed to read the config file {path}: '{key}' scheduled for {} can't change while the proxy runs, only {} can be scheduled",
+                    scheduled.from.to_rfc3339(),
+                    RELOADABLE.join(", ")
+                ));
+            }
+        }
+    }
+
+    let command = Opt::command();
+
+    let given = given_options(&args);
+    let mut config_args = Vec::new();
+    for (key, value) in document.effective(Utc::now()) {
+        let name = key.replace('_', "-");
+        if given.contains(&name) {
+            continue;
+        }
+
+        let action = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(name.as_str()))
+            .map(|arg| arg.get_action().clone());
+        match (action, value) {
+            (Some(ArgAction::SetTrue), Value::Bool(true)) => config_args.push(format!("--{name}")),
+            (Some(ArgAction::SetTrue), Value::Bool(false)) => {}
+            (Some(ArgAction::Append), Value::Array(items)) => {
+                for item in items {
+                    config_args.push(format!("--{name}={}", item.to_arg()));
+                }
+            }
+            // Unknown keys too, clap reports them like a mistyped flag
+            (_, value) => config_args.push(format!("--{name}={}", value.to_arg())),
+        }
+    }
+
+    args.splice(1..1, config_args.into_iter().map(OsString::from));
+    Ok(args)
+}
+
+/// Earliest time after `now` that settings of the config file are scheduled for, `None`
+/// without a config file, one that can't be read or nothing scheduled.
+pub fn next_change(now: DateTime<Utc>) -> Option<DateTime<Utc>> {
+    let args = std::env::args_os().collect::<Vec<OsString>>();
+    read(&config_path(&args)?)
+        .ok()?
+        .scheduled
+        .iter()
+        .map(|scheduled| scheduled.from.with_timezone(&Utc))
+        .filter(|from| *from > now)
+        .min()
+}
+
+fn read(path: &str) -> Result<Document, String> {
+    fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|contents| parse(&contents))
+        .map_err(|e| format!("failed to read the config file {path}: {e}"))
+}
+
+/// Value of `--config`, looked up before clap parses the full command line.
+fn config_path(args: &[OsString]) -> Option<String> {
+    let mut args = args.iter().skip(1).map(|arg| arg.to_string_lossy());
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            return None;
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(path.to_string());
+        }
+        if arg == "--config" {
+            return args.next().map(|path| path.to_string());
+        }
+    }
+    None
+}
+
+/// Long option names given on the command line.
+fn given_options(args: &[OsString]) -> HashSet<String> {
+    args.iter()
+        .skip(1)
+        .map(|arg| arg.to_string_lossy())
+        .take_while(|arg| arg != "--")
+        .filter_map(|arg| {
+            let name = arg.strip_prefix("--")?;
+            Some(name.split('=').next().unwrap_or(name).to_string())
+        })
+        .collect()
+}
+
+/// Parse the `key = value` pairs of a TOML document. Every option lives at the top level,
+/// the only tables are `[scheduled."<time>"]` ones, the time in RFC 3339 with an offset.
+fn parse(input: &str) -> Result<Document, String> {
+    let mut document = Document::default();
+    let mut keys = HashSet::new();
+    let mut lines = input.lines().enumerate();
+
+    while let Some((number, line)) = lines.next() {
+        let line = strip_comment(line).trim().to_string();
+        if line.is_empty() {
+            continue;
+        }
+        let error = |message: String| format!("line {}: {message}", number + 1);
+        if line.starts_with('[') {
+            let from = scheduled_time(&line).map_err(error)?;
+            if document
+                .scheduled
+                .iter()
+                .any(|scheduled| scheduled.from == from)
+            {
+                return Err(error(format!("duplicate table {line}")));
+            }
+            document.scheduled.push(Scheduled {
+                from,
+                settings: Vec::new(),
+            });
+            keys.clear();
+            continue;
+        }
+
+        let (key, value) = line
+            .split_once('=')
+            .ok_or_else(|| error(format!("expected key = value, got '{line}'")))?;
+        let key = key.trim().trim_matches('"').to_string();
+        if key.is_empty() {
+            return Err(error("missing key".to_string()));
+        }
+        if !keys.insert(key.replace('_', "-")) {
+            return Err(error(format!("duplicate key '{key}'")));
+        }
+
+        // Arrays may span lines until the closing bracket
+        let mut value = value.trim().to_string();
+        if value.starts_with('[') {
+            while !array_closed(&value) {
+                let (_, next) = lines
+                    .next()
+                    .ok_or_else(|| error(format!("unterminated array for '{key}'")))?;
+                value.push(' ');
+                value.push_str(strip_comment(next).trim());
+            }
+        }
+
+        let mut parser = Parser {
+            input: &value,
+            pos: 0,
+        };
+        let parsed = parser.value().map_err(|e| error(format!("{key}: {e}")))?;
+        parser.skip_whitespace();
+        if parser.pos != parser.input.len() {
+            return Err(error(format!("{key}: unexpected text after the value")));
+        }
+        match document.scheduled.last_mut() {
+            Some(scheduled) => scheduled.settings.push((key, parsed)),
+            None => document.settings.push((key, parsed)),
+        }
+    }
+    Ok(document)
+}
+
+/// Time of a `[scheduled."<time>"]` table header.
+fn scheduled_time(header: &str) -> Result<DateTime<FixedOffset>, String> {
+    let unsupported =
+        || format!("tables other than [{SCHEDULED_TABLE}.\"<time>\"] are not supported: {header}");
+    let time = header
+        .strip_prefix('[')
+        .and_then(|header| header.strip_suffix(']'))
+        .and_then(|name| name.trim().strip_prefix(SCHEDULED_TABLE))
+        .and_then(|name| name.strip_prefix('.'))
+        .map(|time| time.trim().trim_matches(|c| c == '"' || c == '\''))
+        .ok_or_else(unsupported)?;
+    DateTime::parse_from_rfc3339(time)
+        .map_err(|e| format!("invalid time '{time}' in {header}, expected RFC 3339 like 2026-11-01T00:00:00+01:00: {e}"))
+}
+
+/// `line` without a trailing `#` comment, keeping `#` inside strings.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+fn array_closed(value: &str) -> bool {
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for c in value.chars() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '[') => depth += 1,
+            (None, ']') => depth -= 1,
+            _ => {}
+        }
+        escaped = false;
+    }
+    depth == 0
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self
+            .input
+            .as_bytes()
+            .get(self.pos)
+            .is_some_and(u8::is_ascii_whitespace)
+        {
+            self.pos += 1;
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_whitespace();
+        match self.input.as_bytes().get(self.pos) {
+            Some(b'"') => self.basic_string().map(Value::String),
+            Some(b'\'') => self.literal_string().map(Value::String),
+            Some(b'[') => self.array(),
+            Some(_) => self.bare(),
+            None => Err("missing value".to_string()),
+        }
+    }
+
+    fn basic_string(&mut self) -> Result<String, String> {
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            let c = self.next_char().ok_or("unterminated string")?;
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let escaped = self.next_char().ok_or("unterminated string")?;
+                    s.push(match escaped {
+                        'n' => '\n',
+                        't' => '\t',
+                        'r' => '\r',
+                        '"' => '"',
+                        '\\' => '\\',
+                        'u' | 'U' => {
+                            let len = if escaped == 'u' { 4 } else { 8 };
+                            let hex = self
+                                .input
+                                .get(self.pos..self.pos + len)
+                                .ok_or("invalid unicode escape")?;
+                            self.pos += len;
+                            u32::from_str_radix(hex, 16)
+                                .ok()
+                                .and_then(char::from_u32)
+                                .ok_or("invalid unicode escape")?
+                        }
+                        c => return Err(format!("invalid escape '\\{c}'")),
+                    });
+                }
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn literal_string(&mut self) -> Result<String, String> {
+        self.pos += 1;
+        let start = self.pos;
+        let len = self.input[start..]
+            .find('\'')
+            .ok_or("unterminated string")?;
+        self.pos += len + 1;
+        Ok(self.input[start..start + len].to_string())
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.pos += 1;
+        let mut items = Vec::new();
+        loop {
+            self.skip_whitespace();
+            if self.input.as_bytes().get(self.pos) == Some(&b']') {
+                self.pos += 1;
+                return Ok(Value::Array(items));
+            }
+            match self.value()? {
+                Value::Array(_) => return Err("nested arrays are not supported".to_string()),
+                item => items.push(item),
+            }
+            self.skip_whitespace();
+            match self.input.as_bytes().get(self.pos) {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {}
+                _ => return Err("expected ',' or ']' in array".to_string()),
+            }
+        }
+    }
+
+    /// Booleans and numbers, which TOML writes without quotes.
+    fn bare(&mut self) -> Result<Value, String> {
+        let start = self.pos;
+        while self
+            .input
+            .as_bytes()
+            .get(self.pos)
+            .is_some_and(|b| !b.is_ascii_whitespace() && !matches!(b, b',' | b']'))
+        {
+            self.pos += 1;
+        }
+        let token = &self.input[start..self.pos];
+        let number = token.replace('_', "");
+        match token {
+            "true" => Ok(Value::Bool(true)),
+            "false" => Ok(Value::Bool(false)),
+            _ => number
+                .parse::<i64>()
+                .map(Value::Integer)
+                .or_else(|_| number.parse::<f64>().map(Value::Float))
+                .map_err(|_| format!("invalid value '{token}', strings need quotes")),
+        }
+    }
+
+    fn next_char(&mut self) -> Option<char> {
+        let c = self.input[self.pos..].chars().next()?;
+        self.pos += c.len_utf8();
+        Some(c)
+    }
+}
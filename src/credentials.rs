@@ -0,0 +1,92 @@
+//! Basic and Digest credentials as clients send them in Proxy-Authorization, decoded
+//! apart from the listeners so `fuzz/` can build this file by itself.
+
+use base64::{engine::general_purpose::STANDARD as b64, Engine};
+
+/// Login and password of a `Basic` Proxy-Authorization header, split at the first ':'
+/// as RFC 7617 has it, so a password may contain one but a login can't.
+pub fn basic_credentials(credentials_header: &str) -> Option<(String, String)> {
+    let encoded = credentials_header.trim().strip_prefix("Basic ")?;
+    let decoded = String::from_utf8(b64.decode(encoded.trim()).ok()?).ok()?;
+    let (login, password) = decoded.split_once(':')?;
+
+    Some((login.to_string(), password.to_string()))
+}
+
+/// Login of the `login:password` pair of `credentials_allowed` that a `Basic`
+/// Proxy-Authorization header carries, the login and the password each matching exactly.
+pub fn allowed_login(credentials_header: &str, credentials_allowed: &[String]) -> Option<String> {
+    let (login, password) = basic_credentials(credentials_header)?;
+
+    credentials_allowed
+        .iter()
+        .filter_map(|credentials| credentials.split_once(':'))
+        .find(|(allowed_login, allowed_password)| {
+            *allowed_login == login && *allowed_password == password
+        })
+        .map(|(login, _)| login.to_string())
+}
+
+/// Login a `Basic` or `Digest` Proxy-Authorization header claims, if it can be decoded.
+/// Not checked against anything, so only fit for telling a login it is being guessed.
+pub fn credentials_login(credentials_header: &str) -> Option<String> {
+    if let Some(params) = digest_params(credentials_header) {
+        return params
+            .into_iter()
+            .find(|(name, _)| name == "username")
+            .map(|(_, username)| username)
+            .filter(|username| !username.contains(':'));
+    }
+    basic_credentials(credentials_header).map(|(login, _)| login)
+}
+
+/// Parameters of a `Digest` Proxy-Authorization header (RFC 7616), names lowercased and
+/// quoted values unescaped. `None` for another scheme or a malformed list.
+pub fn digest_params(credentials_header: &str) -> Option<Vec<(String, String)>> {
+    let mut rest = credentials_header
+        .trim()
+        .strip_prefix("Digest ")?
+        .trim_start();
+    let mut params = Vec::new();
+
+    while !rest.is_empty() {
+        let (name, after) = rest.split_once('=')?;
+        let name = name.trim();
+        if name.is_empty()
+            || !name
+                .bytes()
+                .all(|byte| byte.is_ascii_alphanumeric() || byte == b'-' || byte == b'_')
+        {
+            return None;
+        }
+        let after = after.trim_start();
+
+        let (value, after) = match after.strip_prefix('"') {
+            Some(quoted) => {
+                let mut value = String::new();
+                let mut chars = quoted.char_indices();
+                let end = loop {
+                    match chars.next()? {
+                        (at, '"') => break at,
+                        (_, '\\') => value.push(chars.next()?.1),
+                        (_, c) => value.push(c),
+                    }
+                };
+                (value, &quoted[end + 1..])
+            }
+            None => {
+                let end = after.find(',').unwrap_or(after.len());
+                (after[..end].trim().to_string(), &after[end..])
+            }
+        };
+        params.push((name.to_ascii_lowercase(), value));
+
+        rest = after.trim_start();
+        match rest.strip_prefix(',') {
+            Some(after) => rest = after.trim_start(),
+            None if rest.is_empty() => {}
+            None => return None,
+        }
+    }
+    Some(params)
+}
@@ -0,0 +1,312 @@
+use crate::credentials::digest_params;
+use crate::options::Opt;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::{engine::general_purpose::STANDARD as b64, Engine};
+use openssl::hash::{hash, MessageDigest};
+use rand::Rng;
+
+const REALM: &str = "proxerver";
+
+// How long a nonce is taken, after which clients are told it's stale and retry with a
+// fresh one without asking the user again
+const NONCE_LIFETIME: Duration = Duration::from_secs(5 * 60);
+
+// Nonce layout: issue time, random bytes so no two clients share one, and a MAC of both
+const NONCE_TIME_LEN: usize = 8;
+const NONCE_RANDOM_LEN: usize = 8;
+const NONCE_MAC_LEN: usize = 16;
+
+static SECRET: OnceLock<[u8; 32]> = OnceLock::new();
+static NONCE_COUNTS: OnceLock<Mutex<HashMap<String, (SystemTime, u32)>>> = OnceLock::new();
+
+/// How the HTTP listener asks clients for their credentials, `--auth-scheme`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthScheme {
+    Basic,
+    Digest,
+}
+
+impl FromStr for AuthScheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "basic" => Ok(AuthScheme::Basic),
+            "digest" => Ok(AuthScheme::Digest),
+            _ => Err(format!(
+                "Unknown authentication scheme '{s}', expected basic or digest"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for AuthScheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthScheme::Basic => write!(f, "basic"),
+            AuthScheme::Digest => write!(f, "digest"),
+        }
+    }
+}
+
+pub fn enabled() -> bool {
+    Opt::global().auth_scheme == AuthScheme::Digest
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Algorithm {
+    Md5,
+    Sha256,
+}
+
+impl Algorithm {
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Md5 => "MD5",
+            Algorithm::Sha256 => "SHA-256",
+        }
+    }
+
+    /// Lowercase hex digest of `input`, the form every Digest hash is combined in.
+    fn hex(self, input: &str) -> String {
+        let digest = match self {
+            Algorithm::Md5 => MessageDigest::md5(),
+            Algorithm::Sha256 => MessageDigest::sha256(),
+        };
+        let digest = hash(digest, input.as_bytes()).expect("digest algorithms are built in");
+        digest.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+}
+
+/// Why Digest credentials were refused.
+#[derive(Debug)]
+pub enum Failure {
+    /// Right credentials with a nonce past its lifetime, the client retries with a fresh one.
+    Stale,
+    Refused(String),
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Failure::Stale => write!(f, "stale nonce"),
+            Failure::Refused(reason) => write!(f, "{reason}"),
+        }
+    }
+}
+
+/// Proxy-Authenticate challenges, SHA-256 first as RFC 7616 prefers and MD5 for the
+/// clients that know no other.
+pub fn challenges(stale: bool) -> Vec<String> {
+    let nonce = nonce(SystemTime::now());
+    [Algorithm::Sha256, Algorithm::Md5]
+        .into_iter()
+        .map(|algorithm| {
+            let mut challenge = format!(
+                "Digest realm=\"{REALM}\", qop=\"auth\", algorithm={}, nonce=\"{nonce}\"",
+                algorithm.name()
+            );
+            if stale {
+                challenge.push_str(", stale=true");
+            }
+            challenge
+        })
+        .collect()
+}
+
+/// Login of a `Digest` Proxy-Authorization header answering one of our challenges for the
+/// request `method uri`, with the password of one of the `login:password` pairs of
+/// `credentials_allowed`. Each nonce count is only taken once, so a response seen on the
+/// wire can't be replayed.
+pub fn authenticate(
+    credentials_header: &str,
+    method: &str,
+    uris: &[&str],
+    credentials_allowed: &[String],
+) -> Result<String, Failure> {
+    let refused = |reason: &str| Failure::Refused(reason.to_string());
+    let params = digest_params(credentials_header).ok_or_else(|| refused("malformed header"))?;
+    let param = |name: &str| {
+        params
+            .iter()
+            .find(|(param, _)| param == name)
+            .map(|(_, value)| value.as_str())
+    };
+    let required = |name: &str| param(name).ok_or_else(|| refused(&format!("no {name}")));
+
+    let username = required("username")?;
+    let nonce = required("nonce")?;
+    let uri = required("uri")?;
+    let response = required("response")?;
+    let cnonce = required("cnonce")?;
+    let nc_hex = required("nc")?;
+    if param("realm") != Some(REALM) {
+        return Err(refused("another realm"));
+    }
+    if param("qop") != Some("auth") {
+        return Err(refused("qop other than auth"));
+    }
+    if param("userhash").is_some_and(|userhash| userhash.eq_ignore_ascii_case("true")) {
+        return Err(refused("hashed usernames aren't supported"));
+    }
+    let algorithm = match param("algorithm") {
+        None => Algorithm::Md5,
+        Some(name) if name.eq_ignore_ascii_case("MD5") => Algorithm::Md5,
+        Some(name) if name.eq_ignore_ascii_case("SHA-256") => Algorithm::Sha256,
+        Some(name) => return Err(refused(&format!("unsupported algorithm {name}"))),
+    };
+    // The digest covers the URI the client claims, which must be the one it asked for
+    if !uris.contains(&uri) {
+        return Err(refused("digest for another URI"));
+    }
+    let nc = u32::from_str_radix(nc_hex, 16).map_err(|_| refused("invalid nonce count"))?;
+
+    let password = credentials_allowed
+        .iter()
+        .filter_map(|credentials| credentials.split_once(':'))
+        .find(|(login, _)| *login == username)
+        .map(|(_, password)| password)
+        .ok_or_else(|| refused("unknown login"))?;
+    let expected = Digest {
+        algorithm,
+        login: username,
+        password,
+        method,
+        uri,
+        nonce,
+        nc: nc_hex,
+        cnonce,
+    }
+    .response();
+    if expected.len() != response.len()
+        || !openssl::memcmp::eq(expected.as_bytes(), response.as_bytes())
+    {
+        return Err(refused("wrong password"));
+    }
+
+    let issued = nonce_issued(nonce).ok_or_else(|| refused("forged nonce"))?;
+    if is_stale(issued, SystemTime::now()) {
+        return Err(Failure::Stale);
+    }
+    count_nonce(nonce, issued, nc)?;
+    Ok(username.to_string())
+}
+
+/// `Digest` Proxy-Authorization header for the request `method uri`, with a nonce of our
+/// own, for the self-test to get past the listener like a client would.
+pub fn authorization(credentials: &str, method: &str, uri: &str) -> String {
+    let (login, password) = credentials.split_once(':').unwrap_or((credentials, ""));
+    let nonce = nonce(SystemTime::now());
+    let cnonce = hex_random();
+    let nc = "00000001";
+    let algorithm = Algorithm::Sha256;
+    let response = Digest {
+        algorithm,
+        login,
+        password,
+        method,
+        uri,
+        nonce: &nonce,
+        nc,
+        cnonce: &cnonce,
+    }
+    .response();
+    format!(
+        "Digest username=\"{login}\", realm=\"{REALM}\", nonce=\"{nonce}\", uri=\"{uri}\", algorithm={}, qop=auth, nc={nc}, cnonce=\"{cnonce}\", response=\"{response}\"",
+        algorithm.name()
+    )
+}
+
+/// What a Digest response covers, with qop=auth.
+struct Digest<'a> {
+    algorithm: Algorithm,
+    login: &'a str,
+    password: &'a str,
+    method: &'a str,
+    uri: &'a str,
+    nonce: &'a str,
+    nc: &'a str,
+    cnonce: &'a str,
+}
+
+impl Digest<'_> {
+    fn response(&self) -> String {
+        let hex = |input: String| self.algorithm.hex(&input);
+        let ha1 = hex(format!("{}:{REALM}:{}", self.login, self.password));
+        let ha2 = hex(format!("{}:{}", self.method, self.uri));
+        hex(format!(
+            "{ha1}:{}:{}:{}:auth:{ha2}",
+            self.nonce, self.nc, self.cnonce
+        ))
+    }
+}
+
+fn secret() -> &'static [u8; 32] {
+    SECRET.get_or_init(|| rand::thread_rng().gen())
+}
+
+fn nonce_mac(time_and_random: &[u8]) -> Vec<u8> {
+    let mut input = secret().to_vec();
+    input.extend_from_slice(time_and_random);
+    let mac = hash(MessageDigest::sha256(), &input).expect("SHA-256 is built in");
+    mac[..NONCE_MAC_LEN].to_vec()
+}
+
+/// A nonce the proxy can check without keeping it: when it was issued, and a MAC of that
+/// under a secret of this process.
+fn nonce(now: SystemTime) -> String {
+    let issued = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let mut nonce = issued.to_be_bytes().to_vec();
+    nonce.extend_from_slice(&rand::thread_rng().gen::<[u8; NONCE_RANDOM_LEN]>());
+    let mac = nonce_mac(&nonce);
+    nonce.extend_from_slice(&mac);
+    b64.encode(nonce)
+}
+
+/// When a nonce of this process was issued, `None` for any other string.
+fn nonce_issued(nonce: &str) -> Option<SystemTime> {
+    let nonce = b64.decode(nonce).ok()?;
+    if nonce.len() != NONCE_TIME_LEN + NONCE_RANDOM_LEN + NONCE_MAC_LEN {
+        return None;
+    }
+    let (time_and_random, mac) = nonce.split_at(NONCE_TIME_LEN + NONCE_RANDOM_LEN);
+    if !openssl::memcmp::eq(&nonce_mac(time_and_random), mac) {
+        return None;
+    }
+    let issued = u64::from_be_bytes(time_and_random[..NONCE_TIME_LEN].try_into().ok()?);
+    Some(UNIX_EPOCH + Duration::from_secs(issued))
+}
+
+fn is_stale(issued: SystemTime, now: SystemTime) -> bool {
+    now.duration_since(issued)
+        .is_ok_and(|age| age > NONCE_LIFETIME)
+}
+
+/// Take the nonce count `nc` of `nonce`, which must be above the last one taken.
+/// Counts of stale nonces are forgotten, those nonces aren't taken anymore anyway.
+fn count_nonce(nonce: &str, issued: SystemTime, nc: u32) -> Result<(), Failure> {
+    let mut counts = NONCE_COUNTS.get_or_init(Default::default).lock().unwrap();
+    let now = SystemTime::now();
+    counts.retain(|_, (issued, _)| !is_stale(*issued, now));
+
+    let (_, last) = counts.entry(nonce.to_string()).or_insert((issued, 0));
+    if nc <= *last {
+        return Err(Failure::Refused("replayed nonce count".to_string()));
+    }
+    *last = nc;
+    Ok(())
+}
+
+fn hex_random() -> String {
+    rand::thread_rng()
+        .gen::<[u8; 16]>()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
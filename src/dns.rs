@@ -0,0 +1,452 @@
+use crate::geoip;
+use crate::negative;
+use crate::options::Opt;
+use crate::policy::Decision;
+use crate::utils::{formatted_time, interface_addrs, is_no_log};
+
+use std::future::{ready, Ready};
+use std::io::{Error as IoError, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Mutex, OnceLock};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use hyper::client::connect::dns::Name;
+use hyper::client::HttpConnector;
+use hyper::service::Service;
+use hyper::Uri;
+use log::{debug, info, warn};
+use rand::Rng;
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::time::timeout;
+
+pub const QTYPE_A: u16 = 1;
+pub const QTYPE_AAAA: u16 = 28;
+pub const QCLASS_IN: u16 = 1;
+
+const RESOLVER_TIMEOUT: Duration = Duration::from_secs(3);
+
+// How long the host's own addresses are trusted before they're listed again
+const LOCAL_ADDRS_TTL: Duration = Duration::from_secs(10);
+
+static LOCAL_ADDRS: OnceLock<Mutex<Option<LocalAddrs>>> = OnceLock::new();
+
+/// The host's own addresses, as listed at `listed`.
+struct LocalAddrs {
+    listed: Instant,
+    addrs: Vec<IpAddr>,
+}
+
+/// Result of resolving a tunnel target. `ttl` is only known when the answer came from
+/// the configured `--resolver`, the system resolver doesn't expose it.
+#[derive(Debug, Clone)]
+pub struct Resolution {
+    pub addrs: Vec<SocketAddr>,
+    pub ttl: Option<u32>,
+}
+
+/// Resolve a `host:port` tunnel target and log the answer for auditing.
+/// `client` identifies who asked for it in the log line.
+pub async fn resolve(target: &str, client: &str) -> std::io::Result<Resolution> {
+    let (host, port) = split_host_port(target)?;
+
+    // IP literals don't need a lookup and aren't worth logging
+    if let Ok(ip) = host.parse::<IpAddr>() {
+        return Ok(Resolution {
+            addrs: vec![SocketAddr::new(ip, port)],
+            ttl: None,
+        });
+    }
+
+    // A name that just failed to resolve isn't asked for again right away
+    let key = negative::dns_key(host);
+    negative::check(&key, target, client)?;
+
+    let result = match Opt::global().resolver {
+        Some(resolver) => query_resolver(resolver, host, port).await,
+        None => lookup_host((host, port)).await.map(|addrs| Resolution {
+            addrs: addrs.collect(),
+            ttl: None,
+        }),
+    };
+    if let Err(e) = &result {
+        negative::record(&key, e);
+    }
+
+    // Resolutions would give away where no-log users go
+    if is_no_log(client) {
+        return result;
+    }
+
+    let time = formatted_time();
+    match &result {
+        Ok(resolution) => {
+            let ips = resolution
+                .addrs
+                .iter()
+                .map(|addr| addr.ip().to_string())
+                .collect::<Vec<String>>()
+                .join(", ");
+            let ttl = resolution
+                .ttl
+                .map(|ttl| ttl.to_string())
+                .unwrap_or_else(|| "n/a".to_string());
+
+            debug!("[{time}] DNS {host} -> [{ips}] ttl={ttl} client={client}");
+        }
+        Err(e) => warn!("[{time}] DNS {host} failed: {e} client={client}"),
+    }
+
+    result
+}
+
+/// Resolve a target once and keep only the addresses a connection may be made to.
+/// Callers must connect to exactly this set: a second lookup could return addresses
+/// that were never validated.
+pub async fn resolve_pinned(target: &str, client: &str) -> std::io::Result<Vec<SocketAddr>> {
+    let (addrs, decision) = resolve_permitted(target, client).await?;
+
+    decision.log(client, target);
+    if !decision.is_allowed() {
+        return Err(IoError::new(
+            ErrorKind::PermissionDenied,
+            format!("No permitted addresses for {target}"),
+        ));
+    }
+    Ok(addrs)
+}
+
+/// Resolve a target and keep the addresses a connection may be made to, outside the
+/// `--deny-dest-countries` and, without `--allow-private-destinations`, not private, with
+/// the decision on whether any are left.
+pub async fn resolve_permitted(
+    target: &str,
+    client: &str,
+) -> std::io::Result<(Vec<SocketAddr>, Decision)> {
+    let resolution = resolve(target, client).await?;
+
+    // Countries and private ranges are checked before NAT64 hides the addresses in its
+    // prefix
+    let mut denied_country = None;
+    let mut private = false;
+    let allow_private = Opt::global().allow_private_destinations;
+    let addrs = resolution
+        .addrs
+        .into_iter()
+        .filter(|addr| match geoip::denied_dest_country(addr.ip()) {
+            Some(country) => {
+                denied_country.get_or_insert(country);
+                false
+            }
+            None => true,
+        })
+        .filter(|addr| {
+            let allowed = allow_private || !is_private_destination(addr.ip());
+            private |= !allowed;
+            allowed
+        })
+        .collect::<Vec<SocketAddr>>();
+
+    let addrs = synthesize_nat64(target, addrs, client)
+        .into_iter()
+        .filter(|addr| is_destination_allowed(addr.ip()))
+        .collect::<Vec<SocketAddr>>();
+
+    // Unspecified, multicast and broadcast addresses are never connected to
+    let decision = match (addrs.is_empty(), denied_country) {
+        (false, _) => Decision::allow("destination:default"),
+        (true, Some(country)) => Decision::deny(format!("dest-countries:{country}")),
+        (true, None) if private => Decision::deny("destination:private"),
+        (true, None) => Decision::deny("destination:unroutable"),
+    };
+    Ok((addrs, decision))
+}
+
+/// On IPv6-only hosts IPv4-only destinations are reached through a NAT64 gateway:
+/// map their addresses into `--nat64-prefix` when no IPv6 address is available.
+fn synthesize_nat64(target: &str, addrs: Vec<SocketAddr>, client: &str) -> Vec<SocketAddr> {
+    let Some(prefix) = Opt::global().nat64_prefix else {
+        return addrs;
+    };
+    if addrs.iter().any(SocketAddr::is_ipv6) {
+        return addrs;
+    }
+
+    let synthesized = addrs
+        .iter()
+        .filter_map(|addr| match addr.ip() {
+            IpAddr::V4(ip) => prefix
+                .embed_ipv4(ip)
+                .map(|ip| SocketAddr::new(IpAddr::V6(ip), addr.port())),
+            IpAddr::V6(_) => None,
+        })
+        .collect::<Vec<SocketAddr>>();
+
+    if is_no_log(client) {
+        return synthesized;
+    }
+
+    let time = formatted_time();
+    let ips = synthesized
+        .iter()
+        .map(|addr| addr.ip().to_string())
+        .collect::<Vec<String>>()
+        .join(", ");
+    debug!("[{time}] NAT64 {target} -> [{ips}] client={client}");
+
+    synthesized
+}
+
+fn is_destination_allowed(ip: IpAddr) -> bool {
+    let broadcast = matches!(ip, IpAddr::V4(ip) if ip.is_broadcast());
+    !(ip.is_unspecified() || ip.is_multicast() || broadcast)
+}
+
+/// Whether `ip` is loopback, private (RFC 1918 or unique local), link-local or one of the
+/// host's own addresses, which would let clients reach services not meant for them.
+fn is_private_destination(ip: IpAddr) -> bool {
+    let ip = ip.to_canonical();
+    let private = match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local(),
+        IpAddr::V6(ip) => {
+            ip.is_loopback()
+                || ip.segments()[0] & 0xFE00 == 0xFC00
+                || ip.segments()[0] & 0xFFC0 == 0xFE80
+        }
+    };
+    private || is_local_addr(ip)
+}
+
+fn is_local_addr(ip: IpAddr) -> bool {
+    let mut local_addrs = LOCAL_ADDRS.get_or_init(Default::default).lock().unwrap();
+    let local_addrs = match &mut *local_addrs {
+        Some(local_addrs) if local_addrs.listed.elapsed() < LOCAL_ADDRS_TTL => local_addrs,
+        stale => stale.insert(LocalAddrs {
+            listed: Instant::now(),
+            addrs: interface_addrs().into_iter().map(|(_, ip)| ip).collect(),
+        }),
+    };
+    local_addrs.addrs.contains(&ip)
+}
+
+/// `host:port` a plain HTTP request has to be sent to.
+pub fn uri_target(uri: &Uri) -> Option<String> {
+    let host = uri.host()?;
+    let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+        Some("https") => 443,
+        _ => 80,
+    });
+    Some(format!("{host}:{port}"))
+}
+
+/// hyper resolver answering with an already resolved and validated address set, so the
+/// client connects where the checks were made instead of resolving the name again.
+#[derive(Debug, Clone)]
+pub struct PinnedResolver(Vec<SocketAddr>);
+
+impl Service<Name> for PinnedResolver {
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = IoError;
+    type Future = Ready<Result<Self::Response, IoError>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), IoError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _name: Name) -> Self::Future {
+        ready(Ok(self.0.clone().into_iter()))
+    }
+}
+
+/// Connector for exactly `addrs`, giving up on each after `--connect-timeout` and racing
+/// them after `--connect-stagger` like tunnels do.
+pub fn pinned_connector(addrs: Vec<SocketAddr>) -> HttpConnector<PinnedResolver> {
+    let options = Opt::global();
+    let mut http = HttpConnector::new_with_resolver(PinnedResolver(addrs));
+    if options.connect_timeout > 0 {
+        http.set_connect_timeout(Some(Duration::from_secs(options.connect_timeout)));
+    }
+    http.set_happy_eyeballs_timeout(Some(Duration::from_millis(options.connect_stagger)));
+    http
+}
+
+/// Log the address a tunnel actually ended up connected to.
+pub fn log_connected(target: &str, addr: SocketAddr, local: Option<SocketAddr>, client: &str) {
+    if is_no_log(client) {
+        return;
+    }
+
+    let time = formatted_time();
+    let local = local
+        .map(|local| local.to_string())
+        .unwrap_or_else(|| "-".to_string());
+
+    info!("[{time}] Tunnel {target} connected to {addr} from {local} client={client}");
+}
+
+pub fn split_host_port(target: &str) -> std::io::Result<(&str, u16)> {
+    let invalid = || IoError::new(ErrorKind::InvalidInput, format!("Invalid target: {target}"));
+
+    let (host, port) = target.rsplit_once(':').ok_or_else(invalid)?;
+    let port = port.parse::<u16>().map_err(|_| invalid())?;
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    if host.is_empty() {
+        return Err(invalid());
+    }
+    Ok((host, port))
+}
+
+async fn query_resolver(
+    resolver: SocketAddr,
+    host: &str,
+    port: u16,
+) -> std::io::Result<Resolution> {
+    let (v4, v6) = tokio::join!(
+        query(resolver, host, QTYPE_A),
+        query(resolver, host, QTYPE_AAAA)
+    );
+
+    let mut answers = Vec::new();
+    let mut last_error = None;
+    for result in [v4, v6] {
+        match result {
+            Ok(records) => answers.extend(records),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    if answers.is_empty() {
+        return Err(last_error.unwrap_or_else(|| {
+            IoError::new(
+                ErrorKind::NotFound,
+                format!("No addresses found for {host}"),
+            )
+        }));
+    }
+
+    Ok(Resolution {
+        ttl: answers.iter().map(|(_, ttl)| *ttl).min(),
+        addrs: answers
+            .into_iter()
+            .map(|(ip, _)| SocketAddr::new(ip, port))
+            .collect(),
+    })
+}
+
+async fn query(
+    resolver: SocketAddr,
+    host: &str,
+    qtype: u16,
+) -> std::io::Result<Vec<(IpAddr, u32)>> {
+    let bind_addr: SocketAddr = match resolver {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr).await?;
+    socket.connect(resolver).await?;
+
+    let id = rand::thread_rng().gen::<u16>();
+    socket.send(&build_query(id, host, qtype)?).await?;
+
+    let mut buffer = [0u8; 1500];
+    timeout(RESOLVER_TIMEOUT, async {
+        loop {
+            let n = socket.recv(&mut buffer).await?;
+            if n >= 2 && buffer[..2] == id.to_be_bytes() {
+                return parse_response(&buffer[..n], qtype);
+            }
+        }
+    })
+    .await
+    .map_err(|_| {
+        IoError::new(
+            ErrorKind::TimedOut,
+            format!("Resolver {resolver} timed out"),
+        )
+    })?
+}
+
+fn build_query(id: u16, host: &str, qtype: u16) -> std::io::Result<Vec<u8>> {
+    let mut packet = Vec::with_capacity(18 + host.len());
+    packet.extend_from_slice(&id.to_be_bytes());
+    packet.extend_from_slice(&0x0100u16.to_be_bytes()); // standard query, recursion desired
+    packet.extend_from_slice(&1u16.to_be_bytes()); // QDCOUNT
+    packet.extend_from_slice(&[0; 6]); // ANCOUNT, NSCOUNT, ARCOUNT
+
+    for label in host.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            return Err(IoError::new(
+                ErrorKind::InvalidInput,
+                format!("Invalid hostname: {host}"),
+            ));
+        }
+        packet.push(label.len() as u8);
+        packet.extend_from_slice(label.as_bytes());
+    }
+    packet.push(0);
+
+    packet.extend_from_slice(&qtype.to_be_bytes());
+    packet.extend_from_slice(&QCLASS_IN.to_be_bytes());
+    Ok(packet)
+}
+
+fn parse_response(packet: &[u8], qtype: u16) -> std::io::Result<Vec<(IpAddr, u32)>> {
+    let malformed = || IoError::new(ErrorKind::InvalidData, "Malformed DNS response");
+
+    if packet.len() < 12 {
+        return Err(malformed());
+    }
+
+    match packet[3] & 0x0f {
+        0 => {}
+        3 => return Err(IoError::new(ErrorKind::NotFound, "NXDOMAIN")),
+        rcode => return Err(IoError::other(format!("DNS server returned rcode {rcode}"))),
+    }
+
+    let qdcount = u16::from_be_bytes([packet[4], packet[5]]);
+    let ancount = u16::from_be_bytes([packet[6], packet[7]]);
+
+    let mut pos = 12;
+    for _ in 0..qdcount {
+        pos = skip_name(packet, pos).ok_or_else(malformed)? + 4;
+    }
+
+    let mut records = Vec::new();
+    for _ in 0..ancount {
+        pos = skip_name(packet, pos).ok_or_else(malformed)?;
+        let header = packet.get(pos..pos + 10).ok_or_else(malformed)?;
+
+        let rtype = u16::from_be_bytes([header[0], header[1]]);
+        let ttl = u32::from_be_bytes([header[4], header[5], header[6], header[7]]);
+        let rdlength = u16::from_be_bytes([header[8], header[9]]) as usize;
+        let rdata = packet
+            .get(pos + 10..pos + 10 + rdlength)
+            .ok_or_else(malformed)?;
+        pos += 10 + rdlength;
+
+        // CNAME chains are followed by the resolver, only the final addresses matter
+        if rtype != qtype {
+            continue;
+        }
+        match rdata.len() {
+            4 => records.push((IpAddr::from(<[u8; 4]>::try_from(rdata).unwrap()), ttl)),
+            16 => records.push((IpAddr::from(<[u8; 16]>::try_from(rdata).unwrap()), ttl)),
+            _ => return Err(malformed()),
+        }
+    }
+
+    Ok(records)
+}
+
+/// Returns the position right after a (possibly compressed) domain name.
+fn skip_name(packet: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *packet.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            len if len & 0xc0 == 0xc0 => return Some(pos + 2),
+            len => pos += 1 + len as usize,
+        }
+    }
+}
@@ -0,0 +1,54 @@
+use std::ffi::{CStr, CString};
+use std::os::raw::c_void;
+
+/// System library loaded at runtime, so optional integrations (GSSAPI, LDAP, PAM) need
+/// neither headers at build time nor the library on hosts that don't use them.
+#[derive(Debug)]
+pub struct Library {
+    handle: *mut c_void,
+}
+
+// The handle is only used to look up symbols, which dlsym allows from any thread
+unsafe impl Send for Library {}
+unsafe impl Sync for Library {}
+
+impl Library {
+    /// Open the first of `names` that can be loaded.
+    pub fn open(names: &[&str]) -> Result<Library, String> {
+        let mut errors = Vec::new();
+
+        for name in names {
+            let c_name = CString::new(*name).map_err(|e| e.to_string())?;
+            let handle =
+                unsafe { libc::dlopen(c_name.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+            if !handle.is_null() {
+                return Ok(Library { handle });
+            }
+            errors.push(last_error());
+        }
+
+        Err(errors.join("; "))
+    }
+
+    /// Look up a function. `T` must be the `extern "C" fn` type matching its C declaration.
+    pub unsafe fn symbol<T: Copy>(&self, name: &str) -> Result<T, String> {
+        assert_eq!(std::mem::size_of::<T>(), std::mem::size_of::<*mut c_void>());
+
+        let c_name = CString::new(name).map_err(|e| e.to_string())?;
+        let symbol = libc::dlsym(self.handle, c_name.as_ptr());
+        if symbol.is_null() {
+            return Err(format!("{name}: {}", last_error()));
+        }
+        Ok(std::mem::transmute_copy::<*mut c_void, T>(&symbol))
+    }
+}
+
+fn last_error() -> String {
+    let error = unsafe { libc::dlerror() };
+    if error.is_null() {
+        return "unknown error".to_string();
+    }
+    unsafe { CStr::from_ptr(error) }
+        .to_string_lossy()
+        .into_owned()
+}
@@ -0,0 +1,142 @@
+use crate::options::Opt;
+use crate::rules::IpNet;
+
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use rand::Rng;
+
+static STARTED: OnceLock<DateTime<Utc>> = OnceLock::new();
+
+/// Source addresses outbound connections are spread over, with the share of connections
+/// each gets. A fresh range starts at `weight` and ramps up linearly to full weight (100)
+/// over `ramp_days` from `since`, so target sites see its traffic grow gradually.
+#[derive(Debug, Clone)]
+pub struct EgressAddr {
+    pub net: IpNet,
+    pub weight: u32,
+    pub ramp_days: u32,
+    /// Start of the ramp-up, the proxy's start when not given
+    pub since: Option<DateTime<Utc>>,
+}
+
+impl EgressAddr {
+    /// Weight at `now`, between `weight` and 100 while ramping up.
+    pub fn weight_at(&self, now: DateTime<Utc>) -> f64 {
+        let weight = f64::from(self.weight);
+        if self.ramp_days == 0 || self.weight >= 100 {
+            return weight;
+        }
+
+        let since = self.since.unwrap_or_else(started);
+        let ramp = f64::from(self.ramp_days) * 86400.0;
+        let progress = ((now - since).num_seconds() as f64 / ramp).clamp(0.0, 1.0);
+        weight + (100.0 - weight) * progress
+    }
+}
+
+impl FromStr for EgressAddr {
+    type Err = String;
+
+    /// Parse `net=198.51.100.0/28;weight=10;ramp_days=14;since=2026-10-15`. A single
+    /// address may be given without a prefix length.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut net = None;
+        let (mut weight, mut ramp_days, mut since) = (100, 0, None);
+
+        for field in s
+            .split(';')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+        {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key=value, got '{field}'"))?;
+            let value = value.trim();
+
+            match key.trim() {
+                "net" => {
+                    net = Some(match value.parse::<IpAddr>() {
+                        Ok(addr) => IpNet {
+                            addr,
+                            prefix_len: if addr.is_ipv4() { 32 } else { 128 },
+                        },
+                        Err(_) => value.parse()?,
+                    })
+                }
+                "weight" => {
+                    weight = value
+                        .parse::<u32>()
+                        .ok()
+                        .filter(|weight| (1..=100).contains(weight))
+                        .ok_or_else(|| {
+                            format!("Invalid egress weight '{value}', expected 1 to 100")
+                        })?
+                }
+                "ramp_days" => {
+                    ramp_days = value
+                        .parse()
+                        .map_err(|e| format!("Invalid egress ramp_days '{value}': {e}"))?
+                }
+                "since" => {
+                    let date = NaiveDate::parse_from_str(value, "%Y-%m-%d")
+                        .map_err(|e| format!("Invalid egress since '{value}': {e}"))?;
+                    since = Some(date.and_hms_opt(0, 0, 0).unwrap().and_utc());
+                }
+                key => return Err(format!("Unknown egress setting '{key}'")),
+            }
+        }
+
+        Ok(EgressAddr {
+            net: net.ok_or("Egress net is required")?,
+            weight,
+            ramp_days,
+            since,
+        })
+    }
+}
+
+fn started() -> DateTime<Utc> {
+    *STARTED.get_or_init(Utc::now)
+}
+
+/// Start the ramp-up clock of the `--egress-addr` entries without a `since`.
+pub fn init() {
+    started();
+}
+
+pub fn enabled() -> bool {
+    !Opt::global().egress_addr.is_empty()
+}
+
+/// Source address for a connection to an IPv6 (or IPv4) destination, drawn from the
+/// `--egress-addr` entries of that family in proportion to their current weights. `None`
+/// when there are none.
+pub fn pick(ipv6: bool) -> Option<IpAddr> {
+    let now = Utc::now();
+    let candidates = Opt::global()
+        .egress_addr
+        .iter()
+        .filter(|egress| egress.net.addr.is_ipv6() == ipv6)
+        .map(|egress| (egress, egress.weight_at(now)))
+        .collect::<Vec<(&EgressAddr, f64)>>();
+
+    if candidates.is_empty() {
+        return None;
+    }
+    let total = candidates.iter().map(|(_, weight)| weight).sum::<f64>();
+
+    let mut draw = rand::thread_rng().gen_range(0.0..total);
+    for (egress, weight) in &candidates {
+        if draw < *weight {
+            return Some(egress.net.random_addr());
+        }
+        draw -= weight;
+    }
+    // Rounding may leave a sliver past the last entry
+    candidates
+        .last()
+        .map(|(egress, _)| egress.net.random_addr())
+}
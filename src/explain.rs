@@ -0,0 +1,482 @@
+use crate::auth;
+use crate::dns::{resolve_permitted, uri_target};
+use crate::geoip;
+use crate::http::Proxy;
+use crate::json::{object, Value};
+use crate::negotiate;
+use crate::options::Opt;
+use crate::outbound::wireguard_peer;
+use crate::policy::{
+    self, check_client, check_connect_port, check_host, check_host_header, check_maintenance,
+    check_quota, check_token, check_user_connect_port, check_user_host, tenant_rule, Decision,
+};
+use crate::signed_token;
+use crate::upstream::{is_cacheable, ParentCache, Upstream};
+use crate::usage;
+use crate::users::UserStore;
+
+use std::net::IpAddr;
+
+use hyper::{Body, Method, Request, StatusCode, Uri};
+
+/// Request to evaluate the policy for without sending it, as given to `POST /v1/explain`.
+pub struct Hypothetical {
+    user: Option<String>,
+    client_ip: IpAddr,
+    method: Method,
+    target: Uri,
+    /// Host header the client would send
+    host: Option<String>,
+    token: Option<String>,
+    tenant: Option<String>,
+}
+
+impl Hypothetical {
+    /// `{"user": "bob", "client_ip": "203.0.113.7", "method": "CONNECT", "target": "example.com:443"}`,
+    /// optionally with the `host` header and plain secret `token` the client would send and
+    /// the `tenant` whose rules apply. Without `user` the request carries no credentials.
+    pub fn from_json(request: &Value) -> Result<Hypothetical, String> {
+        let field = |name: &str| request.get(name).filter(|value| !value.is_null());
+        let string = |name: &str| -> Result<Option<String>, String> {
+            field(name)
+                .map(|value| {
+                    value
+                        .as_str()
+                        .map(str::to_string)
+                        .ok_or_else(|| format!("{name} must be a string"))
+                })
+                .transpose()
+        };
+
+        let client_ip = string("client_ip")?
+            .ok_or("client_ip is required")?
+            .parse::<IpAddr>()
+            .map_err(|e| format!("Invalid client_ip: {e}"))?;
+        let method = string("method")?
+            .ok_or("method is required")?
+            .to_ascii_uppercase()
+            .parse::<Method>()
+            .map_err(|e| format!("Invalid method: {e}"))?;
+        let target = string("target")?
+            .ok_or("target is required")?
+            .parse::<Uri>()
+            .map_err(|e| format!("Invalid target: {e}"))?;
+        if target.host().is_none() {
+            return Err("target must be host:port or an absolute URL".to_string());
+        }
+
+        Ok(Hypothetical {
+            user: string("user")?,
+            client_ip,
+            method,
+            target,
+            host: string("host")?,
+            token: string("token")?,
+            tenant: string("tenant")?,
+        })
+    }
+}
+
+/// One check of the evaluation: the rules it looked at and the one that decided.
+struct Step {
+    check: &'static str,
+    consulted: Vec<String>,
+    decision: Decision,
+    note: Option<String>,
+}
+
+impl Step {
+    fn new(check: &'static str, consulted: Vec<String>, decision: Decision) -> Step {
+        Step {
+            check,
+            consulted,
+            decision,
+            note: None,
+        }
+    }
+
+    fn note(mut self, note: impl Into<String>) -> Step {
+        self.note = Some(note.into());
+        self
+    }
+
+    fn to_json(&self) -> Value {
+        object([
+            ("check", self.check.into()),
+            ("consulted", self.consulted.clone().into()),
+            ("rule", self.decision.rule.as_str().into()),
+            ("verdict", self.decision.verdict.to_string().into()),
+            ("note", self.note.clone().into()),
+        ])
+    }
+}
+
+/// Walk a hypothetical request through the same checks, in the same order, as the HTTP
+/// listener would, and return the trace. Nothing is sent to the target, only its name is
+/// resolved when the request would go direct.
+pub async fn explain(request: &Hypothetical) -> Result<Value, String> {
+    let proxy = match &request.tenant {
+        Some(name) => Opt::global()
+            .tenant
+            .iter()
+            .find(|tenant| &tenant.name == name)
+            .map(Proxy::from_tenant)
+            .ok_or_else(|| format!("Unknown tenant {name}"))?,
+        None => Proxy::from_options(),
+    };
+
+    let mut trace = Vec::new();
+    let (verdict, rule, status) = match evaluate(&proxy, request, &mut trace).await {
+        Some((rule, status)) => ("deny", Some(rule), status),
+        None => ("allow", None, StatusCode::OK),
+    };
+
+    Ok(object([
+        ("verdict", verdict.into()),
+        ("rule", rule.into()),
+        ("status", u64::from(status.as_u16()).into()),
+        ("client_ip", request.client_ip.to_string().into()),
+        (
+            "trace",
+            Value::Array(trace.iter().map(Step::to_json).collect()),
+        ),
+    ]))
+}
+
+/// Run the checks until one denies, returning its rule and the status the client would get.
+async fn evaluate(
+    proxy: &Proxy,
+    request: &Hypothetical,
+    trace: &mut Vec<Step>,
+) -> Option<(String, StatusCode)> {
+    // Client networks apply to every listener, their rules aren't a tenant's
+    let options = Opt::global();
+    let networks = options
+        .deny_ips
+        .iter()
+        .map(|net| format!("deny-ips:{net}"))
+        .chain((options.ban_threshold > 0).then(|| "bans:failed-logins".to_string()))
+        .chain(
+            geoip::countries(&options.deny_client_countries)
+                .into_iter()
+                .map(|country| format!("client-countries:{country}")),
+        )
+        .chain(
+            options
+                .allow_ips
+                .iter()
+                .map(|net| format!("allow-ips:{net}")),
+        )
+        .collect();
+    let decision = check_client(request.client_ip);
+    if !decision.is_allowed() {
+        let rule = decision.rule.clone();
+        let step =
+            Step::new("client", networks, decision).note("closed before any request is read");
+        trace.push(step);
+        return Some((rule, StatusCode::FORBIDDEN));
+    }
+    trace.push(Step::new("client", networks, decision));
+
+    let mut record = |mut step: Step, status: StatusCode| {
+        let tenant = proxy.tenant.as_deref();
+        step.decision = step.decision.for_tenant(tenant);
+        step.consulted = step
+            .consulted
+            .into_iter()
+            .map(|rule| tenant_rule(rule, tenant))
+            .collect();
+        let denied = (!step.decision.is_allowed()).then(|| (step.decision.rule.clone(), status));
+        trace.push(step);
+        denied
+    };
+
+    let step = Step::new("maintenance", Vec::new(), check_maintenance());
+    if let Some(denied) = record(step, StatusCode::SERVICE_UNAVAILABLE) {
+        return Some(denied);
+    }
+
+    let host = request.target.host().unwrap_or("");
+    let hosts = proxy
+        .allowed_hosts
+        .iter()
+        .map(|pattern| format!("hosts:{pattern}"))
+        .collect();
+    let step = Step::new("hosts", hosts, check_host(host, &proxy.allowed_hosts));
+    if let Some(denied) = record(step, StatusCode::BAD_REQUEST) {
+        return Some(denied);
+    }
+
+    if request.method == Method::CONNECT {
+        let port = request.target.port_u16().unwrap_or(0);
+        let ports = Opt::global()
+            .allow_connect_ports
+            .0
+            .iter()
+            .map(|range| format!("connect-ports:{range}"))
+            .collect();
+        let step = Step::new("connect-ports", ports, check_connect_port(host, port));
+        if let Some(denied) = record(step, StatusCode::FORBIDDEN) {
+            return Some(denied);
+        }
+    }
+
+    let decision = check_host_header(
+        &request.target,
+        request.method == Method::CONNECT,
+        request.host.as_deref(),
+    );
+    let consulted = vec![format!("host-mismatch:{}", Opt::global().host_mismatch)];
+    let step = Step::new("host-header", consulted, decision);
+    if let Some(denied) = record(step, StatusCode::BAD_REQUEST) {
+        return Some(denied);
+    }
+
+    let token_header = request.token.as_deref().map(signed_token::header_value);
+    let decision = check_token(
+        &proxy.secret_token,
+        Opt::global().no_http_token,
+        token_header.as_deref(),
+        false,
+    );
+    let consulted = match proxy.secret_token.is_empty() {
+        true => Vec::new(),
+        false if Opt::global().signed_token_only => vec!["token:signed".to_string()],
+        false => vec!["token:valid".to_string(), "token:signed".to_string()],
+    };
+    let step = Step::new("token", consulted, decision);
+    if let Some(denied) = record(step, StatusCode::BAD_REQUEST) {
+        return Some(denied);
+    }
+
+    let step = explain_auth(proxy, request.user.as_deref());
+    if let Some(denied) = record(step, StatusCode::PROXY_AUTHENTICATION_REQUIRED) {
+        return Some(denied);
+    }
+
+    // Accounts of the users file can have their own hosts and CONNECT ports on top
+    let user = request
+        .user
+        .as_deref()
+        .filter(|_| proxy.tenant.is_none())
+        .and_then(|login| UserStore::global()?.get(login));
+    if let Some(user) = &user {
+        let login = &user.login;
+        let consulted = user
+            .deny_hosts
+            .iter()
+            .map(|pattern| format!("user-deny-hosts:{login}/{pattern}"))
+            .chain(
+                user.hosts
+                    .iter()
+                    .map(|pattern| format!("user-hosts:{login}/{pattern}")),
+            )
+            .collect();
+        let step = Step::new("user-hosts", consulted, check_user_host(user, host));
+        if let Some(denied) = record(step, StatusCode::BAD_REQUEST) {
+            return Some(denied);
+        }
+
+        if request.method == Method::CONNECT {
+            let port = request.target.port_u16().unwrap_or(0);
+            let consulted = user
+                .connect_ports
+                .iter()
+                .flat_map(|ports| &ports.0)
+                .map(|range| format!("user-connect-ports:{login}/{range}"))
+                .collect();
+            let step = Step::new(
+                "user-connect-ports",
+                consulted,
+                check_user_connect_port(user, host, port),
+            );
+            if let Some(denied) = record(step, StatusCode::FORBIDDEN) {
+                return Some(denied);
+            }
+        }
+
+        let decision = check_quota(user, host);
+        let mut step = Step::new("quota", vec![decision.rule.clone()], decision);
+        if let Some(quota) = user.quota {
+            let used = usage::counters(login).used(user.quota_period);
+            step = step.note(format!("{used} of {quota} bytes used"));
+        }
+        if let Some(denied) = record(step, StatusCode::FORBIDDEN) {
+            return Some(denied);
+        }
+    }
+
+    let is_connect = request.method == Method::CONNECT;
+    if let Some(parent) = ParentCache::from_options() {
+        let req = Request::builder()
+            .method(request.method.clone())
+            .uri(request.target.clone())
+            .body(Body::empty())
+            .unwrap();
+        if !is_connect && is_cacheable(&req) {
+            let step = Step::new(
+                "route",
+                vec!["route:parent-cache".to_string()],
+                Decision::allow("route:parent-cache"),
+            )
+            .note(format!(
+                "Goes to the next route if {} refuses the URL or is unavailable",
+                parent.addr
+            ));
+            record(step, StatusCode::OK);
+        }
+    }
+
+    let options = Opt::global();
+    let host = request.target.host().unwrap_or_default();
+
+    let upstream = Upstream::for_target(host);
+    let decision = policy::route(upstream.as_ref(), wireguard_peer());
+    let step = Step::new("route", vec![decision.rule.clone()], decision);
+    record(step, StatusCode::OK);
+
+    if let Some((mark, decision)) = policy::fwmark(host, &options.fwmark_rule, options.fwmark) {
+        let mut consulted = options
+            .fwmark_rule
+            .iter()
+            .map(|rule| format!("fwmark:{}", rule.pattern))
+            .collect::<Vec<String>>();
+        consulted.push("fwmark:default".to_string());
+        let step = Step::new("fwmark", consulted, decision).note(mark.to_string());
+        record(step, StatusCode::OK);
+    }
+
+    if upstream.is_some() {
+        // The upstream proxy resolves and connects to the target itself
+        return None;
+    }
+
+    let target = match is_connect {
+        true => request
+            .target
+            .authority()
+            .map(|authority| authority.to_string()),
+        false => uri_target(&request.target),
+    }
+    .unwrap_or_default();
+    let consulted = geoip::countries(&Opt::global().deny_dest_countries)
+        .into_iter()
+        .map(|country| format!("dest-countries:{country}"))
+        .chain(
+            (!Opt::global().allow_private_destinations).then(|| "destination:private".to_string()),
+        )
+        .chain(["destination:unroutable".to_string()])
+        .collect::<Vec<String>>();
+    let client = format!("{} explain", request.client_ip);
+    let step = match resolve_permitted(&target, &client).await {
+        Ok((addrs, decision)) => {
+            let addrs = addrs
+                .iter()
+                .map(|addr| addr.to_string())
+                .collect::<Vec<String>>()
+                .join(", ");
+            Step::new("destination", consulted, decision).note(format!("[{addrs}]"))
+        }
+        // A real request would fail with 502 before any rule applies
+        Err(e) => Step::new(
+            "destination",
+            consulted,
+            Decision::deny("destination:unresolvable"),
+        )
+        .note(format!("{target}: {e}")),
+    };
+    record(step, StatusCode::BAD_GATEWAY)
+}
+
+/// Which credentials rule would let `user` in, assuming its password is right.
+fn explain_auth(proxy: &Proxy, user: Option<&str>) -> Step {
+    // Users from the admin API, Kerberos and the providers only serve the main listener
+    let main_listener = proxy.tenant.is_none();
+    let user_store = UserStore::global().filter(|_| main_listener);
+    let kerberos = negotiate::enabled() && main_listener;
+    let providers = match main_listener {
+        true => auth::names(),
+        false => Vec::new(),
+    };
+
+    let mut consulted = Vec::new();
+    if user_store.is_some() {
+        consulted.push("auth:users".to_string());
+    }
+    if !proxy.allowed_credentials.is_empty() {
+        consulted.push("auth:credentials".to_string());
+    }
+    if kerberos {
+        consulted.push("auth:kerberos".to_string());
+    }
+    consulted.extend(
+        providers
+            .iter()
+            .map(|name| format!("auth:{}", name.to_lowercase())),
+    );
+
+    if consulted.is_empty() {
+        return Step::new("auth", consulted, Decision::allow("auth:default"));
+    }
+    let Some(login) = user else {
+        return Step::new("auth", consulted, Decision::deny("auth:missing"));
+    };
+
+    if let Some(user) = user_store.and_then(|store| store.get(login)) {
+        if user.is_active() {
+            return Step::new(
+                "auth",
+                consulted,
+                Decision::allow(format!("auth:users/{login}")),
+            );
+        }
+        return Step::new("auth", consulted, Decision::deny("auth:default"))
+            .note(format!("User {login} is disabled or expired"));
+    }
+
+    let in_credentials = proxy.allowed_credentials.iter().any(|credentials| {
+        credentials
+            .split_once(':')
+            .map(|(allowed_login, _)| allowed_login == login)
+            .unwrap_or(false)
+    });
+    if in_credentials {
+        return Step::new(
+            "auth",
+            consulted,
+            Decision::allow(format!("auth:credentials/{login}")),
+        );
+    }
+
+    // A Kerberos principal has a realm, Basic logins for the providers usually don't
+    if kerberos && login.contains('@') {
+        return Step::new("auth", consulted, Decision::allow("auth:kerberos"))
+            .note("If the client has a ticket for this principal");
+    }
+    // Providers with accounts of their own, like --auth-file, know the login for sure
+    if let Some(name) = auth::knowing(login).filter(|_| main_listener) {
+        return Step::new(
+            "auth",
+            consulted,
+            Decision::allow(format!("auth:{}", name.to_lowercase())),
+        );
+    }
+    let providers = match main_listener {
+        true => auth::undecided(login),
+        false => Vec::new(),
+    };
+    if let Some(name) = providers.first() {
+        return Step::new(
+            "auth",
+            consulted,
+            Decision::allow(format!("auth:{}", name.to_lowercase())),
+        )
+        .note(format!(
+            "Whether {} accepts {login} can't be known without the password",
+            providers.join(" or ")
+        ));
+    }
+
+    Step::new("auth", consulted, Decision::deny("auth:default"))
+        .note(format!("No rule knows {login}"))
+}
@@ -0,0 +1,36 @@
+use crate::options::Opt;
+use crate::sockopt::{self, Capability};
+
+use std::io;
+
+use socket2::SockRef;
+
+// Connections a listener keeps waiting for their handshake to finish after accepting data
+// in their SYN
+const QUEUE: i32 = 128;
+
+/// Whether `--tcp-fast-open` is given and the system has it, it's turned off with a
+/// warning at startup otherwise.
+pub fn enabled() -> bool {
+    Opt::global().tcp_fast_open && sockopt::supported(Capability::FastOpen)
+}
+
+/// Accept data in the SYN of clients that have a Fast Open cookie from this listener, with
+/// `--tcp-fast-open`. Needs bit 2 of the `net.ipv4.tcp_fastopen` sysctl.
+pub fn listen(socket: SockRef<'_>) -> io::Result<()> {
+    if !enabled() {
+        return Ok(());
+    }
+    sockopt::fast_open_listen(socket, QUEUE)
+}
+
+/// Send the first data of a connection in its SYN once the destination has handed out a
+/// Fast Open cookie, with `--tcp-fast-open`. The connect then completes at once, and a
+/// destination that turned unreachable only shows when the data is sent. Needs bit 1 of
+/// the `net.ipv4.tcp_fastopen` sysctl, which is set by default.
+pub fn connect(socket: SockRef<'_>) -> io::Result<()> {
+    if !enabled() {
+        return Ok(());
+    }
+    sockopt::fast_open_connect(socket)
+}
@@ -0,0 +1,120 @@
+use crate::utils::percent_decode;
+
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Read};
+use std::path::{Path, PathBuf};
+
+use hyper::header::{CONTENT_LENGTH, CONTENT_TYPE};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::warn;
+use tokio::runtime::Handle;
+
+// Files are sent in chunks of this size, so installers don't have to fit in memory
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Serve `req` from the static directory `root`, for the decoy site, PAC files and
+/// client downloads. Directories are served by their `index.html`, and nothing outside
+/// `root` is reachable, not even through symlinks.
+pub async fn serve(root: &str, req: &Request<Body>) -> Response<Body> {
+    if req.method() != Method::GET && req.method() != Method::HEAD {
+        return status_response(StatusCode::METHOD_NOT_ALLOWED);
+    }
+
+    let Some(path) = resolve(Path::new(root), req.uri().path()) else {
+        return status_response(StatusCode::NOT_FOUND);
+    };
+    let opened = File::open(&path).and_then(|file| Ok((file.metadata()?.len(), file)));
+    let (length, file) = match opened {
+        Ok(opened) => opened,
+        Err(e) if e.kind() == ErrorKind::NotFound => return status_response(StatusCode::NOT_FOUND),
+        Err(e) => {
+            warn!("Cannot read {}: {e}", path.display());
+            return status_response(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+
+    let builder = Response::builder()
+        .header(CONTENT_TYPE, content_type(&path))
+        .header(CONTENT_LENGTH, length);
+    if req.method() == Method::HEAD {
+        return builder.body(Body::empty()).unwrap();
+    }
+
+    let (sender, body) = Body::channel();
+    let runtime = Handle::current();
+    tokio::task::spawn_blocking(move || {
+        if let Err(e) = send_file(file, sender, &runtime) {
+            warn!("Sending {} stopped: {e}", path.display());
+        }
+    });
+    builder.body(body).unwrap()
+}
+
+fn send_file(mut file: File, mut sender: hyper::body::Sender, runtime: &Handle) -> io::Result<()> {
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        let n = file.read(&mut buffer)?;
+        if n == 0 {
+            return Ok(());
+        }
+        runtime
+            .block_on(sender.send_data(buffer[..n].to_vec().into()))
+            .map_err(|_| io::Error::new(ErrorKind::BrokenPipe, "client went away"))?;
+    }
+}
+
+/// File under `root` that the URL path `path` names.
+fn resolve(root: &Path, path: &str) -> Option<PathBuf> {
+    let path = percent_decode(path)?;
+
+    let mut resolved = root.to_path_buf();
+    for segment in path.split('/').filter(|segment| !segment.is_empty()) {
+        if segment == "." || segment == ".." || segment.contains(['\\', '\0']) {
+            return None;
+        }
+        resolved.push(segment);
+    }
+    if resolved.is_dir() {
+        resolved.push("index.html");
+    }
+
+    let resolved = fs::canonicalize(resolved).ok()?;
+    let root = fs::canonicalize(root).ok()?;
+    (resolved.starts_with(&root) && resolved.is_file()).then_some(resolved)
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path
+        .extension()
+        .and_then(|extension| extension.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+
+    match extension.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "pac" => "application/x-ns-proxy-autoconfig",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "ico" => "image/x-icon",
+        "webp" => "image/webp",
+        "woff2" => "font/woff2",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" | "tgz" => "application/gzip",
+        _ => "application/octet-stream",
+    }
+}
+
+fn status_response(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+        .body(Body::from(format!("{status}\n")))
+        .unwrap()
+}
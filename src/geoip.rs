@@ -0,0 +1,294 @@
+use crate::options::Opt;
+
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::net::IpAddr;
+use std::sync::OnceLock;
+
+// Start of the metadata section, which is searched for in the file's last 128 KiB
+const METADATA_MARKER: &[u8] = b"\xAB\xCD\xEFMaxMind.com";
+const METADATA_MAX_SIZE: usize = 128 * 1024;
+
+// Zero bytes between the search tree and the data section
+const DATA_SECTION_SEPARATOR: usize = 16;
+
+// Field types of the data section
+const POINTER: u8 = 1;
+const STRING: u8 = 2;
+const UINT16: u8 = 5;
+const UINT32: u8 = 6;
+const MAP: u8 = 7;
+const UINT64: u8 = 9;
+const UINT128: u8 = 10;
+const ARRAY: u8 = 11;
+const BOOLEAN: u8 = 14;
+
+static DATABASE: OnceLock<GeoIp> = OnceLock::new();
+static DENIED_CLIENT_COUNTRIES: OnceLock<Vec<String>> = OnceLock::new();
+static DENIED_DEST_COUNTRIES: OnceLock<Vec<String>> = OnceLock::new();
+
+/// Load the `--geoip-db`, so a missing or broken database is reported at startup.
+pub fn init() -> io::Result<()> {
+    let Some(path) = &Opt::global().geoip_db else {
+        return Ok(());
+    };
+    let database = GeoIp::open(path)?;
+    let _ = DATABASE.set(database);
+    Ok(())
+}
+
+pub fn enabled() -> bool {
+    DATABASE.get().is_some()
+}
+
+/// ISO 3166 code of the country `ip` is in, or registered in when the database doesn't
+/// place it. `None` without a database or for addresses it doesn't know.
+pub fn country(ip: IpAddr) -> Option<String> {
+    DATABASE.get()?.country(ip.to_canonical())
+}
+
+/// Country of a client `ip` in `--deny-client-countries`.
+pub fn denied_client_country(ip: IpAddr) -> Option<String> {
+    let denied =
+        DENIED_CLIENT_COUNTRIES.get_or_init(|| countries(&Opt::global().deny_client_countries));
+    country(ip).filter(|country| denied.contains(country))
+}
+
+/// Country of a destination `ip` in `--deny-dest-countries`.
+pub fn denied_dest_country(ip: IpAddr) -> Option<String> {
+    let denied =
+        DENIED_DEST_COUNTRIES.get_or_init(|| countries(&Opt::global().deny_dest_countries));
+    country(ip).filter(|country| denied.contains(country))
+}
+
+/// Country codes of a comma-separated list, upper-cased like in the database.
+pub fn countries(list: &Option<String>) -> Vec<String> {
+    list.iter()
+        .flat_map(|list| list.split(','))
+        .map(|country| country.trim().to_ascii_uppercase())
+        .filter(|country| !country.is_empty())
+        .collect()
+}
+
+/// Database in the MaxMind DB format, like GeoLite2 Country or City, read into memory.
+/// Only what's needed to look up countries is decoded.
+struct GeoIp {
+    bytes: Vec<u8>,
+    node_count: usize,
+    record_size: usize,
+    ip_version: u64,
+    /// Node IPv4 addresses start at, `::/96` in an IPv6 tree
+    ipv4_start: usize,
+    data_start: usize,
+}
+
+impl GeoIp {
+    fn open(path: &str) -> io::Result<GeoIp> {
+        let invalid =
+            |message: &str| io::Error::new(ErrorKind::InvalidData, format!("{path}: {message}"));
+        let bytes = fs::read(path)?;
+
+        let search_from = bytes.len().saturating_sub(METADATA_MAX_SIZE);
+        let marker = bytes[search_from..]
+            .windows(METADATA_MARKER.len())
+            .rposition(|window| window == METADATA_MARKER)
+            .ok_or_else(|| invalid("not a MaxMind DB file"))?;
+        let metadata_start = search_from + marker + METADATA_MARKER.len();
+
+        let metadata = Section(&bytes[metadata_start..]);
+        let number = |key: &str| {
+            metadata
+                .get(0, key)
+                .and_then(|offset| metadata.uint(offset))
+                .ok_or_else(|| invalid(&format!("missing {key} in the metadata")))
+        };
+        let node_count = number("node_count")? as usize;
+        let record_size = number("record_size")? as usize;
+        let ip_version = number("ip_version")?;
+        if ![24, 28, 32].contains(&record_size) || ![4, 6].contains(&ip_version) {
+            return Err(invalid("unsupported record size or IP version"));
+        }
+
+        let data_start = node_count * record_size / 4 + DATA_SECTION_SEPARATOR;
+        if data_start > search_from + marker {
+            return Err(invalid("search tree runs into the metadata"));
+        }
+
+        let mut geoip = GeoIp {
+            bytes,
+            node_count,
+            record_size,
+            ip_version,
+            ipv4_start: 0,
+            data_start,
+        };
+        if ip_version == 6 {
+            for _ in 0..96 {
+                if geoip.ipv4_start >= node_count {
+                    break;
+                }
+                geoip.ipv4_start = geoip
+                    .record(geoip.ipv4_start, false)
+                    .ok_or_else(|| invalid("search tree is cut short"))?;
+            }
+        }
+        Ok(geoip)
+    }
+
+    fn country(&self, ip: IpAddr) -> Option<String> {
+        let (mut node, octets) = match ip {
+            IpAddr::V4(ip) => (self.ipv4_start, ip.octets().to_vec()),
+            IpAddr::V6(_) if self.ip_version == 4 => return None,
+            IpAddr::V6(ip) => (0, ip.octets().to_vec()),
+        };
+
+        let bits = octets
+            .iter()
+            .flat_map(|octet| (0..8).rev().map(move |bit| octet >> bit & 1 == 1));
+        for right in bits {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, right)?;
+        }
+        // Equal to the node count when the address isn't in the database
+        if node <= self.node_count {
+            return None;
+        }
+
+        let data = Section(&self.bytes[self.data_start..]);
+        let record = (node - self.node_count).checked_sub(DATA_SECTION_SEPARATOR)?;
+        ["country", "registered_country"].iter().find_map(|key| {
+            let country = data.get(record, key)?;
+            let iso_code = data.get(country, "iso_code")?;
+            data.string(iso_code).map(str::to_string)
+        })
+    }
+
+    /// Left or right record of a search tree node.
+    fn record(&self, node: usize, right: bool) -> Option<usize> {
+        let node_size = self.record_size / 4;
+        let bytes = self.bytes.get(node * node_size..(node + 1) * node_size)?;
+        let be = |bytes: &[u8]| {
+            bytes
+                .iter()
+                .fold(0, |value, &byte| value << 8 | byte as usize)
+        };
+
+        Some(match (self.record_size, right) {
+            (28, false) => (bytes[3] as usize & 0xF0) << 20 | be(&bytes[..3]),
+            (28, true) => (bytes[3] as usize & 0x0F) << 24 | be(&bytes[4..]),
+            (_, false) => be(&bytes[..node_size / 2]),
+            (_, true) => be(&bytes[node_size / 2..]),
+        })
+    }
+}
+
+/// Data or metadata section, whose pointers are offsets from its start.
+struct Section<'a>(&'a [u8]);
+
+impl Section<'_> {
+    /// Type and size of the field at `offset` and where its payload starts. For a pointer,
+    /// the size is the offset it points to and the payload start is the end of the pointer.
+    fn header(&self, offset: usize) -> Option<(u8, usize, usize)> {
+        let control = *self.0.get(offset)?;
+        let mut position = offset + 1;
+        let mut kind = control >> 5;
+
+        if kind == POINTER {
+            let (length, base) = match control >> 3 & 0x3 {
+                0 => (1, 0),
+                1 => (2, 2048),
+                2 => (3, 526_336),
+                _ => (4, 0),
+            };
+            let high = match length {
+                4 => 0,
+                _ => usize::from(control & 0x7),
+            };
+            let bytes = self.0.get(position..position + length)?;
+            let target = bytes
+                .iter()
+                .fold(high, |value, &byte| value << 8 | byte as usize);
+            return Some((POINTER, target + base, position + length));
+        }
+
+        if kind == 0 {
+            kind = 7 + *self.0.get(position)?;
+            position += 1;
+        }
+        let size = usize::from(control & 0x1F);
+        let extra = match size {
+            29 => 1,
+            30 => 2,
+            31 => 3,
+            _ => 0,
+        };
+        let bytes = self.0.get(position..position + extra)?;
+        let value = bytes
+            .iter()
+            .fold(0, |value, &byte| value << 8 | byte as usize);
+        let size = match size {
+            29 => 29 + value,
+            30 => 285 + value,
+            31 => 65_821 + value,
+            size => size,
+        };
+        Some((kind, size, position + extra))
+    }
+
+    /// Header of the field at `offset`, or of the one it points to.
+    fn resolve(&self, offset: usize) -> Option<(u8, usize, usize)> {
+        match self.header(offset)? {
+            (POINTER, target, _) => self.header(target),
+            header => Some(header),
+        }
+    }
+
+    /// Offset of the field after the one at `offset`.
+    fn skip(&self, offset: usize) -> Option<usize> {
+        let (kind, size, payload) = self.header(offset)?;
+        match kind {
+            POINTER | BOOLEAN => Some(payload),
+            MAP => (0..size * 2).try_fold(payload, |position, _| self.skip(position)),
+            ARRAY => (0..size).try_fold(payload, |position, _| self.skip(position)),
+            _ => Some(payload + size),
+        }
+    }
+
+    /// Offset of the value of `key` in the map at `offset`.
+    fn get(&self, offset: usize, key: &str) -> Option<usize> {
+        let (MAP, size, mut position) = self.resolve(offset)? else {
+            return None;
+        };
+        for _ in 0..size {
+            let matches = self.string(position)? == key;
+            position = self.skip(position)?;
+            if matches {
+                return Some(position);
+            }
+            position = self.skip(position)?;
+        }
+        None
+    }
+
+    fn string(&self, offset: usize) -> Option<&str> {
+        let (STRING, size, payload) = self.resolve(offset)? else {
+            return None;
+        };
+        std::str::from_utf8(self.0.get(payload..payload + size)?).ok()
+    }
+
+    fn uint(&self, offset: usize) -> Option<u64> {
+        let (kind, size, payload) = self.resolve(offset)?;
+        if ![UINT16, UINT32, UINT64, UINT128].contains(&kind) || size > 8 {
+            return None;
+        }
+        let bytes = self.0.get(payload..payload + size)?;
+        Some(
+            bytes
+                .iter()
+                .fold(0, |value, &byte| value << 8 | u64::from(byte)),
+        )
+    }
+}
@@ -0,0 +1,91 @@
+//! Request heads as the HTTPS listener reads them. Nothing here depends on the rest of
+//! the proxy, so the fuzz targets build it on its own.
+
+use std::collections::HashMap;
+
+use hyper::header::{HeaderName, HeaderValue};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// Longest request head read from a client, longer ones are refused with 431.
+pub const MAX_HEAD: usize = 16 * 1024;
+
+/// Method, URI, version and headers, names lower-cased.
+pub type RequestInfo = (String, String, String, HashMap<String, String>);
+
+/// Read from the client until the end of the request head, at most [`MAX_HEAD`] bytes.
+/// Returns where the head ends, `None` if the client stopped sending or the limit was
+/// reached first. Whatever came in after the head is left in `buffer`.
+pub async fn read_head<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buffer: &mut Vec<u8>,
+) -> std::io::Result<Option<usize>> {
+    let mut chunk = vec![0; 4096];
+    while buffer.len() < MAX_HEAD {
+        let n = reader.read(&mut chunk).await?;
+        if n == 0 {
+            return Ok(None);
+        }
+        // The end may straddle two reads
+        let from = buffer.len().saturating_sub(3);
+        buffer.extend_from_slice(&chunk[..n]);
+        if let Some(position) = buffer[from..]
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+        {
+            return Ok(Some(from + position + 4));
+        }
+    }
+    Ok(None)
+}
+
+/// Parse a request head strictly, since the request is passed on rebuilt: lines must end
+/// in CRLF, folded lines and header names with whitespace are refused, and repeated
+/// headers are joined with commas so none is lost.
+pub fn parse_request(request: &str) -> Result<RequestInfo, &'static str> {
+    let head = request
+        .strip_suffix("\r\n\r\n")
+        .ok_or("Unterminated head")?;
+    let mut lines = head.split("\r\n");
+
+    // Get the first line with the method, URI, and version
+    let request_line = lines.next().ok_or("Missing request line")?;
+    let mut request_parts = request_line.split_whitespace();
+
+    let method = request_parts.next().ok_or("Missing method")?.to_string();
+    let uri = request_parts.next().ok_or("Missing URI")?.to_string();
+    let version = request_parts.next().ok_or("Missing version")?.to_string();
+    if request_parts.next().is_some() {
+        return Err("Malformed request line");
+    }
+
+    // Initialize a hash map for headers
+    let mut headers = HashMap::new();
+
+    // Process the rest of the lines as headers
+    for line in lines {
+        if line.starts_with([' ', '\t']) {
+            return Err("Folded header line");
+        }
+        let (key, value) = parse_header(line)?;
+        headers
+            .entry(key.to_lowercase())
+            .and_modify(|values: &mut String| {
+                values.push_str(", ");
+                values.push_str(&value);
+            })
+            .or_insert(value);
+    }
+
+    Ok((method, uri, version, headers))
+}
+
+fn parse_header(line: &str) -> Result<(String, String), &'static str> {
+    let mut parts = line.splitn(2, ':');
+    let key = parts.next().ok_or("Missing header key")?;
+    let value = parts.next().ok_or("Missing header value")?.trim();
+
+    // Whitespace before the colon could make servers disagree on the name
+    HeaderName::from_bytes(key.as_bytes()).map_err(|_| "Invalid header name")?;
+    HeaderValue::from_str(value).map_err(|_| "Invalid header value")?;
+    Ok((key.to_string(), value.to_string()))
+}
@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::mem::MaybeUninit;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use wildmatch::WildMatch;
+
+// Host patterns starting with this are regular expressions instead of wildcards
+const REGEX_PREFIX: &str = "re:";
+
+// Longest error message regerror writes for a pattern that doesn't compile
+const MAX_ERROR: usize = 256;
+
+/// Regular expressions of the host patterns seen so far, compiled once. Patterns come from
+/// the options, the config file and the admin API, so there are only ever a few.
+static REGEXES: OnceLock<Mutex<HashMap<String, Option<Arc<Regex>>>>> = OnceLock::new();
+
+/// Whether `host` matches `pattern`: a wildcard pattern like `*.example.com`, or after
+/// `re:` a POSIX extended regular expression the whole host must match, ignoring case,
+/// like `re:api[0-9]+\.example\.com`. A regular expression that doesn't compile
+/// matches nothing.
+pub fn matches(pattern: &str, host: &str) -> bool {
+    match pattern.strip_prefix(REGEX_PREFIX) {
+        Some(expr) => regex(expr).is_some_and(|regex| regex.is_match(host)),
+        None => WildMatch::new(pattern).matches(host),
+    }
+}
+
+/// Check a host pattern before it's used, a regular expression must compile.
+pub fn check(pattern: &str) -> Result<(), String> {
+    match pattern.strip_prefix(REGEX_PREFIX) {
+        Some(expr) => Regex::new(expr)
+            .map(drop)
+            .map_err(|e| format!("Invalid regular expression in host pattern '{pattern}': {e}")),
+        None => Ok(()),
+    }
+}
+
+fn regex(expr: &str) -> Option<Arc<Regex>> {
+    // Fuzzing tries endless patterns, keeping them all would look like a leak
+    if cfg!(fuzzing) {
+        return Regex::new(expr).ok().map(Arc::new);
+    }
+    let mut regexes = REGEXES.get_or_init(Default::default).lock().unwrap();
+    regexes
+        .entry(expr.to_string())
+        .or_insert_with(|| Regex::new(expr).ok().map(Arc::new))
+        .clone()
+}
+
+/// POSIX extended regular expression compiled by the C library, anchored to the whole
+/// string.
+struct Regex(Box<libc::regex_t>);
+
+// SAFETY: regexec doesn't change the compiled expression, the C libraries this builds
+// against allow matching with it from several threads at once
+unsafe impl Send for Regex {}
+unsafe impl Sync for Regex {}
+
+impl Regex {
+    fn new(expr: &str) -> Result<Regex, String> {
+        let anchored = CString::new(format!("^({expr})$"))
+            .map_err(|_| "contains a NUL character".to_string())?;
+        let mut regex = Box::new(MaybeUninit::<libc::regex_t>::zeroed());
+        let flags = libc::REG_EXTENDED | libc::REG_ICASE | libc::REG_NOSUB;
+
+        let result = unsafe { libc::regcomp(regex.as_mut_ptr(), anchored.as_ptr(), flags) };
+        if result != 0 {
+            let mut message = [0 as libc::c_char; MAX_ERROR];
+            unsafe { libc::regerror(result, regex.as_ptr(), message.as_mut_ptr(), MAX_ERROR) };
+            let message = unsafe { CStr::from_ptr(message.as_ptr()) };
+            return Err(message.to_string_lossy().into_owned());
+        }
+        // SAFETY: regcomp succeeded, so it initialized the expression
+        Ok(Regex(unsafe { Box::from_raw(Box::into_raw(regex).cast()) }))
+    }
+
+    fn is_match(&self, s: &str) -> bool {
+        let Ok(s) = CString::new(s) else {
+            return false;
+        };
+        unsafe { libc::regexec(&*self.0, s.as_ptr(), 0, std::ptr::null_mut(), 0) == 0 }
+    }
+}
+
+impl Drop for Regex {
+    fn drop(&mut self) {
+        unsafe { libc::regfree(&mut *self.0) };
+    }
+}
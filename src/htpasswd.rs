@@ -0,0 +1,213 @@
+use crate::argon2::Argon2Hash;
+use crate::auth::AuthProvider;
+use crate::dylib::Library;
+use crate::utils::to_sha256;
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::fs;
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::SystemTime;
+
+use log::{info, warn};
+
+type CryptRa =
+    unsafe extern "C" fn(*const c_char, *const c_char, *mut *mut c_void, *mut c_int) -> *mut c_char;
+
+static CRYPT: OnceLock<Result<Crypt, String>> = OnceLock::new();
+
+/// bcrypt through the system's libxcrypt, which every supported distribution ships.
+struct Crypt {
+    _library: Library,
+    crypt_ra: CryptRa,
+}
+
+impl Crypt {
+    fn get() -> Result<&'static Crypt, String> {
+        CRYPT
+            .get_or_init(|| {
+                let library = Library::open(&["libcrypt.so.1", "libcrypt.so"])?;
+                unsafe {
+                    Ok(Crypt {
+                        crypt_ra: library.symbol("crypt_ra")?,
+                        _library: library,
+                    })
+                }
+            })
+            .as_ref()
+            .map_err(|e| format!("bcrypt needs libxcrypt: {e}"))
+    }
+
+    /// Whether `password` hashes to `setting`, a full bcrypt hash with its salt and cost.
+    fn verify(&self, password: &str, setting: &CStr) -> bool {
+        let Ok(password) = CString::new(password) else {
+            return false;
+        };
+
+        let mut data = ptr::null_mut();
+        let mut size = 0;
+        // SAFETY: crypt_ra allocates its work area with malloc, freed once the result
+        // living in it is compared
+        unsafe {
+            let hashed = (self.crypt_ra)(password.as_ptr(), setting.as_ptr(), &mut data, &mut size);
+            // Failures come back as a string starting with '*', never a valid hash
+            let matched = !hashed.is_null()
+                && CStr::from_ptr(hashed).to_bytes().len() == setting.to_bytes().len()
+                && openssl::memcmp::eq(CStr::from_ptr(hashed).to_bytes(), setting.to_bytes());
+            libc::free(data);
+            matched
+        }
+    }
+}
+
+#[derive(Debug)]
+enum PasswordHash {
+    Bcrypt(CString),
+    Argon2(Argon2Hash),
+}
+
+impl FromStr for PasswordHash {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if ["$2a$", "$2b$", "$2y$"]
+            .iter()
+            .any(|prefix| s.starts_with(prefix))
+        {
+            Crypt::get()?;
+            let setting = CString::new(s).map_err(|_| "invalid bcrypt hash".to_string())?;
+            return Ok(PasswordHash::Bcrypt(setting));
+        }
+        if s.starts_with("$argon2") {
+            return Ok(PasswordHash::Argon2(s.parse()?));
+        }
+        Err("not a bcrypt or Argon2 hash, `htpasswd -B` writes bcrypt ones".to_string())
+    }
+}
+
+impl PasswordHash {
+    fn verify(&self, password: &str) -> bool {
+        match self {
+            PasswordHash::Bcrypt(setting) => {
+                Crypt::get().is_ok_and(|crypt| crypt.verify(password, setting))
+            }
+            PasswordHash::Argon2(hash) => hash.verify(password.as_bytes()),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    modified: Option<SystemTime>,
+    hashes: HashMap<String, Arc<PasswordHash>>,
+}
+
+/// Accounts of an htpasswd-style file, `--auth-file`: one `login:hash` per line, the
+/// hash bcrypt (`htpasswd -B`) or Argon2, so no password is ever written out. The file
+/// is read again when it changes, and a login is only hashed again after a wrong
+/// password or a change of its line, since bcrypt and Argon2 are slow on purpose.
+pub struct Htpasswd {
+    path: String,
+    entries: Mutex<Entries>,
+    verified: Mutex<HashMap<String, String>>,
+}
+
+impl Htpasswd {
+    pub fn load(path: &str) -> Result<Htpasswd, String> {
+        let entries = read(path).map_err(|e| format!("{path}: {e}"))?;
+        info!("Loaded {} accounts from {path}", entries.hashes.len());
+        Ok(Htpasswd {
+            path: path.to_string(),
+            entries: Mutex::new(entries),
+            verified: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Read the file again if it changed since. A broken file keeps the accounts read
+    /// last, so a half-written edit doesn't lock everyone out.
+    fn refresh(&self) {
+        let modified = fs::metadata(&self.path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        if modified == self.entries.lock().unwrap().modified {
+            return;
+        }
+        match read(&self.path) {
+            Ok(entries) => {
+                info!(
+                    "Reloaded {} accounts from {}",
+                    entries.hashes.len(),
+                    self.path
+                );
+                *self.entries.lock().unwrap() = entries;
+                self.verified.lock().unwrap().clear();
+            }
+            Err(e) => {
+                warn!("Keeping the accounts of {}: {e}", self.path);
+                self.entries.lock().unwrap().modified = modified;
+            }
+        }
+    }
+}
+
+fn read(path: &str) -> Result<Entries, String> {
+    let modified = fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok();
+    let content = fs::read_to_string(path).map_err(|e| e.to_string())?;
+
+    let mut hashes = HashMap::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |message: String| format!("line {}: {message}", number + 1);
+        let (login, hash) = line
+            .split_once(':')
+            .ok_or_else(|| error("expected login:hash".to_string()))?;
+        let hash = hash
+            .parse::<PasswordHash>()
+            .map_err(|e| error(format!("{login}: {e}")))?;
+        if hashes.insert(login.to_string(), Arc::new(hash)).is_some() {
+            return Err(error(format!("duplicate login {login}")));
+        }
+    }
+    Ok(Entries { modified, hashes })
+}
+
+impl AuthProvider for Htpasswd {
+    fn name(&self) -> &'static str {
+        "htpasswd"
+    }
+
+    fn authenticate(&self, login: &str, password: &str) -> Result<bool, String> {
+        self.refresh();
+
+        let password_hash = to_sha256(&format!("{login}:{password}"));
+        if self.verified.lock().unwrap().get(login) == Some(&password_hash) {
+            return Ok(true);
+        }
+
+        // Hashed without the lock, other logins needn't wait for this one
+        let hash = self.entries.lock().unwrap().hashes.get(login).cloned();
+        let allowed = match hash {
+            Some(hash) => hash.verify(password),
+            None => return Ok(false),
+        };
+        if allowed {
+            self.verified
+                .lock()
+                .unwrap()
+                .insert(login.to_string(), password_hash);
+        }
+        Ok(allowed)
+    }
+
+    fn knows(&self, login: &str) -> Option<bool> {
+        Some(self.entries.lock().unwrap().hashes.contains_key(login))
+    }
+}
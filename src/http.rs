@@ -1,71 +1,475 @@
+#[cfg(feature = "wireguard")]
+use crate::wireguard::WireGuardConnector;
 use crate::{
+    access::{self, Access},
+    alerts::record_failed_login,
+    auth, bans, breaker,
+    credentials::allowed_login,
+    digest,
+    dns::{pinned_connector, resolve_pinned, uri_target},
+    egress, fastopen,
+    limiter::{self, is_queue_full, is_queue_full_error, ClientSlot, LimitedConnector},
+    listener::{self, Listen},
+    maintenance, mss, negotiate,
     options::Opt,
+    outbound::{connect_target, error_status, fwmark_for, wireguard_peer, MarkedConnector},
+    policy::{
+        self, check_connect_port, check_framing, check_host, check_host_header, check_maintenance,
+        check_quota, check_token, check_user_connect_port, check_user_host, Decision,
+    },
+    probe::{self, is_echo_host, is_probe_host, Caller, ECHO_HOST},
+    reload, secrets, selftest,
+    sessions::{self, Source, SESSION_HEADER},
+    signed_token::{self, TokenHeader},
+    stats,
+    tenant::{self, ConnectionGuard, MemoryReservation, Tenant, TenantLimits},
+    throttle::{self, relay_body, Bandwidth, ConnectionBandwidth, ThrottledStream},
+    tunnel,
+    upstream::{try_parent_cache, Upstream, UpstreamConnector},
+    usage::{self, meter_body, Direction, Meter, MeteredStream},
+    users::UserStore,
     utils::{
-        formatted_time, get_rand_ipv4_socket_addr, is_credentials_allowed, is_host_allowed,
-        require_basic_auth, to_sha256,
+        client_label, formatted_time, is_no_log, limited_response, loggable, rate_limited,
+        redacted_headers, require_basic_auth, retry_headers, unverified_label,
     },
+    warmup,
 };
 
-use clap::Parser;
-use std::net::{IpAddr, SocketAddr, ToSocketAddrs};
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
 
 use hyper::{
-    client::HttpConnector,
-    header::PROXY_AUTHORIZATION,
+    body::{Bytes, HttpBody},
+    header::{
+        HeaderName, HeaderValue, CONNECTION, CONTENT_LENGTH, CONTENT_TYPE, HOST,
+        PROXY_AUTHENTICATE, PROXY_AUTHORIZATION, TRANSFER_ENCODING,
+    },
     server::conn::AddrStream,
     service::{make_service_fn, service_fn},
-    Body, Client, Method, Request, Response, Server, StatusCode,
+    Body, Client, Method, Request, Response, Server, StatusCode, Version,
 };
+use log::{debug, info, warn};
 
-use tokio::{
-    io::{AsyncRead, AsyncWrite},
-    net::TcpSocket,
-};
+/// How a client got past the credentials check.
+enum Authentication {
+    /// No credentials are required
+    Anonymous,
+    Credentials(String, Source),
+    Session(String),
+}
+
+/// Label of the client a response went to, for its access log line.
+#[derive(Clone)]
+struct Identified(String);
 
 #[derive(Debug, Clone)]
 pub(crate) struct Proxy {
     pub allowed_credentials: Vec<String>,
     pub allowed_hosts: Vec<String>,
     pub secret_token: String,
+    pub tenant: Option<String>,
 }
 
 impl Proxy {
+    /// Settings of the main listener, from the flags or, where it has them, the secret manager.
+    pub(crate) fn from_options() -> Proxy {
+        Proxy::from_opt(Opt::global()).with_secrets()
+    }
+
+    /// Settings of the main listener from the flags alone.
+    pub(crate) fn from_opt(options: &Opt) -> Proxy {
+        let split = |list: &Option<String>| {
+            list.iter()
+                .flat_map(|list| list.split(','))
+                .map(|item| item.trim().to_string())
+                .collect::<Vec<String>>()
+        };
+
+        Proxy {
+            allowed_credentials: split(&options.auth),
+            allowed_hosts: split(&options.hosts),
+            secret_token: options.token.clone().unwrap_or_default(),
+            tenant: None,
+        }
+    }
+
     pub(crate) async fn proxy(
         self,
         req: Request<Body>,
         server_ip: IpAddr,
+        client_addr: SocketAddr,
+    ) -> Result<Response<Body>, hyper::Error> {
+        // Who sent the request isn't known yet, it is only shown if it can't be a
+        // --no-log user's
+        if !is_no_log(&unverified_label(client_addr)) {
+            debug!("Method: {:?}", req.method());
+            debug!("URI: {:?}", req.uri());
+            debug!("Version: {:?}", req.version());
+            debug!(
+                "Headers: {:?}",
+                redacted_headers(
+                    req.headers()
+                        .iter()
+                        .map(|(name, value)| (name.as_str(), value.to_str().unwrap_or("[binary]")))
+                )
+            );
+            debug!("Body: {:?}", req.body());
+        }
+
+        // On the main listener, a tenant's secret token selects that tenant's settings
+        if self.tenant.is_none() {
+            if let Some(tenant_proxy) = Proxy::for_token_tenant(&req) {
+                return tenant_proxy.handle(req, server_ip, client_addr).await;
+            }
+        }
+
+        let proxy = match self.tenant {
+            None => self.with_reloaded().with_secrets(),
+            Some(_) => self,
+        };
+        proxy.handle(req, server_ip, client_addr).await
+    }
+
+    /// Settings reloaded on SIGHUP replace those the listener started with.
+    fn with_reloaded(self) -> Proxy {
+        match reload::current() {
+            Some(reloaded) => Proxy {
+                tenant: self.tenant,
+                ..(*reloaded).clone()
+            },
+            None => self,
+        }
+    }
+
+    /// Secrets from a secret manager replace the flags of the main listener, and change
+    /// on refresh.
+    fn with_secrets(mut self) -> Proxy {
+        let secrets = secrets::current();
+        if let Some(credentials) = &secrets.credentials {
+            self.allowed_credentials = credentials.clone();
+        }
+        if let Some(token) = &secrets.token {
+            self.secret_token = token.clone();
+        }
+        self
+    }
+
+    fn for_token_tenant(req: &Request<Body>) -> Option<Proxy> {
+        let secret_token_header = req.headers().get("x-http-secret-token")?.to_str().ok()?;
+
+        Opt::global()
+            .tenant
+            .iter()
+            .filter(|tenant| tenant.port.is_none())
+            // An expired signed value still names its tenant, whose check refuses it
+            .find(|tenant| {
+                signed_token::verify(&tenant.secret_token, secret_token_header)
+                    != TokenHeader::Invalid
+            })
+            .map(Proxy::from_tenant)
+    }
+
+    pub(crate) fn from_tenant(tenant: &Tenant) -> Proxy {
+        Proxy {
+            allowed_credentials: tenant.allowed_credentials.clone(),
+            allowed_hosts: tenant.allowed_hosts.clone(),
+            secret_token: tenant.secret_token.clone(),
+            tenant: Some(tenant.name.clone()),
+        }
+    }
+
+    async fn handle(
+        self,
+        mut req: Request<Body>,
+        server_ip: IpAddr,
+        client_addr: SocketAddr,
     ) -> Result<Response<Body>, hyper::Error> {
-        println!("Method: {:?}", req.method());
-        println!("URI: {:?}", req.uri());
-        println!("Version: {:?}", req.version());
-        println!("Headers: {:?}", req.headers());
-        println!("Body: {:?}", req.body());
+        // Until the credentials are checked, the client is no one in particular
+        let mut unverified_client = match self.requires_auth() {
+            true => unverified_label(client_addr),
+            false => client_label(client_addr, None),
+        };
+        if let Some(tenant) = &self.tenant {
+            unverified_client.push_str(&format!(" tenant={tenant}"));
+        }
+
+        // Requests the server behind could read differently than the proxy are refused
+        let values = |name: HeaderName| {
+            req.headers()
+                .get_all(name)
+                .iter()
+                .map(|value| value.to_str().unwrap_or_default())
+                .collect::<Vec<&str>>()
+        };
+        let decision = check_framing(
+            req.version() == Version::HTTP_10,
+            req.headers().get_all(HOST).iter().count(),
+            &values(CONTENT_LENGTH),
+            &values(TRANSFER_ENCODING),
+        );
+        if !self.decide(decision, &req, &unverified_client).is_allowed() {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .header(CONNECTION, "close")
+                .body(Body::empty())
+                .unwrap());
+        }
+
+        // During maintenance, tunnels already open are kept but new requests are refused
+        if !self
+            .decide(check_maintenance(), &req, &unverified_client)
+            .is_allowed()
+        {
+            return Ok(maintenance::response());
+        }
 
         // Check request for inclusion in the white list of hosts that can be proxied
-        if let Err(response) = self.check_allowed_hosts(&req).await {
+        if let Err(response) = self.check_allowed_hosts(&req, &unverified_client).await {
             return Ok(response);
         }
 
+        if req.method() == Method::CONNECT {
+            let host = req.uri().host().unwrap_or("");
+            let port = req.uri().port_u16().unwrap_or(0);
+            if !self
+                .decide(check_connect_port(host, port), &req, &unverified_client)
+                .is_allowed()
+            {
+                return Ok(Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body(Body::empty())
+                    .unwrap());
+            }
+        }
+
+        let host_header = req
+            .headers()
+            .get(HOST)
+            .and_then(|value| value.to_str().ok());
+        let decision = check_host_header(req.uri(), req.method() == Method::CONNECT, host_header);
+        if !self.decide(decision, &req, &unverified_client).is_allowed() {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::empty())
+                .unwrap());
+        }
+
         // If secret token is not empty and no_http_token is false, check if the secret token is valid
-        if let Err(response) = self.check_secret_token(&req).await {
+        if let Err(response) = self.check_secret_token(&req, &unverified_client).await {
             return Ok(response);
         }
 
         // Process authentication if a list of login:password pairs is specified
-        if let Err(response) = self.check_credentials(&req).await {
-            return Ok(response);
+        let authentication = match self
+            .check_credentials(&req, client_addr, &unverified_client)
+            .await
+        {
+            Ok(authentication) => authentication,
+            Err(response) => return Ok(response),
+        };
+        // The session token is between the client and the proxy only
+        req.headers_mut().remove(SESSION_HEADER);
+
+        let mut client = match &authentication {
+            Authentication::Credentials(login, _) | Authentication::Session(login) => {
+                client_label(client_addr, Some(login))
+            }
+            Authentication::Anonymous => client_label(client_addr, None),
+        };
+        if let Some(tenant) = &self.tenant {
+            client.push_str(&format!(" tenant={tenant}"));
+        }
+
+        // The access log names the client as it authenticated
+        let mut response = self
+            .serve(req, server_ip, client_addr, authentication, client.clone())
+            .await?;
+        response.extensions_mut().insert(Identified(client));
+        Ok(response)
+    }
+
+    /// Serve `req` for `client`, once it has authenticated.
+    async fn serve(
+        self,
+        mut req: Request<Body>,
+        server_ip: IpAddr,
+        client_addr: SocketAddr,
+        authentication: Authentication,
+        client: String,
+    ) -> Result<Response<Body>, hyper::Error> {
+        let login = match &authentication {
+            Authentication::Credentials(login, _) | Authentication::Session(login) => Some(login),
+            Authentication::Anonymous => None,
+        };
+
+        // Accounts of --users-file can be held to their own hosts and CONNECT ports
+        let user = login
+            .filter(|_| self.tenant.is_none())
+            .and_then(|login| UserStore::global()?.get(login));
+        if let Some(user) = &user {
+            let host = req.uri().host().unwrap_or("");
+            if !self
+                .decide(check_user_host(user, host), &req, &client)
+                .is_allowed()
+            {
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::empty())
+                    .unwrap());
+            }
+            if req.method() == Method::CONNECT {
+                let port = req.uri().port_u16().unwrap_or(0);
+                if !self
+                    .decide(check_user_connect_port(user, host, port), &req, &client)
+                    .is_allowed()
+                {
+                    return Ok(Response::builder()
+                        .status(StatusCode::FORBIDDEN)
+                        .body(Body::empty())
+                        .unwrap());
+                }
+            }
+            if !self
+                .decide(check_quota(user, host), &req, &client)
+                .is_allowed()
+            {
+                return Ok(Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .header(CONTENT_TYPE, "text/plain")
+                    .body(Body::from(usage::quota_message(user)))
+                    .unwrap());
+            }
+        }
+
+        if req.method() == Method::GET && req.uri().host().is_some_and(is_probe_host) {
+            return Ok(probe::respond(&Caller {
+                addr: client_addr,
+                login: login.map(String::as_str),
+                tenant: self.tenant.as_deref(),
+                allowed_hosts: &self.allowed_hosts,
+                local_ip: Some(server_ip),
+            }));
+        }
+        if req.uri().host().is_some_and(is_echo_host) {
+            if req.method() != Method::CONNECT {
+                return Ok(probe::echo_response(Some(server_ip)));
+            }
+            tokio::task::spawn(async move {
+                match hyper::upgrade::on(req).await {
+                    Ok(upgraded) => probe::serve_echo(upgraded, Some(server_ip)).await,
+                    Err(e) => warn!("Failed to upgrade connection for {ECHO_HOST}: {e}"),
+                }
+            });
+            return Ok(Response::new(Body::empty()));
+        }
+
+        // Every user, or client address without credentials, gets its share of --rate-limit
+        if let Err(retry_after) = limiter::take_request(login.map(String::as_str), client_addr.ip())
+        {
+            let limit =
+                limiter::request_rate(login.map(String::as_str)).map(|rate| rate.requests as usize);
+            return Ok(limited_response(
+                StatusCode::TOO_MANY_REQUESTS,
+                retry_headers(limit, retry_after),
+            ));
         }
 
+        // Tenants are held to their own connection cap, so one can't starve the others,
+        // and users still warming up to the --warm-up caps on top of that
+        let limits = self.tenant.as_deref().and_then(tenant::limits);
+        let warm_up = login.and_then(|login| warmup::limits(login));
+        let mut guards = Vec::new();
+        for limits in limits.iter().chain(&warm_up) {
+            match limits.acquire_connection() {
+                Some(guard) => guards.push(guard),
+                None => {
+                    warn!(
+                        "Connection limit reached ({} active), rejecting client={client}",
+                        limits.connections()
+                    );
+                    return Ok(rate_limited(
+                        StatusCode::SERVICE_UNAVAILABLE,
+                        limits.max_connections(),
+                    ));
+                }
+            }
+        }
+
+        let tenant = self.tenant.clone();
+
+        // Besides the tenant's cap, transfers are paced by the warm-up, user and connection caps
+        let bandwidths = warm_up
+            .iter()
+            .filter_map(|limits| limits.bandwidth.clone())
+            .chain(throttle::user_bandwidth(
+                login.map(String::as_str),
+                client_addr.ip(),
+            ))
+            .chain(
+                req.extensions()
+                    .get::<ConnectionBandwidth>()
+                    .map(|bandwidth| bandwidth.0.clone()),
+            )
+            .collect();
+
+        // What authenticated clients transfer is counted, against their quota if they have one
+        if let Some(meter) = usage::meter_for(login.map(String::as_str), tenant.as_deref()) {
+            req.extensions_mut().insert(meter);
+        }
+
+        let session = match authentication {
+            Authentication::Credentials(login, source) => sessions::issue(
+                &login,
+                source,
+                client_addr.ip(),
+                tenant.as_deref(),
+                &self.allowed_credentials,
+            ),
+            _ => None,
+        };
+
         // Process method and call the appropriate handler
-        match req.method() {
-            &Method::CONNECT => self.process_connect(req, server_ip).await,
-            _ => self.process_request(req, server_ip).await,
+        let mut response = match req.method() {
+            &Method::CONNECT => {
+                self.process_connect(req, server_ip, client, limits, bandwidths, guards)
+                    .await?
+            }
+            _ => {
+                self.process_request(req, server_ip, client, limits, bandwidths, guards)
+                    .await?
+            }
+        };
+
+        // Clients that just sent valid credentials get a session to present instead next time
+        if let Some(token) = session {
+            response
+                .headers_mut()
+                .insert(SESSION_HEADER, token.parse().unwrap());
         }
+
+        Ok(response)
     }
 
-    async fn check_allowed_hosts(&self, req: &Request<Body>) -> Result<(), Response<Body>> {
+    /// Log a policy decision about `req`, scoped to the tenant if this is one.
+    fn decide(&self, decision: Decision, req: &Request<Body>, client: &str) -> Decision {
+        let decision = decision.for_tenant(self.tenant.as_deref());
+        let target = uri_target(req.uri()).unwrap_or_else(|| "-".to_string());
+        decision.log(client, &target);
+        decision
+    }
+
+    async fn check_allowed_hosts(
+        &self,
+        req: &Request<Body>,
+        client: &str,
+    ) -> Result<(), Response<Body>> {
         let host = req.uri().host().unwrap_or("");
-        if !self.allowed_hosts.is_empty() && !is_host_allowed(host, &self.allowed_hosts) {
+        if !self
+            .decide(check_host(host, &self.allowed_hosts), req, client)
+            .is_allowed()
+        {
             return Err(Response::builder()
                 .status(StatusCode::BAD_REQUEST)
                 .body(Body::empty())
@@ -74,53 +478,251 @@ impl Proxy {
         Ok(())
     }
 
-    async fn check_secret_token(&self, req: &Request<Body>) -> Result<(), Response<Body>> {
-        let options = Opt::parse();
+    async fn check_secret_token(
+        &self,
+        req: &Request<Body>,
+        client: &str,
+    ) -> Result<(), Response<Body>> {
+        let options = Opt::global();
 
-        if !self.secret_token.is_empty() && !options.no_http_token {
-            if let Some(secret_token_header) = req.headers().get("x-http-secret-token") {
-                if secret_token_header.to_str().unwrap_or_default().trim()
-                    != to_sha256(self.secret_token.trim())
-                {
-                    return Err(Response::builder()
-                        .status(StatusCode::BAD_REQUEST)
-                        .body(Body::empty())
-                        .unwrap());
-                }
-            } else if req.headers().get("x-https-secret-token").is_none() {
-                return Err(Response::builder()
-                    .status(StatusCode::BAD_REQUEST)
-                    .body(Body::empty())
-                    .unwrap());
-            }
+        let decision = check_token(
+            &self.secret_token,
+            options.no_http_token,
+            req.headers()
+                .get("x-http-secret-token")
+                .map(|value| value.to_str().unwrap_or_default()),
+            req.headers().contains_key("x-https-secret-token"),
+        );
+        if !self.decide(decision, req, client).is_allowed() {
+            return Err(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::empty())
+                .unwrap());
         }
         Ok(())
     }
 
-    async fn check_credentials(&self, req: &Request<Body>) -> Result<(), Response<Body>> {
-        if !self.allowed_credentials.is_empty() {
+    /// Whether clients have to authenticate to use this listener.
+    fn requires_auth(&self) -> bool {
+        let main = self.tenant.is_none();
+        !self.allowed_credentials.is_empty()
+            || (main && (UserStore::global().is_some() || negotiate::enabled() || auth::enabled()))
+    }
+
+    async fn check_credentials(
+        &self,
+        req: &Request<Body>,
+        client_addr: SocketAddr,
+        client: &str,
+    ) -> Result<Authentication, Response<Body>> {
+        // Users from the admin API and Kerberos principals can use the main listeners,
+        // tenants have their own
+        let user_store = UserStore::global().filter(|_| self.tenant.is_none());
+        let kerberos = negotiate::enabled() && self.tenant.is_none();
+        let providers = auth::enabled() && self.tenant.is_none();
+
+        if self.requires_auth() {
+            let session_login = req
+                .headers()
+                .get(SESSION_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|token| {
+                    sessions::resume(
+                        token,
+                        client_addr.ip(),
+                        self.tenant.as_deref(),
+                        &self.allowed_credentials,
+                    )
+                });
+            if let Some(login) = session_login {
+                self.decide(Decision::allow("auth:session"), req, client);
+                return Ok(Authentication::Session(login));
+            }
+
             if let Some(auth_header) = req.headers().get(PROXY_AUTHORIZATION) {
                 let header_credentials = auth_header.to_str().unwrap_or_default();
-                if !is_credentials_allowed(header_credentials, &self.allowed_credentials) {
-                    return Err(require_basic_auth());
+
+                if kerberos && header_credentials.trim_start().starts_with("Negotiate ") {
+                    let header_credentials = header_credentials.to_string();
+                    let result = tokio::task::spawn_blocking(move || {
+                        negotiate::authenticate(&header_credentials)
+                    })
+                    .await
+                    .unwrap_or_else(|e| Err(e.to_string()));
+
+                    return match result {
+                        Ok(login) => {
+                            self.decide(Decision::allow("auth:kerberos"), req, client);
+                            Ok(Authentication::Credentials(login, Source::Kerberos))
+                        }
+                        Err(e) => {
+                            warn!("Kerberos authentication of {client_addr} failed: {e}");
+                            self.decide(Decision::deny("auth:kerberos"), req, client);
+                            Err(failed_auth_response(client_addr.ip()))
+                        }
+                    };
                 }
+
+                if digest::enabled() {
+                    return self
+                        .check_digest(req, client_addr, client, header_credentials)
+                        .await;
+                }
+
+                // The login is the one that matched, not whatever the header claims
+                let user = user_store.and_then(|store| store.authenticate(header_credentials));
+                let (decision, verified) = if let Some(user) = user {
+                    (
+                        Decision::allow(format!("auth:users/{}", user.login)),
+                        Some((user.login, Source::Users)),
+                    )
+                } else if let Some(login) =
+                    allowed_login(header_credentials, &self.allowed_credentials)
+                {
+                    let source = Source::credentials(&login, &self.allowed_credentials);
+                    (
+                        Decision::allow(format!("auth:credentials/{login}")),
+                        source.map(|source| (login, source)),
+                    )
+                } else {
+                    let provider = match providers {
+                        true => auth::authenticate(header_credentials).await,
+                        false => None,
+                    };
+                    match provider {
+                        Some((provider, login)) => (
+                            Decision::allow(format!("auth:{}", provider.to_lowercase())),
+                            Some((login, Source::Provider(provider))),
+                        ),
+                        None => (Decision::deny("auth:default"), None),
+                    }
+                };
+                let allowed = self.decide(decision, req, client).is_allowed();
+                let Some((login, source)) = verified.filter(|_| allowed) else {
+                    record_failed_login(
+                        header_credentials,
+                        &self.allowed_credentials,
+                        client_addr.ip(),
+                    );
+                    return Err(failed_auth_response(client_addr.ip()));
+                };
+
+                return Ok(Authentication::Credentials(login, source));
             } else {
-                return Err(require_basic_auth());
+                self.decide(Decision::deny("auth:missing"), req, client);
+                return Err(require_proxy_auth());
+            }
+        }
+        self.decide(Decision::allow("auth:default"), req, client);
+        Ok(Authentication::Anonymous)
+    }
+
+    /// Check Digest credentials against the listener's `login:password` pairs. Basic
+    /// ones are refused without counting as a failed login, the client is only asked for
+    /// Digest instead.
+    async fn check_digest(
+        &self,
+        req: &Request<Body>,
+        client_addr: SocketAddr,
+        client: &str,
+        header_credentials: &str,
+    ) -> Result<Authentication, Response<Body>> {
+        if !header_credentials.trim_start().starts_with("Digest ") {
+            self.decide(Decision::deny("auth:scheme"), req, client);
+            return Err(require_proxy_auth());
+        }
+
+        // Clients sign the request target as sent, some with the path alone
+        let uri = req.uri().to_string();
+        let path = req.uri().path_and_query().map(|path| path.as_str());
+        let uris = [Some(uri.as_str()), path]
+            .into_iter()
+            .flatten()
+            .collect::<Vec<&str>>();
+
+        match digest::authenticate(
+            header_credentials,
+            req.method().as_str(),
+            &uris,
+            &self.allowed_credentials,
+        ) {
+            Ok(login) => {
+                let Some(source) = Source::credentials(&login, &self.allowed_credentials) else {
+                    return Err(failed_auth_response(client_addr.ip()));
+                };
+                self.decide(
+                    Decision::allow(format!("auth:credentials/{login}")),
+                    req,
+                    client,
+                );
+                Ok(Authentication::Credentials(login, source))
+            }
+            Err(digest::Failure::Stale) => Err(require_digest_auth(true)),
+            Err(e) => {
+                debug!("Digest authentication of {client_addr} failed: {e}");
+                self.decide(Decision::deny("auth:default"), req, client);
+                record_failed_login(
+                    header_credentials,
+                    &self.allowed_credentials,
+                    client_addr.ip(),
+                );
+                Err(failed_auth_response(client_addr.ip()))
             }
         }
-        Ok(())
     }
 
     async fn process_connect(
         self,
         req: Request<Body>,
         server_ip: IpAddr,
+        client: String,
+        limits: Option<Arc<TenantLimits>>,
+        bandwidths: Vec<Arc<Bandwidth>>,
+        guards: Vec<ConnectionGuard>,
     ) -> Result<Response<Body>, hyper::Error> {
-        tokio::task::spawn(async move {
-            let remote_addr = req.uri().authority().map(|auth| auth.to_string()).unwrap();
-            let mut upgraded = hyper::upgrade::on(req).await.unwrap();
+        let meter = req.extensions().get::<Arc<Meter>>().cloned();
+        let remote_addr = req
+            .uri()
+            .authority()
+            .map(|auth| auth.to_string())
+            .unwrap_or_default();
 
-            self.tunnel(&mut upgraded, remote_addr, server_ip).await
+        // Connect upstream before confirming the tunnel, so failures reach the client
+        let server = match connect_target(&remote_addr, Some(server_ip), &client).await {
+            Ok(server) => server,
+            Err(e) if is_queue_full(&e) => {
+                let limit = Opt::global().max_connects_per_host;
+                return Ok(rate_limited(StatusCode::SERVICE_UNAVAILABLE, Some(limit)));
+            }
+            Err(e) => {
+                if let Some(retry_after) = breaker::retry_after(&e) {
+                    return Ok(breaker::open_response(retry_after));
+                }
+                return Ok(Response::builder()
+                    .status(error_status(&e))
+                    .body(Body::empty())
+                    .unwrap());
+            }
+        };
+
+        let tenant_bandwidth = limits.and_then(|limits| limits.bandwidth.clone());
+        let server = ThrottledStream::new(server, tenant_bandwidth.into_iter().chain(bandwidths));
+        let server = MeteredStream::new(server, meter, Direction::Down);
+
+        // hyper lets go of the connection once it's upgraded, the tunnel keeps it counted
+        let slot = req.extensions().get::<Arc<ClientSlot>>().cloned();
+        tokio::task::spawn(async move {
+            let _guards = guards;
+            let _slot = slot;
+            match hyper::upgrade::on(req).await {
+                Ok(upgraded) => {
+                    tunnel::relay(upgraded, server, &[], &remote_addr, &client).await;
+                }
+                Err(e) => warn!(
+                    "Failed to upgrade connection for {}: {e}",
+                    loggable(&remote_addr, &client)
+                ),
+            }
         });
 
         Ok(Response::new(Body::empty()))
@@ -130,80 +732,371 @@ impl Proxy {
         self,
         req: Request<Body>,
         server_ip: IpAddr,
+        client: String,
+        limits: Option<Arc<TenantLimits>>,
+        bandwidths: Vec<Arc<Bandwidth>>,
+        guards: Vec<ConnectionGuard>,
     ) -> Result<Response<Body>, hyper::Error> {
-        let mut http = HttpConnector::new();
-        http.set_local_address(Some(server_ip));
+        let meter = req.extensions().get::<Arc<Meter>>().cloned();
 
-        let client = Client::builder()
-            .http1_title_case_headers(true)
-            .http1_preserve_header_case(true)
-            .build(http);
-        let res = client.request(req).await?;
+        // Traffic is paced by the tenant's bandwidth cap and the others that apply, and
+        // tenant bodies are buffered within the memory cap. The connections and the memory
+        // stay accounted until the response is sent.
+        let other_bandwidths = bandwidths.clone();
+        let bandwidths = limits
+            .iter()
+            .filter_map(|limits| limits.bandwidth.clone())
+            .chain(bandwidths)
+            .collect::<Vec<Arc<Bandwidth>>>();
 
-        Ok(res)
-    }
+        // Cacheable requests go through the parent cache first, if one is configured
+        if let Some(res) = try_parent_cache(&req).await {
+            let target = uri_target(req.uri()).unwrap_or_else(|| "-".to_string());
+            Decision::allow("route:parent-cache").log(&client, &target);
+            return Ok(pace_response(res, meter, bandwidths, guards, None));
+        }
 
-    async fn tunnel<A>(
-        self,
-        upgraded: &mut A,
-        addr_str: String,
-        server_ip: IpAddr,
-    ) -> std::io::Result<()>
-    where
-        A: AsyncRead + AsyncWrite + Unpin + ?Sized,
-    {
-        if let Ok(addrs) = addr_str.to_socket_addrs() {
-            for addr in addrs {
-                let socket = TcpSocket::new_v4()?;
-                let bind_addr = get_rand_ipv4_socket_addr(server_ip);
-
-                if socket.bind(bind_addr).is_ok() {
-                    if let Ok(mut server) = socket.connect(addr).await {
-                        tokio::io::copy_bidirectional(upgraded, &mut server).await?;
-                        return Ok(());
-                    }
+        // Resolve once and make the client connect to exactly the validated addresses
+        let Some(target) = uri_target(req.uri()) else {
+            return Ok(Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::empty())
+                .unwrap());
+        };
+        // The upstream proxy, if there is one, resolves the target itself
+        let upstream = Upstream::for_target(&target);
+        policy::route(upstream.as_ref(), wireguard_peer()).log(&client, &target);
+        let mark = fwmark_for(&target, &client);
+        let addrs = match &upstream {
+            Some(_) => Vec::new(),
+            None => match resolve_pinned(&target, &client).await {
+                Ok(addrs) => addrs,
+                Err(e) => {
+                    return Ok(Response::builder()
+                        .status(error_status(&e))
+                        .body(Body::empty())
+                        .unwrap())
                 }
+            },
+        };
+
+        let mut reservation = None;
+        let req = match &limits {
+            Some(limits) if limits.max_body_memory().is_some() => {
+                let (parts, body) = req.into_parts();
+                let body = match buffer_body(body, &parts, limits, &client).await {
+                    Ok((body, body_reservation)) => {
+                        reservation = Some(body_reservation);
+                        // The tenant's cap paced the buffering already
+                        relay_body(Body::from(body), other_bandwidths, ())
+                    }
+                    Err(response) => return Ok(response),
+                };
+                Request::from_parts(parts, body)
+            }
+            _ if !bandwidths.is_empty() => req.map(|body| relay_body(body, bandwidths.clone(), ())),
+            _ => req,
+        };
+        let req = req.map(|body| meter_body(body, meter.clone(), Direction::Up));
+
+        let mut builder = Client::builder();
+        builder
+            .http1_title_case_headers(true)
+            .http1_preserve_header_case(true);
+        let res = match (upstream, mark) {
+            (Some(upstream), mark) => {
+                builder
+                    .build(LimitedConnector::new(UpstreamConnector::new(
+                        upstream,
+                        mark,
+                        client.clone(),
+                    )))
+                    .request(req)
+                    .await
+            }
+            #[cfg(feature = "wireguard")]
+            (None, _) if wireguard_peer().is_some() => {
+                builder
+                    .build(LimitedConnector::new(WireGuardConnector::new(addrs)))
+                    .request(req)
+                    .await
+            }
+            // Bound to the listener's address, `HttpConnector` would skip every destination
+            // address of the other family, and it can't draw sources from the IPv6 prefix
+            (None, mark)
+                if mark.is_some()
+                    || egress::enabled()
+                    || mss::enabled()
+                    || fastopen::enabled()
+                    || server_ip.is_ipv6()
+                    || addrs.iter().any(SocketAddr::is_ipv6) =>
+            {
+                let marked = MarkedConnector::new(addrs, Some(server_ip), mark);
+                builder
+                    .build(LimitedConnector::new(marked))
+                    .request(req)
+                    .await
             }
+            (None, _) => {
+                let mut http = pinned_connector(addrs);
+                http.set_local_address(Some(server_ip));
+                builder
+                    .build(LimitedConnector::new(http))
+                    .request(req)
+                    .await
+            }
+        };
+        let res = match res {
+            Ok(res) => res,
+            Err(e) if is_queue_full_error(&e) => {
+                let limit = Opt::global().max_connects_per_host;
+                return Ok(rate_limited(StatusCode::SERVICE_UNAVAILABLE, Some(limit)));
+            }
+            Err(e) => {
+                warn!(
+                    "Failed to forward request to {}: {e}",
+                    loggable(&target, &client)
+                );
+                return Ok(match breaker::retry_after(&e) {
+                    Some(retry_after) => breaker::open_response(retry_after),
+                    None => Response::builder()
+                        .status(error_status(&e))
+                        .body(Body::empty())
+                        .unwrap(),
+                });
+            }
+        };
+
+        Ok(pace_response(res, meter, bandwidths, guards, reservation))
+    }
+}
+
+/// `res` counted on the client's meter and paced by `bandwidths`, keeping `guards` and
+/// `reservation` until its body is sent.
+fn pace_response(
+    res: Response<Body>,
+    meter: Option<Arc<Meter>>,
+    bandwidths: Vec<Arc<Bandwidth>>,
+    guards: Vec<ConnectionGuard>,
+    reservation: Option<MemoryReservation>,
+) -> Response<Body> {
+    let res = res.map(|body| meter_body(body, meter, Direction::Down));
+    match guards.is_empty() && reservation.is_none() && bandwidths.is_empty() {
+        true => res,
+        false => res.map(|body| relay_body(body, bandwidths, (guards, reservation))),
+    }
+}
+
+/// 407 for credentials that didn't check out. A client this gets banned loses the
+/// connection, so it can't go on guessing over it.
+fn failed_auth_response(ip: IpAddr) -> Response<Body> {
+    let mut response = require_proxy_auth();
+    if bans::record_failure(ip) {
+        response
+            .headers_mut()
+            .insert(CONNECTION, HeaderValue::from_static("close"));
+    }
+    response
+}
+
+/// 407 offering every scheme the proxy accepts.
+fn require_proxy_auth() -> Response<Body> {
+    let mut response = match digest::enabled() {
+        true => require_digest_auth(false),
+        false => require_basic_auth(),
+    };
+    if negotiate::enabled() {
+        response
+            .headers_mut()
+            .append(PROXY_AUTHENTICATE, "Negotiate".parse().unwrap());
+    }
+    response
+}
+
+/// 407 with Digest challenges, `stale` for right credentials that answered a nonce past
+/// its lifetime, which clients retry with the fresh one without asking the user.
+fn require_digest_auth(stale: bool) -> Response<Body> {
+    let mut response = Response::builder()
+        .status(StatusCode::PROXY_AUTHENTICATION_REQUIRED)
+        .body(Body::empty())
+        .unwrap();
+    for challenge in digest::challenges(stale) {
+        response
+            .headers_mut()
+            .append(PROXY_AUTHENTICATE, challenge.parse().unwrap());
+    }
+    response
+}
+
+/// Read a tenant's request body into memory reserved from its buffer cap.
+/// A body that could never fit is refused with 413, one that doesn't fit right now
+/// because of the tenant's other requests with 503.
+async fn buffer_body(
+    mut body: Body,
+    parts: &hyper::http::request::Parts,
+    limits: &Arc<TenantLimits>,
+    client: &str,
+) -> Result<(Bytes, MemoryReservation), Response<Body>> {
+    let max_body_memory = limits.max_body_memory().unwrap_or(usize::MAX);
+    let reject = |size: usize| {
+        let status = if size > max_body_memory {
+            StatusCode::PAYLOAD_TOO_LARGE
         } else {
-            println!("error: {addr_str}");
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        warn!("Body buffer limit reached ({size} bytes), rejecting client={client}");
+        match status {
+            StatusCode::SERVICE_UNAVAILABLE => rate_limited(status, None),
+            _ => Response::builder()
+                .status(status)
+                .body(Body::empty())
+                .unwrap(),
         }
+    };
 
-        Ok(())
+    let content_length = parts
+        .headers
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<usize>().ok())
+        .unwrap_or(0);
+    let mut reservation = limits
+        .reserve_body_memory(content_length)
+        .ok_or_else(|| reject(content_length))?;
+
+    let mut reserved = content_length;
+    let mut buffer = Vec::with_capacity(content_length);
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|_| {
+            Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(Body::empty())
+                .unwrap()
+        })?;
+
+        // Content-Length is only a hint, keep reserving if the body turns out larger
+        let size = buffer.len() + chunk.len();
+        if size > reserved {
+            if !reservation.grow(size - reserved) {
+                return Err(reject(size));
+            }
+            reserved = size;
+        }
+        if let Some(bandwidth) = &limits.bandwidth {
+            bandwidth.consume(chunk.len()).await;
+        }
+        buffer.extend_from_slice(&chunk);
+    }
+
+    Ok((Bytes::from(buffer), reservation))
+}
+
+/// Access log entry for `req`, its body counted from the Content-Length. The client is
+/// named once it has authenticated, by the `Identified` of the response.
+fn request_access(req: &Request<Body>, client_addr: SocketAddr) -> Access {
+    let client = unverified_label(client_addr);
+    let target = uri_target(req.uri()).unwrap_or_else(|| "-".to_string());
+    Access {
+        bytes_up: req.body().size_hint().exact().unwrap_or_default(),
+        ..Access::new(&client, req.method().as_str(), &target)
     }
 }
 
-pub async fn start_proxy(
-    listen_addr: SocketAddr,
-    allowed_credentials: Vec<String>,
-    allowed_hosts: Vec<String>,
-    secret_token: String,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let proxy = Proxy {
-        allowed_credentials,
-        allowed_hosts,
-        secret_token,
+pub async fn start_proxy(listen: Listen, proxy: Proxy) -> Result<(), Box<dyn std::error::Error>> {
+    let server_name = match &proxy.tenant {
+        Some(tenant) => format!("HTTP server: {tenant}"),
+        None => "HTTP server".to_string(),
     };
 
+    let server_ip = listen.local_ip();
+    let listener = listener::bind(listen, &server_name).await?;
+    if proxy.tenant.is_none() {
+        selftest::listening();
+    }
+
     let make_service = make_service_fn(move |addr: &AddrStream| {
-        let server_ip = listen_addr.ip();
+        let client_addr = addr.remote_addr();
+        let admitted = policy::admit_client(client_addr);
         let proxy_clone = proxy.clone();
         let time = formatted_time();
 
-        println!(
-            "\n\x1b[1m[{time}] [HTTP server] New connection from: {}\x1b[0m",
+        info!(
+            "\n\x1b[1m[{time}] [{server_name}] New connection from: {}\x1b[0m",
             addr.remote_addr()
         );
 
+        let requests = Arc::new(AtomicUsize::new(0));
+        // Counted as open until hyper drops the service along with the connection
+        let open = Arc::new(stats::OpenConnection::open());
+        // Over a connection limit, requests get a 503 and the connection is closed
+        let slot = match admitted {
+            true => limiter::acquire_client(client_addr),
+            false => None,
+        };
+        let limited = slot.is_none();
+        let slot = slot.map(Arc::new);
+        let bandwidth = throttle::connection_bandwidth();
+
         async move {
-            Ok::<_, hyper::Error>(service_fn(move |req| {
-                proxy_clone.clone().proxy(req, server_ip)
+            // hyper closes the connection when it gets no service for it
+            if !admitted {
+                return Err(io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("client {client_addr} refused"),
+                ));
+            }
+
+            Ok::<_, io::Error>(service_fn(move |mut req: Request<Body>| {
+                let _open = &open;
+                if let Some(slot) = &slot {
+                    req.extensions_mut().insert(slot.clone());
+                }
+                if let Some(bandwidth) = &bandwidth {
+                    req.extensions_mut().insert(bandwidth.clone());
+                }
+                stats::HTTP_REQUESTS.fetch_add(1, Ordering::Relaxed);
+                let served = requests.fetch_add(1, Ordering::Relaxed) + 1;
+                let is_connect = req.method() == Method::CONNECT;
+                let access = access::enabled().then(|| request_access(&req, client_addr));
+                let response = proxy_clone.clone().proxy(req, server_ip, client_addr);
+
+                async move {
+                    let mut response = match limited {
+                        true => rate_limited(StatusCode::SERVICE_UNAVAILABLE, None),
+                        false => response.await?,
+                    };
+
+                    // Established tunnels are logged when they close
+                    if let Some(mut access) = access {
+                        if let Some(Identified(client)) = response.extensions().get() {
+                            access.client = client.clone();
+                        }
+                        if !(is_connect && response.status().is_success()) {
+                            response = access.log_response(response);
+                        }
+                    }
+
+                    // A tunnel ends the connection anyway, other requests make it close after the
+                    // response once the connection has used up its requests
+                    let max_requests = Opt::global().max_requests_per_connection;
+                    if limited || (max_requests > 0 && served >= max_requests && !is_connect) {
+                        response
+                            .headers_mut()
+                            .insert(CONNECTION, HeaderValue::from_static("close"));
+                    }
+                    Ok::<_, hyper::Error>(response)
+                }
             }))
         }
     });
 
-    Server::bind(&listen_addr)
+    Server::from_tcp(listener)?
         .http1_preserve_header_case(true)
         .http1_title_case_headers(true)
+        // Clients may shut down their side once the request is sent and wait for the response
+        .http1_half_close(true)
+        .tcp_keepalive(listener::client_keepalive())
+        .tcp_keepalive_interval(listener::client_keepalive())
         .serve(make_service)
         .await
         .map_err(Into::into)
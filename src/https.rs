@@ -1,49 +1,75 @@
+use crate::access::{self, Access};
+use crate::acme;
+use crate::alerts::record_failed_login;
+use crate::auth;
+use crate::bans;
+use crate::breaker;
+use crate::credentials::allowed_login;
+use crate::dns::{pinned_connector, resolve_pinned, split_host_port, uri_target};
+use crate::egress;
+use crate::fastopen;
+use crate::head::{parse_request, read_head, MAX_HEAD};
+use crate::limiter::{self, is_queue_full, is_queue_full_error, LimitedConnector};
+use crate::listener::{self, Listen};
+use crate::maintenance;
+use crate::mss;
+use crate::mux;
+use crate::ocsp;
 use crate::options::Opt;
+use crate::outbound::{connect_target, error_status, fwmark_for, wireguard_peer, MarkedConnector};
+use crate::policy::{
+    self, check_connect_port, check_framing, check_host, check_host_header, check_maintenance,
+    check_quota, check_token, check_user_connect_port, check_user_host, Decision,
+};
+use crate::probe::{self, is_echo_host, is_probe_host, Caller};
+use crate::reload;
+use crate::secrets::{self, CertResolver};
+use crate::sessions::{self, Source, SESSION_HEADER};
+use crate::stats;
+use crate::throttle::{self, ThrottledStream};
+use crate::tickets;
+use crate::tunnel;
+use crate::upstream::{try_parent_cache, Upstream, UpstreamConnector};
+use crate::usage::{self, Direction, MeteredStream};
+use crate::users::{User, UserStore};
 use crate::utils::{
-    create_basic_auth_response, formatted_time, is_credentials_allowed, is_host_allowed, to_sha256,
+    client_label, create_basic_auth_response, formatted_time, is_no_log, loggable,
+    rate_limit_headers, redacted_headers, retry_headers, unverified_label,
 };
+use crate::warmup;
+#[cfg(feature = "wireguard")]
+use crate::wireguard::WireGuardConnector;
 
 use std::collections::HashMap;
 use std::fs::File;
-use std::io::{BufReader, Error as IoError, ErrorKind};
+use std::io::{BufRead, BufReader, Error as IoError, ErrorKind};
 use std::net::SocketAddr;
-use std::net::ToSocketAddrs;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
 
+use hyper::body::{Bytes, HttpBody};
 use hyper::header::{HeaderName, HeaderValue};
 use hyper::http::HeaderMap;
-use hyper::{Body, StatusCode};
+use hyper::{Body, StatusCode, Uri};
 use hyper::{Client, Request as HttpRequest};
 use hyper_tls::HttpsConnector;
+use log::{debug, info, warn};
 
+use rustls::server::ResolvesServerCert;
 use rustls::{Certificate, PrivateKey, ServerConfig};
 use rustls_pemfile::read_one;
 
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
-use tokio::net::{TcpListener, TcpSocket, TcpStream};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt,
+};
+use tokio::net::TcpListener;
 use tokio_rustls::TlsAcceptor;
 
-use clap::Parser;
-
-async fn tunnel_to_remote<A>(upgraded: &mut A, addr: String) -> std::io::Result<()>
-where
-    A: AsyncRead + AsyncWrite + Unpin + ?Sized,
-{
-    if let Ok(addrs) = addr.to_socket_addrs() {
-        for addr in addrs {
-            let socket = TcpSocket::new_v4()?;
-            if let Ok(mut server) = socket.connect(addr).await {
-                tokio::io::copy_bidirectional(upgraded, &mut server).await?;
-                return Ok(());
-            }
-        }
-    }
-    eprintln!("Failed to connect to {addr}");
-    Ok(())
+pub fn load_certs(filename: &str) -> std::io::Result<Vec<Certificate>> {
+    read_certs(&mut BufReader::new(File::open(filename)?))
 }
 
-fn load_certs(filename: &str) -> std::io::Result<Vec<Certificate>> {
-    let cert_file = &mut BufReader::new(File::open(filename)?);
+pub fn read_certs(cert_file: &mut dyn BufRead) -> std::io::Result<Vec<Certificate>> {
     let certs: Vec<Certificate> = rustls_pemfile::certs(cert_file)
         .filter_map(|item| item.ok())
         .map(|cert| Certificate(cert.to_vec()))
@@ -58,8 +84,11 @@ fn load_certs(filename: &str) -> std::io::Result<Vec<Certificate>> {
     Ok(certs)
 }
 
-fn load_private_key(filename: &str) -> std::io::Result<PrivateKey> {
-    let key_file = &mut BufReader::new(File::open(filename)?);
+pub fn load_private_key(filename: &str) -> std::io::Result<PrivateKey> {
+    read_private_key(&mut BufReader::new(File::open(filename)?))
+}
+
+pub fn read_private_key(key_file: &mut dyn BufRead) -> std::io::Result<PrivateKey> {
     let mut keys: Vec<PrivateKey> = Vec::new();
 
     while let Ok(Some(item)) = read_one(key_file) {
@@ -97,163 +126,631 @@ fn create_server_config(certs: Vec<Certificate>, key: PrivateKey) -> Result<Serv
     Ok(config)
 }
 
+/// Server config taking the certificate from `resolver`, with OCSP responses stapled
+/// with `--ocsp-stapling`.
+fn resolving_server_config(resolver: Arc<dyn ResolvesServerCert>) -> ServerConfig {
+    let resolver: Arc<dyn ResolvesServerCert> = match ocsp::enabled() {
+        true => Arc::new(ocsp::Stapler { inner: resolver }),
+        false => resolver,
+    };
+    ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_cert_resolver(resolver)
+}
+
 pub async fn start_proxy(
-    listen_addr: SocketAddr,
+    listen: Listen,
     allowed_credentials: Vec<String>,
     allowed_hosts: Vec<String>,
     secret_token: String,
-    cert_file_path: String,
-    key_file_path: String,
+    cert_file_path: Option<String>,
+    key_file_path: Option<String>,
 ) -> Result<(), Box<dyn std::error::Error>> {
-    let certs = load_certs(&cert_file_path)?;
-    let key = load_private_key(&key_file_path)?;
+    let certs = cert_file_path.as_deref().map(load_certs).transpose()?;
+    let key = key_file_path.as_deref().map(load_private_key).transpose()?;
+
+    let config = if acme::enabled() {
+        // Renewed ACME certificates are picked up by new connections
+        resolving_server_config(Arc::new(acme::CertResolver))
+    } else if secrets::enabled() {
+        // Certificates from the secret manager are picked up again after a refresh
+        let fallback = match (certs, key) {
+            (Some(certs), Some(key)) => Some(secrets::certified_key(certs, key)?),
+            _ => None,
+        };
+        if fallback.is_none() && secrets::current().certified_key.is_none() {
+            return Err("no TLS certificate in the secrets and no --cert/--pkey".into());
+        }
+        if let Some(fallback) = fallback.clone().filter(|_| ocsp::enabled()) {
+            ocsp::watch(fallback);
+        }
+        resolving_server_config(Arc::new(CertResolver { fallback }))
+    } else {
+        let (Some(certs), Some(key)) = (certs, key) else {
+            return Err("--cert and --pkey are required".into());
+        };
+        if ocsp::enabled() {
+            let key = secrets::certified_key(certs, key)?;
+            ocsp::watch(key.clone());
+            resolving_server_config(Arc::new(ocsp::SingleCert(key)))
+        } else {
+            create_server_config(certs, key)?
+        }
+    };
 
-    let config = create_server_config(certs, key)?;
+    let mut config = config;
+    if let Some(ticketer) = tickets::ticketer()? {
+        config.ticketer = ticketer;
+    }
+    config.alpn_protocols = mux::alpn_protocols();
 
     let acceptor = TlsAcceptor::from(Arc::new(config));
-    let listener = TcpListener::bind(listen_addr).await?;
+    let listener = TcpListener::from_std(listener::bind(listen, "HTTPS server").await?)?;
 
     loop {
         let (stream, addr) = listener.accept().await?;
+        if !policy::admit_client(addr) {
+            continue;
+        }
+        // Over a connection limit the client is closed on, a TLS handshake would cost what
+        // the limit saves
+        let Some(slot) = limiter::acquire_client(addr) else {
+            continue;
+        };
         let acceptor = acceptor.clone();
+        if let Err(e) = listener::set_client_keepalive(&stream) {
+            warn!("Failed to enable keepalives for {addr}: {e}");
+        }
 
-        let allowed_credentials = allowed_credentials.clone();
+        // Settings reloaded on SIGHUP replace the flags, and secrets from a secret manager
+        // replace both, changing on refresh
+        let reloaded = reload::current();
+        let (allowed_credentials, allowed_hosts, secret_token) = match &reloaded {
+            Some(reloaded) => (
+                &reloaded.allowed_credentials,
+                &reloaded.allowed_hosts,
+                &reloaded.secret_token,
+            ),
+            None => (&allowed_credentials, &allowed_hosts, &secret_token),
+        };
+        let secrets = secrets::current();
+        let allowed_credentials = secrets
+            .credentials
+            .clone()
+            .unwrap_or_else(|| allowed_credentials.clone());
         let allowed_hosts = allowed_hosts.clone();
-        let secret_token = secret_token.clone();
+        let secret_token = secrets
+            .token
+            .clone()
+            .unwrap_or_else(|| secret_token.clone());
 
         tokio::spawn(async move {
-            let mut stream = match acceptor.accept(stream).await {
+            let _open = stats::OpenConnection::open();
+            let _slot = slot;
+            let stream = match acceptor.accept(stream).await {
                 Ok(s) => s,
                 Err(_) => return, // Обработка ошибок TLS
             };
 
-            let mut buffer = vec![0; 1024];
-            match stream.read(&mut buffer).await {
-                Ok(n) => {
-                    let request = String::from_utf8_lossy(&buffer[..n]);
+            // A multiplexing client sends each of its requests on a stream of its own
+            if mux::negotiated(stream.get_ref().1) {
+                mux::serve(stream, addr, move |stream| {
+                    // Each stream counts like a connection of its own, a stream over the
+                    // limit is reset
+                    let slot = limiter::acquire_client(addr);
+                    let (allowed_credentials, allowed_hosts, secret_token) = (
+                        allowed_credentials.clone(),
+                        allowed_hosts.clone(),
+                        secret_token.clone(),
+                    );
+                    async move {
+                        let Some(_slot) = slot else {
+                            return;
+                        };
+                        let _open = stats::OpenConnection::open();
+                        serve_client(
+                            stream,
+                            addr,
+                            allowed_credentials,
+                            allowed_hosts,
+                            secret_token,
+                        )
+                        .await;
+                    }
+                })
+                .await;
+                return;
+            }
+            serve_client(
+                stream,
+                addr,
+                allowed_credentials,
+                allowed_hosts,
+                secret_token,
+            )
+            .await;
+        });
+    }
+    // Ok(())
+}
+
+/// Serve the request a client sends over `stream`, a TLS connection or one of the
+/// streams of a multiplexed one.
+async fn serve_client<S>(
+    mut stream: S,
+    addr: SocketAddr,
+    allowed_credentials: Vec<String>,
+    allowed_hosts: Vec<String>,
+    secret_token: String,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut buffer = Vec::new();
+    match read_head(&mut stream, &mut buffer).await {
+        Ok(head_len) => {
+            stats::HTTPS_REQUESTS.fetch_add(1, Ordering::Relaxed);
+            // A head that doesn't end can't be told apart from what follows it
+            let n = buffer.len();
+            let Some(head_len) = head_len else {
+                if n >= MAX_HEAD {
+                    let error_response =
+                        create_error_response(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE);
+                    if let Err(e) = stream.write_all(&error_response).await {
+                        warn!("Failed to write error response to client: {:?}", e);
+                    }
+                }
+                return;
+            };
+            // A client may send tunnel data right behind the CONNECT head, and even
+            // half-close, without waiting for the response
+            let request = String::from_utf8_lossy(&buffer[..head_len]);
+            let early_data = &buffer[head_len..n];
 
-                    let options = Opt::parse();
+            let options = Opt::global();
 
-                    match parse_request(&request) {
-                        Ok((method, uri, version, headers)) => {
-                            let time = formatted_time();
+            let mut new_session = None;
+            let mut access = None;
+            let warm_up;
+            let user_bandwidth;
+            let meter;
+            let client;
+            match parse_request(&request) {
+                Ok((method, uri, version, headers)) => {
+                    let time = formatted_time();
 
-                            println!("\n\x1b[38;5;28m\x1b[1m[{time}] [HTTPS server] New connection from: {}\x1b[0m", addr);
+                    info!("\n\x1b[38;5;28m\x1b[1m[{time}] [HTTPS server] New connection from: {}\x1b[0m", addr);
 
-                            println!("Method: {}", method);
-                            println!("URI: {}", uri);
-                            println!("Version: {}", version);
-                            println!("Headers: {:?}", headers);
+                    // Until the credentials are checked, the client is no one in particular
+                    let requires_auth = !allowed_credentials.is_empty()
+                        || UserStore::global().is_some()
+                        || auth::enabled();
+                    let unverified_client = match requires_auth {
+                        true => unverified_label(addr),
+                        false => client_label(addr, None),
+                    };
 
-                            // Check request for inclusion in the white list of hosts that can be proxied
-                            // let host = headers.get("host").unwrap().split(':').next().unwrap_or("");
-                            let host = headers
-                                .get("host")
-                                .and_then(|h| h.split(':').next())
-                                .unwrap_or("");
-                            if !allowed_hosts.is_empty() && !is_host_allowed(host, &allowed_hosts) {
-                                let error_response = create_error_response(StatusCode::BAD_REQUEST);
-                                if let Err(e) = stream.write_all(&error_response).await {
-                                    eprintln!("Failed to write error response to client: {:?}", e);
-                                }
-                                return;
+                    if !is_no_log(&unverified_client) {
+                        debug!("Method: {}", method);
+                        debug!("URI: {}", uri);
+                        debug!("Version: {}", version);
+                        debug!(
+                            "Headers: {:?}",
+                            redacted_headers(
+                                headers
+                                    .iter()
+                                    .map(|(name, value)| (name.as_str(), value.as_str()))
+                            )
+                        );
+                    }
+
+                    // Check request for inclusion in the white list of hosts that can be proxied
+                    // let host = headers.get("host").unwrap().split(':').next().unwrap_or("");
+                    let host = headers
+                        .get("host")
+                        .and_then(|h| h.split(':').next())
+                        .unwrap_or("");
+                    let target = match method.as_str() {
+                        "CONNECT" => uri.clone(),
+                        _ => headers.get("host").cloned().unwrap_or_default(),
+                    };
+                    if access::enabled() {
+                        access = Some(Access::new(&unverified_client, &method, &target));
+                    }
+
+                    // Requests the server behind could read differently than the proxy
+                    // are refused. Repeated headers were joined, which keeps the values
+                    let values = |name: &str| headers.get(name).map(String::as_str);
+                    let decision = check_framing(
+                        version == "HTTP/1.0",
+                        // Hosts have no commas, so joined ones are told apart
+                        headers
+                            .get("host")
+                            .map_or(0, |host| host.split(',').count()),
+                        Vec::from_iter(values("content-length")).as_slice(),
+                        Vec::from_iter(values("transfer-encoding")).as_slice(),
+                    );
+                    decision.log(&unverified_client, &target);
+                    if !decision.is_allowed() {
+                        let error_response = create_error_response(StatusCode::BAD_REQUEST);
+                        if let Err(e) = stream.write_all(&error_response).await {
+                            warn!("Failed to write error response to client: {:?}", e);
+                        }
+                        log_answer(access, &error_response);
+                        return;
+                    }
+
+                    // During maintenance, tunnels already open are kept but new
+                    // requests are refused
+                    let decision = check_maintenance();
+                    decision.log(&unverified_client, &target);
+                    if !decision.is_allowed() {
+                        let response = maintenance::raw_response();
+                        if let Err(e) = stream.write_all(&response).await {
+                            warn!("Failed to write error response to client: {:?}", e);
+                        }
+                        log_answer(access, &response);
+                        return;
+                    }
+
+                    let decision = check_host(host, &allowed_hosts);
+                    decision.log(&unverified_client, &target);
+                    if !decision.is_allowed() {
+                        let error_response = create_error_response(StatusCode::BAD_REQUEST);
+                        if let Err(e) = stream.write_all(&error_response).await {
+                            warn!("Failed to write error response to client: {:?}", e);
+                        }
+                        log_answer(access, &error_response);
+                        return;
+                    }
+
+                    if method == "CONNECT" {
+                        let port = split_host_port(&target).map_or(0, |(_, port)| port);
+                        let decision = check_connect_port(host, port);
+                        decision.log(&unverified_client, &target);
+                        if !decision.is_allowed() {
+                            let error_response = create_error_response(StatusCode::FORBIDDEN);
+                            if let Err(e) = stream.write_all(&error_response).await {
+                                warn!("Failed to write error response to client: {:?}", e);
                             }
+                            log_answer(access, &error_response);
+                            return;
+                        }
+                    }
 
-                            // If secret token is not empty and no_http_token is false, check if the secret token is valid
-                            if !secret_token.is_empty() && !options.no_https_token {
-                                if let Some(secret_token_header) =
-                                    headers.get("x-https-secret-token")
-                                {
-                                    if secret_token_header.trim() != to_sha256(secret_token.trim())
-                                    {
-                                        let error_response =
-                                            create_error_response(StatusCode::BAD_REQUEST);
-
-                                        if let Err(e) = stream.write_all(&error_response).await {
-                                            eprintln!(
-                                                "Failed to write error response to client: {:?}",
-                                                e
-                                            );
-                                        }
-                                        return;
-                                    }
-                                } else if !headers.contains_key("x-http-secret-token") {
-                                    let error_response =
-                                        create_error_response(StatusCode::BAD_REQUEST);
-
-                                    if let Err(e) = stream.write_all(&error_response).await {
-                                        eprintln!(
-                                            "Failed to write error response to client: {:?}",
-                                            e
-                                        );
-                                    }
-                                    return;
+                    if let Ok(parsed) = uri.parse::<Uri>() {
+                        let decision = check_host_header(
+                            &parsed,
+                            method == "CONNECT",
+                            headers.get("host").map(String::as_str),
+                        );
+                        decision.log(&unverified_client, &target);
+                        if !decision.is_allowed() {
+                            let error_response = create_error_response(StatusCode::BAD_REQUEST);
+                            if let Err(e) = stream.write_all(&error_response).await {
+                                warn!("Failed to write error response to client: {:?}", e);
+                            }
+                            log_answer(access, &error_response);
+                            return;
+                        }
+                    }
+
+                    // If secret token is not empty and no_http_token is false, check if the secret token is valid
+                    let decision = check_token(
+                        &secret_token,
+                        options.no_https_token,
+                        headers.get("x-https-secret-token").map(String::as_str),
+                        headers.contains_key("x-http-secret-token"),
+                    );
+                    decision.log(&unverified_client, &target);
+                    if !decision.is_allowed() {
+                        let error_response = create_error_response(StatusCode::BAD_REQUEST);
+
+                        if let Err(e) = stream.write_all(&error_response).await {
+                            warn!("Failed to write error response to client: {:?}", e);
+                        }
+                        log_answer(access, &error_response);
+                        return;
+                    }
+
+                    // Process authentication if a list of login:password pairs is specified
+                    let user_store = UserStore::global();
+                    let session_login = headers.get(SESSION_HEADER).and_then(|token| {
+                        sessions::resume(token, addr.ip(), None, &allowed_credentials)
+                    });
+                    let mut verified_login = session_login.clone();
+                    if session_login.is_some() {
+                        Decision::allow("auth:session").log(&unverified_client, &target);
+                    } else if requires_auth {
+                        if let Some(header_credentials) = headers.get("proxy-authorization") {
+                            // The login is the one that matched, not whatever the
+                            // header claims
+                            let user =
+                                user_store.and_then(|store| store.authenticate(header_credentials));
+                            let (decision, verified) = if let Some(user) = user {
+                                (
+                                    Decision::allow(format!("auth:users/{}", user.login)),
+                                    Some((user.login, Source::Users)),
+                                )
+                            } else if let Some(login) =
+                                allowed_login(header_credentials, &allowed_credentials)
+                            {
+                                let source = Source::credentials(&login, &allowed_credentials);
+                                (
+                                    Decision::allow(format!("auth:credentials/{login}")),
+                                    source.map(|source| (login, source)),
+                                )
+                            } else {
+                                match auth::authenticate(header_credentials).await {
+                                    Some((provider, login)) => (
+                                        Decision::allow(format!(
+                                            "auth:{}",
+                                            provider.to_lowercase()
+                                        )),
+                                        Some((login, Source::Provider(provider))),
+                                    ),
+                                    None => (Decision::deny("auth:default"), None),
                                 }
+                            };
+                            decision.log(&unverified_client, &target);
+                            let Some((login, source)) = verified else {
+                                record_failed_login(
+                                    header_credentials,
+                                    &allowed_credentials,
+                                    addr.ip(),
+                                );
+                                bans::record_failure(addr.ip());
+                                let auth_response = create_basic_auth_response();
+                                if let Err(e) = stream.write_all(&auth_response).await {
+                                    warn!(
+                                        "Failed to write authentication response to client: {:?}",
+                                        e
+                                    );
+                                }
+                                log_answer(access, &auth_response);
+                                return;
+                            };
+
+                            // Offer a session to present instead of credentials next time
+                            new_session = sessions::issue(
+                                &login,
+                                source,
+                                addr.ip(),
+                                None,
+                                &allowed_credentials,
+                            );
+                            verified_login = Some(login);
+                        } else {
+                            Decision::deny("auth:missing").log(&unverified_client, &target);
+                            let auth_response = create_basic_auth_response();
+                            if let Err(e) = stream.write_all(&auth_response).await {
+                                warn!("Failed to write authentication response to client: {:?}", e);
                             }
+                            log_answer(access, &auth_response);
+                            return;
+                        }
+                    } else {
+                        Decision::allow("auth:default").log(&unverified_client, &target);
+                    }
+                    warm_up = verified_login.as_deref().and_then(warmup::limits);
+                    user_bandwidth = throttle::user_bandwidth(verified_login.as_deref(), addr.ip());
+                    meter = usage::meter_for(verified_login.as_deref(), None);
+                    client = client_label(addr, verified_login.as_deref());
+                    if let Some(access) = &mut access {
+                        access.client = client.clone();
+                    }
 
-                            // Process authentication if a list of login:password pairs is specified
-                            if !allowed_credentials.is_empty() {
-                                if let Some(header_credentials) = headers.get("proxy-authorization")
-                                {
-                                    if !is_credentials_allowed(
-                                        header_credentials,
-                                        &allowed_credentials,
-                                    ) {
-                                        let auth_response = create_basic_auth_response();
-                                        if let Err(e) = stream.write_all(&auth_response).await {
-                                            eprintln!("Failed to write authentication response to client: {:?}", e);
-                                        }
-                                        return;
-                                    }
-                                } else {
-                                    let auth_response = create_basic_auth_response();
-                                    if let Err(e) = stream.write_all(&auth_response).await {
-                                        eprintln!("Failed to write authentication response to client: {:?}", e);
-                                    }
-                                    return;
+                    // Accounts of --users-file can be held to their own hosts and
+                    // CONNECT ports
+                    let user = verified_login
+                        .as_deref()
+                        .and_then(|login| user_store?.get(login));
+                    if let Some(user) = &user {
+                        let decision = check_user_host(user, host);
+                        decision.log(&client, &target);
+                        if !decision.is_allowed() {
+                            let error_response = create_error_response(StatusCode::BAD_REQUEST);
+                            if let Err(e) = stream.write_all(&error_response).await {
+                                warn!("Failed to write error response to client: {:?}", e);
+                            }
+                            log_answer(access, &error_response);
+                            return;
+                        }
+                        if method == "CONNECT" {
+                            let port = split_host_port(&target).map_or(0, |(_, port)| port);
+                            let decision = check_user_connect_port(user, host, port);
+                            decision.log(&client, &target);
+                            if !decision.is_allowed() {
+                                let error_response = create_error_response(StatusCode::FORBIDDEN);
+                                if let Err(e) = stream.write_all(&error_response).await {
+                                    warn!("Failed to write error response to client: {:?}", e);
                                 }
+                                log_answer(access, &error_response);
+                                return;
                             }
                         }
-                        Err(err) => {
-                            println!("Error parsing request: {}", err);
+                        let decision = check_quota(user, host);
+                        decision.log(&client, &target);
+                        if !decision.is_allowed() {
+                            let response = create_quota_response(user);
+                            if let Err(e) = stream.write_all(&response).await {
+                                warn!("Failed to write error response to client: {:?}", e);
+                            }
+                            log_answer(access, &response);
+                            return;
                         }
                     }
 
-                    // Process request method and call the appropriate handler
-                    if request.starts_with("CONNECT") {
-                        // Process CONNECT request
-                        let parts: Vec<&str> = request.split_whitespace().collect();
-                        if parts.len() >= 2 {
-                            let remote_addr = parts[1].to_string();
+                    // Every user, or client address without credentials, gets its
+                    // share of --rate-limit, or its own rate limit
+                    if let Err(retry_after) =
+                        limiter::take_request(verified_login.as_deref(), addr.ip())
+                    {
+                        let limit = limiter::request_rate(verified_login.as_deref())
+                            .map(|rate| rate.requests as usize);
+                        let response = create_limited_response(
+                            StatusCode::TOO_MANY_REQUESTS,
+                            retry_headers(limit, retry_after),
+                        );
+                        if let Err(e) = stream.write_all(&response).await {
+                            warn!("Failed to write response to client: {:?}", e);
+                        }
+                        log_answer(access, &response);
+                        return;
+                    }
 
-                            // Send confirmation of connection setup
+                    if method == "GET" && is_probe_host(host) {
+                        let body = format!(
+                            "{}\n",
+                            probe::to_json(&Caller {
+                                addr,
+                                login: verified_login.as_deref(),
+                                tenant: None,
+                                allowed_hosts: &allowed_hosts,
+                                local_ip: None,
+                            })
+                        );
+                        let response = format!(
+                            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{body}",
+                            body.len()
+                        );
+                        if let Err(e) = stream.write_all(response.as_bytes()).await {
+                            warn!("Failed to write response to client {}: {:?}", addr, e);
+                        }
+                        return;
+                    }
+                    let target_host = split_host_port(&target)
+                        .map(|(host, _)| host)
+                        .unwrap_or(host);
+                    if is_echo_host(target_host) {
+                        if method == "CONNECT" {
                             let response = "HTTP/1.1 200 Connection Established\r\n\r\n";
                             if let Err(e) = stream.write_all(response.as_bytes()).await {
-                                eprintln!("Failed to write response to client {}: {:?}", addr, e);
+                                warn!("Failed to write response to client {}: {:?}", addr, e);
                                 return;
                             }
+                            probe::serve_echo(stream, None).await;
+                            return;
+                        }
 
-                            // Create a tunnel
-                            if let Err(e) = tunnel_to_remote(&mut stream, remote_addr).await {
-                                eprintln!("Tunneling error for {}: {:?}", addr, e);
-                            }
-                        } else {
-                            eprintln!("Invalid CONNECT request from {}", addr);
+                        let response = match probe::echo_text(None) {
+                            Some(text) => format!(
+                                "HTTP/1.1 200 OK\r\nContent-Type: text/plain; charset=utf-8\r\nContent-Length: {}\r\n\r\n{text}",
+                                text.len()
+                            ),
+                            None => String::from_utf8_lossy(&create_error_response(
+                                StatusCode::NOT_IMPLEMENTED,
+                            ))
+                            .into_owned(),
+                        };
+                        if let Err(e) = stream.write_all(response.as_bytes()).await {
+                            warn!("Failed to write response to client {}: {:?}", addr, e);
                         }
-                    } else {
-                        // Process regular HTTP requests
-                        handle_http_request(stream, request.to_string()).await;
+                        return;
                     }
                 }
-                Err(e) => {
-                    eprintln!("Error reading from client {}: {:?}", addr, e);
+                Err(err) => {
+                    warn!("Error parsing request: {}", err);
+
+                    // Never let a request the checks couldn't read through
+                    let error_response = create_error_response(StatusCode::BAD_REQUEST);
+                    if let Err(e) = stream.write_all(&error_response).await {
+                        warn!("Failed to write error response to client: {:?}", e);
+                    }
+                    return;
                 }
             }
 
-            // println!("Connection closed: {}", addr);
-        });
+            // Users still warming up are held to the --warm-up caps
+            let _guard = match &warm_up {
+                Some(limits) => match limits.acquire_connection() {
+                    Some(guard) => Some(guard),
+                    None => {
+                        warn!(
+                            "Connection limit reached ({} active), rejecting client={addr}",
+                            limits.connections()
+                        );
+                        let response = create_rate_limited_response(limits.max_connections());
+                        if let Err(e) = stream.write_all(&response).await {
+                            warn!("Failed to write response to client: {:?}", e);
+                        }
+                        log_answer(access, &response);
+                        return;
+                    }
+                },
+                None => None,
+            };
+            // The stream is the client connection, so it gets a connection cap of its own
+            let bandwidths = warm_up
+                .and_then(|limits| limits.bandwidth.clone())
+                .into_iter()
+                .chain(user_bandwidth)
+                .chain(throttle::connection_bandwidth().map(|bandwidth| bandwidth.0));
+            let stream = ThrottledStream::new(stream, bandwidths);
+            // What the client sends goes up, including what came along with the head
+            if let Some(meter) = &meter {
+                meter.add(Direction::Up, early_data.len());
+            }
+            let mut stream = MeteredStream::new(stream, meter, Direction::Up);
+
+            // Process request method and call the appropriate handler
+            if request.starts_with("CONNECT") {
+                // Process CONNECT request
+                let parts: Vec<&str> = request.split_whitespace().collect();
+                if parts.len() >= 2 {
+                    let remote_addr = parts[1].to_string();
+
+                    // Connect upstream before confirming the tunnel, so failures reach the client
+                    let server = match connect_target(&remote_addr, None, &client).await {
+                        Ok(server) => server,
+                        Err(e) => {
+                            warn!(
+                                "Failed to connect to {}: {e}",
+                                loggable(&remote_addr, &client)
+                            );
+
+                            let error_response = if let Some(retry_after) = breaker::retry_after(&e)
+                            {
+                                create_circuit_open_response(retry_after)
+                            } else if is_queue_full(&e) {
+                                create_rate_limited_response(Some(options.max_connects_per_host))
+                            } else {
+                                create_error_response(error_status(&e))
+                            };
+                            if let Err(e) = stream.write_all(&error_response).await {
+                                warn!("Failed to write error response to client: {:?}", e);
+                            }
+                            log_answer(access, &error_response);
+                            return;
+                        }
+                    };
+
+                    // Send confirmation of connection setup
+                    let response = match &new_session {
+                        Some(token) => format!(
+                            "HTTP/1.1 200 Connection Established\r\n{SESSION_HEADER}: {token}\r\n\r\n"
+                        ),
+                        None => "HTTP/1.1 200 Connection Established\r\n\r\n".to_string(),
+                    };
+                    if let Err(e) = stream.write_all(response.as_bytes()).await {
+                        warn!("Failed to write response to client {}: {:?}", addr, e);
+                        return;
+                    }
+
+                    // Create a tunnel
+                    tunnel::relay(stream, server, early_data, &remote_addr, &client).await;
+                } else {
+                    warn!("Invalid CONNECT request from {}", addr);
+                }
+            } else {
+                // Process regular HTTP requests
+                handle_http_request(stream, request.to_string(), early_data.to_vec(), client).await;
+            }
+        }
+        Err(e) => {
+            warn!("Error reading from client {}: {:?}", addr, e);
+        }
     }
-    // Ok(())
 }
 
 fn create_error_response(status_code: StatusCode) -> Vec<u8> {
@@ -265,93 +762,406 @@ fn create_error_response(status_code: StatusCode) -> Vec<u8> {
     response.into_bytes()
 }
 
+/// 403 with a body telling a user they used up their quota.
+fn create_quota_response(user: &User) -> Vec<u8> {
+    let body = usage::quota_message(user);
+    format!(
+        "HTTP/1.1 403 Forbidden\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{body}",
+        body.len()
+    )
+    .into_bytes()
+}
+
+/// 503 for a request refused by a limit, see [`rate_limit_headers`].
+fn create_rate_limited_response(limit: Option<usize>) -> Vec<u8> {
+    create_limited_response(StatusCode::SERVICE_UNAVAILABLE, rate_limit_headers(limit))
+}
+
+/// Empty answer refusing a request with the headers of [`retry_headers`].
+fn create_limited_response(status: StatusCode, headers: Vec<(&'static str, String)>) -> Vec<u8> {
+    let mut response = format!(
+        "HTTP/1.1 {} {}\r\nContent-Length: 0\r\n",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or("Unknown")
+    );
+    for (name, value) in headers {
+        response.push_str(&format!("{name}: {value}\r\n"));
+    }
+    response.push_str("\r\n");
+    response.into_bytes()
+}
+
+/// Log an answer the server wrote itself, when there is an access log.
+fn log_answer(access: Option<Access>, response: &[u8]) {
+    let Some(access) = access else {
+        return;
+    };
+    // The status is the second word of the status line
+    let status = String::from_utf8_lossy(response)
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .unwrap_or_default();
+    access::record(&Access {
+        status,
+        bytes_down: response.len() as u64,
+        ..access
+    });
+}
+
+/// 503 for a destination whose circuit is open, see [`breaker`].
+fn create_circuit_open_response(retry_after: u64) -> Vec<u8> {
+    format!("HTTP/1.1 503 Service Unavailable\r\nContent-Length: 0\r\nRetry-After: {retry_after}\r\n\r\n")
+        .into_bytes()
+}
+
 // Process regular HTTP requests
-async fn handle_http_request(
-    mut stream: tokio_rustls::server::TlsStream<TcpStream>,
+async fn handle_http_request<S>(
+    stream: MeteredStream<ThrottledStream<S>>,
     request: String,
-) {
+    early_data: Vec<u8>,
+    client_id: String,
+) where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (reader, mut stream) = tokio::io::split(stream);
+
     match parse_request(&request) {
-        Ok((method, uri, _, headers)) => {
-            // Create a HTTPS client
-            let https = HttpsConnector::new();
-            let client = Client::builder().build::<_, hyper::Body>(https);
+        Ok((method, uri, _, mut headers)) => {
+            // Repeats of the same length, which the framing check let through, are sent once
+            if let Some(length) = headers.get_mut("content-length") {
+                let first = length.split(',').next().unwrap_or_default();
+                *length = first.trim().to_string();
+            }
+            // The session token is between the client and the proxy only
+            headers.remove(SESSION_HEADER);
+            let target = uri.parse().ok().as_ref().and_then(uri_target);
+            let mut access = access::enabled()
+                .then(|| Access::new(&client_id, &method, target.as_deref().unwrap_or("-")));
+
+            // The request body is passed on as it arrives rather than held in memory
+            let body = match request_body_length(&headers) {
+                Some(BodyLength::Length(0)) => Body::empty(),
+                Some(BodyLength::Length(length)) => {
+                    if let Some(access) = &mut access {
+                        access.bytes_up = length;
+                    }
+                    let (sender, body) = Body::channel();
+                    tokio::spawn(send_request_body(reader, early_data, length, sender));
+                    body
+                }
+                Some(BodyLength::Chunked) => {
+                    let (sender, body) = Body::channel();
+                    tokio::spawn(send_chunked_body(reader, early_data, sender));
+                    body
+                }
+                None => {
+                    let error_response = create_error_response(StatusCode::BAD_REQUEST);
+                    if let Err(e) = stream.write_all(&error_response).await {
+                        warn!("Failed to write error response to client: {:?}", e);
+                    }
+                    log_answer(access, &error_response);
+                    return;
+                }
+            };
 
             // Create a new HTTP request
             let mut http_request = HttpRequest::builder()
                 .method(method.as_str())
                 .uri(uri)
-                .body(Body::empty())
+                .body(body)
                 .expect("Failed to build request");
 
             // Add the headers from the original request
             *http_request.headers_mut() = hash_map_to_header_map(headers.clone());
 
-            // Send the request to the final server
-            match client.request(http_request).await {
+            // Send the request to the parent cache if it takes it, otherwise to the final server
+            let result = match try_parent_cache(&http_request).await {
+                Some(response) => {
+                    let target = uri_target(http_request.uri()).unwrap_or_else(|| "-".to_string());
+                    Decision::allow("route:parent-cache").log(&client_id, &target);
+                    Ok(response)
+                }
+                None => {
+                    let target = uri_target(http_request.uri());
+                    let upstream = match &target {
+                        Some(target) => Upstream::for_target(target),
+                        None => Upstream::from_options(),
+                    };
+                    policy::route(upstream.as_ref(), wireguard_peer())
+                        .log(&client_id, target.as_deref().unwrap_or("-"));
+                    let mark = target
+                        .as_deref()
+                        .and_then(|target| fwmark_for(target, &client_id));
+
+                    if let Some(upstream) = upstream {
+                        // The upstream proxy resolves the target itself
+                        let https = HttpsConnector::new_with_connector(LimitedConnector::new(
+                            UpstreamConnector::new(upstream, mark, client_id.clone()),
+                        ));
+                        let client = Client::builder().build::<_, hyper::Body>(https);
+                        client.request(http_request).await
+                    } else {
+                        // Resolve once and make the client connect to exactly the validated addresses
+                        let addrs = match &target {
+                            Some(target) => resolve_pinned(target, &client_id).await,
+                            None => Err(IoError::new(ErrorKind::InvalidInput, "No target")),
+                        };
+                        let addrs = match addrs {
+                            Ok(addrs) => addrs,
+                            Err(e) => {
+                                let error_response = create_error_response(error_status(&e));
+                                if let Err(e) = stream.write_all(&error_response).await {
+                                    warn!("Failed to write error response to client: {:?}", e);
+                                }
+                                log_answer(access, &error_response);
+                                return;
+                            }
+                        };
+
+                        // Create a HTTPS client
+                        match (wireguard_peer(), mark) {
+                            #[cfg(feature = "wireguard")]
+                            (Some(_), _) => {
+                                let https = HttpsConnector::new_with_connector(
+                                    LimitedConnector::new(WireGuardConnector::new(addrs)),
+                                );
+                                let client = Client::builder().build::<_, hyper::Body>(https);
+                                client.request(http_request).await
+                            }
+                            (_, mark)
+                                if mark.is_some()
+                                    || egress::enabled()
+                                    || mss::enabled()
+                                    || fastopen::enabled()
+                                    || (addrs.iter().any(SocketAddr::is_ipv6)
+                                        && Opt::global().ipv6_egress_prefix.is_some()) =>
+                            {
+                                let marked = MarkedConnector::new(addrs, None, mark);
+                                let https = HttpsConnector::new_with_connector(
+                                    LimitedConnector::new(marked),
+                                );
+                                let client = Client::builder().build::<_, hyper::Body>(https);
+                                client.request(http_request).await
+                            }
+                            _ => {
+                                let mut http = pinned_connector(addrs);
+                                http.enforce_http(false);
+                                let https =
+                                    HttpsConnector::new_with_connector(LimitedConnector::new(http));
+                                let client = Client::builder().build::<_, hyper::Body>(https);
+
+                                client.request(http_request).await
+                            }
+                        }
+                    }
+                }
+            };
+
+            match result {
                 Ok(response) => {
-                    // Send the response back to the client
+                    // Send the response back to the client chunk by chunk, so memory stays
+                    // the same however large the body is. Without a known length, the end
+                    // of the body is marked by closing the connection.
                     let status = response.status();
-                    let response_body = hyper::body::to_bytes(response.into_body()).await.unwrap();
-
-                    let response_header = format!("HTTP/1.1 {}\r\n", status);
-                    let response_length = response_body.len();
-                    let response = format!(
-                        "{}Content-Length: {}\r\n\r\n",
-                        response_header, response_length
-                    );
-                    let full_response = [response.into_bytes(), response_body.to_vec()].concat();
+                    let mut response_body = response.into_body();
+                    let framing = match response_body.size_hint().exact() {
+                        Some(length) => format!("Content-Length: {length}\r\n"),
+                        None => "Connection: close\r\n".to_string(),
+                    };
+                    let response = format!("HTTP/1.1 {status}\r\n{framing}\r\n");
+                    if let Err(e) = stream.write_all(response.as_bytes()).await {
+                        warn!("Failed to write response to client: {:?}", e);
+                        return;
+                    }
 
-                    // Send the full response back to the client
-                    if let Err(e) = stream.write_all(&full_response).await {
-                        eprintln!("Failed to write response to client: {:?}", e);
+                    let mut sent = 0;
+                    while let Some(chunk) = response_body.data().await {
+                        let result = match chunk {
+                            Ok(chunk) => stream.write_all(&chunk).await.map(|_| chunk.len()),
+                            Err(e) => {
+                                warn!("Error while reading response from server: {:?}", e);
+                                break;
+                            }
+                        };
+                        match result {
+                            Ok(len) => sent += len as u64,
+                            Err(e) => {
+                                warn!("Failed to write response to client: {:?}", e);
+                                break;
+                            }
+                        }
                     }
+                    let _ = stream.shutdown().await;
+                    if let Some(access) = access {
+                        access::record(&Access {
+                            status: status.as_u16(),
+                            bytes_down: sent,
+                            ..access
+                        });
+                    }
+                }
+                Err(e) if is_queue_full_error(&e) => {
+                    let response =
+                        create_rate_limited_response(Some(Opt::global().max_connects_per_host));
+                    if let Err(e) = stream.write_all(&response).await {
+                        warn!("Failed to write response to client: {:?}", e);
+                    }
+                    log_answer(access, &response);
                 }
                 Err(e) => {
-                    eprintln!("Error while forwarding request: {:?}", e);
+                    warn!("Error while forwarding request: {:?}", e);
+                    let response = match breaker::retry_after(&e) {
+                        Some(retry_after) => create_circuit_open_response(retry_after),
+                        None => create_error_response(error_status(&e)),
+                    };
+                    if let Err(e) = stream.write_all(&response).await {
+                        warn!("Failed to write response to client: {:?}", e);
+                    }
+                    log_answer(access, &response);
                 }
             }
         }
         Err(err) => {
-            println!("Error parsing HTTP request: {}", err);
+            warn!("Error parsing HTTP request: {}", err);
         }
     }
 }
 
-type RequestInfo = (String, String, String, HashMap<String, String>);
+/// How the request body ends: after a length, or with the last chunk of a chunked body.
+/// The framing check only lets through a Transfer-Encoding ending in `chunked`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BodyLength {
+    Length(u64),
+    Chunked,
+}
 
-fn parse_request(request: &str) -> Result<RequestInfo, &'static str> {
-    let mut lines = request.lines();
+/// Length of the request body, `None` for a Content-Length that isn't a number.
+fn request_body_length(headers: &HashMap<String, String>) -> Option<BodyLength> {
+    if headers.contains_key("transfer-encoding") {
+        return Some(BodyLength::Chunked);
+    }
+    match headers.get("content-length") {
+        Some(length) => length.trim().parse().ok().map(BodyLength::Length),
+        None => Some(BodyLength::Length(0)),
+    }
+}
 
-    // Get the first line with the method, URI, and version
-    let request_line = lines.next().ok_or("Missing request line")?;
-    let mut request_parts = request_line.split_whitespace();
+/// Feed `length` bytes of request body, starting with what came in with the head, to
+/// `sender`. The channel holds one chunk at a time, so a slow server slows the client down.
+async fn send_request_body<R: AsyncRead + Unpin>(
+    mut reader: R,
+    mut early_data: Vec<u8>,
+    length: u64,
+    mut sender: hyper::body::Sender,
+) {
+    early_data.truncate(length.min(early_data.len() as u64) as usize);
+    let mut remaining = length - early_data.len() as u64;
+    if !early_data.is_empty() && sender.send_data(early_data.into()).await.is_err() {
+        return;
+    }
 
-    let method = request_parts.next().ok_or("Missing method")?.to_string();
-    let uri = request_parts.next().ok_or("Missing URI")?.to_string();
-    let version = request_parts.next().ok_or("Missing version")?.to_string();
+    let mut buffer = vec![0; 16 * 1024];
+    while remaining > 0 {
+        let limit = remaining.min(buffer.len() as u64) as usize;
+        let n = match reader.read(&mut buffer[..limit]).await {
+            Ok(0) | Err(_) => {
+                // The client went away mid-body, the server must not take it as complete
+                sender.abort();
+                return;
+            }
+            Ok(n) => n,
+        };
+        if sender
+            .send_data(Bytes::copy_from_slice(&buffer[..n]))
+            .await
+            .is_err()
+        {
+            return;
+        }
+        remaining -= n as u64;
+    }
+}
 
-    // Initialize a hash map for headers
-    let mut headers = HashMap::new();
+/// Decode the chunked request body, starting with what came in with the head, into
+/// `sender`. Hyper frames it in chunks again towards the server, so chunk extensions and
+/// trailers are left out. A body that isn't well-formed is cut off, so the server doesn't
+/// take it as complete.
+async fn send_chunked_body<R: AsyncRead + Unpin>(
+    reader: R,
+    early_data: Vec<u8>,
+    mut sender: hyper::body::Sender,
+) {
+    let mut reader = tokio::io::BufReader::new(std::io::Cursor::new(early_data).chain(reader));
+    loop {
+        let Some(size) = read_chunk_line(&mut reader).await.and_then(chunk_size) else {
+            sender.abort();
+            return;
+        };
+        if size == 0 {
+            break;
+        }
 
-    // Process the rest of the lines as headers
-    for line in lines {
-        let line = line.trim();
-        if line.is_empty() {
-            continue; // Skip empty lines
+        let mut remaining = size;
+        while remaining > 0 {
+            let buffer = match reader.fill_buf().await {
+                Ok([]) | Err(_) => {
+                    sender.abort();
+                    return;
+                }
+                Ok(buffer) => buffer,
+            };
+            let n = buffer
+                .len()
+                .min(usize::try_from(remaining).unwrap_or(usize::MAX));
+            let chunk = Bytes::copy_from_slice(&buffer[..n]);
+            reader.consume(n);
+            if sender.send_data(chunk).await.is_err() {
+                return;
+            }
+            remaining -= n as u64;
+        }
+        if read_chunk_line(&mut reader).await.as_deref() != Some("") {
+            sender.abort();
+            return;
         }
-        let (key, value) = parse_header(line)?;
-        headers.insert(key.to_string().to_lowercase(), value.to_string());
     }
 
-    Ok((method, uri, version, headers))
+    // Trailers up to the empty line
+    loop {
+        match read_chunk_line(&mut reader).await.as_deref() {
+            Some("") => return,
+            Some(_) => {}
+            None => {
+                sender.abort();
+                return;
+            }
+        }
+    }
 }
 
-fn parse_header(line: &str) -> Result<(String, String), &'static str> {
-    let mut parts = line.splitn(2, ':');
-    let key = parts.next().ok_or("Missing header key")?.trim();
-    let value = parts.next().ok_or("Missing header value")?.trim();
+/// A line of chunked framing without its CRLF, `None` when the body ends before one or
+/// the line is too long to be framing.
+async fn read_chunk_line<R: AsyncBufRead + Unpin>(reader: &mut R) -> Option<String> {
+    const MAX_LINE: u64 = 4096;
+    let mut line = Vec::new();
+    reader
+        .take(MAX_LINE)
+        .read_until(b'\n', &mut line)
+        .await
+        .ok()?;
+    let line = line.strip_suffix(b"\r\n")?;
+    String::from_utf8(line.to_vec()).ok()
+}
 
-    Ok((key.to_string(), value.to_string()))
+/// Size from a chunk-size line, its extensions left out. Only hex digits are taken, so
+/// the server can't read another size from it.
+fn chunk_size(line: String) -> Option<u64> {
+    let size = line.split(';').next()?;
+    if size.is_empty() || size.len() > 15 || !size.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    u64::from_str_radix(size, 16).ok()
 }
 
 fn hash_map_to_header_map(headers: HashMap<String, String>) -> HeaderMap {
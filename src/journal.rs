@@ -0,0 +1,179 @@
+use crate::json::{object, Value};
+use crate::options::Opt;
+use crate::policy::Verdict;
+use crate::utils::{label_user, log_time};
+
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::{Mutex, OnceLock};
+
+use chrono::{DateTime, Utc};
+
+static ENTRIES: OnceLock<Mutex<VecDeque<Entry>>> = OnceLock::new();
+
+/// One logged policy decision, kept in memory for the admin API's log viewer.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    pub time: DateTime<Utc>,
+    pub verdict: Verdict,
+    pub rule: String,
+    pub target: String,
+    pub client: String,
+}
+
+impl Entry {
+    /// Login from the client label, `-` when the client didn't authenticate.
+    pub fn user(&self) -> &str {
+        label_user(&self.client)
+    }
+
+    fn to_json(&self) -> Value {
+        object([
+            ("time", log_time(self.time).to_rfc3339().into()),
+            ("verdict", self.verdict.to_string().into()),
+            ("rule", self.rule.as_str().into()),
+            ("target", self.target.as_str().into()),
+            ("user", self.user().into()),
+            ("client", self.client.as_str().into()),
+        ])
+    }
+}
+
+/// Which entries to return, every field narrowing the selection further.
+#[derive(Debug, Default)]
+pub struct Filter {
+    /// Exact login
+    pub user: Option<String>,
+    /// Part of the `host:port` target
+    pub destination: Option<String>,
+    /// `allow`, `deny`, or part of the deciding rule
+    pub decision: Option<String>,
+    /// Entries from this time on
+    pub since: Option<DateTime<Utc>>,
+    /// Entries before this time
+    pub until: Option<DateTime<Utc>>,
+    /// Only the newest entries, up to this many
+    pub limit: Option<usize>,
+}
+
+impl Filter {
+    pub fn from_query(query: &BTreeMap<String, String>) -> Result<Filter, String> {
+        let non_empty = |key: &str| query.get(key).filter(|value| !value.is_empty()).cloned();
+        let limit = match non_empty("limit") {
+            Some(limit) => Some(
+                limit
+                    .parse::<usize>()
+                    .map_err(|_| format!("Invalid limit '{limit}'"))?,
+            ),
+            None => None,
+        };
+
+        let time = |key: &str| match non_empty(key) {
+            Some(value) => DateTime::parse_from_rfc3339(&value)
+                .map(|time| Some(time.with_timezone(&Utc)))
+                .map_err(|e| format!("Invalid {key} '{value}': {e}")),
+            None => Ok(None),
+        };
+
+        Ok(Filter {
+            user: non_empty("user"),
+            destination: non_empty("destination").map(|value| value.to_ascii_lowercase()),
+            decision: non_empty("decision"),
+            since: time("since")?,
+            until: time("until")?,
+            limit,
+        })
+    }
+
+    fn matches(&self, entry: &Entry) -> bool {
+        let user = self.user.as_ref().is_none_or(|user| entry.user() == user);
+        let destination = self.destination.as_ref().is_none_or(|destination| {
+            entry
+                .target
+                .to_ascii_lowercase()
+                .contains(destination.as_str())
+        });
+        let decision = self.decision.as_ref().is_none_or(|decision| {
+            entry.verdict.to_string() == *decision || entry.rule.contains(decision.as_str())
+        });
+        let since = self.since.is_none_or(|since| entry.time >= since);
+        let until = self.until.is_none_or(|until| entry.time < until);
+        user && destination && decision && since && until
+    }
+}
+
+fn entries() -> &'static Mutex<VecDeque<Entry>> {
+    ENTRIES.get_or_init(Default::default)
+}
+
+/// Remember a decision, dropping the oldest one once `--log-buffer` entries are kept.
+pub fn record(entry: Entry) {
+    let capacity = Opt::global().log_buffer;
+    if capacity == 0 {
+        return;
+    }
+
+    let mut entries = entries().lock().unwrap();
+    while entries.len() >= capacity {
+        entries.pop_front();
+    }
+    entries.push_back(entry);
+}
+
+/// Forget the kept entries about `login`. Returns how many there were.
+pub fn purge(login: &str) -> usize {
+    let mut entries = entries().lock().unwrap();
+    let before = entries.len();
+    entries.retain(|entry| entry.user() != login);
+    before - entries.len()
+}
+
+/// Kept entries matching `filter`, oldest first.
+pub fn query(filter: &Filter) -> Vec<Entry> {
+    let entries = entries().lock().unwrap();
+    let mut matching = entries
+        .iter()
+        .rev()
+        .filter(|entry| filter.matches(entry))
+        .take(filter.limit.unwrap_or(usize::MAX))
+        .cloned()
+        .collect::<Vec<Entry>>();
+    matching.reverse();
+    matching
+}
+
+/// Entries as CSV with a header row, quoting fields as RFC 4180 does.
+pub fn to_csv(entries: &[Entry]) -> String {
+    let mut csv = String::from("time,verdict,rule,target,user,client\r\n");
+    for entry in entries {
+        let fields = [
+            log_time(entry.time).to_rfc3339(),
+            entry.verdict.to_string(),
+            entry.rule.clone(),
+            entry.target.clone(),
+            entry.user().to_string(),
+            entry.client.clone(),
+        ];
+        let fields = fields
+            .iter()
+            .map(|field| csv_field(field))
+            .collect::<Vec<String>>();
+        csv.push_str(&fields.join(","));
+        csv.push_str("\r\n");
+    }
+    csv
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\r', '\n']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+pub fn to_json(entries: &[Entry]) -> Value {
+    object([(
+        "entries",
+        Value::Array(entries.iter().map(Entry::to_json).collect()),
+    )])
+}
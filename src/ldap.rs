@@ -0,0 +1,396 @@
+use crate::auth::AuthProvider;
+use crate::dylib::Library;
+use crate::options::Opt;
+use crate::utils::to_sha256;
+
+use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_ulong, c_void};
+use std::ptr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// OpenLDAP result codes and options (ldap.h)
+const LDAP_SUCCESS: c_int = 0;
+const LDAP_SIZELIMIT_EXCEEDED: c_int = 4;
+const LDAP_INVALID_CREDENTIALS: c_int = 49;
+const LDAP_SERVER_DOWN: c_int = -1;
+const LDAP_OPT_REFERRALS: c_int = 0x0008;
+const LDAP_OPT_PROTOCOL_VERSION: c_int = 0x0011;
+const LDAP_OPT_NETWORK_TIMEOUT: c_int = 0x5005;
+const LDAP_VERSION3: c_int = 3;
+const LDAP_SCOPE_SUBTREE: c_int = 2;
+
+#[repr(C)]
+struct BerValue {
+    bv_len: c_ulong,
+    bv_val: *mut c_char,
+}
+
+type Initialize = unsafe extern "C" fn(*mut *mut c_void, *const c_char) -> c_int;
+type SetOption = unsafe extern "C" fn(*mut c_void, c_int, *const c_void) -> c_int;
+type SaslBindS = unsafe extern "C" fn(
+    *mut c_void,
+    *const c_char,
+    *const c_char,
+    *const BerValue,
+    *mut c_void,
+    *mut c_void,
+    *mut *mut BerValue,
+) -> c_int;
+type SearchExtS = unsafe extern "C" fn(
+    *mut c_void,
+    *const c_char,
+    c_int,
+    *const c_char,
+    *const *const c_char,
+    c_int,
+    *mut c_void,
+    *mut c_void,
+    *mut libc::timeval,
+    c_int,
+    *mut *mut c_void,
+) -> c_int;
+type CountEntries = unsafe extern "C" fn(*mut c_void, *mut c_void) -> c_int;
+type FirstEntry = unsafe extern "C" fn(*mut c_void, *mut c_void) -> *mut c_void;
+type GetDn = unsafe extern "C" fn(*mut c_void, *mut c_void) -> *mut c_char;
+type MemFree = unsafe extern "C" fn(*mut c_void);
+type MsgFree = unsafe extern "C" fn(*mut c_void) -> c_int;
+type UnbindExtS = unsafe extern "C" fn(*mut c_void, *mut c_void, *mut c_void) -> c_int;
+type Err2String = unsafe extern "C" fn(c_int) -> *const c_char;
+
+/// OpenLDAP client library, loaded when `--ldap-uri` is set.
+struct LdapApi {
+    _library: Library,
+    initialize: Initialize,
+    set_option: SetOption,
+    sasl_bind_s: SaslBindS,
+    search_ext_s: SearchExtS,
+    count_entries: CountEntries,
+    first_entry: FirstEntry,
+    get_dn: GetDn,
+    memfree: MemFree,
+    msgfree: MsgFree,
+    unbind_ext_s: UnbindExtS,
+    err2string: Err2String,
+}
+
+impl LdapApi {
+    fn load() -> Result<LdapApi, String> {
+        let library = Library::open(&["libldap-2.5.so.0", "libldap.so.2", "libldap-2.4.so.2"])?;
+
+        unsafe {
+            Ok(LdapApi {
+                initialize: library.symbol("ldap_initialize")?,
+                set_option: library.symbol("ldap_set_option")?,
+                sasl_bind_s: library.symbol("ldap_sasl_bind_s")?,
+                search_ext_s: library.symbol("ldap_search_ext_s")?,
+                count_entries: library.symbol("ldap_count_entries")?,
+                first_entry: library.symbol("ldap_first_entry")?,
+                get_dn: library.symbol("ldap_get_dn")?,
+                memfree: library.symbol("ldap_memfree")?,
+                msgfree: library.symbol("ldap_msgfree")?,
+                unbind_ext_s: library.symbol("ldap_unbind_ext_s")?,
+                err2string: library.symbol("ldap_err2string")?,
+                _library: library,
+            })
+        }
+    }
+
+    fn error(&self, code: c_int) -> String {
+        let message = unsafe { (self.err2string)(code) };
+        if message.is_null() {
+            return format!("LDAP error {code}");
+        }
+        unsafe { CStr::from_ptr(message) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+/// Connection handle. It belongs to one thread at a time: it is either in the pool
+/// or checked out for a single authentication.
+struct Connection {
+    ld: *mut c_void,
+    unbind_ext_s: UnbindExtS,
+    // Whether the last bind was the service bind, so searches can run
+    service_bound: bool,
+}
+
+unsafe impl Send for Connection {}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        unsafe { (self.unbind_ext_s)(self.ld, ptr::null_mut(), ptr::null_mut()) };
+    }
+}
+
+/// Authenticates against LDAP or Active Directory: search the user's entry as the
+/// service account, then bind as that entry with the client's password.
+pub struct Ldap {
+    api: LdapApi,
+    uri: CString,
+    base_dn: CString,
+    bind_dn: Option<CString>,
+    bind_password: String,
+    user_filter: String,
+    group_filter: String,
+    timeout: Duration,
+    pool: Mutex<Vec<Connection>>,
+    pool_size: usize,
+    // Logins that recently authenticated, with a hash of the password they used
+    cache: Mutex<HashMap<String, (String, Instant)>>,
+    cache_ttl: Duration,
+}
+
+impl Ldap {
+    pub fn from_options() -> Result<Ldap, String> {
+        let options = Opt::global();
+        let c_string = |s: &str| CString::new(s).map_err(|e| e.to_string());
+
+        Ok(Ldap {
+            api: LdapApi::load()?,
+            uri: c_string(options.ldap_uri.as_deref().unwrap_or_default())?,
+            base_dn: c_string(options.ldap_base_dn.as_deref().unwrap_or_default())?,
+            bind_dn: options.ldap_bind_dn.as_deref().map(c_string).transpose()?,
+            bind_password: options.ldap_bind_password.clone().unwrap_or_default(),
+            user_filter: options.ldap_user_filter.clone(),
+            group_filter: options.ldap_group_filter.clone().unwrap_or_default(),
+            timeout: Duration::from_secs(options.ldap_timeout),
+            pool: Mutex::new(Vec::new()),
+            pool_size: options.ldap_pool_size,
+            cache: Mutex::new(HashMap::new()),
+            cache_ttl: Duration::from_secs(options.ldap_cache_ttl),
+        })
+    }
+
+    fn timeval(&self) -> libc::timeval {
+        libc::timeval {
+            tv_sec: self.timeout.as_secs() as libc::time_t,
+            tv_usec: 0,
+        }
+    }
+
+    fn open(&self) -> Result<Connection, String> {
+        let mut ld = ptr::null_mut();
+        let code = unsafe { (self.api.initialize)(&mut ld, self.uri.as_ptr()) };
+        if code != LDAP_SUCCESS {
+            return Err(self.api.error(code));
+        }
+        let connection = Connection {
+            ld,
+            unbind_ext_s: self.api.unbind_ext_s,
+            service_bound: false,
+        };
+
+        let version = LDAP_VERSION3;
+        let timeout = self.timeval();
+        unsafe {
+            (self.api.set_option)(
+                ld,
+                LDAP_OPT_PROTOCOL_VERSION,
+                &version as *const c_int as *const c_void,
+            );
+            (self.api.set_option)(
+                ld,
+                LDAP_OPT_NETWORK_TIMEOUT,
+                &timeout as *const libc::timeval as *const c_void,
+            );
+            // Active Directory refers to other partitions, which can't be followed with our bind
+            (self.api.set_option)(ld, LDAP_OPT_REFERRALS, ptr::null());
+        }
+
+        Ok(connection)
+    }
+
+    fn bind(&self, connection: &Connection, dn: Option<&CStr>, password: &str) -> c_int {
+        let credentials = BerValue {
+            bv_len: password.len() as c_ulong,
+            bv_val: password.as_ptr() as *mut c_char,
+        };
+        unsafe {
+            (self.api.sasl_bind_s)(
+                connection.ld,
+                dn.map(CStr::as_ptr).unwrap_or(ptr::null()),
+                ptr::null(),
+                &credentials,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        }
+    }
+
+    /// DN of the only entry matching the user and group filters.
+    fn find_user(&self, connection: &Connection, login: &str) -> Result<Option<CString>, c_int> {
+        let filter = format!(
+            "(&{}{})",
+            self.user_filter.replace("{login}", &escape_filter(login)),
+            self.group_filter
+        );
+        let Ok(filter) = CString::new(filter) else {
+            return Ok(None);
+        };
+        // Ask for no attributes, only the DN is needed
+        let no_attributes = c"1.1";
+        let attributes = [no_attributes.as_ptr(), ptr::null()];
+        let mut timeout = self.timeval();
+        let mut result = ptr::null_mut();
+
+        let code = unsafe {
+            (self.api.search_ext_s)(
+                connection.ld,
+                self.base_dn.as_ptr(),
+                LDAP_SCOPE_SUBTREE,
+                filter.as_ptr(),
+                attributes.as_ptr(),
+                0,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                &mut timeout,
+                2,
+                &mut result,
+            )
+        };
+
+        let dn = match code {
+            LDAP_SUCCESS => unsafe {
+                if (self.api.count_entries)(connection.ld, result) != 1 {
+                    None
+                } else {
+                    let entry = (self.api.first_entry)(connection.ld, result);
+                    let dn = (self.api.get_dn)(connection.ld, entry);
+                    if dn.is_null() {
+                        None
+                    } else {
+                        let owned = CStr::from_ptr(dn).to_owned();
+                        (self.api.memfree)(dn as *mut c_void);
+                        Some(owned)
+                    }
+                }
+            },
+            // More than one entry: the filter doesn't identify a single user
+            LDAP_SIZELIMIT_EXCEEDED => None,
+            _ => {
+                if !result.is_null() {
+                    unsafe { (self.api.msgfree)(result) };
+                }
+                return Err(code);
+            }
+        };
+
+        if !result.is_null() {
+            unsafe { (self.api.msgfree)(result) };
+        }
+        Ok(dn)
+    }
+
+    fn verify(
+        &self,
+        connection: &mut Connection,
+        login: &str,
+        password: &str,
+    ) -> Result<bool, c_int> {
+        if !connection.service_bound {
+            let code = self.bind(connection, self.bind_dn.as_deref(), &self.bind_password);
+            if code != LDAP_SUCCESS {
+                return Err(code);
+            }
+            connection.service_bound = true;
+        }
+
+        let Some(dn) = self.find_user(connection, login)? else {
+            return Ok(false);
+        };
+
+        connection.service_bound = false;
+        match self.bind(connection, Some(&dn), password) {
+            LDAP_SUCCESS => Ok(true),
+            LDAP_INVALID_CREDENTIALS => Ok(false),
+            code => Err(code),
+        }
+    }
+
+    fn is_cached(&self, login: &str, password_hash: &str) -> bool {
+        let cache = self.cache.lock().unwrap();
+        cache
+            .get(login)
+            .map(|(hash, at)| hash == password_hash && at.elapsed() < self.cache_ttl)
+            .unwrap_or(false)
+    }
+
+    fn remember(&self, login: &str, password_hash: String) {
+        if self.cache_ttl.is_zero() {
+            return;
+        }
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, (_, at)| at.elapsed() < self.cache_ttl);
+        cache.insert(login.to_string(), (password_hash, Instant::now()));
+    }
+}
+
+impl AuthProvider for Ldap {
+    fn name(&self) -> &'static str {
+        "LDAP"
+    }
+
+    fn authenticate(&self, login: &str, password: &str) -> Result<bool, String> {
+        // An empty password makes a simple bind anonymous, which servers accept
+        if login.is_empty() || password.is_empty() {
+            return Ok(false);
+        }
+
+        let password_hash = to_sha256(&format!("{login}:{password}"));
+        if self.is_cached(login, &password_hash) {
+            return Ok(true);
+        }
+
+        // A pooled connection may have been closed by the server, retry on a fresh one
+        let pooled = self.pool.lock().unwrap().pop();
+        let (mut connection, mut reused) = match pooled {
+            Some(connection) => (connection, true),
+            None => (self.open()?, false),
+        };
+        let result = loop {
+            match self.verify(&mut connection, login, password) {
+                Err(LDAP_SERVER_DOWN) if reused => {
+                    connection = self.open()?;
+                    reused = false;
+                }
+                result => break result,
+            }
+        };
+
+        match result {
+            Ok(allowed) => {
+                let mut pool = self.pool.lock().unwrap();
+                if pool.len() < self.pool_size {
+                    pool.push(connection);
+                }
+                drop(pool);
+
+                if allowed {
+                    self.remember(login, password_hash);
+                }
+                Ok(allowed)
+            }
+            Err(code) => Err(self.api.error(code)),
+        }
+    }
+}
+
+/// Escape a value for use in an LDAP filter (RFC 4515).
+fn escape_filter(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '*' => escaped.push_str("\\2a"),
+            '(' => escaped.push_str("\\28"),
+            ')' => escaped.push_str("\\29"),
+            '\\' => escaped.push_str("\\5c"),
+            '\0' => escaped.push_str("\\00"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
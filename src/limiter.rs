@@ -0,0 +1,280 @@
+use crate::breaker;
+use crate::dns::split_host_port;
+use crate::options::Opt;
+use crate::stats;
+use crate::users::UserStore;
+use crate::utils::RequestRate;
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use hyper::service::Service;
+use hyper::Uri;
+use log::warn;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+static SLOTS: OnceLock<Mutex<HashMap<String, Arc<Semaphore>>>> = OnceLock::new();
+static CLIENTS: OnceLock<Mutex<ClientConnections>> = OnceLock::new();
+static REQUESTS: OnceLock<Mutex<HashMap<String, RequestBucket>>> = OnceLock::new();
+
+// Buckets kept before full ones are forgotten, they'd start out full again anyway
+const MAX_IDLE_BUCKETS: usize = 1024;
+
+/// Client connections open on the proxy listeners, in all and per client address.
+#[derive(Default)]
+struct ClientConnections {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+}
+
+fn clients() -> &'static Mutex<ClientConnections> {
+    CLIENTS.get_or_init(Default::default)
+}
+
+/// A client connection counted against `--max-connections` and `--max-connections-per-ip`
+/// until dropped.
+pub struct ClientSlot(IpAddr);
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        let mut clients = clients().lock().unwrap();
+        clients.total -= 1;
+        if let Some(count) = clients.per_ip.get_mut(&self.0) {
+            *count -= 1;
+            if *count == 0 {
+                clients.per_ip.remove(&self.0);
+            }
+        }
+    }
+}
+
+/// Count a connection just accepted from `addr`. `None` when the proxy already has
+/// `--max-connections` client connections open, or the client `--max-connections-per-ip`,
+/// so one client can't use up the file descriptors of all.
+pub fn acquire_client(addr: SocketAddr) -> Option<ClientSlot> {
+    let options = Opt::global();
+    let ip = addr.ip().to_canonical();
+    let mut clients = clients().lock().unwrap();
+    let from_ip = clients.per_ip.get(&ip).copied().unwrap_or(0);
+
+    let limit = match (options.max_connections, options.max_connections_per_ip) {
+        (max, _) if max > 0 && clients.total >= max => Some(format!("{max} in all")),
+        (_, max) if max > 0 && from_ip >= max => Some(format!("{max} from {ip}")),
+        _ => None,
+    };
+    if let Some(limit) = limit {
+        drop(clients);
+        let total = stats::CONNECTIONS_LIMITED.fetch_add(1, Ordering::Relaxed) + 1;
+        warn!("Connection limit reached ({limit}), refusing client {addr} (total={total})");
+        return None;
+    }
+
+    clients.total += 1;
+    *clients.per_ip.entry(ip).or_default() += 1;
+    Some(ClientSlot(ip))
+}
+
+/// Requests left to a user or client address under its rate limit.
+struct RequestBucket {
+    available: f64,
+    updated: Instant,
+    rate: RequestRate,
+}
+
+/// Rate limit of `login`: its own from `--users-file`, or `--rate-limit`.
+pub fn request_rate(login: Option<&str>) -> Option<RequestRate> {
+    login
+        .and_then(|login| UserStore::global()?.get(login)?.rate_limit)
+        .or(Opt::global().rate_limit)
+}
+
+/// Take a request from the rate limit bucket of `login`, or of the client address `ip`
+/// for requests without credentials, so one client can't starve the others. `Err` with
+/// the seconds until the next request is allowed when the bucket is empty.
+pub fn take_request(login: Option<&str>, ip: IpAddr) -> Result<(), u64> {
+    let Some(rate) = request_rate(login) else {
+        return Ok(());
+    };
+    let key = match login {
+        Some(login) => format!("user={login}"),
+        None => format!("ip={}", ip.to_canonical()),
+    };
+    let now = Instant::now();
+    let refill = |bucket: &RequestBucket| {
+        let capacity = f64::from(bucket.rate.requests);
+        let per_second = capacity / bucket.rate.seconds as f64;
+        (bucket.available + now.duration_since(bucket.updated).as_secs_f64() * per_second)
+            .min(capacity)
+    };
+
+    let mut buckets = REQUESTS.get_or_init(Default::default).lock().unwrap();
+    if buckets.len() > MAX_IDLE_BUCKETS {
+        buckets.retain(|_, bucket| refill(bucket) < f64::from(bucket.rate.requests));
+    }
+    let bucket = buckets.entry(key).or_insert(RequestBucket {
+        available: f64::from(rate.requests),
+        updated: now,
+        rate,
+    });
+    // A rate changed through the admin API applies from the next request on
+    bucket.rate = rate;
+    bucket.available = refill(bucket);
+    bucket.updated = now;
+    if bucket.available < 1.0 {
+        let per_second = f64::from(rate.requests) / rate.seconds as f64;
+        let wait = (1.0 - bucket.available) / per_second;
+        drop(buckets);
+        let total = stats::REQUESTS_RATE_LIMITED.fetch_add(1, Ordering::Relaxed) + 1;
+        let client = login.map_or_else(|| format!("client {ip}"), |login| format!("user {login}"));
+        warn!("Request rate limit reached ({rate}), refusing {client} (total={total})");
+        return Err(wait.ceil() as u64);
+    }
+    bucket.available -= 1.0;
+    Ok(())
+}
+
+fn slots() -> &'static Mutex<HashMap<String, Arc<Semaphore>>> {
+    SLOTS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// Right to open one connection to a host under `--max-connects-per-host`, held until
+/// the connection is established or has failed.
+pub struct ConnectPermit {
+    host: String,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for ConnectPermit {
+    fn drop(&mut self) {
+        let mut slots = slots().lock().unwrap();
+        drop(self.permit.take());
+
+        // Nobody connecting or queued for the host any more, forget it
+        if slots
+            .get(&self.host)
+            .is_some_and(|semaphore| Arc::strong_count(semaphore) == 1)
+        {
+            slots.remove(&self.host);
+        }
+    }
+}
+
+/// Wait for a free connect slot to `host`. Only `--max-connects-per-host` connections
+/// to the same host are opened at once, the next `--connect-queue` wait their turn and
+/// anything beyond that is turned away, so bursts don't trip an origin's rate limits.
+pub async fn acquire(host: &str) -> io::Result<Option<ConnectPermit>> {
+    let options = Opt::global();
+    if options.max_connects_per_host == 0 {
+        return Ok(None);
+    }
+
+    let host = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_ascii_lowercase();
+    let semaphore = {
+        let mut slots = slots().lock().unwrap();
+        let semaphore = slots
+            .entry(host.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(options.max_connects_per_host)))
+            .clone();
+
+        // Every connection being opened and every waiter holds a reference besides the map's
+        let connecting = options.max_connects_per_host - semaphore.available_permits();
+        let queued = (Arc::strong_count(&semaphore) - 2).saturating_sub(connecting);
+        if semaphore.available_permits() == 0 && queued >= options.connect_queue {
+            drop(semaphore);
+            let total = stats::CONNECT_QUEUE_FULL.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!("Connect queue to {host} is full, refusing the connection (total={total})");
+            return Err(io::Error::new(
+                ErrorKind::ResourceBusy,
+                format!("Too many connections being opened to {host}"),
+            ));
+        }
+        semaphore
+    };
+
+    // Build the permit before waiting, so a cancelled waiter still cleans up the host
+    let mut permit = ConnectPermit { host, permit: None };
+    permit.permit = Some(
+        semaphore
+            .acquire_owned()
+            .await
+            .expect("Connect slots are never closed"),
+    );
+    Ok(Some(permit))
+}
+
+/// Connect slot for `target` (`host:port`).
+pub async fn acquire_target(target: &str) -> io::Result<Option<ConnectPermit>> {
+    let host = split_host_port(target)
+        .map(|(host, _)| host)
+        .unwrap_or(target);
+    acquire(host).await
+}
+
+pub fn is_queue_full(e: &io::Error) -> bool {
+    e.kind() == ErrorKind::ResourceBusy
+}
+
+/// Whether a client error comes from a connector refused by a full connect queue.
+pub fn is_queue_full_error(e: &(dyn Error + 'static)) -> bool {
+    let mut source = Some(e);
+    while let Some(e) = source {
+        if e.downcast_ref::<io::Error>().is_some_and(is_queue_full) {
+            return true;
+        }
+        source = e.source();
+    }
+    false
+}
+
+/// Connector that takes a connect slot for the destination host before letting the
+/// wrapped connector open the connection.
+#[derive(Debug, Clone)]
+pub struct LimitedConnector<C> {
+    inner: C,
+}
+
+impl<C> LimitedConnector<C> {
+    pub fn new(inner: C) -> Self {
+        LimitedConnector { inner }
+    }
+}
+
+impl<C> Service<Uri> for LimitedConnector<C>
+where
+    C: Service<Uri> + Clone + Send + 'static,
+    C::Future: Send,
+    C::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    type Response = C::Response;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = Pin<Box<dyn Future<Output = Result<C::Response, Self::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, dst: Uri) -> Self::Future {
+        // The clone may not be ready, the connector that was polled makes the connection
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        Box::pin(async move {
+            let host = dst.host().unwrap_or_default().to_string();
+            breaker::check(&host)?;
+            let _permit = acquire(&host).await?;
+            let result = inner.call(dst).await.map_err(Into::into);
+            breaker::record(&host, result.is_ok());
+            result
+        })
+    }
+}
@@ -0,0 +1,355 @@
+use crate::fastopen;
+use crate::options::Opt;
+use crate::sockopt;
+use crate::supervisor::{LISTENER_ENV, LISTEN_FD};
+
+use std::env;
+use std::ffi::CString;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6, TcpListener, UdpSocket};
+use std::os::fd::FromRawFd;
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use tokio::time::sleep;
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(250);
+const MAX_BACKOFF: Duration = Duration::from_secs(5);
+
+// Pending connections the kernel queues for a listener, as std uses
+const BACKLOG: i32 = 128;
+
+static INHERITED: AtomicBool = AtomicBool::new(false);
+
+/// Address family of an interface listener.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Family {
+    Ipv4,
+    Ipv6,
+}
+
+/// Where a listener accepts connections, from `--http-listen` and the like: an address of
+/// this host (`192.0.2.10`, `fe80::1%eth0` with the scope of a link-local address), or
+/// every address of an interface, of both families or only one (`eth0`, `eth0/ipv6`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ListenOn {
+    Addr(IpAddr, u32),
+    Interface(String, Option<Family>),
+}
+
+impl ListenOn {
+    /// The listener on `port`.
+    pub fn listen(&self, port: u16) -> Listen {
+        match self {
+            ListenOn::Addr(IpAddr::V6(ip), scope_id) => {
+                Listen::from(SocketAddr::from(SocketAddrV6::new(*ip, port, 0, *scope_id)))
+            }
+            ListenOn::Addr(ip, _) => Listen::from(SocketAddr::new(*ip, port)),
+            ListenOn::Interface(name, family) => Listen {
+                addr: match family {
+                    Some(Family::Ipv4) => SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), port),
+                    _ => SocketAddr::new(Ipv6Addr::UNSPECIFIED.into(), port),
+                },
+                interface: Some(name.clone()),
+                only_v6: match family {
+                    Some(Family::Ipv4) => None,
+                    family => Some(*family == Some(Family::Ipv6)),
+                },
+            },
+        }
+    }
+
+    /// Whether listeners on the same port here and on `other` would take each other's
+    /// connections, with `dual_stack` from `--listen-dual-stack`. Addresses of interfaces
+    /// aren't looked up, so an address and an interface only overlap when the address is
+    /// a wildcard.
+    pub fn overlaps(&self, other: &ListenOn, dual_stack: Option<bool>) -> bool {
+        // Unless it's made IPv6-only, an IPv6 wildcard takes IPv4 connections as well, as
+        // Linux has it by default
+        let covers = |wildcard: &IpAddr, ip: &IpAddr| {
+            wildcard.is_unspecified()
+                && (ip.is_ipv4() == wildcard.is_ipv4()
+                    || (wildcard.is_ipv6() && dual_stack != Some(false)))
+        };
+        match (self, other) {
+            (ListenOn::Addr(a, _), ListenOn::Addr(b, _)) => a == b || covers(a, b) || covers(b, a),
+            (ListenOn::Interface(a, a_family), ListenOn::Interface(b, b_family)) => {
+                a == b && (a_family.is_none() || b_family.is_none() || a_family == b_family)
+            }
+            (ListenOn::Addr(ip, _), ListenOn::Interface(..))
+            | (ListenOn::Interface(..), ListenOn::Addr(ip, _)) => ip.is_unspecified(),
+        }
+    }
+}
+
+impl FromStr for ListenOn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let unbracketed = s.trim_start_matches('[').trim_end_matches(']');
+
+        if let Some((ip, zone)) = unbracketed.split_once('%') {
+            let ip = ip
+                .parse::<Ipv6Addr>()
+                .map_err(|_| format!("Only IPv6 addresses have a scope ID, got '{s}'"))?;
+            if !ip.is_unicast_link_local() {
+                return Err(format!(
+                    "A scope ID is only meaningful for link-local addresses (fe80::/10), got '{s}'"
+                ));
+            }
+            let scope_id = match zone.parse::<u32>() {
+                Ok(index) => index,
+                Err(_) => interface_index(zone)?,
+            };
+            return Ok(ListenOn::Addr(ip.into(), scope_id));
+        }
+
+        if let Ok(ip) = unbracketed.parse::<IpAddr>() {
+            return Ok(ListenOn::Addr(ip, 0));
+        }
+
+        let (name, family) = match s.split_once('/') {
+            Some((name, "ipv4")) => (name, Some(Family::Ipv4)),
+            Some((name, "ipv6")) => (name, Some(Family::Ipv6)),
+            Some((_, family)) => {
+                return Err(format!(
+                    "Unknown address family '{family}', expected ipv4 or ipv6"
+                ))
+            }
+            None => (s, None),
+        };
+        interface_index(name)?;
+        Ok(ListenOn::Interface(name.to_string(), family))
+    }
+}
+
+impl fmt::Display for ListenOn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenOn::Addr(ip, 0) => write!(f, "{ip}"),
+            ListenOn::Addr(ip, scope_id) => write!(f, "{ip}%{scope_id}"),
+            ListenOn::Interface(name, None) => write!(f, "{name}"),
+            ListenOn::Interface(name, Some(Family::Ipv4)) => write!(f, "{name}/ipv4"),
+            ListenOn::Interface(name, Some(Family::Ipv6)) => write!(f, "{name}/ipv6"),
+        }
+    }
+}
+
+/// Index of the network interface `name`, which has to exist.
+fn interface_index(name: &str) -> Result<u32, String> {
+    let c_name = CString::new(name).map_err(|_| format!("Invalid interface name '{name}'"))?;
+    match unsafe { libc::if_nametoindex(c_name.as_ptr()) } {
+        0 => Err(format!("No network interface named '{name}'")),
+        index => Ok(index),
+    }
+}
+
+/// Socket address a listener binds, and the interface it is tied to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Listen {
+    pub addr: SocketAddr,
+    pub interface: Option<String>,
+    /// `IPV6_V6ONLY`: take IPv6 connections only, not IPv4 ones mapped to IPv6 as well.
+    /// The system's default when not set, which differs between Linux, BSD and Windows
+    pub only_v6: Option<bool>,
+}
+
+impl Listen {
+    /// Address outbound connections of this listener's clients are bound to. Interface
+    /// and link-local listeners leave the choice to the routing table, with the IPv4
+    /// wildcard, as the IPv6 one would keep plain requests from IPv4 destinations.
+    pub fn local_ip(&self) -> IpAddr {
+        let ip = self.addr.ip();
+        let link_local = matches!(ip, IpAddr::V6(ip) if ip.is_unicast_link_local());
+        match self.interface.is_some() || link_local {
+            true => Ipv4Addr::UNSPECIFIED.into(),
+            false => ip,
+        }
+    }
+
+    /// Address clients are told to connect to: the listener's, or `server_ip` for
+    /// interface and wildcard listeners.
+    pub fn public_addr(&self, server_ip: IpAddr) -> SocketAddr {
+        match self.interface.is_none() && !self.addr.ip().is_unspecified() {
+            true => self.addr,
+            false => SocketAddr::new(server_ip, self.addr.port()),
+        }
+    }
+
+    fn try_bind(&self) -> io::Result<TcpListener> {
+        let socket = Socket::new(
+            Domain::for_address(self.addr),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        // Like std, so a restart doesn't wait for the old connections' TIME_WAIT
+        socket.set_reuse_address(true)?;
+        if let (true, Some(only_v6)) = (self.addr.is_ipv6(), self.only_v6) {
+            socket.set_only_v6(only_v6)?;
+        }
+        if let Some(interface) = &self.interface {
+            sockopt::bind_device(SockRef::from(&socket), interface, self.addr.is_ipv6())?;
+        }
+        socket.bind(&self.addr.into())?;
+        fastopen::listen(SockRef::from(&socket))?;
+        socket.listen(BACKLOG)?;
+        Ok(socket.into())
+    }
+}
+
+impl From<SocketAddr> for Listen {
+    /// Listener on `addr`, an IPv6 wildcard one taking IPv4 connections as well or not as
+    /// `--listen-dual-stack` has it.
+    fn from(addr: SocketAddr) -> Listen {
+        Listen {
+            addr,
+            interface: None,
+            only_v6: Opt::global()
+                .listen_dual_stack
+                .map(|dual_stack| !dual_stack),
+        }
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.interface {
+            Some(interface) => write!(f, "{} on {interface}", self.addr),
+            None => write!(f, "{}", self.addr),
+        }
+    }
+}
+
+/// Bind a listener, retrying with backoff for `--bind-retry` seconds while the address is
+/// busy or not yet available (e.g. the previous instance is still shutting down, or the
+/// interface isn't up yet). Prints what to do about it when the bind finally fails.
+pub async fn bind(listen: impl Into<Listen>, server_name: &str) -> io::Result<TcpListener> {
+    let listen = listen.into();
+    if let Some(listener) = inherited(server_name) {
+        return Ok(listener);
+    }
+    let deadline = Instant::now() + Duration::from_secs(Opt::global().bind_retry);
+    let mut backoff = INITIAL_BACKOFF;
+
+    loop {
+        let error = match listen.try_bind() {
+            Ok(listener) => {
+                listener.set_nonblocking(true)?;
+                return Ok(listener);
+            }
+            Err(e) => e,
+        };
+
+        let retryable = matches!(
+            error.kind(),
+            ErrorKind::AddrInUse | ErrorKind::AddrNotAvailable
+        );
+        if !retryable || Instant::now() + backoff > deadline {
+            print_diagnostics(&listen, server_name, &error);
+            return Err(error);
+        }
+
+        eprintln!("{server_name}: cannot bind {listen} ({error}), retrying in {backoff:?}");
+        sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+/// Bind the UDP socket of `server_name`, or take the one a supervisor bound for it.
+pub fn bind_udp(addr: SocketAddr, server_name: &str) -> io::Result<UdpSocket> {
+    let socket = match is_inherited(server_name) {
+        // SAFETY: the supervisor put a UDP socket on this descriptor, and nothing else
+        // in the worker uses it
+        true => unsafe { UdpSocket::from_raw_fd(LISTEN_FD) },
+        false => UdpSocket::bind(addr)?,
+    };
+    socket.set_nonblocking(true)?;
+    Ok(socket)
+}
+
+/// Listening socket of `server_name` a supervisor bound and passed on to this worker,
+/// see `--isolate-listeners`.
+fn inherited(server_name: &str) -> Option<TcpListener> {
+    if !is_inherited(server_name) {
+        return None;
+    }
+    // SAFETY: the supervisor put a listening socket on this descriptor, and nothing
+    // else in the worker uses it
+    let listener = unsafe { TcpListener::from_raw_fd(LISTEN_FD) };
+    listener.set_nonblocking(true).ok()?;
+    Some(listener)
+}
+
+/// Whether this worker got the socket of `server_name`. Taken once, the worker runs a
+/// single server.
+fn is_inherited(server_name: &str) -> bool {
+    env::var(LISTENER_ENV).is_ok_and(|name| name == server_name)
+        && !INHERITED.swap(true, Ordering::Relaxed)
+}
+
+/// How long a client connection may be idle before keepalives are sent, and how often
+/// they are sent after that, from `--client-keepalive`.
+pub fn client_keepalive() -> Option<Duration> {
+    let seconds = Opt::global().client_keepalive;
+    (seconds > 0).then(|| Duration::from_secs(seconds))
+}
+
+/// Turn on `--client-keepalive` for an accepted client connection.
+pub fn set_client_keepalive(stream: &tokio::net::TcpStream) -> io::Result<()> {
+    let Some(keepalive) = client_keepalive() else {
+        return Ok(());
+    };
+    SockRef::from(stream).set_tcp_keepalive(
+        &TcpKeepalive::new()
+            .with_time(keepalive)
+            .with_interval(keepalive),
+    )
+}
+
+fn print_diagnostics(listen: &Listen, server_name: &str, error: &io::Error) {
+    let addr = listen.addr;
+    eprintln!("\n\x1B[31m\x1B[1m{server_name}: failed to bind {listen}: {error}\x1B[0m");
+
+    match error.kind() {
+        ErrorKind::AddrInUse => {
+            if let Some(holder) = port_holder(addr.port()) {
+                eprintln!("Port {} is held by:\n{holder}", addr.port());
+            }
+            eprintln!(
+                "Stop the process holding the port, choose another port, or use --bind-retry <seconds> to wait for it to be released."
+            );
+        }
+        ErrorKind::AddrNotAvailable => {
+            eprintln!(
+                "{} is not assigned to any interface of this host. Check `ip addr` or listen on another address.",
+                addr.ip()
+            );
+        }
+        ErrorKind::PermissionDenied => {
+            eprintln!(
+                "Ports below 1024 need root or the CAP_NET_BIND_SERVICE capability: sudo setcap 'cap_net_bind_service=+ep' $(which proxerver)"
+            );
+        }
+        _ => {}
+    }
+}
+
+/// Who listens on the port, as reported by `ss` (or `lsof` where `ss` is missing).
+fn port_holder(port: u16) -> Option<String> {
+    let commands = [
+        format!("ss -Hltnp 'sport = :{port}'"),
+        format!("lsof -nP -iTCP:{port} -sTCP:LISTEN"),
+    ];
+
+    commands.iter().find_map(|command| {
+        let output = Command::new("sh").arg("-c").arg(command).output().ok()?;
+        let stdout = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+        (output.status.success() && !stdout.is_empty()).then_some(stdout)
+    })
+}
@@ -0,0 +1,28 @@
+use log::{LevelFilter, Log, Metadata, Record};
+
+static LOGGER: Logger = Logger;
+
+/// Prints log records to stdout as they are, like the rest of the logs.
+struct Logger;
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        println!("{}", record.args());
+    }
+
+    fn flush(&self) {}
+}
+
+/// Log records at `level` and more severe ones.
+pub fn init(level: LevelFilter) {
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
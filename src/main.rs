@@ -1,54 +1,200 @@
+mod access;
+mod acme;
+mod admin;
+mod age;
+mod alerts;
+mod argon2;
+mod auth;
+mod bans;
+mod breaker;
+mod commands;
+mod config;
+mod credentials;
+mod digest;
+mod dns;
+mod dylib;
+mod egress;
+mod explain;
+mod fastopen;
+mod files;
+mod geoip;
+mod head;
+mod hostmatch;
+mod htpasswd;
 mod http;
 mod https;
+mod journal;
+mod json;
+mod ldap;
+mod limiter;
+mod listener;
+mod logger;
+mod maintenance;
+mod metrics;
+mod mss;
+mod mux;
+mod nameserver;
+mod negative;
+mod negotiate;
+#[cfg(feature = "wireguard")]
+mod netstack;
+mod ntlm;
+mod ocsp;
 mod options;
+mod outbound;
+mod pam;
+mod policy;
+mod pool;
+mod probe;
+mod reload;
+mod rules;
+mod secrets;
+mod selftest;
+mod sessions;
+mod signed_token;
+mod sockopt;
+mod socks;
+mod socks5;
+mod stats;
+mod supervisor;
+mod tenant;
+mod throttle;
+mod tickets;
+mod tunnel;
+mod upstream;
+mod usage;
+mod users;
 mod utils;
+mod warmup;
+#[cfg(feature = "wireguard")]
+mod wireguard;
 
-use options::Opt;
+use http::Proxy;
+use listener::{Listen, ListenOn};
+use options::{Command, Opt};
+use selftest::Identity;
+use users::UserStore;
 use utils::get_server_ip;
 
-use clap::Parser;
+use futures_util::future::join_all;
 use std::net::SocketAddr;
+use std::process::exit;
 
 #[tokio::main]
 async fn main() {
-    // Get server IP or use 0.0.0.0 if failed
-    let server_ip = get_server_ip().await;
-
     // Parse and validate CLI arguments
-    let options = Opt::parse();
+    let options = Opt::global();
+    logger::init(options.log_level);
+
+    // Management subcommands run and exit without starting the servers
+    if let Some(command) = options
+        .command
+        .as_ref()
+        .filter(|command| !matches!(command, Command::Run))
+    {
+        commands::run(command);
+        return;
+    }
     options.validate();
+    egress::init();
 
-    // Prepare allowed credentials from CLI options
-    let allowed_credentials = if let Some(allowed_credentials) = options.auth {
-        allowed_credentials
-            .split(',')
-            .map(|credentials| credentials.trim().to_string())
-            .collect::<Vec<String>>()
-    } else {
-        Vec::<String>::new()
+    // Get server IP or use 0.0.0.0 if failed
+    let server_ip = get_server_ip().await;
+
+    // Listeners without an address or interface of their own take the server's address
+    let listen_on = |on: &Option<ListenOn>, port: u16| match on {
+        Some(on) => on.listen(port),
+        None => Listen::from(SocketAddr::new(server_ip, port)),
     };
 
-    // Prepare allowed hosts from CLI options
-    let allowed_hosts = if let Some(allowed_hosts) = options.hosts {
-        allowed_hosts
-            .split(',')
-            .map(|host| host.trim().to_string())
-            .collect::<Vec<String>>()
-    } else {
-        Vec::<String>::new()
+    // With --isolate-listeners this process only supervises, each server runs in a worker
+    if options.isolate_listeners && options.worker.is_none() {
+        supervisor::run(supervisor::workers(listen_on)).await;
+    }
+    // Whether this process runs the server `name`, a worker runs only its own
+    let runs = |name: &str| {
+        options
+            .worker
+            .as_deref()
+            .is_none_or(|worker| worker == name)
     };
 
-    // Get secret token from CLI options
-    let secret_token = options.token.unwrap_or_default();
+    // Secrets from a secret manager take the place of --auth, --token, --cert and --pkey
+    if let Err(e) = secrets::init().await {
+        eprintln!("Error: failed to fetch secrets: {e}");
+        exit(1);
+    }
+
+    if let Err(e) = access::init() {
+        eprintln!("Error: failed to open the access log: {e}");
+        exit(1);
+    }
+
+    if let Err(e) = geoip::init() {
+        eprintln!("Error: failed to load the GeoIP database: {e}");
+        exit(1);
+    }
+
+    if let Err(e) = alerts::init() {
+        eprintln!("Error: failed to read the abuse report template: {e}");
+        exit(1);
+    }
+
+    if let Err(e) = maintenance::init() {
+        eprintln!("Error: failed to read the maintenance page: {e}");
+        exit(1);
+    }
+
+    // The HTTPS server's certificate from an ACME CA, obtained before it starts
+    if let Err(e) = acme::init().await {
+        eprintln!("Error: failed to get a certificate from the ACME CA: {e}");
+        exit(1);
+    }
+
+    // Prepare allowed credentials, hosts and secret token from CLI options or the secrets
+    let Proxy {
+        allowed_credentials,
+        allowed_hosts,
+        secret_token,
+        ..
+    } = Proxy::from_options();
+
+    // Load users managed through the admin API, so a broken file is reported at startup
+    if let Some(store) = UserStore::global() {
+        println!("Loaded {} users from the users file", store.list().len());
+    }
+
+    if let Err(e) = usage::init() {
+        eprintln!("Error: failed to load the usage file: {e}");
+        exit(1);
+    }
+
+    if let Err(e) = negotiate::init() {
+        eprintln!("Error: failed to set up Kerberos authentication: {e}");
+        exit(1);
+    }
+
+    if let Err(e) = auth::init() {
+        eprintln!("Error: failed to set up authentication providers: {e}");
+        exit(1);
+    }
+
+    #[cfg(feature = "wireguard")]
+    if let Err(e) = wireguard::init().await {
+        eprintln!("Error: failed to set up the WireGuard egress: {e}");
+        exit(1);
+    }
 
     // Create future for HTTP server
     let http_future = async {
-        if options.no_http_server {
+        if options.no_http_server || !runs("http") {
             return;
         }
 
         let http_port = options.http_port.unwrap_or(58080);
-        let proxy_url = format!("http://{server_ip}:{http_port}");
+        let listen = listen_on(&options.http_listen, http_port);
+        let http_addr = listen.public_addr(server_ip);
+        let proxy_url = format!("http://{http_addr}");
 
         if allowed_credentials.is_empty() {
             println!("\n\x1B[34m\x1B[1mRunning HTTP server:\x1B[0m\n{proxy_url}\nTest: curl -v -x {proxy_url} https://api.ipify.org");
@@ -56,7 +202,7 @@ async fn main() {
             println!("\n\x1B[34m\x1B[1mRunning HTTP server with credentials:\x1B[0m");
 
             for credentials in &allowed_credentials {
-                let proxy_url = format!("http://{credentials}@{server_ip}:{http_port}");
+                let proxy_url = format!("http://{credentials}@{http_addr}");
 
                 println!(
                     "Proxy Url: {proxy_url}\nTest: curl -v -x {proxy_url} https://api.ipify.org"
@@ -74,23 +220,22 @@ async fn main() {
             println!("Secret Token: {secret_token}");
         }
 
-        let bind_addr: SocketAddr = format!("{}:{}", server_ip, http_port).parse().unwrap();
+        let proxy = Proxy {
+            allowed_credentials: allowed_credentials.clone(),
+            allowed_hosts: allowed_hosts.clone(),
+            secret_token: secret_token.clone(),
+            tenant: None,
+        };
 
-        if let Err(e) = http::start_proxy(
-            bind_addr,
-            allowed_credentials.clone(),
-            allowed_hosts.clone(),
-            secret_token.clone(),
-        )
-        .await
-        {
+        if let Err(e) = http::start_proxy(listen, proxy).await {
             println!("Error starting HTTP server: {e}");
+            exit(1);
         }
     };
 
     // Create future for HTTPS server
     let https_future = async {
-        if options.no_https_server {
+        if options.no_https_server || !runs("https") {
             return;
         }
 
@@ -120,22 +265,150 @@ async fn main() {
             println!("Secret Token: {secret_token}");
         }
 
-        let bind_addr: SocketAddr = format!("{}:{}", server_ip, https_port).parse().unwrap();
+        let listen = listen_on(&options.https_listen, https_port);
 
         if let Err(e) = https::start_proxy(
-            bind_addr,
+            listen,
             allowed_credentials.clone(),
             allowed_hosts.clone(),
             secret_token.clone(),
-            options.cert.unwrap(),
-            options.pkey.unwrap(),
+            options.cert.clone(),
+            options.pkey.clone(),
         )
         .await
         {
             println!("Error starting HTTPS server: {e}");
+            exit(1);
+        }
+    };
+
+    // Create future for the SOCKS5 server
+    let socks_future = async {
+        let Some(socks_port) = options.socks_port.filter(|_| runs("socks")) else {
+            return;
+        };
+
+        let listen = listen_on(&options.socks_listen, socks_port);
+        println!(
+            "\n\x1B[34m\x1B[1mRunning SOCKS5 server:\x1B[0m\nsocks5://{}",
+            listen.public_addr(server_ip)
+        );
+
+        if let Err(e) =
+            socks::start_proxy(listen, allowed_credentials.clone(), allowed_hosts.clone()).await
+        {
+            println!("Error starting SOCKS5 server: {e}");
+            exit(1);
+        }
+    };
+
+    // Create futures for tenants with their own HTTP listeners
+    let tenant_futures = options.tenant.iter().map(|tenant| async move {
+        let Some(port) = tenant.port else {
+            if !options.no_http_server && runs("http") {
+                println!(
+                    "\n\x1B[34m\x1B[1mTenant {} uses the main HTTP server, identified by its token\x1B[0m",
+                    tenant.name
+                );
+            }
+            return;
+        };
+        if !runs(&supervisor::tenant_worker(&tenant.name)) {
+            return;
+        }
+
+        let listen = listen_on(&options.http_listen, port);
+        println!(
+            "\n\x1B[34m\x1B[1mRunning HTTP server for tenant {}:\x1B[0m\nhttp://{}",
+            tenant.name,
+            listen.public_addr(server_ip)
+        );
+
+        if let Err(e) = http::start_proxy(listen, Proxy::from_tenant(tenant)).await {
+            println!("Error starting HTTP server for tenant {}: {e}", tenant.name);
+            exit(1);
+        }
+    });
+
+    // Create future for the self-test, run once the HTTP server is bound
+    let self_test_future = async {
+        let Some(target) = options.self_test.as_ref().filter(|_| runs("http")) else {
+            return;
+        };
+
+        let listen = listen_on(&options.http_listen, options.http_port.unwrap_or(58080));
+        let identity = Identity {
+            credentials: allowed_credentials.first().map(String::as_str),
+            secret_token: &secret_token,
+        };
+        if let Err(e) = selftest::run(listen.public_addr(server_ip), target, &identity).await {
+            eprintln!("Error: self-test failed: {e}");
+            exit(1);
+        }
+        println!("\n\x1B[34m\x1B[1mSelf-test passed:\x1B[0m\nCONNECT and plain requests for {target} went through");
+    };
+
+    // Create future for the admin API
+    let admin_future = async {
+        // It works on the state of a single process, so workers never serve it
+        let Some(admin_addr) = options.admin_listen.filter(|_| options.worker.is_none()) else {
+            return;
+        };
+
+        println!("\n\x1B[34m\x1B[1mRunning admin API:\x1B[0m\nhttp://{admin_addr}");
+
+        if let Err(e) = admin::start_admin(admin_addr).await {
+            println!("Error starting admin API: {e}");
+            exit(1);
+        }
+    };
+
+    // Create future for the built-in DNS server
+    let nameserver_future = async {
+        let Some(zone) = options
+            .dns_zone
+            .as_ref()
+            .filter(|_| runs(supervisor::DNS_WORKER))
+        else {
+            return;
+        };
+
+        let answers = if options.dns_answer.is_empty() {
+            if server_ip.is_unspecified() {
+                println!(
+                    "Error starting DNS server: the server's address is unknown, use --dns-answer"
+                );
+                exit(1);
+            }
+            vec![server_ip]
+        } else {
+            options.dns_answer.clone()
+        };
+        println!(
+            "\n\x1B[34m\x1B[1mRunning DNS server for {zone}:\x1B[0m\n{} -> {answers:?}",
+            options.dns_listen
+        );
+
+        if let Err(e) = nameserver::start_nameserver(options.dns_listen, zone, answers).await {
+            println!("Error starting DNS server: {e}");
+            exit(1);
         }
     };
 
     // Join futures and wait for them to complete
-    tokio::join!(http_future, https_future);
+    tokio::join!(
+        http_future,
+        https_future,
+        socks_future,
+        join_all(tenant_futures),
+        admin_future,
+        nameserver_future,
+        self_test_future,
+        secrets::refresh_periodically(),
+        acme::renew_periodically(),
+        ocsp::staple_periodically(),
+        usage::save_periodically(),
+        pool::check_health(),
+        reload::reload_on_hangup()
+    );
 }
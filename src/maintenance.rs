@@ -0,0 +1,117 @@
+use crate::json::{object, Value};
+use crate::options::Opt;
+
+use std::fs;
+use std::io;
+use std::sync::{Mutex, OnceLock};
+
+use chrono::{DateTime, Utc};
+use hyper::header::{CONTENT_TYPE, RETRY_AFTER};
+use hyper::{Body, Response, StatusCode};
+
+const DEFAULT_PAGE: &str = "<!DOCTYPE html>
+<html>
+<head><title>Down for maintenance</title></head>
+<body>
+<h1>Down for maintenance</h1>
+<p>The proxy is down for planned maintenance. Please try again later.</p>
+</body>
+</html>
+";
+
+static PAGE: OnceLock<String> = OnceLock::new();
+static STATE: OnceLock<Mutex<Option<Window>>> = OnceLock::new();
+
+/// A maintenance window started through the admin API.
+#[derive(Debug, Clone, Copy)]
+pub struct Window {
+    pub since: DateTime<Utc>,
+    /// Seconds clients are told to wait in Retry-After
+    pub retry_after: u64,
+}
+
+/// Read the `--maintenance-page`, so a missing file is reported at startup.
+pub fn init() -> io::Result<()> {
+    let page = match &Opt::global().maintenance_page {
+        Some(path) => fs::read_to_string(path)?,
+        None => DEFAULT_PAGE.to_string(),
+    };
+    let _ = PAGE.set(page);
+    Ok(())
+}
+
+/// Answer new requests with the maintenance page until [`end`], telling clients to retry
+/// after `retry_after` seconds, `--maintenance-retry-after` if not given. Tunnels already
+/// open are kept. Starting it again only changes the Retry-After.
+pub fn start(retry_after: Option<u64>) -> Window {
+    let retry_after = retry_after.unwrap_or(Opt::global().maintenance_retry_after);
+    let mut state = state().lock().unwrap();
+    let window = Window {
+        since: state.map_or_else(Utc::now, |window| window.since),
+        retry_after,
+    };
+    *state = Some(window);
+    window
+}
+
+/// End the maintenance window, returning it if there was one.
+pub fn end() -> Option<Window> {
+    state().lock().unwrap().take()
+}
+
+pub fn current() -> Option<Window> {
+    *state().lock().unwrap()
+}
+
+fn state() -> &'static Mutex<Option<Window>> {
+    STATE.get_or_init(Default::default)
+}
+
+pub fn to_json() -> Value {
+    let window = current();
+    object([
+        ("enabled", window.is_some().into()),
+        (
+            "since",
+            window.map(|window| window.since.to_rfc3339()).into(),
+        ),
+        (
+            "retry_after",
+            window.map(|window| window.retry_after).into(),
+        ),
+    ])
+}
+
+/// The maintenance answer as written by listeners that don't use hyper.
+pub fn raw_response() -> Vec<u8> {
+    let retry_after = retry_after();
+    let status = StatusCode::SERVICE_UNAVAILABLE;
+    let head = format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: text/html; charset=utf-8\r\nContent-Length: {}\r\nRetry-After: {retry_after}\r\n\r\n",
+        status.as_u16(),
+        status.canonical_reason().unwrap_or("Unknown"),
+        page().len()
+    );
+    [head.as_bytes(), page().as_bytes()].concat()
+}
+
+/// The maintenance answer, 503 with the page and Retry-After.
+pub fn response() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::SERVICE_UNAVAILABLE)
+        .header(CONTENT_TYPE, "text/html; charset=utf-8")
+        .header(RETRY_AFTER, retry_after().to_string())
+        .body(Body::from(page().to_string()))
+        .unwrap()
+}
+
+/// Retry-After of the current window, of the option if it just ended.
+fn retry_after() -> u64 {
+    current().map_or(Opt::global().maintenance_retry_after, |window| {
+        window.retry_after
+    })
+}
+
+fn page() -> &'static str {
+    PAGE.get().map_or(DEFAULT_PAGE, String::as_str)
+}
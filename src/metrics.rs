@@ -0,0 +1,174 @@
+use crate::stats;
+use crate::tunnel;
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+/// Upper bounds in seconds of the tunnel duration buckets, `+Inf` aside.
+const DURATION_BUCKETS: [f64; 8] = [1.0, 5.0, 15.0, 60.0, 300.0, 900.0, 3600.0, 14400.0];
+
+/// How long tunnels stayed open, observed as they close.
+pub static TUNNEL_DURATIONS: Histogram = Histogram::new();
+
+/// Prometheus histogram over [`DURATION_BUCKETS`].
+pub struct Histogram {
+    /// Observations up to each bound, so the counts are cumulative already
+    buckets: [AtomicU64; DURATION_BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Histogram {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; DURATION_BUCKETS.len()],
+            count: AtomicU64::new(0),
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let seconds = duration.as_secs_f64();
+        for (bound, bucket) in DURATION_BUCKETS.iter().zip(&self.buckets) {
+            if seconds <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn write(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, help, "histogram");
+        for (bound, bucket) in DURATION_BUCKETS.iter().zip(&self.buckets) {
+            let count = bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{le=\"{bound}\"}} {count}");
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{name}_bucket{{le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum {sum}");
+        let _ = writeln!(out, "{name}_count {count}");
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    let _ = writeln!(out, "# HELP {name} {help}");
+    let _ = writeln!(out, "# TYPE {name} {kind}");
+}
+
+/// Metric with one sample per label value.
+fn labeled<'a>(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    kind: &str,
+    label: &str,
+    samples: impl IntoIterator<Item = (&'a str, u64)>,
+) {
+    header(out, name, help, kind);
+    for (value, sample) in samples {
+        let _ = writeln!(out, "{name}{{{label}=\"{value}\"}} {sample}");
+    }
+}
+
+fn single(out: &mut String, name: &str, help: &str, kind: &str, sample: u64) {
+    header(out, name, help, kind);
+    let _ = writeln!(out, "{name} {sample}");
+}
+
+/// Counters and histograms in the Prometheus text exposition format, for `/metrics`.
+pub fn to_prometheus() -> String {
+    let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+    let mut out = String::new();
+
+    labeled(
+        &mut out,
+        "proxerver_requests_total",
+        "Requests received, by listener.",
+        "counter",
+        "listener",
+        [
+            ("http", load(&stats::HTTP_REQUESTS)),
+            ("https", load(&stats::HTTPS_REQUESTS)),
+            ("socks", load(&stats::SOCKS_REQUESTS)),
+        ],
+    );
+    single(
+        &mut out,
+        "proxerver_active_connections",
+        "Client connections open right now.",
+        "gauge",
+        load(&stats::OPEN_CONNECTIONS),
+    );
+    single(
+        &mut out,
+        "proxerver_active_tunnels",
+        "CONNECT and SOCKS tunnels open right now.",
+        "gauge",
+        tunnel::active_count() as u64,
+    );
+    labeled(
+        &mut out,
+        "proxerver_tunnel_bytes_total",
+        "Bytes relayed by tunnels, up from clients and down to them.",
+        "counter",
+        "direction",
+        [
+            ("up", load(&stats::TUNNEL_BYTES_UP)),
+            ("down", load(&stats::TUNNEL_BYTES_DOWN)),
+        ],
+    );
+    let denied = stats::denied();
+    labeled(
+        &mut out,
+        "proxerver_denied_total",
+        "Requests denied, by the check that denied them (auth for failed authentication, hosts for blocked hosts).",
+        "counter",
+        "check",
+        denied.iter().map(|(check, count)| (check.as_str(), *count)),
+    );
+    TUNNEL_DURATIONS.write(
+        &mut out,
+        "proxerver_tunnel_duration_seconds",
+        "How long tunnels stayed open.",
+    );
+    single(
+        &mut out,
+        "proxerver_port_exhausted_total",
+        "Upstream connections that failed because no local port could be bound.",
+        "counter",
+        load(&stats::PORT_EXHAUSTED),
+    );
+    single(
+        &mut out,
+        "proxerver_connect_queue_full_total",
+        "Upstream connections refused because too many were queued for the host.",
+        "counter",
+        load(&stats::CONNECT_QUEUE_FULL),
+    );
+    single(
+        &mut out,
+        "proxerver_connections_limited_total",
+        "Client connections refused by --max-connections or --max-connections-per-ip.",
+        "counter",
+        load(&stats::CONNECTIONS_LIMITED),
+    );
+    single(
+        &mut out,
+        "proxerver_requests_rate_limited_total",
+        "Requests refused by --rate-limit.",
+        "counter",
+        load(&stats::REQUESTS_RATE_LIMITED),
+    );
+    single(
+        &mut out,
+        "proxerver_negative_cache_hits_total",
+        "Lookups and connects failed straight away because the same one failed a moment ago.",
+        "counter",
+        load(&stats::NEGATIVE_CACHE_HITS),
+    );
+    out
+}
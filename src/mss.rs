@@ -0,0 +1,117 @@
+use crate::options::Opt;
+use crate::outbound::{set_fwmark, Fwmark};
+use crate::sockopt::{self, Capability};
+use crate::utils::interface_addrs;
+
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::str::FromStr;
+
+use socket2::SockRef;
+
+// Range Linux accepts for TCP_MAXSEG
+const MIN_MSS: u32 = 88;
+const MAX_MSS: u32 = 32767;
+
+/// MSS outbound connections leaving through `interface` are clamped to, or those through
+/// any interface without a rule of its own when `interface` is `None`. Works around path
+/// MTU blackholes, where the ICMP messages that would shrink the segments never arrive.
+#[derive(Debug, Clone)]
+pub struct MssRule {
+    pub interface: Option<String>,
+    pub mss: u32,
+}
+
+impl FromStr for MssRule {
+    type Err = String;
+
+    /// Parse `1360`, or `wg0=1280` for a single interface.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (interface, mss) = match s.trim().split_once('=') {
+            Some((interface, mss)) => (Some(interface.trim()), mss.trim()),
+            None => (None, s.trim()),
+        };
+        if interface.is_some_and(str::is_empty) {
+            return Err(format!("Missing interface in '{s}'"));
+        }
+
+        let mss = mss
+            .parse::<u32>()
+            .ok()
+            .filter(|mss| (MIN_MSS..=MAX_MSS).contains(mss))
+            .ok_or_else(|| format!("Invalid MSS '{mss}', expected {MIN_MSS} to {MAX_MSS}"))?;
+        Ok(MssRule {
+            interface: interface.map(str::to_string),
+            mss,
+        })
+    }
+}
+
+/// Whether `--outbound-mss` is given and the system can clamp the MSS, it's turned off
+/// with a warning at startup otherwise.
+pub fn enabled() -> bool {
+    !Opt::global().outbound_mss.is_empty() && sockopt::supported(Capability::Mss)
+}
+
+/// Clamp the MSS of a socket about to connect to `addr` from `source` (`None` when the
+/// system picks it), as the `--outbound-mss` rule for the interface it leaves through
+/// says. Must be set before connecting, so the SYN announces it to the other end.
+pub fn clamp(
+    socket: SockRef<'_>,
+    addr: SocketAddr,
+    source: Option<IpAddr>,
+    mark: Option<Fwmark>,
+) -> io::Result<()> {
+    if !enabled() {
+        return Ok(());
+    }
+    let rules = &Opt::global().outbound_mss;
+
+    // Looking the interface up costs a syscall or two, only worth it with rules for one
+    let interface = match rules.iter().any(|rule| rule.interface.is_some()) {
+        true => egress_interface(addr, source, mark),
+        false => None,
+    };
+    let rule = rules
+        .iter()
+        .find(|rule| rule.interface.is_some() && rule.interface == interface)
+        .or_else(|| rules.iter().find(|rule| rule.interface.is_none()));
+
+    match rule {
+        Some(rule) => sockopt::set_mss(socket, rule.mss),
+        None => Ok(()),
+    }
+}
+
+/// Interface holding the address connections to `addr` leave from. Without a `source`,
+/// the routing table picks one for a connected UDP socket, which sends nothing. `None`
+/// when the address isn't on any interface, like `--egress-addr` ranges that are only
+/// routed to the host.
+fn egress_interface(
+    addr: SocketAddr,
+    source: Option<IpAddr>,
+    mark: Option<Fwmark>,
+) -> Option<String> {
+    let source = match source.filter(|ip| !ip.is_unspecified()) {
+        Some(ip) => ip,
+        None => {
+            let unspecified: IpAddr = match addr {
+                SocketAddr::V4(_) => Ipv4Addr::UNSPECIFIED.into(),
+                SocketAddr::V6(_) => Ipv6Addr::UNSPECIFIED.into(),
+            };
+            let socket = UdpSocket::bind(SocketAddr::new(unspecified, 0)).ok()?;
+            set_fwmark(SockRef::from(&socket), mark).ok()?;
+            socket.connect(addr).ok()?;
+            socket.local_addr().ok()?.ip()
+        }
+    };
+    interface_of(source)
+}
+
+/// Name of the interface `ip` is configured on.
+fn interface_of(ip: IpAddr) -> Option<String> {
+    interface_addrs()
+        .into_iter()
+        .find(|(_, addr)| *addr == ip)
+        .map(|(name, _)| name)
+}
@@ -0,0 +1,433 @@
+use crate::options::Opt;
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use log::{debug, warn};
+use rustls::ServerConnection;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::{AcquireError, OwnedSemaphorePermit, Semaphore};
+
+/// ALPN protocol a client picks to multiplex its requests over one TLS connection.
+pub const ALPN: &[u8] = b"proxerver-yamux/1";
+
+// yamux (github.com/hashicorp/yamux/blob/master/spec.md): a 12-byte header of version,
+// type, flags, stream ID and length, all big-endian
+const HEADER_LEN: usize = 12;
+const VERSION: u8 = 0;
+
+const TYPE_DATA: u8 = 0;
+const TYPE_WINDOW_UPDATE: u8 = 1;
+const TYPE_PING: u8 = 2;
+const TYPE_GO_AWAY: u8 = 3;
+
+const FLAG_SYN: u16 = 1;
+const FLAG_ACK: u16 = 2;
+const FLAG_FIN: u16 = 4;
+const FLAG_RST: u16 = 8;
+
+const GO_AWAY_PROTOCOL_ERROR: u32 = 1;
+
+// Bytes either side may send on a stream before the other acknowledges reading them
+const INITIAL_WINDOW: u32 = 256 * 1024;
+
+// Most a client may let a stream send ahead, however large the window updates it sends
+const MAX_SEND_WINDOW: u32 = 16 * 1024 * 1024;
+
+// Largest data frame sent, so streams take turns on the connection
+const MAX_FRAME: usize = 16 * 1024;
+
+// Bytes of frames queued for a client that doesn't read them, beyond which streams wait
+// to write and the client's frames aren't read
+const MAX_QUEUED: u32 = 1024 * 1024;
+
+// Streams a client may have open at once, each being a request or tunnel
+const MAX_STREAMS: usize = 256;
+
+pub fn enabled() -> bool {
+    Opt::global().multiplex
+}
+
+/// ALPN protocols the HTTPS server offers, empty unless `--multiplex` is set. HTTP/1.1
+/// is offered too, or clients asking for it only would fail the handshake.
+pub fn alpn_protocols() -> Vec<Vec<u8>> {
+    match enabled() {
+        true => vec![ALPN.to_vec(), b"http/1.1".to_vec()],
+        false => Vec::new(),
+    }
+}
+
+/// Whether the client of `connection` chose to multiplex.
+pub fn negotiated(connection: &ServerConnection) -> bool {
+    enabled() && connection.alpn_protocol() == Some(ALPN)
+}
+
+#[derive(Default)]
+struct StreamState {
+    received: Vec<u8>,
+    // Bytes the client may still send, and those read since the last window update
+    receive_window: u32,
+    consumed: u32,
+    send_window: u32,
+    remote_closed: bool,
+    local_closed: bool,
+    reset: bool,
+    read_waker: Option<Waker>,
+    write_waker: Option<Waker>,
+}
+
+impl StreamState {
+    fn wake(&mut self) {
+        if let Some(waker) = self.read_waker.take() {
+            waker.wake();
+        }
+        if let Some(waker) = self.write_waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+type Streams = Arc<Mutex<HashMap<u32, Arc<Mutex<StreamState>>>>>;
+
+type Acquiring = Pin<Box<dyn Future<Output = Result<OwnedSemaphorePermit, AcquireError>> + Send>>;
+
+/// A frame on its way to the client, holding its share of the connection's budget until
+/// written.
+struct Queued {
+    frame: Vec<u8>,
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+/// Frames queued for the client of a connection, in at most `MAX_QUEUED` bytes.
+#[derive(Clone)]
+struct Outbox {
+    frames: UnboundedSender<Queued>,
+    budget: Arc<Semaphore>,
+}
+
+impl Outbox {
+    /// Queue `frame` once there's room for it.
+    async fn send(&self, frame: Vec<u8>) -> io::Result<()> {
+        let permit = self
+            .budget
+            .clone()
+            .acquire_many_owned(frame.len() as u32)
+            .await
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        self.queue(frame, Some(permit))
+    }
+
+    /// Queue `frame` right away. Only for the few frames a stream ends with or sends per
+    /// window read, which the budget of the frames opening the stream bounds.
+    fn send_now(&self, frame: Vec<u8>) {
+        let _ = self.queue(frame, None);
+    }
+
+    fn queue(&self, frame: Vec<u8>, permit: Option<OwnedSemaphorePermit>) -> io::Result<()> {
+        let queued = Queued {
+            frame,
+            _permit: permit,
+        };
+        self.frames
+            .send(queued)
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+fn frame(kind: u8, flags: u16, id: u32, length: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.push(VERSION);
+    frame.push(kind);
+    frame.extend_from_slice(&flags.to_be_bytes());
+    frame.extend_from_slice(&id.to_be_bytes());
+    frame.extend_from_slice(&length.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// A stream of a multiplexed connection, which a request comes in on like on a connection
+/// of its own.
+pub struct MuxStream {
+    id: u32,
+    state: Arc<Mutex<StreamState>>,
+    streams: Streams,
+    outbox: Outbox,
+    // Room in the outbox for the next data frame, while waiting for it
+    acquiring: Option<Acquiring>,
+}
+
+impl AsyncRead for MuxStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let mut state = self.state.lock().unwrap();
+        if !state.received.is_empty() {
+            let n = buf.remaining().min(state.received.len());
+            buf.put_slice(&state.received[..n]);
+            state.received.drain(..n);
+
+            // Let the client send more once half the window has been read
+            state.consumed += n as u32;
+            if state.consumed >= INITIAL_WINDOW / 2 {
+                let delta = std::mem::take(&mut state.consumed);
+                state.receive_window += delta;
+                self.outbox
+                    .send_now(frame(TYPE_WINDOW_UPDATE, 0, self.id, delta, &[]));
+            }
+            return Poll::Ready(Ok(()));
+        }
+        if state.reset {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        if state.remote_closed {
+            return Poll::Ready(Ok(()));
+        }
+        state.read_waker = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl AsyncWrite for MuxStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        {
+            let mut state = this.state.lock().unwrap();
+            if state.reset || state.local_closed {
+                return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+            if state.send_window == 0 {
+                state.write_waker = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+        }
+
+        // Room for a full frame, what this one doesn't take goes back
+        let budget = &this.outbox.budget;
+        let acquiring = this.acquiring.get_or_insert_with(|| {
+            Box::pin(
+                budget
+                    .clone()
+                    .acquire_many_owned((HEADER_LEN + MAX_FRAME) as u32),
+            )
+        });
+        let mut permit = match acquiring.as_mut().poll(cx) {
+            Poll::Ready(Ok(permit)) => permit,
+            Poll::Ready(Err(_)) => return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into())),
+            Poll::Pending => return Poll::Pending,
+        };
+        this.acquiring = None;
+
+        // Only this stream takes from its send window, which is still open
+        let mut state = this.state.lock().unwrap();
+        let n = buf.len().min(state.send_window as usize).min(MAX_FRAME);
+        state.send_window -= n as u32;
+        let permit = permit.split(HEADER_LEN + n);
+        this.outbox
+            .queue(frame(TYPE_DATA, 0, this.id, n as u32, &buf[..n]), permit)?;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut state = self.state.lock().unwrap();
+        if !state.local_closed && !state.reset {
+            state.local_closed = true;
+            self.outbox
+                .send_now(frame(TYPE_DATA, FLAG_FIN, self.id, 0, &[]));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        self.streams.lock().unwrap().remove(&self.id);
+        let state = self.state.lock().unwrap();
+        // A stream left before both sides finished is abandoned, not ended
+        let finished = state.local_closed && state.remote_closed;
+        if !state.reset && !finished {
+            self.outbox
+                .send_now(frame(TYPE_WINDOW_UPDATE, FLAG_RST, self.id, 0, &[]));
+        }
+    }
+}
+
+/// Serve a multiplexed connection: run `handler` on each stream the client opens, until
+/// it closes the connection or breaks the protocol.
+pub async fn serve<S, F, Fut>(stream: S, addr: SocketAddr, handler: F)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    F: Fn(MuxStream) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let (mut reader, mut writer) = tokio::io::split(stream);
+    let (frames, mut outgoing) = unbounded_channel::<Queued>();
+    let outbox = Outbox {
+        frames,
+        budget: Arc::new(Semaphore::new(MAX_QUEUED as usize)),
+    };
+    let writing = tokio::spawn(async move {
+        while let Some(queued) = outgoing.recv().await {
+            if writer.write_all(&queued.frame).await.is_err() {
+                break;
+            }
+        }
+        let _ = writer.shutdown().await;
+    });
+
+    let streams: Streams = Default::default();
+    let result = read_frames(&mut reader, &streams, &outbox, &handler).await;
+    if let Err(e) = result {
+        match e.kind() {
+            io::ErrorKind::InvalidData => {
+                warn!("Closing the multiplexed connection of {addr}: {e}");
+                outbox.send_now(frame(TYPE_GO_AWAY, 0, 0, GO_AWAY_PROTOCOL_ERROR, &[]));
+            }
+            _ => debug!("Multiplexed connection of {addr} ended: {e}"),
+        }
+    }
+
+    // Streams still open won't hear from the client anymore
+    for state in streams.lock().unwrap().values() {
+        let mut state = state.lock().unwrap();
+        state.reset = true;
+        state.wake();
+    }
+    drop(outbox);
+    let _ = writing.await;
+}
+
+async fn read_frames<R, F, Fut>(
+    reader: &mut R,
+    streams: &Streams,
+    outbox: &Outbox,
+    handler: &F,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    F: Fn(MuxStream) -> Fut,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    let mut header = [0; HEADER_LEN];
+
+    loop {
+        match reader.read_exact(&mut header).await {
+            Ok(_) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        if header[0] != VERSION {
+            return Err(invalid("unknown yamux version"));
+        }
+        let kind = header[1];
+        let flags = u16::from_be_bytes([header[2], header[3]]);
+        let id = u32::from_be_bytes(header[4..8].try_into().unwrap());
+        let length = u32::from_be_bytes(header[8..12].try_into().unwrap());
+
+        match kind {
+            TYPE_PING => {
+                // Waits for a client that pings without reading the answers
+                if flags & FLAG_SYN != 0 {
+                    outbox
+                        .send(frame(TYPE_PING, FLAG_ACK, 0, length, &[]))
+                        .await?;
+                }
+                continue;
+            }
+            TYPE_GO_AWAY => return Ok(()),
+            TYPE_DATA | TYPE_WINDOW_UPDATE => {}
+            _ => return Err(invalid("unknown frame type")),
+        }
+
+        if flags & FLAG_SYN != 0 {
+            // Clients open odd streams, as yamux has it
+            if id % 2 == 0 || streams.lock().unwrap().contains_key(&id) {
+                return Err(invalid("invalid stream ID"));
+            }
+            if streams.lock().unwrap().len() >= MAX_STREAMS {
+                outbox
+                    .send(frame(TYPE_WINDOW_UPDATE, FLAG_RST, id, 0, &[]))
+                    .await?;
+                if kind == TYPE_DATA {
+                    skip(reader, length).await?;
+                }
+                continue;
+            }
+            let state = Arc::new(Mutex::new(StreamState {
+                receive_window: INITIAL_WINDOW,
+                send_window: INITIAL_WINDOW,
+                ..Default::default()
+            }));
+            streams.lock().unwrap().insert(id, state.clone());
+            outbox
+                .send(frame(TYPE_WINDOW_UPDATE, FLAG_ACK, id, 0, &[]))
+                .await?;
+            tokio::spawn(handler(MuxStream {
+                id,
+                state,
+                streams: streams.clone(),
+                outbox: outbox.clone(),
+                acquiring: None,
+            }));
+        }
+
+        // Frames of streams already gone are dropped, the client hadn't heard yet
+        let state = streams.lock().unwrap().get(&id).cloned();
+        let Some(state) = state else {
+            if kind == TYPE_DATA {
+                skip(reader, length).await?;
+            }
+            continue;
+        };
+
+        if kind == TYPE_DATA {
+            if length > state.lock().unwrap().receive_window {
+                return Err(invalid("data beyond the stream's window"));
+            }
+            let mut data = vec![0; length as usize];
+            reader.read_exact(&mut data).await?;
+            let mut state = state.lock().unwrap();
+            state.receive_window -= length;
+            state.received.extend_from_slice(&data);
+        } else {
+            let mut state = state.lock().unwrap();
+            state.send_window = state
+                .send_window
+                .saturating_add(length)
+                .min(MAX_SEND_WINDOW);
+        }
+
+        let mut state = state.lock().unwrap();
+        if flags & FLAG_FIN != 0 {
+            state.remote_closed = true;
+        }
+        if flags & FLAG_RST != 0 {
+            state.reset = true;
+        }
+        state.wake();
+    }
+}
+
+async fn skip<R: AsyncRead + Unpin>(reader: &mut R, length: u32) -> io::Result<()> {
+    let copied = tokio::io::copy(&mut reader.take(length as u64), &mut tokio::io::sink()).await?;
+    match copied == length as u64 {
+        true => Ok(()),
+        false => Err(io::ErrorKind::UnexpectedEof.into()),
+    }
+}
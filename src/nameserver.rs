@@ -0,0 +1,147 @@
+use crate::dns::{QCLASS_IN, QTYPE_A, QTYPE_AAAA};
+use crate::listener;
+use crate::utils::formatted_time;
+
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+
+use log::{debug, warn};
+use tokio::net::UdpSocket;
+
+/// Name the DNS server's socket is bound under, see [`listener::bind_udp`].
+pub const SERVER_NAME: &str = "DNS server";
+
+const QTYPE_ANY: u16 = 255;
+const RCODE_FORMERR: u8 = 1;
+const RCODE_NOTIMP: u8 = 4;
+const RCODE_REFUSED: u8 = 5;
+
+// Answers only change with a restart, but clients shouldn't hold on to them for days
+const ANSWER_TTL: u32 = 300;
+
+/// Built-in DNS server for `--dns-zone`: the zone and every name inside it resolve to
+/// `answers`, names outside it are refused. Only plain UDP queries are understood, which
+/// is all resolvers need for A and AAAA records.
+pub async fn start_nameserver(
+    listen_addr: SocketAddr,
+    zone: &str,
+    answers: Vec<IpAddr>,
+) -> io::Result<()> {
+    let zone = zone.trim_end_matches('.').to_ascii_lowercase();
+    let socket = UdpSocket::from_std(listener::bind_udp(listen_addr, SERVER_NAME)?)?;
+
+    let mut buffer = [0u8; 512];
+    loop {
+        let (n, client) = match socket.recv_from(&mut buffer).await {
+            Ok(received) => received,
+            Err(e) => {
+                warn!("DNS server failed to receive: {e}");
+                continue;
+            }
+        };
+        let Some(response) = respond(&buffer[..n], &zone, &answers, client) else {
+            continue;
+        };
+        if let Err(e) = socket.send_to(&response, client).await {
+            warn!("DNS server failed to answer {client}: {e}");
+        }
+    }
+}
+
+/// Response to the query in `packet`, `None` for packets not worth answering.
+fn respond(packet: &[u8], zone: &str, answers: &[IpAddr], client: SocketAddr) -> Option<Vec<u8>> {
+    let header = packet.get(..12)?;
+    if header[2] & 0x80 != 0 {
+        // A response, maybe reflected at us
+        return None;
+    }
+
+    let opcode = (header[2] >> 3) & 0x0f;
+    let qdcount = u16::from_be_bytes([header[4], header[5]]);
+    if opcode != 0 {
+        return Some(error_response(header, RCODE_NOTIMP));
+    }
+    if qdcount != 1 {
+        return Some(error_response(header, RCODE_FORMERR));
+    }
+
+    let Some((name, end)) = read_name(packet, 12) else {
+        return Some(error_response(header, RCODE_FORMERR));
+    };
+    let Some(question) = packet.get(12..end + 4) else {
+        return Some(error_response(header, RCODE_FORMERR));
+    };
+    let qtype = u16::from_be_bytes([packet[end], packet[end + 1]]);
+    let qclass = u16::from_be_bytes([packet[end + 2], packet[end + 3]]);
+
+    let in_zone = name == zone || name.ends_with(&format!(".{zone}"));
+    let records = answers
+        .iter()
+        .filter(|ip| match qtype {
+            QTYPE_A => ip.is_ipv4(),
+            QTYPE_AAAA => ip.is_ipv6(),
+            QTYPE_ANY => true,
+            _ => false,
+        })
+        .filter(|_| in_zone && qclass == QCLASS_IN)
+        .collect::<Vec<&IpAddr>>();
+
+    let time = formatted_time();
+    let ips = records
+        .iter()
+        .map(|ip| ip.to_string())
+        .collect::<Vec<String>>()
+        .join(", ");
+    debug!("[{time}] DNS query {name} type={qtype} -> [{ips}] client={client}");
+
+    let rcode = if in_zone { 0 } else { RCODE_REFUSED };
+    let mut response = response_header(header, rcode, 1, records.len() as u16);
+    response.extend_from_slice(question);
+    for ip in records {
+        // The answer's name points back at the question's
+        response.extend_from_slice(&0xc00cu16.to_be_bytes());
+        let (rtype, rdata) = match ip {
+            IpAddr::V4(ip) => (QTYPE_A, ip.octets().to_vec()),
+            IpAddr::V6(ip) => (QTYPE_AAAA, ip.octets().to_vec()),
+        };
+        response.extend_from_slice(&rtype.to_be_bytes());
+        response.extend_from_slice(&QCLASS_IN.to_be_bytes());
+        response.extend_from_slice(&ANSWER_TTL.to_be_bytes());
+        response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        response.extend_from_slice(&rdata);
+    }
+    Some(response)
+}
+
+/// Lowercased name starting at `pos`, and the position after it. Queries don't compress names.
+fn read_name(packet: &[u8], mut pos: usize) -> Option<(String, usize)> {
+    let mut labels = Vec::new();
+    loop {
+        let len = *packet.get(pos)? as usize;
+        pos += 1;
+        if len == 0 {
+            return Some((labels.join(".").to_ascii_lowercase(), pos));
+        }
+        if len > 63 {
+            return None;
+        }
+        labels.push(String::from_utf8_lossy(packet.get(pos..pos + len)?).into_owned());
+        pos += len;
+    }
+}
+
+fn response_header(query: &[u8], rcode: u8, qdcount: u16, ancount: u16) -> Vec<u8> {
+    let mut header = Vec::with_capacity(512);
+    header.extend_from_slice(&query[..2]);
+    // Response, same opcode, authoritative, recursion desired copied from the query
+    header.push(0x80 | (query[2] & 0x78) | 0x04 | (query[2] & 0x01));
+    header.push(rcode);
+    header.extend_from_slice(&qdcount.to_be_bytes());
+    header.extend_from_slice(&ancount.to_be_bytes());
+    header.extend_from_slice(&[0; 4]); // NSCOUNT, ARCOUNT
+    header
+}
+
+fn error_response(query: &[u8], rcode: u8) -> Vec<u8> {
+    response_header(query, rcode, 0, 0)
+}
@@ -0,0 +1,82 @@
+use crate::options::Opt;
+use crate::stats;
+use crate::utils::{formatted_time, loggable};
+
+use std::collections::HashMap;
+use std::io::{self, ErrorKind};
+use std::sync::atomic::Ordering;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use log::info;
+use rand::Rng;
+
+static FAILURES: OnceLock<Mutex<HashMap<String, Failure>>> = OnceLock::new();
+
+/// A lookup or connect that failed, answered from memory until `expires`.
+struct Failure {
+    kind: ErrorKind,
+    message: String,
+    expires: Instant,
+}
+
+fn failures() -> &'static Mutex<HashMap<String, Failure>> {
+    FAILURES.get_or_init(Default::default)
+}
+
+/// Key for failed lookups of `host`.
+pub fn dns_key(host: &str) -> String {
+    format!("dns:{}", host.to_ascii_lowercase())
+}
+
+/// Key for failed connects to a `host:port` target.
+pub fn connect_key(target: &str) -> String {
+    format!("connect:{}", target.to_ascii_lowercase())
+}
+
+/// The error `key` failed with a moment ago, while `--negative-cache-ttl` hasn't passed.
+/// `target` and `client` are only for the log line.
+pub fn check(key: &str, target: &str, client: &str) -> io::Result<()> {
+    if Opt::global().negative_cache_ttl == 0 {
+        return Ok(());
+    }
+
+    let failures = failures().lock().unwrap();
+    let Some(failure) = failures.get(key) else {
+        return Ok(());
+    };
+    if failure.expires <= Instant::now() {
+        return Ok(());
+    }
+
+    let total = stats::NEGATIVE_CACHE_HITS.fetch_add(1, Ordering::Relaxed) + 1;
+    info!(
+        "[{}] Failing {} from the negative cache: {} (total: {total}) client={client}",
+        formatted_time(),
+        loggable(target, client),
+        failure.message
+    );
+    Err(io::Error::new(failure.kind, failure.message.clone()))
+}
+
+/// Remember that `key` failed with `e`. The TTL is jittered by up to a quarter either way,
+/// so clients that failed together don't all retry in the same instant.
+pub fn record(key: &str, e: &io::Error) {
+    let ttl = Opt::global().negative_cache_ttl;
+    if ttl == 0 {
+        return;
+    }
+
+    let ttl = Duration::from_secs(ttl).mul_f64(rand::thread_rng().gen_range(0.75..1.25));
+    let now = Instant::now();
+    let mut failures = failures().lock().unwrap();
+    failures.retain(|_, failure| failure.expires > now);
+    failures.insert(
+        key.to_string(),
+        Failure {
+            kind: e.kind(),
+            message: e.to_string(),
+            expires: now + ttl,
+        },
+    );
+}
@@ -0,0 +1,247 @@
+use crate::dylib::Library;
+use crate::options::Opt;
+
+use std::ffi::CString;
+use std::os::raw::{c_char, c_void};
+use std::ptr;
+use std::sync::OnceLock;
+
+use base64::{engine::general_purpose::STANDARD as b64, Engine};
+
+// GSSAPI status codes and constants (RFC 2744)
+const GSS_S_COMPLETE: u32 = 0;
+const GSS_S_CONTINUE_NEEDED: u32 = 1;
+const GSS_C_GSS_CODE: i32 = 1;
+const GSS_C_MECH_CODE: i32 = 2;
+
+static GSSAPI: OnceLock<Result<Gssapi, String>> = OnceLock::new();
+
+#[repr(C)]
+struct GssBuffer {
+    length: usize,
+    value: *mut c_void,
+}
+
+impl GssBuffer {
+    fn empty() -> Self {
+        GssBuffer {
+            length: 0,
+            value: ptr::null_mut(),
+        }
+    }
+
+    unsafe fn to_vec(&self) -> Vec<u8> {
+        if self.value.is_null() {
+            return Vec::new();
+        }
+        std::slice::from_raw_parts(self.value as *const u8, self.length).to_vec()
+    }
+}
+
+type AcceptSecContext = unsafe extern "C" fn(
+    *mut u32,
+    *mut *mut c_void,
+    *mut c_void,
+    *mut GssBuffer,
+    *mut c_void,
+    *mut *mut c_void,
+    *mut *mut c_void,
+    *mut GssBuffer,
+    *mut u32,
+    *mut u32,
+    *mut *mut c_void,
+) -> u32;
+type DisplayName =
+    unsafe extern "C" fn(*mut u32, *mut c_void, *mut GssBuffer, *mut *mut c_void) -> u32;
+type DisplayStatus =
+    unsafe extern "C" fn(*mut u32, u32, i32, *mut c_void, *mut u32, *mut GssBuffer) -> u32;
+type ReleaseBuffer = unsafe extern "C" fn(*mut u32, *mut GssBuffer) -> u32;
+type ReleaseName = unsafe extern "C" fn(*mut u32, *mut *mut c_void) -> u32;
+type DeleteSecContext = unsafe extern "C" fn(*mut u32, *mut *mut c_void, *mut GssBuffer) -> u32;
+type RegisterAcceptorIdentity = unsafe extern "C" fn(*const c_char) -> u32;
+
+/// MIT Kerberos GSSAPI, loaded when `--kerberos-keytab` is set.
+struct Gssapi {
+    _library: Library,
+    accept_sec_context: AcceptSecContext,
+    display_name: DisplayName,
+    display_status: DisplayStatus,
+    release_buffer: ReleaseBuffer,
+    release_name: ReleaseName,
+    delete_sec_context: DeleteSecContext,
+}
+
+impl Gssapi {
+    fn load(keytab: &str) -> Result<Gssapi, String> {
+        let library = Library::open(&["libgssapi_krb5.so.2", "libgssapi_krb5.so"])?;
+
+        unsafe {
+            // Accept tickets for the service principals in our keytab, not the system one
+            let register: RegisterAcceptorIdentity =
+                library.symbol("krb5_gss_register_acceptor_identity")?;
+            let keytab = CString::new(keytab).map_err(|e| e.to_string())?;
+            if register(keytab.as_ptr()) != GSS_S_COMPLETE {
+                return Err("Failed to register the keytab".to_string());
+            }
+
+            Ok(Gssapi {
+                accept_sec_context: library.symbol("gss_accept_sec_context")?,
+                display_name: library.symbol("gss_display_name")?,
+                display_status: library.symbol("gss_display_status")?,
+                release_buffer: library.symbol("gss_release_buffer")?,
+                release_name: library.symbol("gss_release_name")?,
+                delete_sec_context: library.symbol("gss_delete_sec_context")?,
+                _library: library,
+            })
+        }
+    }
+
+    fn status_message(&self, major: u32, minor: u32) -> String {
+        let mut messages = Vec::new();
+
+        for (code, code_type) in [(major, GSS_C_GSS_CODE), (minor, GSS_C_MECH_CODE)] {
+            let mut message_context = 0u32;
+            loop {
+                let mut status = 0u32;
+                let mut buffer = GssBuffer::empty();
+                let result = unsafe {
+                    (self.display_status)(
+                        &mut status,
+                        code,
+                        code_type,
+                        ptr::null_mut(),
+                        &mut message_context,
+                        &mut buffer,
+                    )
+                };
+                if result != GSS_S_COMPLETE {
+                    break;
+                }
+                messages.push(String::from_utf8_lossy(unsafe { &buffer.to_vec() }).into_owned());
+                unsafe { (self.release_buffer)(&mut status, &mut buffer) };
+
+                if message_context == 0 {
+                    break;
+                }
+            }
+        }
+
+        messages.join(": ")
+    }
+
+    /// Accept a single-leg Kerberos token and return the client principal. The reply
+    /// token for mutual authentication is dropped, proxy clients don't ask for it.
+    fn accept(&self, token: &[u8]) -> Result<String, String> {
+        let mut minor = 0u32;
+        let mut context = ptr::null_mut();
+        let mut input = GssBuffer {
+            length: token.len(),
+            value: token.as_ptr() as *mut c_void,
+        };
+        let mut src_name = ptr::null_mut();
+        let mut output = GssBuffer::empty();
+
+        let major = unsafe {
+            (self.accept_sec_context)(
+                &mut minor,
+                &mut context,
+                ptr::null_mut(),
+                &mut input,
+                ptr::null_mut(),
+                &mut src_name,
+                ptr::null_mut(),
+                &mut output,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                ptr::null_mut(),
+            )
+        };
+
+        let result = match major {
+            GSS_S_COMPLETE => self.principal(src_name),
+            // Proxy authentication is per request, so multi-leg mechanisms can't finish
+            GSS_S_CONTINUE_NEEDED => Err(
+                "Only single-leg Kerberos is supported, the client likely offered NTLM".to_string(),
+            ),
+            _ => Err(self.status_message(major, minor)),
+        };
+
+        unsafe {
+            let mut status = 0u32;
+            (self.release_buffer)(&mut status, &mut output);
+            if !src_name.is_null() {
+                (self.release_name)(&mut status, &mut src_name);
+            }
+            if !context.is_null() {
+                (self.delete_sec_context)(&mut status, &mut context, ptr::null_mut());
+            }
+        }
+
+        result
+    }
+
+    fn principal(&self, name: *mut c_void) -> Result<String, String> {
+        let mut minor = 0u32;
+        let mut buffer = GssBuffer::empty();
+
+        let major = unsafe { (self.display_name)(&mut minor, name, &mut buffer, ptr::null_mut()) };
+        if major != GSS_S_COMPLETE {
+            return Err(self.status_message(major, minor));
+        }
+
+        let principal = String::from_utf8_lossy(unsafe { &buffer.to_vec() }).into_owned();
+        unsafe { (self.release_buffer)(&mut minor, &mut buffer) };
+        Ok(principal)
+    }
+}
+
+/// Whether `Proxy-Authorization: Negotiate` is accepted.
+pub fn enabled() -> bool {
+    Opt::global().kerberos_keytab.is_some()
+}
+
+/// Load GSSAPI at startup, so a missing library or keytab is reported right away.
+pub fn init() -> Result<(), String> {
+    let Some(keytab) = &Opt::global().kerberos_keytab else {
+        return Ok(());
+    };
+    GSSAPI
+        .get_or_init(|| Gssapi::load(keytab))
+        .as_ref()
+        .map(|_| ())
+        .map_err(Clone::clone)
+}
+
+/// Authenticate a `Negotiate` Proxy-Authorization header and return the login, the
+/// client principal without its realm. Reads the keytab and replay cache, so call it
+/// off the async threads.
+pub fn authenticate(credentials_header: &str) -> Result<String, String> {
+    let gssapi = GSSAPI
+        .get()
+        .ok_or("Kerberos is not configured")?
+        .as_ref()
+        .map_err(Clone::clone)?;
+
+    let token = credentials_header
+        .trim()
+        .strip_prefix("Negotiate ")
+        .ok_or("Not a Negotiate header")?;
+    let token = b64
+        .decode(token.trim())
+        .map_err(|e| format!("Invalid Negotiate token: {e}"))?;
+
+    let principal = gssapi.accept(&token)?;
+    let (login, realm) = principal
+        .rsplit_once('@')
+        .ok_or_else(|| format!("Principal without realm: {principal}"))?;
+
+    if let Some(allowed_realm) = &Opt::global().kerberos_realm {
+        if !realm.eq_ignore_ascii_case(allowed_realm) {
+            return Err(format!(
+                "Principal {principal} is not from realm {allowed_realm}"
+            ));
+        }
+    }
+
+    Ok(login.to_string())
+}
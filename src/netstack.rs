@@ -0,0 +1,343 @@
+//! TCP/IPv4 connections over the WireGuard egress, whose IP packets never go through the
+//! host's own network stack. The TCP is smoltcp's, driven here from the runtime: one task
+//! polls the interface whenever packets come in, a connection has something to send or a
+//! timer is due.
+
+use std::collections::VecDeque;
+use std::future::poll_fn;
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::ops::RangeInclusive;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use rand::Rng;
+use smoltcp::iface::{Config, Interface, SocketHandle, SocketSet};
+use smoltcp::phy::{self, DeviceCapabilities, Medium};
+use smoltcp::socket::tcp;
+use smoltcp::socket::AnySocket;
+use smoltcp::time::{Duration as SmolDuration, Instant};
+use smoltcp::wire::{HardwareAddress, IpAddress, IpCidr};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::Notify;
+use tokio::time::timeout;
+
+// The MTU of a WireGuard tunnel, which leaves 1380 bytes for a TCP segment's payload
+const MTU: usize = 1420;
+// Bytes buffered in each direction, also the largest window offered
+const BUFFER_LEN: usize = 64 * 1024;
+// A connection whose SYN or data goes unacknowledged this long is given up
+const RETRANSMIT_TIMEOUT: u64 = 60;
+// Like Linux's tcp_fin_timeout, for connections closed on our side the peer never closes
+const ORPHAN_TIMEOUT: Duration = Duration::from_secs(60);
+const EPHEMERAL_PORTS: RangeInclusive<u16> = 49152..=65535;
+
+/// TCP connections from one address, sending and receiving whole IPv4 packets.
+pub struct Stack {
+    address: Ipv4Addr,
+    state: Mutex<State>,
+    /// Wakes the task polling the interface
+    changed: Arc<Notify>,
+}
+
+struct State {
+    interface: Interface,
+    device: Queue,
+    sockets: SocketSet<'static>,
+    /// Sockets whose stream is gone, removed once closed
+    orphans: Vec<(SocketHandle, std::time::Instant)>,
+}
+
+impl Stack {
+    /// `outbound` receives the IP packets to send.
+    pub fn new(address: Ipv4Addr, outbound: UnboundedSender<Vec<u8>>) -> Arc<Stack> {
+        let mut device = Queue {
+            received: VecDeque::new(),
+            outbound,
+        };
+        let mut config = Config::new(HardwareAddress::Ip);
+        config.random_seed = rand::thread_rng().gen();
+        let mut interface = Interface::new(config, &mut device, Instant::now());
+        interface.update_ip_addrs(|addrs| {
+            let _ = addrs.push(IpCidr::new(IpAddress::Ipv4(address), 32));
+        });
+        // Everything goes to the peer, there is no one else on the link
+        let _ = interface.routes_mut().add_default_ipv4_route(address);
+
+        let stack = Arc::new(Stack {
+            address,
+            state: Mutex::new(State {
+                interface,
+                device,
+                sockets: SocketSet::new(Vec::new()),
+                orphans: Vec::new(),
+            }),
+            changed: Arc::new(Notify::new()),
+        });
+        tokio::spawn(poll(Arc::downgrade(&stack)));
+        stack
+    }
+
+    pub fn address(&self) -> Ipv4Addr {
+        self.address
+    }
+
+    /// Open a connection to `remote`. Returns the stream and the local address it is from.
+    pub async fn connect(
+        self: &Arc<Self>,
+        remote: SocketAddrV4,
+    ) -> io::Result<(TcpStream, SocketAddrV4)> {
+        let (handle, local) = {
+            let mut state = self.state.lock().unwrap();
+            let State {
+                interface, sockets, ..
+            } = &mut *state;
+
+            let used = sockets
+                .iter()
+                .filter_map(|(_, socket)| tcp::Socket::downcast(socket)?.local_endpoint())
+                .map(|endpoint| endpoint.port)
+                .collect::<Vec<u16>>();
+            let port = (0..EPHEMERAL_PORTS.len())
+                .map(|_| rand::thread_rng().gen_range(EPHEMERAL_PORTS))
+                .find(|port| !used.contains(port))
+                .ok_or_else(|| io::Error::new(ErrorKind::AddrInUse, "No free local port"))?;
+            let local = SocketAddrV4::new(self.address, port);
+
+            let mut socket = tcp::Socket::new(
+                tcp::SocketBuffer::new(vec![0; BUFFER_LEN]),
+                tcp::SocketBuffer::new(vec![0; BUFFER_LEN]),
+            );
+            socket.set_timeout(Some(SmolDuration::from_secs(RETRANSMIT_TIMEOUT)));
+            socket
+                .connect(interface.context(), remote, local)
+                .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e.to_string()))?;
+            (sockets.add(socket), local)
+        };
+        self.changed.notify_one();
+
+        // Dropped on failure, which lets the socket go
+        let stream = TcpStream {
+            stack: self.clone(),
+            handle,
+        };
+        poll_fn(|cx| stream.poll_established(cx, remote)).await?;
+        Ok((stream, local))
+    }
+
+    /// Hand an IP packet that came out of the tunnel to the stack. Segments for
+    /// connections that don't exist (anymore) are answered with a reset.
+    pub fn deliver(&self, packet: &[u8]) {
+        self.state
+            .lock()
+            .unwrap()
+            .device
+            .received
+            .push_back(packet.to_vec());
+        self.changed.notify_one();
+    }
+
+    /// Run `action` on the socket of `handle`, then have the interface polled, as
+    /// whatever it did may have something to send.
+    fn with_socket<T>(
+        &self,
+        handle: SocketHandle,
+        action: impl FnOnce(&mut tcp::Socket<'static>) -> T,
+    ) -> T {
+        let result = action(self.state.lock().unwrap().sockets.get_mut(handle));
+        self.changed.notify_one();
+        result
+    }
+}
+
+/// Poll the interface of `stack` for as long as it exists, each time it changed or a
+/// timer of its connections is due.
+async fn poll(stack: Weak<Stack>) {
+    loop {
+        let Some(stack) = stack.upgrade() else {
+            return;
+        };
+
+        let delay = {
+            let mut state = stack.state.lock().unwrap();
+            let State {
+                interface,
+                device,
+                sockets,
+                orphans,
+            } = &mut *state;
+            let now = Instant::now();
+            interface.poll(now, device, sockets);
+
+            orphans.retain(|&(handle, since)| {
+                let socket = sockets.get_mut::<tcp::Socket>(handle);
+                if socket.state() == tcp::State::Closed {
+                    sockets.remove(handle);
+                    return false;
+                }
+                if since.elapsed() >= ORPHAN_TIMEOUT {
+                    // Removed once the reset is out, on the next poll
+                    socket.abort();
+                }
+                true
+            });
+            interface.poll_delay(now, sockets)
+        };
+
+        // The stack is only held while polling, so it can go away in between
+        let changed = stack.changed.clone();
+        drop(stack);
+        // Orphans are checked after a while at the latest
+        let delay = delay.map_or(ORPHAN_TIMEOUT, Duration::from);
+        let _ = timeout(delay, changed.notified()).await;
+    }
+}
+
+/// The tunnel side of the interface: packets out of the tunnel wait in `received`,
+/// those for it go to `outbound`.
+struct Queue {
+    received: VecDeque<Vec<u8>>,
+    outbound: UnboundedSender<Vec<u8>>,
+}
+
+impl phy::Device for Queue {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(RxToken, TxToken<'_>)> {
+        let packet = self.received.pop_front()?;
+        Some((RxToken(packet), TxToken(&self.outbound)))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<TxToken<'_>> {
+        Some(TxToken(&self.outbound))
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.medium = Medium::Ip;
+        capabilities.max_transmission_unit = MTU;
+        capabilities
+    }
+}
+
+struct RxToken(Vec<u8>);
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F: FnOnce(&[u8]) -> R>(self, f: F) -> R {
+        f(&self.0)
+    }
+}
+
+struct TxToken<'a>(&'a UnboundedSender<Vec<u8>>);
+
+impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F: FnOnce(&mut [u8]) -> R>(self, len: usize, f: F) -> R {
+        let mut packet = vec![0; len];
+        let result = f(&mut packet);
+        let _ = self.0.send(packet);
+        result
+    }
+}
+
+/// A connection of the stack. Dropping it closes the connection, the stack finishes
+/// closing it.
+pub struct TcpStream {
+    stack: Arc<Stack>,
+    handle: SocketHandle,
+}
+
+impl TcpStream {
+    fn poll_established(&self, cx: &mut Context<'_>, remote: SocketAddrV4) -> Poll<io::Result<()>> {
+        let mut state = self.stack.state.lock().unwrap();
+        let socket = state.sockets.get_mut::<tcp::Socket>(self.handle);
+        match socket.state() {
+            tcp::State::SynSent | tcp::State::SynReceived => {
+                socket.register_send_waker(cx.waker());
+                Poll::Pending
+            }
+            tcp::State::Closed => Poll::Ready(Err(io::Error::new(
+                ErrorKind::ConnectionRefused,
+                format!("Connection to {remote} was refused or timed out"),
+            ))),
+            _ => Poll::Ready(Ok(())),
+        }
+    }
+}
+
+impl AsyncRead for TcpStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+        self.stack.with_socket(self.handle, |socket| {
+            if socket.can_recv() {
+                // The window opens up again, which the peer is told on the next poll
+                let read = socket
+                    .recv_slice(buf.initialize_unfilled())
+                    .map(|n| buf.advance(n))
+                    .map_err(|e| io::Error::new(ErrorKind::ConnectionReset, e.to_string()));
+                return Poll::Ready(read);
+            }
+            if !socket.may_recv() {
+                // The peer is done sending
+                return Poll::Ready(Ok(()));
+            }
+            socket.register_recv_waker(cx.waker());
+            Poll::Pending
+        })
+    }
+}
+
+impl AsyncWrite for TcpStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.stack.with_socket(self.handle, |socket| {
+            if socket.can_send() {
+                let written = socket
+                    .send_slice(buf)
+                    .map_err(|e| io::Error::new(ErrorKind::BrokenPipe, e.to_string()));
+                return Poll::Ready(written);
+            }
+            if !socket.may_send() {
+                return Poll::Ready(Err(io::Error::new(
+                    ErrorKind::BrokenPipe,
+                    "Connection closed for sending",
+                )));
+            }
+            socket.register_send_waker(cx.waker());
+            Poll::Pending
+        })
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // What is written goes out as the window allows, nothing is held back here
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        // A FIN follows the data still buffered
+        self.stack.with_socket(self.handle, tcp::Socket::close);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let mut state = self.stack.state.lock().unwrap();
+        state.sockets.get_mut::<tcp::Socket>(self.handle).close();
+        state.orphans.push((self.handle, std::time::Instant::now()));
+        drop(state);
+        self.stack.changed.notify_one();
+    }
+}
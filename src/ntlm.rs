@@ -0,0 +1,396 @@
+use std::fmt;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use rand::Rng;
+
+const SIGNATURE: &[u8; 8] = b"NTLMSSP\0";
+
+// Negotiate flags (MS-NLMP 2.2.2.5)
+const NEGOTIATE_UNICODE: u32 = 0x0000_0001;
+const NEGOTIATE_OEM: u32 = 0x0000_0002;
+const REQUEST_TARGET: u32 = 0x0000_0004;
+const NEGOTIATE_NTLM: u32 = 0x0000_0200;
+const NEGOTIATE_ALWAYS_SIGN: u32 = 0x0000_8000;
+const NEGOTIATE_EXTENDED_SESSIONSECURITY: u32 = 0x0008_0000;
+const NEGOTIATE_TARGET_INFO: u32 = 0x0080_0000;
+const NEGOTIATE_128: u32 = 0x2000_0000;
+const NEGOTIATE_56: u32 = 0x8000_0000;
+
+const AV_EOL: u16 = 0;
+const AV_TIMESTAMP: u16 = 7;
+
+// Seconds between 1601-01-01 (Windows FILETIME epoch) and 1970-01-01
+const FILETIME_UNIX_OFFSET: u64 = 11_644_473_600;
+
+/// Service account used to answer NTLM challenges, given as `DOMAIN\user:password`.
+#[derive(Clone)]
+pub struct NtlmCredentials {
+    pub domain: String,
+    pub user: String,
+    password: String,
+}
+
+// Keep the password out of logged options
+impl fmt::Debug for NtlmCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NtlmCredentials")
+            .field("domain", &self.domain)
+            .field("user", &self.user)
+            .finish_non_exhaustive()
+    }
+}
+
+impl FromStr for NtlmCredentials {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (account, password) = s.split_once(':').ok_or("Expected DOMAIN\\user:password")?;
+        let (domain, user) = account.split_once('\\').unwrap_or(("", account));
+
+        if user.is_empty() {
+            return Err("NTLM user must not be empty".to_string());
+        }
+        Ok(NtlmCredentials {
+            domain: domain.to_string(),
+            user: user.to_string(),
+            password: password.to_string(),
+        })
+    }
+}
+
+/// Type 1 message opening the handshake.
+pub fn negotiate_message() -> Vec<u8> {
+    let flags = NEGOTIATE_UNICODE
+        | NEGOTIATE_OEM
+        | REQUEST_TARGET
+        | NEGOTIATE_NTLM
+        | NEGOTIATE_ALWAYS_SIGN
+        | NEGOTIATE_EXTENDED_SESSIONSECURITY
+        | NEGOTIATE_128
+        | NEGOTIATE_56;
+
+    let mut message = Vec::with_capacity(32);
+    message.extend_from_slice(SIGNATURE);
+    message.extend_from_slice(&1u32.to_le_bytes());
+    message.extend_from_slice(&flags.to_le_bytes());
+    message.extend_from_slice(&[0; 8]); // domain
+    message.extend_from_slice(&[0; 8]); // workstation
+    message
+}
+
+/// What the server's type 2 message carries that the response depends on.
+#[derive(Debug)]
+pub struct Challenge {
+    flags: u32,
+    server_challenge: [u8; 8],
+    target_info: Vec<u8>,
+}
+
+pub fn parse_challenge(message: &[u8]) -> Result<Challenge, String> {
+    let malformed = || "Malformed NTLM challenge".to_string();
+
+    if message.len() < 32 || &message[..8] != SIGNATURE || read_u32(message, 8) != Some(2) {
+        return Err(malformed());
+    }
+    let flags = read_u32(message, 20).ok_or_else(malformed)?;
+    let server_challenge = message[24..32].try_into().unwrap();
+
+    let target_info = if flags & NEGOTIATE_TARGET_INFO != 0 && message.len() >= 48 {
+        let length = read_u16(message, 40).ok_or_else(malformed)? as usize;
+        let offset = read_u32(message, 44).ok_or_else(malformed)? as usize;
+        message
+            .get(offset..offset + length)
+            .ok_or_else(malformed)?
+            .to_vec()
+    } else {
+        Vec::new()
+    };
+
+    Ok(Challenge {
+        flags,
+        server_challenge,
+        target_info,
+    })
+}
+
+/// Type 3 message answering the challenge with an NTLMv2 response.
+pub fn authenticate_message(credentials: &NtlmCredentials, challenge: &Challenge) -> Vec<u8> {
+    let client_challenge = rand::thread_rng().gen::<[u8; 8]>();
+    let timestamp = av_timestamp(&challenge.target_info).unwrap_or_else(|| {
+        let since_unix = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        (since_unix.as_secs() + FILETIME_UNIX_OFFSET) * 10_000_000
+            + since_unix.subsec_nanos() as u64 / 100
+    });
+
+    let (lm_response, nt_response) =
+        ntlmv2_responses(credentials, challenge, client_challenge, timestamp);
+
+    let unicode = challenge.flags & NEGOTIATE_UNICODE != 0;
+    let encode = |s: &str| {
+        if unicode {
+            utf16le(s)
+        } else {
+            s.as_bytes().to_vec()
+        }
+    };
+    let domain = encode(&credentials.domain);
+    let user = encode(&credentials.user);
+    let workstation = encode("");
+
+    let flags = (challenge.flags & !NEGOTIATE_OEM) | if unicode { 0 } else { NEGOTIATE_OEM };
+    let fields = [
+        &lm_response,
+        &nt_response,
+        &domain,
+        &user,
+        &workstation,
+        &Vec::new(),
+    ];
+
+    let mut message = Vec::new();
+    message.extend_from_slice(SIGNATURE);
+    message.extend_from_slice(&3u32.to_le_bytes());
+
+    let mut offset = 64u32;
+    for field in fields {
+        message.extend_from_slice(&(field.len() as u16).to_le_bytes());
+        message.extend_from_slice(&(field.len() as u16).to_le_bytes());
+        message.extend_from_slice(&offset.to_le_bytes());
+        offset += field.len() as u32;
+    }
+    message.extend_from_slice(&flags.to_le_bytes());
+    for field in fields {
+        message.extend_from_slice(field);
+    }
+    message
+}
+
+fn ntlmv2_responses(
+    credentials: &NtlmCredentials,
+    challenge: &Challenge,
+    client_challenge: [u8; 8],
+    timestamp: u64,
+) -> (Vec<u8>, Vec<u8>) {
+    let nt_hash = md4(&utf16le(&credentials.password));
+    let identity = format!("{}{}", credentials.user.to_uppercase(), credentials.domain);
+    let key = hmac_md5(&nt_hash, &utf16le(&identity));
+
+    let mut blob = vec![1, 1, 0, 0, 0, 0, 0, 0];
+    blob.extend_from_slice(&timestamp.to_le_bytes());
+    blob.extend_from_slice(&client_challenge);
+    blob.extend_from_slice(&[0; 4]);
+    blob.extend_from_slice(&challenge.target_info);
+    blob.extend_from_slice(&[0; 4]);
+
+    let nt_proof = hmac_md5(&key, &[&challenge.server_challenge[..], &blob].concat());
+    let nt_response = [&nt_proof[..], &blob].concat();
+
+    let lm_proof = hmac_md5(
+        &key,
+        &[challenge.server_challenge, client_challenge].concat(),
+    );
+    let lm_response = [&lm_proof[..], &client_challenge].concat();
+
+    (lm_response, nt_response)
+}
+
+/// Server time from the target info, which the response must use when present.
+fn av_timestamp(target_info: &[u8]) -> Option<u64> {
+    let mut pos = 0;
+    while pos + 4 <= target_info.len() {
+        let id = read_u16(target_info, pos)?;
+        let length = read_u16(target_info, pos + 2)? as usize;
+        let value = target_info.get(pos + 4..pos + 4 + length)?;
+
+        match id {
+            AV_EOL => return None,
+            AV_TIMESTAMP if length == 8 => return Some(u64::from_le_bytes(value.try_into().ok()?)),
+            _ => pos += 4 + length,
+        }
+    }
+    None
+}
+
+fn utf16le(s: &str) -> Vec<u8> {
+    s.encode_utf16().flat_map(u16::to_le_bytes).collect()
+}
+
+fn read_u16(bytes: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(
+        bytes.get(pos..pos + 2)?.try_into().ok()?,
+    ))
+}
+
+fn read_u32(bytes: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(
+        bytes.get(pos..pos + 4)?.try_into().ok()?,
+    ))
+}
+
+/// Message padding shared by MD4 and MD5: 0x80, zeros, then the bit length.
+fn md_padded(message: &[u8]) -> Vec<u8> {
+    let mut padded = message.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&((message.len() as u64).wrapping_mul(8)).to_le_bytes());
+    padded
+}
+
+fn md_words(block: &[u8]) -> [u32; 16] {
+    let mut words = [0u32; 16];
+    for (i, word) in words.iter_mut().enumerate() {
+        *word = u32::from_le_bytes(block[i * 4..i * 4 + 4].try_into().unwrap());
+    }
+    words
+}
+
+fn md_digest(state: [u32; 4]) -> [u8; 16] {
+    let mut digest = [0u8; 16];
+    for (i, word) in state.iter().enumerate() {
+        digest[i * 4..i * 4 + 4].copy_from_slice(&word.to_le_bytes());
+    }
+    digest
+}
+
+/// MD4 (RFC 1320), needed for the NT password hash.
+fn md4(message: &[u8]) -> [u8; 16] {
+    let mut state: [u32; 4] = [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476];
+
+    for block in md_padded(message).chunks(64) {
+        let x = md_words(block);
+        let [mut a, mut b, mut c, mut d] = state;
+
+        let f = |x: u32, y: u32, z: u32| (x & y) | (!x & z);
+        let g = |x: u32, y: u32, z: u32| (x & y) | (x & z) | (y & z);
+        let h = |x: u32, y: u32, z: u32| x ^ y ^ z;
+
+        for &i in &[0, 4, 8, 12] {
+            a = a.wrapping_add(f(b, c, d)).wrapping_add(x[i]).rotate_left(3);
+            d = d
+                .wrapping_add(f(a, b, c))
+                .wrapping_add(x[i + 1])
+                .rotate_left(7);
+            c = c
+                .wrapping_add(f(d, a, b))
+                .wrapping_add(x[i + 2])
+                .rotate_left(11);
+            b = b
+                .wrapping_add(f(c, d, a))
+                .wrapping_add(x[i + 3])
+                .rotate_left(19);
+        }
+        for &i in &[0, 1, 2, 3] {
+            let k = 0x5a82_7999u32;
+            a = a
+                .wrapping_add(g(b, c, d))
+                .wrapping_add(x[i])
+                .wrapping_add(k)
+                .rotate_left(3);
+            d = d
+                .wrapping_add(g(a, b, c))
+                .wrapping_add(x[i + 4])
+                .wrapping_add(k)
+                .rotate_left(5);
+            c = c
+                .wrapping_add(g(d, a, b))
+                .wrapping_add(x[i + 8])
+                .wrapping_add(k)
+                .rotate_left(9);
+            b = b
+                .wrapping_add(g(c, d, a))
+                .wrapping_add(x[i + 12])
+                .wrapping_add(k)
+                .rotate_left(13);
+        }
+        for &i in &[0, 2, 1, 3] {
+            let k = 0x6ed9_eba1u32;
+            a = a
+                .wrapping_add(h(b, c, d))
+                .wrapping_add(x[i])
+                .wrapping_add(k)
+                .rotate_left(3);
+            d = d
+                .wrapping_add(h(a, b, c))
+                .wrapping_add(x[i + 8])
+                .wrapping_add(k)
+                .rotate_left(9);
+            c = c
+                .wrapping_add(h(d, a, b))
+                .wrapping_add(x[i + 4])
+                .wrapping_add(k)
+                .rotate_left(11);
+            b = b
+                .wrapping_add(h(c, d, a))
+                .wrapping_add(x[i + 12])
+                .wrapping_add(k)
+                .rotate_left(15);
+        }
+
+        state[0] = state[0].wrapping_add(a);
+        state[1] = state[1].wrapping_add(b);
+        state[2] = state[2].wrapping_add(c);
+        state[3] = state[3].wrapping_add(d);
+    }
+
+    md_digest(state)
+}
+
+/// MD5 (RFC 1321), the hash under NTLMv2's HMAC.
+fn md5(message: &[u8]) -> [u8; 16] {
+    const SHIFTS: [u32; 16] = [7, 12, 17, 22, 5, 9, 14, 20, 4, 11, 16, 23, 6, 10, 15, 21];
+    let constants: [u32; 64] =
+        std::array::from_fn(|i| ((i as f64 + 1.0).sin().abs() * 4_294_967_296.0) as u32);
+
+    let mut state: [u32; 4] = [0x6745_2301, 0xefcd_ab89, 0x98ba_dcfe, 0x1032_5476];
+
+    for block in md_padded(message).chunks(64) {
+        let x = md_words(block);
+        let [mut a, mut b, mut c, mut d] = state;
+
+        for i in 0..64 {
+            let (f, g) = match i / 16 {
+                0 => ((b & c) | (!b & d), i),
+                1 => ((d & b) | (!d & c), (5 * i + 1) % 16),
+                2 => (b ^ c ^ d, (3 * i + 5) % 16),
+                _ => (c ^ (b | !d), (7 * i) % 16),
+            };
+            let rotated = a
+                .wrapping_add(f)
+                .wrapping_add(constants[i])
+                .wrapping_add(x[g])
+                .rotate_left(SHIFTS[(i / 16) * 4 + i % 4]);
+
+            a = d;
+            d = c;
+            c = b;
+            b = b.wrapping_add(rotated);
+        }
+
+        state[0] = state[0].wrapping_add(a);
+        state[1] = state[1].wrapping_add(b);
+        state[2] = state[2].wrapping_add(c);
+        state[3] = state[3].wrapping_add(d);
+    }
+
+    md_digest(state)
+}
+
+fn hmac_md5(key: &[u8], message: &[u8]) -> [u8; 16] {
+    let mut block_key = [0u8; 64];
+    if key.len() > 64 {
+        block_key[..16].copy_from_slice(&md5(key));
+    } else {
+        block_key[..key.len()].copy_from_slice(key);
+    }
+
+    let inner_pad = block_key.map(|byte| byte ^ 0x36);
+    let outer_pad = block_key.map(|byte| byte ^ 0x5c);
+
+    let inner = md5(&[&inner_pad[..], message].concat());
+    md5(&[&outer_pad[..], &inner[..]].concat())
+}
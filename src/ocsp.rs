@@ -0,0 +1,402 @@
+use crate::options::Opt;
+use crate::utils::formatted_time;
+
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::{Duration, Instant};
+
+use chrono::{NaiveDateTime, Utc};
+use hyper::client::HttpConnector;
+use hyper::header::CONTENT_TYPE;
+use hyper::{Body, Client, Request};
+use hyper_tls::HttpsConnector;
+use log::{info, warn};
+use openssl::asn1::Asn1GeneralizedTimeRef;
+use openssl::hash::MessageDigest;
+use openssl::ocsp::{
+    OcspCertId, OcspCertStatus, OcspFlag, OcspRequest, OcspResponse, OcspResponseStatus,
+};
+use openssl::stack::Stack;
+use openssl::x509::store::X509StoreBuilder;
+use openssl::x509::verify::X509VerifyFlags;
+use openssl::x509::X509;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use tokio::sync::Notify;
+use tokio::time::{sleep_until, timeout};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Responses are fetched again halfway through their validity, within these bounds, and
+// after a failure once the retry delay has passed
+const MIN_REFRESH: Duration = Duration::from_secs(5 * 60);
+const MAX_REFRESH: Duration = Duration::from_secs(24 * 60 * 60);
+const RETRY_DELAY: Duration = Duration::from_secs(10 * 60);
+
+// Clock skew allowed between the responder and this host
+const MAX_SKEW_SECS: u32 = 5 * 60;
+
+// Extension of certificates with SCTs embedded by the CA (RFC 6962, 3.3), DER encoded
+const SCT_LIST_OID: [u8; 12] = [
+    0x06, 0x0a, 0x2b, 0x06, 0x01, 0x04, 0x01, 0xd6, 0x79, 0x02, 0x04, 0x02,
+];
+
+static CERTIFICATES: OnceLock<RwLock<HashMap<Vec<u8>, Stapled>>> = OnceLock::new();
+static SEEN: OnceLock<Notify> = OnceLock::new();
+
+/// A certificate the HTTPS server has served, with its OCSP response once there is one.
+struct Stapled {
+    key: Arc<CertifiedKey>,
+    stapled: Option<Arc<CertifiedKey>>,
+    next_fetch: Instant,
+    expired: bool,
+}
+
+fn certificates() -> &'static RwLock<HashMap<Vec<u8>, Stapled>> {
+    CERTIFICATES.get_or_init(Default::default)
+}
+
+fn seen() -> &'static Notify {
+    SEEN.get_or_init(Notify::new)
+}
+
+pub fn enabled() -> bool {
+    Opt::global().ocsp_stapling
+}
+
+/// Serves the certificates of `inner` with their OCSP responses stapled, with
+/// `--ocsp-stapling`. Certificates without a response yet, like one just renewed, are
+/// served as they are until the background fetch gets one.
+pub struct Stapler {
+    pub inner: Arc<dyn ResolvesServerCert>,
+}
+
+impl ResolvesServerCert for Stapler {
+    fn resolve(&self, client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        let key = self.inner.resolve(client_hello)?;
+        Some(staple(key))
+    }
+}
+
+/// Serves a single certificate, the one of `--cert` and `--pkey`.
+pub struct SingleCert(pub Arc<CertifiedKey>);
+
+impl ResolvesServerCert for SingleCert {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.0.clone())
+    }
+}
+
+fn staple(key: Arc<CertifiedKey>) -> Arc<CertifiedKey> {
+    let Some(leaf) = key.cert.first() else {
+        return key;
+    };
+    if let Some(certificate) = certificates().read().unwrap().get(&leaf.0) {
+        return certificate.stapled.clone().unwrap_or(key);
+    }
+    watch(key.clone());
+    key
+}
+
+/// Start fetching OCSP responses for a certificate the HTTPS server serves, and log
+/// whether it carries the SCTs browsers enforcing Certificate Transparency look for.
+pub fn watch(key: Arc<CertifiedKey>) {
+    let Some(leaf) = key.cert.first().map(|leaf| leaf.0.clone()) else {
+        return;
+    };
+    {
+        let mut certificates = certificates().write().unwrap();
+        if certificates.contains_key(&leaf) {
+            return;
+        }
+        // Certificates replaced by a renewal are of no use once they have expired
+        certificates.retain(|_, certificate| !certificate.expired);
+        certificates.insert(
+            leaf.clone(),
+            Stapled {
+                key,
+                stapled: None,
+                next_fetch: Instant::now(),
+                expired: false,
+            },
+        );
+    }
+    log_scts(&leaf);
+    seen().notify_one();
+}
+
+fn log_scts(leaf: &[u8]) {
+    let time = formatted_time();
+    let subject = subject(leaf);
+    match embedded_scts(leaf) {
+        Some(count) if count > 0 => {
+            info!("[{time}] Certificate for {subject} embeds {count} SCTs");
+        }
+        _ => warn!(
+            "[{time}] Certificate for {subject} embeds no SCTs, browsers enforcing Certificate Transparency will refuse it unless it's privately trusted"
+        ),
+    }
+}
+
+fn subject(leaf: &[u8]) -> String {
+    X509::from_der(leaf)
+        .ok()
+        .and_then(|cert| {
+            let entry = cert
+                .subject_name()
+                .entries_by_nid(openssl::nid::Nid::COMMONNAME)
+                .next()?
+                .data()
+                .as_utf8()
+                .ok()?
+                .to_string();
+            Some(entry)
+        })
+        .unwrap_or_else(|| "the HTTPS server".to_string())
+}
+
+/// Number of SCTs in the certificate's SCT list extension, `None` without one.
+fn embedded_scts(der: &[u8]) -> Option<usize> {
+    let at = der
+        .windows(SCT_LIST_OID.len())
+        .position(|window| window == SCT_LIST_OID)?;
+    let mut rest = &der[at + SCT_LIST_OID.len()..];
+    // An optional critical flag, then the extension's value wrapping the TLS-encoded list
+    if rest.first() == Some(&0x01) {
+        rest = der_value(rest)?.1;
+    }
+    let (value, _) = der_value(rest)?;
+    let (list, _) = der_value(value)?;
+
+    let length = u16::from_be_bytes(list.get(..2)?.try_into().ok()?) as usize;
+    let mut scts = list.get(2..2 + length)?;
+    let mut count = 0;
+    while !scts.is_empty() {
+        let length = u16::from_be_bytes(scts.get(..2)?.try_into().ok()?) as usize;
+        scts = scts.get(2 + length..)?;
+        count += 1;
+    }
+    Some(count)
+}
+
+/// Contents of the DER element at the start of `der`, and what follows it.
+fn der_value(der: &[u8]) -> Option<(&[u8], &[u8])> {
+    let first = *der.get(1)?;
+    let (length, header) = match first {
+        0..=0x7f => (first as usize, 2),
+        0x81..=0x84 => {
+            let size = (first & 0x7f) as usize;
+            let length = der
+                .get(2..2 + size)?
+                .iter()
+                .fold(0usize, |length, byte| length << 8 | *byte as usize);
+            (length, 2 + size)
+        }
+        _ => return None,
+    };
+    let end = header.checked_add(length)?;
+    Some((der.get(header..end)?, der.get(end..)?))
+}
+
+/// Fetch OCSP responses for the certificates served, and again before they expire, for
+/// as long as the proxy runs.
+pub async fn staple_periodically() {
+    if !enabled() {
+        return;
+    }
+    let client = Client::builder().build::<_, Body>(HttpsConnector::new());
+
+    loop {
+        let now = Instant::now();
+        let due = certificates()
+            .read()
+            .unwrap()
+            .iter()
+            .filter(|(_, certificate)| !certificate.expired && certificate.next_fetch <= now)
+            .map(|(leaf, certificate)| (leaf.clone(), certificate.key.clone()))
+            .collect::<Vec<(Vec<u8>, Arc<CertifiedKey>)>>();
+
+        for (leaf, key) in due {
+            let time = formatted_time();
+            let subject = subject(&leaf);
+            let (stapled, next_fetch, expired) = match fetch(&client, &key).await {
+                Ok((response, refresh)) => {
+                    info!(
+                        "[{time}] Stapling a fresh OCSP response for {subject}, next in {}s",
+                        refresh.as_secs()
+                    );
+                    let stapled = CertifiedKey {
+                        ocsp: Some(response),
+                        ..(*key).clone()
+                    };
+                    (
+                        Some(Some(Arc::new(stapled))),
+                        Instant::now() + refresh,
+                        false,
+                    )
+                }
+                Err(Fetch::Permanent(e)) => {
+                    warn!("[{time}] Not stapling OCSP for {subject}: {e}");
+                    (Some(None), Instant::now() + MAX_REFRESH, e == EXPIRED)
+                }
+                Err(Fetch::Retry(e)) => {
+                    warn!(
+                        "[{time}] Fetching OCSP for {subject} failed, retrying in {}s: {e}",
+                        RETRY_DELAY.as_secs()
+                    );
+                    (None, Instant::now() + RETRY_DELAY, false)
+                }
+            };
+
+            let mut certificates = certificates().write().unwrap();
+            if let Some(certificate) = certificates.get_mut(&leaf) {
+                certificate.next_fetch = next_fetch;
+                certificate.expired = expired;
+                // A refresh failing for now keeps the response stapled, fetched halfway
+                // through its validity it's good for a while still
+                if let Some(stapled) = stapled {
+                    certificate.stapled = stapled;
+                }
+            }
+        }
+
+        let next = certificates()
+            .read()
+            .unwrap()
+            .values()
+            .filter(|certificate| !certificate.expired)
+            .map(|certificate| certificate.next_fetch)
+            .min()
+            .unwrap_or_else(|| Instant::now() + MAX_REFRESH);
+        tokio::select! {
+            _ = sleep_until(next.into()) => {}
+            _ = seen().notified() => {}
+        }
+    }
+}
+
+const EXPIRED: &str = "the certificate has expired";
+
+enum Fetch {
+    /// Nothing to staple for this certificate, asking again won't change that soon.
+    Permanent(String),
+    Retry(String),
+}
+
+/// The responder's answer for `key`'s certificate, and when to ask again.
+async fn fetch(
+    client: &Client<HttpsConnector<HttpConnector>>,
+    key: &CertifiedKey,
+) -> Result<(Vec<u8>, Duration), Fetch> {
+    let permanent = |e: String| Fetch::Permanent(e);
+    let retry = |e: String| Fetch::Retry(e);
+
+    let parse = |der: &[u8]| X509::from_der(der).map_err(|e| permanent(e.to_string()));
+    let leaf = parse(&key.cert[0].0)?;
+    if leaf.not_after() < openssl::asn1::Asn1Time::days_from_now(0).unwrap() {
+        return Err(permanent(EXPIRED.to_string()));
+    }
+    let issuer = match key.cert.get(1) {
+        Some(issuer) => parse(&issuer.0)?,
+        None => {
+            return Err(permanent(
+                "the certificate file has no issuer certificate after the server's".to_string(),
+            ))
+        }
+    };
+    let url = leaf
+        .ocsp_responders()
+        .ok()
+        .and_then(|responders| responders.iter().next().map(|url| url.to_string()))
+        .ok_or_else(|| permanent("the certificate names no OCSP responder".to_string()))?;
+
+    let id = || {
+        OcspCertId::from_cert(MessageDigest::sha1(), &leaf, &issuer)
+            .map_err(|e| permanent(e.to_string()))
+    };
+    let request = {
+        let mut request = OcspRequest::new().map_err(|e| permanent(e.to_string()))?;
+        request
+            .add_id(id()?)
+            .map_err(|e| permanent(e.to_string()))?;
+        request.to_der().map_err(|e| permanent(e.to_string()))?
+    };
+
+    let request = Request::post(&url)
+        .header(CONTENT_TYPE, "application/ocsp-request")
+        .body(Body::from(request))
+        .map_err(|e| permanent(format!("{url}: {e}")))?;
+    let response = match timeout(FETCH_TIMEOUT, client.request(request)).await {
+        Ok(Ok(response)) if response.status().is_success() => response,
+        Ok(Ok(response)) => return Err(retry(format!("{url} answered {}", response.status()))),
+        Ok(Err(e)) => return Err(retry(format!("{url}: {e}"))),
+        Err(_) => return Err(retry(format!("{url} timed out"))),
+    };
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|e| retry(format!("{url}: {e}")))?
+        .to_vec();
+
+    let response = OcspResponse::from_der(&body).map_err(|e| retry(format!("{url}: {e}")))?;
+    if response.status() != OcspResponseStatus::SUCCESSFUL {
+        return Err(retry(format!(
+            "{url} answered status {}",
+            response.status().as_raw()
+        )));
+    }
+    let basic = response.basic().map_err(|e| retry(e.to_string()))?;
+
+    // Signed by the issuer, or by a responder certificate the issuer delegated to
+    let mut certs = Stack::new().map_err(|e| retry(e.to_string()))?;
+    certs
+        .push(issuer.clone())
+        .map_err(|e| retry(e.to_string()))?;
+    let store = {
+        let mut store = X509StoreBuilder::new().map_err(|e| retry(e.to_string()))?;
+        store
+            .add_cert(issuer.clone())
+            .map_err(|e| retry(e.to_string()))?;
+        store
+            .set_flags(X509VerifyFlags::PARTIAL_CHAIN)
+            .map_err(|e| retry(e.to_string()))?;
+        store.build()
+    };
+    basic
+        .verify(&certs, &store, OcspFlag::TRUST_OTHER)
+        .map_err(|e| retry(format!("the response's signature doesn't verify: {e}")))?;
+
+    let id = id()?;
+    let status = basic
+        .find_status(&id)
+        .ok_or_else(|| retry("the response is for another certificate".to_string()))?;
+    status
+        .check_validity(MAX_SKEW_SECS, None)
+        .map_err(|e| retry(format!("the response is out of date: {e}")))?;
+    if status.status == OcspCertStatus::REVOKED {
+        return Err(permanent("the CA has revoked the certificate".to_string()));
+    }
+    if status.status != OcspCertStatus::GOOD {
+        return Err(retry(
+            "the responder doesn't know the certificate".to_string(),
+        ));
+    }
+
+    let refresh = match (
+        parse_time(status.this_update),
+        parse_time(status.next_update),
+    ) {
+        (Some(this_update), Some(next_update)) => {
+            let halfway = this_update + (next_update - this_update) / 2;
+            (halfway - Utc::now().naive_utc())
+                .to_std()
+                .unwrap_or(MIN_REFRESH)
+        }
+        _ => MIN_REFRESH,
+    };
+    Ok((body, refresh.clamp(MIN_REFRESH, MAX_REFRESH)))
+}
+
+/// An ASN.1 time as OpenSSL prints it, `Oct 15 10:00:00 2026 GMT`.
+fn parse_time(time: &Asn1GeneralizedTimeRef) -> Option<NaiveDateTime> {
+    NaiveDateTime::parse_from_str(&time.to_string(), "%b %e %H:%M:%S %Y GMT").ok()
+}
@@ -0,0 +1,526 @@
+use crate::breaker;
+use crate::dns::{log_connected, resolve_pinned, split_host_port};
+use crate::egress;
+use crate::fastopen;
+use crate::limiter::{self, is_queue_full};
+use crate::mss;
+use crate::negative;
+use crate::options::Opt;
+use crate::policy;
+use crate::sockopt;
+use crate::stats;
+use crate::upstream::{Upstream, UpstreamStream};
+use crate::utils::loggable;
+#[cfg(feature = "wireguard")]
+use crate::wireguard::{self, WireGuardStream};
+
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use futures_util::stream::{FuturesUnordered, StreamExt};
+use hyper::service::Service;
+use hyper::{StatusCode, Uri};
+use log::{info, warn};
+use socket2::SockRef;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{lookup_host, TcpSocket, TcpStream};
+use tokio::time::{sleep, timeout};
+
+// How many local ports to try before giving up on a connection
+const BIND_ATTEMPTS: usize = 16;
+
+/// Resolve a tunnel target and connect to the first reachable address.
+/// If the local port range ran out on any address, that error wins, so callers
+/// can tell the client the proxy is overloaded rather than the target is down.
+/// The same goes for a full `--connect-queue` to the target's host.
+pub async fn connect_target(
+    target: &str,
+    local_ip: Option<IpAddr>,
+    client: &str,
+) -> io::Result<Outbound> {
+    let upstream = Upstream::for_target(target);
+    policy::route(upstream.as_ref(), wireguard_peer()).log(client, target);
+
+    // A destination that just failed fails again at once, without taking a connect slot.
+    // Through an upstream proxy the failure may have been the upstream's.
+    let key = negative::connect_key(target);
+    if upstream.is_none() {
+        negative::check(&key, target, client)?;
+    }
+
+    // So does one that keeps failing, until its circuit lets a probe through
+    let host = split_host_port(target)
+        .map(|(host, _)| host)
+        .unwrap_or(target);
+    breaker::check(host)?;
+    let result = connect_route(target, local_ip, client, upstream, &key).await;
+    match &result {
+        Ok(_) => breaker::record(host, true),
+        Err(e) if is_destination_failure(e) => breaker::record(host, false),
+        Err(_) => {}
+    }
+    result
+}
+
+/// Whether a failed connect says something about the destination, rather than about
+/// the proxy's own limits and policy.
+fn is_destination_failure(e: &io::Error) -> bool {
+    !(is_port_exhausted(e) || is_queue_full(e) || e.kind() == ErrorKind::PermissionDenied)
+}
+
+async fn connect_route(
+    target: &str,
+    local_ip: Option<IpAddr>,
+    client: &str,
+    upstream: Option<Upstream>,
+    key: &str,
+) -> io::Result<Outbound> {
+    let _permit = limiter::acquire_target(target).await?;
+
+    #[cfg(feature = "wireguard")]
+    if let Some(device) = wireguard::device() {
+        let addrs = resolve_pinned(target, client).await?;
+        let (server, addr, local) = device.connect(&addrs).await?;
+        log_connected(target, addr, Some(local), client);
+        return Ok(Outbound::WireGuard(server));
+    }
+
+    let mark = fwmark_for(target, client);
+
+    if let Some(upstream) = upstream {
+        let server = upstream.connect(target, mark, client).await?;
+        info!(
+            "Connected to {} via upstream proxy {} client={client}",
+            loggable(target, client),
+            server.proxy
+        );
+        return Ok(Outbound::Upstream(server));
+    }
+
+    let addrs = resolve_pinned(target, client).await?;
+
+    match race(addrs, local_ip, mark).await {
+        Ok((server, addr)) => {
+            log_connected(target, addr, server.local_addr().ok(), client);
+            Ok(Outbound::Tcp(server))
+        }
+        Err(e) if is_port_exhausted(&e) => {
+            let total = stats::PORT_EXHAUSTED.fetch_add(1, Ordering::Relaxed) + 1;
+            warn!("No free local port to connect to {target} (total: {total}) client={client}");
+            Err(e)
+        }
+        Err(e) => {
+            negative::record(key, &e);
+            Err(e)
+        }
+    }
+}
+
+/// Connect to the first of `addrs` to answer, Happy Eyeballs style (RFC 8305): the
+/// families take turns, starting with the resolver's first, and each attempt gets
+/// `--connect-stagger` to succeed before the next one starts alongside it, or less when
+/// it fails sooner. Attempts give up after `--connect-timeout`. Running out of local
+/// ports ends the race at once, as every other address would run out as well.
+pub async fn race(
+    addrs: Vec<SocketAddr>,
+    local_ip: Option<IpAddr>,
+    mark: Option<Fwmark>,
+) -> io::Result<(TcpStream, SocketAddr)> {
+    let options = Opt::global();
+    let stagger = Duration::from_millis(options.connect_stagger);
+    let attempt_timeout = Duration::from_secs(options.connect_timeout);
+
+    let attempt = |addr: SocketAddr| async move {
+        let result = match attempt_timeout.is_zero() {
+            true => connect(addr, local_ip, mark).await,
+            false => timeout(attempt_timeout, connect(addr, local_ip, mark))
+                .await
+                .unwrap_or_else(|_| {
+                    Err(io::Error::new(
+                        ErrorKind::TimedOut,
+                        format!("Connect to {addr} timed out"),
+                    ))
+                }),
+        };
+        (addr, result)
+    };
+
+    let mut pending = interleave_families(addrs).into_iter().peekable();
+    let mut attempts = FuturesUnordered::new();
+    let mut last_error = None;
+    loop {
+        if attempts.is_empty() {
+            match pending.next() {
+                Some(addr) => attempts.push(attempt(addr)),
+                None => break,
+            }
+        }
+
+        tokio::select! {
+            Some((addr, result)) = attempts.next() => match result {
+                Ok(stream) => return Ok((stream, addr)),
+                Err(e) if is_port_exhausted(&e) => return Err(e),
+                Err(e) => {
+                    last_error = Some(e);
+                    if let Some(addr) = pending.next() {
+                        attempts.push(attempt(addr));
+                    }
+                }
+            },
+            _ = sleep(stagger), if pending.peek().is_some() => {
+                if let Some(addr) = pending.next() {
+                    attempts.push(attempt(addr));
+                }
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| io::Error::new(ErrorKind::NotFound, "No addresses")))
+}
+
+/// `addrs` with IPv6 and IPv4 addresses taking turns, starting with the family of the
+/// first, and keeping their order within each family.
+fn interleave_families(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let first_is_ipv6 = addrs.first().is_some_and(SocketAddr::is_ipv6);
+    let (mut first, mut second): (Vec<SocketAddr>, Vec<SocketAddr>) = addrs
+        .into_iter()
+        .partition(|addr| addr.is_ipv6() == first_is_ipv6);
+    first.reverse();
+    second.reverse();
+
+    let mut interleaved = Vec::with_capacity(first.len() + second.len());
+    while !first.is_empty() || !second.is_empty() {
+        interleaved.extend(first.pop());
+        interleaved.extend(second.pop());
+    }
+    interleaved
+}
+
+/// Connection to a tunnel target, from this host, through an upstream proxy or through
+/// the WireGuard egress.
+pub enum Outbound {
+    Tcp(TcpStream),
+    Upstream(UpstreamStream),
+    #[cfg(feature = "wireguard")]
+    WireGuard(WireGuardStream),
+}
+
+impl AsyncRead for Outbound {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Outbound::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Outbound::Upstream(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(feature = "wireguard")]
+            Outbound::WireGuard(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Outbound {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Outbound::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Outbound::Upstream(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(feature = "wireguard")]
+            Outbound::WireGuard(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Outbound::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Outbound::Upstream(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(feature = "wireguard")]
+            Outbound::WireGuard(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Outbound::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Outbound::Upstream(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(feature = "wireguard")]
+            Outbound::WireGuard(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Peer of the WireGuard egress, when connections leave through one.
+pub fn wireguard_peer() -> Option<SocketAddr> {
+    #[cfg(feature = "wireguard")]
+    return wireguard::device().map(|device| device.peer());
+
+    #[cfg(not(feature = "wireguard"))]
+    None
+}
+
+pub fn is_port_exhausted(e: &io::Error) -> bool {
+    matches!(e.kind(), ErrorKind::AddrInUse | ErrorKind::AddrNotAvailable)
+}
+
+/// Status telling the client why the destination couldn't be reached: 503 when the proxy
+/// ran out of local ports, 504 when the destination or its DNS didn't answer in time and
+/// 502 for everything else, from refused connects to unresolvable hosts.
+pub fn error_status(e: &(dyn Error + 'static)) -> StatusCode {
+    let mut source = Some(e);
+    while let Some(e) = source {
+        if let Some(e) = e.downcast_ref::<io::Error>() {
+            if is_port_exhausted(e) {
+                return StatusCode::SERVICE_UNAVAILABLE;
+            }
+            if e.kind() == ErrorKind::TimedOut {
+                return StatusCode::GATEWAY_TIMEOUT;
+            }
+        }
+        source = e.source();
+    }
+    StatusCode::BAD_GATEWAY
+}
+
+/// Open an upstream connection for a tunnel. `local_ip` is the address connections are
+/// bound to when it matches the destination's family. IPv6 destinations get a fresh source
+/// address from `--ipv6-egress-prefix`, so every tunnel leaves with its own identity.
+/// `--egress-addr` ranges of the destination's family take the place of both.
+pub async fn connect(
+    addr: SocketAddr,
+    local_ip: Option<IpAddr>,
+    mark: Option<Fwmark>,
+) -> io::Result<TcpStream> {
+    let options = Opt::global();
+
+    let mut last_error = None;
+    for _ in 0..BIND_ATTEMPTS {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        set_fwmark(SockRef::from(&socket), mark)?;
+
+        let bind_addr = match (
+            addr,
+            egress::pick(addr.is_ipv6()),
+            options.ipv6_egress_prefix,
+        ) {
+            (_, Some(ip), _) => {
+                // Like the IPv6 prefix, the ranges only have to be routed to the host
+                match ip {
+                    IpAddr::V4(_) => SockRef::from(&socket).set_freebind(true)?,
+                    IpAddr::V6(_) => SockRef::from(&socket).set_freebind_ipv6(true)?,
+                }
+                let port = options
+                    .local_port_range
+                    .map(|range| range.random_port())
+                    .unwrap_or(0);
+                Some(SocketAddr::new(ip, port))
+            }
+            (SocketAddr::V6(_), None, Some(prefix)) => {
+                // The address isn't configured on any interface, only routed to the host
+                SockRef::from(&socket).set_freebind_ipv6(true)?;
+                let port = options
+                    .local_port_range
+                    .map(|range| range.random_port())
+                    .unwrap_or(0);
+                Some(SocketAddr::new(prefix.random_addr(), port))
+            }
+            _ => local_ip
+                .filter(|ip| ip.is_ipv6() == addr.is_ipv6())
+                .map(|ip| {
+                    // Without a range the kernel picks a free ephemeral port, whatever the family
+                    let port = options
+                        .local_port_range
+                        .map(|range| range.random_port())
+                        .unwrap_or(0);
+                    SocketAddr::new(ip, port)
+                }),
+        };
+
+        if let Some(bind_addr) = bind_addr {
+            if let Err(e) = socket.bind(bind_addr) {
+                if is_port_exhausted(&e) {
+                    last_error = Some(e);
+                    continue;
+                }
+                return Err(e);
+            }
+        }
+        mss::clamp(
+            SockRef::from(&socket),
+            addr,
+            bind_addr.map(|bind_addr| bind_addr.ip()),
+            mark,
+        )?;
+        fastopen::connect(SockRef::from(&socket))?;
+
+        // The 4-tuple can still be taken even though the bind succeeded
+        match socket.connect(addr).await {
+            Err(e) if is_port_exhausted(&e) && bind_addr.is_some() => last_error = Some(e),
+            result => return result,
+        }
+    }
+
+    Err(io::Error::new(
+        ErrorKind::AddrInUse,
+        format!(
+            "Local port range exhausted: {}",
+            last_error.map(|e| e.to_string()).unwrap_or_default()
+        ),
+    ))
+}
+
+/// Connect to `addr` (`host:port`) with the firewall mark set and the MSS clamped, e.g. to
+/// reach the upstream proxy.
+pub async fn connect_host(addr: &str, mark: Option<Fwmark>) -> io::Result<TcpStream> {
+    if mark.is_none() && !mss::enabled() && !fastopen::enabled() {
+        return TcpStream::connect(addr).await;
+    }
+
+    let mut last_error = None;
+    for addr in lookup_host(addr).await? {
+        let socket = match addr {
+            SocketAddr::V4(_) => TcpSocket::new_v4()?,
+            SocketAddr::V6(_) => TcpSocket::new_v6()?,
+        };
+        set_fwmark(SockRef::from(&socket), mark)?;
+        mss::clamp(SockRef::from(&socket), addr, None, mark)?;
+        fastopen::connect(SockRef::from(&socket))?;
+
+        match socket.connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| io::Error::new(ErrorKind::NotFound, "No addresses")))
+}
+
+/// Firewall mark (`SO_MARK`) of outbound sockets, so `ip rule add fwmark ...` can route proxy
+/// egress through another routing table or a VPN. Given in decimal or as `0x` hex.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fwmark(pub u32);
+
+impl FromStr for Fwmark {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let mark = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => s.parse::<u32>(),
+        };
+        mark.map(Fwmark)
+            .map_err(|e| format!("Invalid firewall mark '{s}': {e}"))
+    }
+}
+
+impl fmt::Display for Fwmark {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#x}", self.0)
+    }
+}
+
+/// Firewall mark for destinations matching a host pattern, e.g. `*.example.com=0x20`.
+#[derive(Debug, Clone)]
+pub struct FwmarkRule {
+    pub pattern: String,
+    pub mark: Fwmark,
+}
+
+impl FromStr for FwmarkRule {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, mark) = s
+            .trim()
+            .rsplit_once('=')
+            .ok_or_else(|| format!("Expected 'pattern=mark', got '{s}'"))?;
+
+        let pattern = pattern.trim();
+        if pattern.is_empty() {
+            return Err(format!("Missing host pattern in '{s}'"));
+        }
+        Ok(FwmarkRule {
+            pattern: pattern.to_string(),
+            mark: mark.parse()?,
+        })
+    }
+}
+
+/// Firewall mark of connections to `target` (`host:port`), logged as a routing decision.
+/// Connections through the WireGuard egress have no socket of their own, the tunnel's
+/// socket carries `--fwmark`.
+pub fn fwmark_for(target: &str, client: &str) -> Option<Fwmark> {
+    if wireguard_peer().is_some() {
+        return None;
+    }
+
+    let options = Opt::global();
+    let host = split_host_port(target)
+        .map(|(host, _)| host)
+        .unwrap_or(target);
+
+    let (mark, decision) = policy::fwmark(host, &options.fwmark_rule, options.fwmark)?;
+    decision.log(client, target);
+    Some(mark)
+}
+
+pub fn set_fwmark(socket: SockRef<'_>, mark: Option<Fwmark>) -> io::Result<()> {
+    match mark {
+        Some(mark) => sockopt::set_mark(socket, mark.0),
+        None => Ok(()),
+    }
+}
+
+/// hyper connector for direct requests whose connections carry a firewall mark, leave
+/// from an `--egress-addr`, get their MSS clamped, use Fast Open or go to IPv6
+/// destinations, which `HttpConnector` can't set up the way tunnels are. Like the pinned
+/// connector, it only connects to the already resolved and validated addresses.
+#[derive(Debug, Clone)]
+pub struct MarkedConnector {
+    addrs: Vec<SocketAddr>,
+    local_ip: Option<IpAddr>,
+    mark: Option<Fwmark>,
+}
+
+impl MarkedConnector {
+    pub fn new(addrs: Vec<SocketAddr>, local_ip: Option<IpAddr>, mark: Option<Fwmark>) -> Self {
+        MarkedConnector {
+            addrs,
+            local_ip,
+            mark,
+        }
+    }
+}
+
+impl Service<Uri> for MarkedConnector {
+    type Response = TcpStream;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<TcpStream>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _dst: Uri) -> Self::Future {
+        let connector = self.clone();
+        Box::pin(async move {
+            let (stream, _) = race(connector.addrs, connector.local_ip, connector.mark).await?;
+            Ok(stream)
+        })
+    }
+}
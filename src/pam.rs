@@ -0,0 +1,182 @@
+use crate::auth::AuthProvider;
+use crate::dylib::Library;
+
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+
+// Linux-PAM return codes, flags and message styles (security/_pam_types.h)
+const PAM_SUCCESS: c_int = 0;
+const PAM_BUF_ERR: c_int = 5;
+const PAM_PERM_DENIED: c_int = 6;
+const PAM_AUTH_ERR: c_int = 7;
+const PAM_USER_UNKNOWN: c_int = 10;
+const PAM_MAXTRIES: c_int = 11;
+const PAM_NEW_AUTHTOK_REQD: c_int = 12;
+const PAM_ACCT_EXPIRED: c_int = 13;
+const PAM_CONV_ERR: c_int = 19;
+const PAM_SILENT: c_int = 0x8000;
+const PAM_DISALLOW_NULL_AUTHTOK: c_int = 0x0001;
+const PAM_PROMPT_ECHO_OFF: c_int = 1;
+const PAM_PROMPT_ECHO_ON: c_int = 2;
+
+#[repr(C)]
+struct PamMessage {
+    msg_style: c_int,
+    msg: *const c_char,
+}
+
+#[repr(C)]
+struct PamResponse {
+    resp: *mut c_char,
+    resp_retcode: c_int,
+}
+
+type Conversation = unsafe extern "C" fn(
+    c_int,
+    *mut *const PamMessage,
+    *mut *mut PamResponse,
+    *mut c_void,
+) -> c_int;
+
+#[repr(C)]
+struct PamConv {
+    conv: Conversation,
+    appdata_ptr: *mut c_void,
+}
+
+type Start =
+    unsafe extern "C" fn(*const c_char, *const c_char, *const PamConv, *mut *mut c_void) -> c_int;
+type Authenticate = unsafe extern "C" fn(*mut c_void, c_int) -> c_int;
+type AcctMgmt = unsafe extern "C" fn(*mut c_void, c_int) -> c_int;
+type End = unsafe extern "C" fn(*mut c_void, c_int) -> c_int;
+type StrError = unsafe extern "C" fn(*mut c_void, c_int) -> *const c_char;
+
+/// Authenticates system accounts through a PAM service, loaded when `--pam-service` is set.
+/// Reading the shadow database usually needs the proxy to run as root.
+pub struct Pam {
+    _library: Library,
+    service: CString,
+    start: Start,
+    authenticate: Authenticate,
+    acct_mgmt: AcctMgmt,
+    end: End,
+    strerror: StrError,
+}
+
+/// What the conversation function answers prompts with.
+struct Credentials {
+    login: CString,
+    password: CString,
+}
+
+/// Answer password prompts with the client's password and login prompts with its login.
+/// Responses are allocated with malloc, PAM frees them.
+unsafe extern "C" fn conversation(
+    num_msg: c_int,
+    msg: *mut *const PamMessage,
+    resp: *mut *mut PamResponse,
+    appdata_ptr: *mut c_void,
+) -> c_int {
+    if num_msg <= 0 || msg.is_null() || resp.is_null() || appdata_ptr.is_null() {
+        return PAM_CONV_ERR;
+    }
+    let credentials = &*(appdata_ptr as *const Credentials);
+
+    let responses =
+        libc::calloc(num_msg as usize, std::mem::size_of::<PamResponse>()) as *mut PamResponse;
+    if responses.is_null() {
+        return PAM_BUF_ERR;
+    }
+
+    for i in 0..num_msg as usize {
+        // Linux-PAM passes an array of pointers to messages
+        let message = &**msg.add(i);
+        let answer = match message.msg_style {
+            PAM_PROMPT_ECHO_OFF => Some(&credentials.password),
+            PAM_PROMPT_ECHO_ON => Some(&credentials.login),
+            _ => None,
+        };
+        if let Some(answer) = answer {
+            (*responses.add(i)).resp = libc::strdup(answer.as_ptr());
+        }
+    }
+
+    *resp = responses;
+    PAM_SUCCESS
+}
+
+impl Pam {
+    pub fn new(service: &str) -> Result<Pam, String> {
+        let library = Library::open(&["libpam.so.0", "libpam.so"])?;
+
+        unsafe {
+            Ok(Pam {
+                service: CString::new(service).map_err(|e| e.to_string())?,
+                start: library.symbol("pam_start")?,
+                authenticate: library.symbol("pam_authenticate")?,
+                acct_mgmt: library.symbol("pam_acct_mgmt")?,
+                end: library.symbol("pam_end")?,
+                strerror: library.symbol("pam_strerror")?,
+                _library: library,
+            })
+        }
+    }
+
+    fn error(&self, handle: *mut c_void, code: c_int) -> String {
+        let message = unsafe { (self.strerror)(handle, code) };
+        if message.is_null() {
+            return format!("PAM error {code}");
+        }
+        unsafe { CStr::from_ptr(message) }
+            .to_string_lossy()
+            .into_owned()
+    }
+}
+
+impl AuthProvider for Pam {
+    fn name(&self) -> &'static str {
+        "PAM"
+    }
+
+    fn authenticate(&self, login: &str, password: &str) -> Result<bool, String> {
+        let (Ok(login), Ok(password)) = (CString::new(login), CString::new(password)) else {
+            return Ok(false);
+        };
+        let credentials = Credentials { login, password };
+        let conv = PamConv {
+            conv: conversation,
+            appdata_ptr: &credentials as *const Credentials as *mut c_void,
+        };
+
+        let mut handle = ptr::null_mut();
+        let code = unsafe {
+            (self.start)(
+                self.service.as_ptr(),
+                credentials.login.as_ptr(),
+                &conv,
+                &mut handle,
+            )
+        };
+        if code != PAM_SUCCESS {
+            return Err(self.error(handle, code));
+        }
+
+        // Authentication alone accepts expired or locked accounts, account management doesn't
+        let mut code =
+            unsafe { (self.authenticate)(handle, PAM_SILENT | PAM_DISALLOW_NULL_AUTHTOK) };
+        if code == PAM_SUCCESS {
+            code = unsafe { (self.acct_mgmt)(handle, PAM_SILENT | PAM_DISALLOW_NULL_AUTHTOK) };
+        }
+
+        let result = match code {
+            PAM_SUCCESS => Ok(true),
+            PAM_AUTH_ERR | PAM_USER_UNKNOWN | PAM_MAXTRIES | PAM_PERM_DENIED | PAM_ACCT_EXPIRED
+            | PAM_NEW_AUTHTOK_REQD => Ok(false),
+            code => Err(self.error(handle, code)),
+        };
+
+        unsafe { (self.end)(handle, code) };
+        result
+    }
+}
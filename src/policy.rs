@@ -0,0 +1,451 @@
+use crate::alerts;
+use crate::bans;
+use crate::geoip;
+use crate::hostmatch;
+use crate::journal::{self, Entry};
+use crate::maintenance;
+use crate::options::Opt;
+use crate::outbound::{Fwmark, FwmarkRule};
+use crate::probe::{is_echo_host, is_probe_host};
+use crate::rules::{self, Matched};
+use crate::signed_token::{self, TokenHeader};
+use crate::stats;
+use crate::upstream::Upstream;
+use crate::usage;
+use crate::users::User;
+use crate::utils::{formatted_time, label_user, loggable, UNVERIFIED};
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::OnceLock;
+
+use chrono::Utc;
+use hyper::http::uri::Authority;
+use hyper::Uri;
+use log::{log, Level};
+use wildmatch::WildMatch;
+
+static DENIED_HOSTS: OnceLock<Vec<String>> = OnceLock::new();
+
+// Rule of a Host header naming another target, logged even when `--host-mismatch log`
+// lets it through
+const HOST_MISMATCH_RULE: &str = "host-header:mismatch";
+
+/// What to do about a Host header that names another target than the request line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HostMismatch {
+    Ignore,
+    Log,
+    Block,
+}
+
+impl FromStr for HostMismatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(HostMismatch::Ignore),
+            "log" => Ok(HostMismatch::Log),
+            "block" => Ok(HostMismatch::Block),
+            _ => Err(format!(
+                "Unknown Host header mismatch policy '{s}', expected ignore, log or block"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for HostMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HostMismatch::Ignore => write!(f, "ignore"),
+            HostMismatch::Log => write!(f, "log"),
+            HostMismatch::Block => write!(f, "block"),
+        }
+    }
+}
+
+/// Whether a check lets a request through.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Allow,
+    Deny,
+}
+
+impl fmt::Display for Verdict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Verdict::Allow => write!(f, "allow"),
+            Verdict::Deny => write!(f, "deny"),
+        }
+    }
+}
+
+/// Outcome of one access, authentication or routing check and the rule that decided it.
+/// Rule IDs are `<check>:<rule>`, the rule being what matched (a host pattern, a login,
+/// an upstream) or `default` when nothing did and the check's default applied. They only
+/// depend on the rule itself, so they stay the same when other rules are added or removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Decision {
+    pub rule: String,
+    pub verdict: Verdict,
+}
+
+impl Decision {
+    pub fn allow(rule: impl Into<String>) -> Decision {
+        Decision {
+            rule: rule.into(),
+            verdict: Verdict::Allow,
+        }
+    }
+
+    pub fn deny(rule: impl Into<String>) -> Decision {
+        Decision {
+            rule: rule.into(),
+            verdict: Verdict::Deny,
+        }
+    }
+
+    pub fn is_allowed(&self) -> bool {
+        self.verdict == Verdict::Allow
+    }
+
+    pub fn for_tenant(mut self, tenant: Option<&str>) -> Decision {
+        self.rule = tenant_rule(self.rule, tenant);
+        self
+    }
+
+    /// Log the decision about a request to `target` and keep it for the admin log viewer.
+    pub fn log(&self, client: &str, target: &str) {
+        let target = loggable(target, client);
+        let time = formatted_time();
+        // Every request passes several checks, so only denials are worth logging by default
+        let level = match self.verdict {
+            Verdict::Allow if self.rule.ends_with(HOST_MISMATCH_RULE) => Level::Info,
+            Verdict::Allow => Level::Debug,
+            Verdict::Deny => {
+                stats::count_denied(&self.rule);
+                alerts::record_blocked_attempt(client, target, &self.rule);
+                Level::Info
+            }
+        };
+        log!(
+            level,
+            "[{time}] Policy {} rule={} target={target} client={client}",
+            self.verdict,
+            self.rule
+        );
+        // The journal is kept and shown per user, so it only learns where a client went
+        // once it knows who the client is
+        let target = match label_user(client) {
+            UNVERIFIED => "-",
+            _ => target,
+        };
+        journal::record(Entry {
+            time: Utc::now(),
+            verdict: self.verdict,
+            rule: self.rule.clone(),
+            target: target.to_string(),
+            client: client.to_string(),
+        });
+    }
+}
+
+/// Tenant rules are prefixed with the tenant's name, e.g. `acme/hosts:*.acme.com`.
+pub fn tenant_rule(rule: String, tenant: Option<&str>) -> String {
+    match tenant {
+        Some(tenant) => format!("{tenant}/{rule}"),
+        None => rule,
+    }
+}
+
+/// Framing check, against request smuggling. The proxy and the server behind it must agree
+/// on where a request ends, so requests that could be read two ways are refused: more than
+/// one Host header, Transfer-Encoding together with Content-Length, in HTTP/1.0 or not
+/// ending in a single `chunked`, and Content-Length that isn't one number. Each slice holds
+/// the values of one header, repeated headers included.
+pub fn check_framing(
+    http10: bool,
+    hosts: usize,
+    content_lengths: &[&str],
+    transfer_encodings: &[&str],
+) -> Decision {
+    if hosts > 1 {
+        return Decision::deny("framing:host");
+    }
+
+    if !transfer_encodings.is_empty() {
+        let codings = transfer_encodings
+            .iter()
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .collect::<Vec<&str>>();
+        let is_chunked = |coding: &str| coding.eq_ignore_ascii_case("chunked");
+        return match (content_lengths.is_empty(), http10) {
+            (false, _) => Decision::deny("framing:te-and-length"),
+            (_, true) => Decision::deny("framing:te-http10"),
+            _ if codings.iter().filter(|coding| is_chunked(coding)).count() != 1
+                || !codings.last().is_some_and(|coding| is_chunked(coding)) =>
+            {
+                Decision::deny("framing:transfer-encoding")
+            }
+            _ => Decision::allow("framing:chunked"),
+        };
+    }
+
+    let mut lengths = content_lengths
+        .iter()
+        .flat_map(|value| value.split(','))
+        .map(str::trim);
+    match lengths.next() {
+        None => Decision::allow("framing:default"),
+        Some(first)
+            if !first.is_empty()
+                && first.bytes().all(|byte| byte.is_ascii_digit())
+                && lengths.all(|length| length == first) =>
+        {
+            Decision::allow("framing:content-length")
+        }
+        Some(_) => Decision::deny("framing:content-length"),
+    }
+}
+
+/// Maintenance check: while maintenance is on, every new request is refused.
+pub fn check_maintenance() -> Decision {
+    match maintenance::current() {
+        Some(_) => Decision::deny("maintenance:on"),
+        None => Decision::allow("maintenance:off"),
+    }
+}
+
+/// Allowed hosts check: a host matching one of `--deny-hosts` is refused, otherwise the
+/// first matching pattern lets it through. Patterns are wildcards or `re:` regular
+/// expressions, see [`hostmatch::matches`]. Without patterns every host that isn't denied
+/// is allowed, and the proxy's own probe hosts always are.
+pub fn check_host(host: &str, allowed_hosts: &[String]) -> Decision {
+    if is_probe_host(host) || is_echo_host(host) {
+        return Decision::allow("hosts:probe");
+    }
+    match rules::evaluate(denied_hosts(), allowed_hosts, |pattern| {
+        hostmatch::matches(pattern, host)
+    }) {
+        Matched::Deny(pattern) => Decision::deny(format!("deny-hosts:{pattern}")),
+        Matched::Allow(pattern) => Decision::allow(format!("hosts:{pattern}")),
+        Matched::Default { allowed: true } => Decision::allow("hosts:default"),
+        Matched::Default { allowed: false } => Decision::deny("hosts:default"),
+    }
+}
+
+/// Client address check: a client in one of `--deny-ips` or `--deny-client-countries`, or
+/// banned for failing to authenticate, is refused, otherwise one in any `--allow-ips`
+/// network is let through. Without `--allow-ips` every client that isn't denied is.
+pub fn check_client(ip: IpAddr) -> Decision {
+    let options = Opt::global();
+    let matched = rules::evaluate(&options.deny_ips, &options.allow_ips, |net| {
+        net.contains(ip)
+    });
+    if let Matched::Deny(net) = matched {
+        return Decision::deny(format!("deny-ips:{net}"));
+    }
+    if bans::is_banned(ip) {
+        return Decision::deny("bans:failed-logins");
+    }
+    if let Some(country) = geoip::denied_client_country(ip) {
+        return Decision::deny(format!("client-countries:{country}"));
+    }
+
+    match matched {
+        Matched::Allow(net) => Decision::allow(format!("allow-ips:{net}")),
+        Matched::Default { allowed: false } => Decision::deny("allow-ips:default"),
+        _ => Decision::allow("allow-ips:default"),
+    }
+}
+
+/// Whether a connection just accepted from `addr` may be served, logging it when not. The
+/// listeners close a refused connection without reading from it.
+pub fn admit_client(addr: SocketAddr) -> bool {
+    let decision = check_client(addr.ip());
+    if !decision.is_allowed() {
+        decision.log(&addr.to_string(), "-");
+    }
+    decision.is_allowed()
+}
+
+/// CONNECT port check: tunnels may only be opened to the `--allow-connect-ports`, so
+/// leaked credentials don't make the proxy a relay for mail or IRC. The proxy's own probe
+/// hosts are answered on any port.
+pub fn check_connect_port(host: &str, port: u16) -> Decision {
+    if is_probe_host(host) || is_echo_host(host) {
+        return Decision::allow("connect-ports:probe");
+    }
+
+    match Opt::global().allow_connect_ports.find(port) {
+        Some(range) => Decision::allow(format!("connect-ports:{range}")),
+        None => Decision::deny("connect-ports:default"),
+    }
+}
+
+/// User hosts check, for accounts of `--users-file` with their own hosts: a host matching
+/// one of the user's `deny_hosts` is refused, and with `hosts` only the hosts matching one
+/// are let through. It comes after [`check_host`], so the global lists still apply.
+pub fn check_user_host(user: &User, host: &str) -> Decision {
+    let login = &user.login;
+    if is_probe_host(host) || is_echo_host(host) {
+        return Decision::allow(format!("user-hosts:{login}/probe"));
+    }
+    match rules::evaluate(&user.deny_hosts, &user.hosts, |pattern| {
+        hostmatch::matches(pattern, host)
+    }) {
+        Matched::Deny(pattern) => Decision::deny(format!("user-deny-hosts:{login}/{pattern}")),
+        Matched::Allow(pattern) => Decision::allow(format!("user-hosts:{login}/{pattern}")),
+        Matched::Default { allowed: true } => {
+            Decision::allow(format!("user-hosts:{login}/default"))
+        }
+        Matched::Default { allowed: false } => {
+            Decision::deny(format!("user-hosts:{login}/default"))
+        }
+    }
+}
+
+/// User CONNECT port check: a user with `connect_ports` may only open tunnels to those,
+/// besides the `--allow-connect-ports` every tunnel is checked against.
+pub fn check_user_connect_port(user: &User, host: &str, port: u16) -> Decision {
+    let login = &user.login;
+    if is_probe_host(host) || is_echo_host(host) {
+        return Decision::allow(format!("user-connect-ports:{login}/probe"));
+    }
+
+    match user.connect_ports.as_ref().map(|ports| ports.find(port)) {
+        None => Decision::allow(format!("user-connect-ports:{login}/default")),
+        Some(Some(range)) => Decision::allow(format!("user-connect-ports:{login}/{range}")),
+        Some(None) => Decision::deny(format!("user-connect-ports:{login}/default")),
+    }
+}
+
+/// Quota check, for accounts of `--users-file` with a `quota`: once the bytes they
+/// transferred in its period reach it, new requests and tunnels are refused. Transfers
+/// already running aren't cut off.
+pub fn check_quota(user: &User, host: &str) -> Decision {
+    let login = &user.login;
+    if is_probe_host(host) || is_echo_host(host) {
+        return Decision::allow(format!("quota:{login}/probe"));
+    }
+    let Some(quota) = user.quota else {
+        return Decision::allow(format!("quota:{login}/default"));
+    };
+
+    let period = user
+        .quota_period
+        .map_or_else(|| "total".to_string(), |period| period.to_string());
+    match usage::counters(login).used(user.quota_period) < quota {
+        true => Decision::allow(format!("quota:{login}/{period}")),
+        false => Decision::deny(format!("quota:{login}/{period}")),
+    }
+}
+
+/// Host header check, for CONNECT and absolute-form requests that name their target twice.
+/// A client could put an allowed host in one and go to the other, so with
+/// `--host-mismatch block` a Host header naming another host or port than the target is
+/// refused, with `log` it's let through but logged. A port left out is the scheme's
+/// default, for CONNECT any. Requests without a Host header or an authority in the target
+/// have nothing to compare.
+pub fn check_host_header(target: &Uri, connect: bool, host_header: Option<&str>) -> Decision {
+    let mode = Opt::global().host_mismatch;
+    let default_port = match (connect, target.scheme_str()) {
+        (true, _) => None,
+        (false, Some("https")) => Some(443),
+        (false, _) => Some(80),
+    };
+    let (Some(target), Some(host_header)) = (target.authority(), host_header) else {
+        return Decision::allow("host-header:default");
+    };
+    if mode == HostMismatch::Ignore {
+        return Decision::allow("host-header:ignore");
+    }
+
+    let matches = host_header.trim().parse::<Authority>().is_ok_and(|host| {
+        let same_port = match (host.port_u16(), target.port_u16()) {
+            (Some(host_port), Some(target_port)) => host_port == target_port,
+            (None, None) => true,
+            (Some(port), None) | (None, Some(port)) => {
+                default_port.is_none_or(|default_port| port == default_port)
+            }
+        };
+        host.host().eq_ignore_ascii_case(target.host()) && same_port
+    });
+    match (matches, mode) {
+        (true, _) => Decision::allow("host-header:match"),
+        (false, HostMismatch::Block) => Decision::deny(HOST_MISMATCH_RULE),
+        (false, _) => Decision::allow(HOST_MISMATCH_RULE),
+    }
+}
+
+/// Patterns of `--deny-hosts`, split once.
+fn denied_hosts() -> &'static [String] {
+    DENIED_HOSTS.get_or_init(|| {
+        Opt::global()
+            .deny_hosts
+            .iter()
+            .flat_map(|list| list.split(','))
+            .map(|pattern| pattern.trim().to_string())
+            .filter(|pattern| !pattern.is_empty())
+            .collect()
+    })
+}
+
+/// Secret token check. `token_header` is the listener's own token header, the SHA-256 of
+/// the token or a signed value expiring soon enough. A client sending only the other
+/// listener's header (`other_header`) is let through.
+pub fn check_token(
+    secret_token: &str,
+    disabled: bool,
+    token_header: Option<&str>,
+    other_header: bool,
+) -> Decision {
+    if secret_token.is_empty() || disabled {
+        return Decision::allow("token:default");
+    }
+
+    match token_header.map(|header| signed_token::verify(secret_token, header)) {
+        Some(TokenHeader::Static) => Decision::allow("token:valid"),
+        Some(TokenHeader::Signed) => Decision::allow("token:signed"),
+        Some(TokenHeader::StaticRefused) => Decision::deny("token:unsigned"),
+        Some(TokenHeader::Expired) => Decision::deny("token:expired"),
+        Some(TokenHeader::TooLong) => Decision::deny("token:lifetime"),
+        Some(TokenHeader::Invalid) => Decision::deny("token:invalid"),
+        None if other_header => Decision::allow("token:other-listener"),
+        None => Decision::deny("token:missing"),
+    }
+}
+
+/// Where a request goes: through the upstream proxy or pool or the WireGuard peer if there is one,
+/// otherwise direct.
+pub fn route(upstream: Option<&Upstream>, wireguard: Option<SocketAddr>) -> Decision {
+    match (upstream, wireguard) {
+        (Some(Upstream::Proxy(upstream)), _) => {
+            Decision::allow(format!("route:upstream/{}", upstream.addr))
+        }
+        (Some(Upstream::Pool(pool)), _) => Decision::allow(format!("route:pool/{}", pool.name)),
+        (None, Some(peer)) => Decision::allow(format!("route:wireguard/{peer}")),
+        (None, None) => Decision::allow("route:default"),
+    }
+}
+
+/// Firewall mark of outbound connections to `host`: the first matching rule, otherwise the
+/// default mark. `None` leaves the connections unmarked.
+pub fn fwmark(
+    host: &str,
+    rules: &[FwmarkRule],
+    default: Option<Fwmark>,
+) -> Option<(Fwmark, Decision)> {
+    match rules
+        .iter()
+        .find(|rule| WildMatch::new(&rule.pattern).matches(host))
+    {
+        Some(rule) => Some((
+            rule.mark,
+            Decision::allow(format!("fwmark:{}", rule.pattern)),
+        )),
+        None => default.map(|mark| (mark, Decision::allow("fwmark:default"))),
+    }
+}
@@ -0,0 +1,343 @@
+use crate::options::Opt;
+use crate::outbound::{connect_host, Fwmark};
+use crate::upstream::{self, UpstreamProxy, UpstreamStream};
+
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, Instant};
+
+use futures_util::future::join_all;
+use log::{info, warn};
+use tokio::time::{sleep, timeout};
+use wildmatch::WildMatch;
+
+// A pool proxy that doesn't accept a connection in this time is taken as down
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+static POOLS: OnceLock<Vec<Arc<Pool>>> = OnceLock::new();
+
+/// How a pool spreads connections over its proxies.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// Each proxy in turn
+    RoundRobin,
+    /// The proxy with the fewest open connections
+    LeastConnections,
+    /// The proxy that accepted connections fastest lately
+    Latency,
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "least-conn" => Ok(Strategy::LeastConnections),
+            "latency" => Ok(Strategy::Latency),
+            _ => Err(format!(
+                "Unknown pool strategy '{s}', expected round-robin, least-conn or latency"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Strategy::RoundRobin => write!(f, "round-robin"),
+            Strategy::LeastConnections => write!(f, "least-conn"),
+            Strategy::Latency => write!(f, "latency"),
+        }
+    }
+}
+
+/// Upstream proxies sharing the connections of the routes that name the pool, with
+/// their reachability checked every `health` seconds.
+#[derive(Debug, Clone)]
+pub struct PoolConfig {
+    pub name: String,
+    pub proxies: Vec<String>,
+    pub strategy: Strategy,
+    pub health: u64,
+}
+
+impl FromStr for PoolConfig {
+    type Err = String;
+
+    /// Parse `name=eu;proxies=10.0.0.1:3128,socks5://10.0.0.2:1080;strategy=latency;health=10`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut pool = PoolConfig {
+            name: String::new(),
+            proxies: Vec::new(),
+            strategy: Strategy::RoundRobin,
+            health: 10,
+        };
+
+        for field in s
+            .split(';')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+        {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key=value, got '{field}'"))?;
+            let value = value.trim();
+
+            match key.trim() {
+                "name" => pool.name = value.to_string(),
+                "proxies" => {
+                    pool.proxies = value
+                        .split(',')
+                        .map(|proxy| proxy.trim().to_string())
+                        .filter(|proxy| !proxy.is_empty())
+                        .collect();
+                    for proxy in &pool.proxies {
+                        upstream::check_addr(proxy)?;
+                    }
+                }
+                "strategy" => pool.strategy = value.parse()?,
+                "health" => {
+                    pool.health = value
+                        .parse::<u64>()
+                        .ok()
+                        .filter(|health| *health > 0)
+                        .ok_or_else(|| format!("Invalid pool health interval '{value}'"))?
+                }
+                key => return Err(format!("Unknown pool setting '{key}'")),
+            }
+        }
+
+        if pool.name.is_empty() {
+            return Err("Pool name is required".to_string());
+        }
+        if pool.proxies.is_empty() {
+            return Err(format!("Pool {} needs at least one proxy", pool.name));
+        }
+        Ok(pool)
+    }
+}
+
+/// Pool for destinations matching a host pattern, e.g. `*.example.eu=eu`.
+#[derive(Debug, Clone)]
+pub struct PoolRoute {
+    pub pattern: String,
+    pub pool: String,
+}
+
+impl FromStr for PoolRoute {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (pattern, pool) = s
+            .trim()
+            .rsplit_once('=')
+            .ok_or_else(|| format!("Expected 'pattern=pool', got '{s}'"))?;
+
+        let (pattern, pool) = (pattern.trim(), pool.trim());
+        if pattern.is_empty() || pool.is_empty() {
+            return Err(format!("Missing host pattern or pool in '{s}'"));
+        }
+        Ok(PoolRoute {
+            pattern: pattern.to_string(),
+            pool: pool.to_string(),
+        })
+    }
+}
+
+/// Proxy of a pool, with what the strategies pick by.
+#[derive(Debug)]
+struct Member {
+    proxy: UpstreamProxy,
+    healthy: AtomicBool,
+    connections: AtomicUsize,
+    /// Moving average of the connect time in microseconds, `u64::MAX` until measured
+    latency: AtomicU64,
+}
+
+impl Member {
+    fn up(&self, pool: &str, latency: Duration) {
+        let sample = latency.as_micros() as u64;
+        let average = match self.latency.load(Ordering::Relaxed) {
+            u64::MAX => sample,
+            average => (average * 7 + sample) / 8,
+        };
+        self.latency.store(average, Ordering::Relaxed);
+
+        if !self.healthy.swap(true, Ordering::Relaxed) {
+            info!("Upstream {} of pool {pool} is back up", self.proxy.addr);
+        }
+    }
+
+    fn down(&self, pool: &str, e: &io::Error) {
+        if self.healthy.swap(false, Ordering::Relaxed) {
+            warn!("Upstream {} of pool {pool} is down: {e}", self.proxy.addr);
+        }
+    }
+}
+
+/// Connection counted against a pool proxy until dropped, for `least-conn`.
+#[derive(Debug)]
+pub struct Lease(Arc<Member>);
+
+impl Lease {
+    fn new(member: Arc<Member>) -> Lease {
+        member.connections.fetch_add(1, Ordering::Relaxed);
+        Lease(member)
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug)]
+pub struct Pool {
+    pub name: String,
+    strategy: Strategy,
+    health: Duration,
+    members: Vec<Arc<Member>>,
+    next: AtomicUsize,
+}
+
+impl Pool {
+    fn new(config: &PoolConfig) -> Pool {
+        Pool {
+            name: config.name.clone(),
+            strategy: config.strategy,
+            health: Duration::from_secs(config.health),
+            members: config
+                .proxies
+                .iter()
+                .map(|addr| {
+                    Arc::new(Member {
+                        proxy: UpstreamProxy::parse(addr),
+                        healthy: AtomicBool::new(true),
+                        connections: AtomicUsize::new(0),
+                        latency: AtomicU64::new(u64::MAX),
+                    })
+                })
+                .collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// Proxies in the order they are tried: the healthy ones as the strategy ranks them,
+    /// then those that are down, in case they have come back since the last check.
+    fn candidates(&self) -> Vec<Arc<Member>> {
+        let (mut healthy, down): (Vec<Arc<Member>>, Vec<Arc<Member>>) = self
+            .members
+            .iter()
+            .cloned()
+            .partition(|member| member.healthy.load(Ordering::Relaxed));
+
+        match self.strategy {
+            Strategy::RoundRobin if !healthy.is_empty() => {
+                let start = self.next.fetch_add(1, Ordering::Relaxed) % healthy.len();
+                healthy.rotate_left(start);
+            }
+            Strategy::RoundRobin => {}
+            Strategy::LeastConnections => {
+                healthy.sort_by_key(|member| member.connections.load(Ordering::Relaxed))
+            }
+            Strategy::Latency => {
+                healthy.sort_by_key(|member| member.latency.load(Ordering::Relaxed))
+            }
+        }
+        healthy.extend(down);
+        healthy
+    }
+
+    /// Open a tunnel to `target` through the proxy the strategy picks. A proxy that
+    /// can't be reached is marked down and the next one is tried; one that is reached
+    /// but refuses the tunnel fails the connect, as the target is likely to blame.
+    pub async fn connect(
+        &self,
+        target: &str,
+        mark: Option<Fwmark>,
+        client: &str,
+    ) -> io::Result<UpstreamStream> {
+        let mut last_error = None;
+        for member in self.candidates() {
+            let started = Instant::now();
+            let stream =
+                match timeout(CONNECT_TIMEOUT, connect_host(&member.proxy.addr, mark)).await {
+                    Ok(Ok(stream)) => stream,
+                    Ok(Err(e)) => {
+                        member.down(&self.name, &e);
+                        last_error = Some(e);
+                        continue;
+                    }
+                    Err(_) => {
+                        let e = io::Error::new(io::ErrorKind::TimedOut, "Connect timed out");
+                        member.down(&self.name, &e);
+                        last_error = Some(e);
+                        continue;
+                    }
+                };
+            member.up(&self.name, started.elapsed());
+
+            let lease = Lease::new(member.clone());
+            let stream = member.proxy.tunnel(stream, target, client).await?;
+            return Ok(UpstreamStream::new(
+                stream,
+                member.proxy.addr.clone(),
+                Some(lease),
+            ));
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::other(format!("Upstream pool {} has no proxies", self.name))
+        }))
+    }
+
+    /// Connect to every proxy of the pool every `health` interval, marking those that
+    /// don't answer as down and measuring the latency of those that do.
+    async fn check_health(&self) {
+        let mark = Opt::global().fwmark;
+        loop {
+            join_all(self.members.iter().map(|member| async move {
+                let started = Instant::now();
+                match timeout(CONNECT_TIMEOUT, connect_host(&member.proxy.addr, mark)).await {
+                    Ok(Ok(_)) => member.up(&self.name, started.elapsed()),
+                    Ok(Err(e)) => member.down(&self.name, &e),
+                    Err(_) => member.down(
+                        &self.name,
+                        &io::Error::new(io::ErrorKind::TimedOut, "Health check timed out"),
+                    ),
+                }
+            }))
+            .await;
+            sleep(self.health).await;
+        }
+    }
+}
+
+fn pools() -> &'static [Arc<Pool>] {
+    POOLS.get_or_init(|| {
+        Opt::global()
+            .upstream_pool
+            .iter()
+            .map(|config| Arc::new(Pool::new(config)))
+            .collect()
+    })
+}
+
+/// Pool of the first `--upstream-route` matching `host`.
+pub fn for_host(host: &str) -> Option<Arc<Pool>> {
+    let route = Opt::global()
+        .upstream_route
+        .iter()
+        .find(|route| WildMatch::new(&route.pattern).matches(host))?;
+    pools().iter().find(|pool| pool.name == route.pool).cloned()
+}
+
+/// Check the health of every pool's proxies for as long as the proxy runs.
+pub async fn check_health() {
+    join_all(pools().iter().map(|pool| pool.check_health())).await;
+}
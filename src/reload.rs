@@ -0,0 +1,141 @@
+use crate::config;
+use crate::http::Proxy;
+use crate::options::Opt;
+use crate::sessions;
+use crate::tickets;
+use crate::users::UserStore;
+use crate::utils::{format_time, formatted_time};
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::Duration;
+
+use chrono::Utc;
+use clap::Parser;
+use log::{info, warn};
+use tokio::time::sleep;
+
+// The signal handler only raises a flag, the reload itself runs on the runtime
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+static HANGUP: AtomicBool = AtomicBool::new(false);
+static RELOADED: OnceLock<RwLock<Option<Arc<Proxy>>>> = OnceLock::new();
+
+fn reloaded() -> &'static RwLock<Option<Arc<Proxy>>> {
+    RELOADED.get_or_init(Default::default)
+}
+
+/// Credentials, allowed hosts and secret token of the main listeners as last reloaded
+/// or changed through the admin API, `None` while they are still those of the flags.
+pub fn current() -> Option<Arc<Proxy>> {
+    reloaded().read().unwrap().clone()
+}
+
+/// Change the main listeners' settings at runtime, as the admin API does. Changes last
+/// until the next SIGHUP reads the files again.
+pub fn update(change: impl FnOnce(&mut Proxy)) {
+    let mut current = reloaded().write().unwrap();
+    let mut proxy = match current.as_deref() {
+        Some(proxy) => proxy.clone(),
+        None => Proxy::from_opt(Opt::global()),
+    };
+    change(&mut proxy);
+    *current = Some(Arc::new(proxy));
+}
+
+extern "C" fn on_hangup(_signal: libc::c_int) {
+    HANGUP.store(true, Ordering::Relaxed);
+}
+
+/// Reload the settings on SIGHUP and when settings scheduled in the config file take
+/// effect, for as long as the proxy runs. Connections already established keep the
+/// settings they were accepted with.
+pub async fn reload_on_hangup() {
+    let mut next_change = config::next_change(Utc::now());
+    on_each_poll(|hangup| {
+        let now = Utc::now();
+        let scheduled = next_change.filter(|time| *time <= now);
+        if let Some(time) = scheduled {
+            info!(
+                "[{}] Settings scheduled for {} take effect",
+                formatted_time(),
+                format_time(time)
+            );
+        }
+        if hangup || scheduled.is_some() {
+            reload();
+            next_change = config::next_change(now);
+        }
+    })
+    .await;
+}
+
+/// Pass SIGHUP on to the worker processes `pids`, which reload their settings.
+pub async fn forward_hangup(pids: Vec<u32>) {
+    on_each_poll(|hangup| {
+        if !hangup {
+            return;
+        }
+        for &pid in &pids {
+            unsafe { libc::kill(pid as libc::pid_t, libc::SIGHUP) };
+        }
+    })
+    .await;
+}
+
+/// Call `action` every poll interval, with whether a SIGHUP came in since the last call.
+async fn on_each_poll(mut action: impl FnMut(bool)) {
+    let handler = on_hangup as extern "C" fn(libc::c_int) as libc::sighandler_t;
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    if unsafe { libc::signal(libc::SIGHUP, handler) } == libc::SIG_ERR {
+        warn!("Failed to install the SIGHUP handler, reloading is disabled");
+        return;
+    }
+
+    loop {
+        sleep(POLL_INTERVAL).await;
+        action(HANGUP.swap(false, Ordering::Relaxed));
+    }
+}
+
+/// Read `--config`, `--users-file` and `--tls-ticket-key` again, ending all sessions. A
+/// file that doesn't parse leaves the settings as they were.
+fn reload() {
+    let time = formatted_time();
+
+    // Parsed like at startup, so flags still take precedence over the config file
+    let options = config::try_args().and_then(|args| {
+        Opt::try_parse_from(args).map_err(|e| {
+            let message = e.to_string();
+            let first_line = message.lines().next().unwrap_or_default();
+            first_line.trim_start_matches("error: ").to_string()
+        })
+    });
+    let options = options.and_then(|options| options.check_hosts().map(|()| options));
+    match options {
+        Ok(options) => {
+            let proxy = Proxy::from_opt(&options);
+            info!(
+                "[{time}] Reloaded {} credentials and {} allowed hosts",
+                proxy.allowed_credentials.len(),
+                proxy.allowed_hosts.len()
+            );
+            *reloaded().write().unwrap() = Some(Arc::new(proxy));
+        }
+        Err(e) => warn!("[{time}] Reload failed, keeping the current settings: {e}"),
+    }
+
+    if let Some(store) = UserStore::global() {
+        match store.reload() {
+            Ok(count) => info!("[{time}] Reloaded {count} users from the users file"),
+            Err(e) => warn!("[{time}] Reloading the users file failed, keeping the users: {e}"),
+        }
+    }
+    tickets::reload();
+
+    // Sessions were verified against the settings just replaced
+    let ended = sessions::revoke_all();
+    if ended > 0 {
+        info!("[{time}] Ended {ended} sessions, their clients authenticate again");
+    }
+}
@@ -0,0 +1,262 @@
+//! Building blocks of the access checks: networks, ports and the deny-then-allow
+//! evaluation of rule lists. Nothing here reads the options, the checks in `policy` pass
+//! their lists in, and `fuzz/` asserts the invariants below on arbitrary rules.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+use rand::Rng;
+
+/// Rule of a deny list and an allow list that decided, see [`evaluate`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum Matched<'a, T> {
+    Deny(&'a T),
+    Allow(&'a T),
+    /// No rule matched, which allows only when there are no allow rules
+    Default {
+        allowed: bool,
+    },
+}
+
+/// Evaluate rules the way every access check does: a rule in `deny` that matches refuses,
+/// whatever `allow` holds. Otherwise the first matching rule in `allow` lets through, and
+/// with no `allow` rules everything that isn't denied is let through.
+pub fn evaluate<'a, T>(
+    deny: &'a [T],
+    allow: &'a [T],
+    matches: impl Fn(&T) -> bool,
+) -> Matched<'a, T> {
+    if let Some(rule) = deny.iter().find(|rule| matches(rule)) {
+        return Matched::Deny(rule);
+    }
+    match allow.iter().find(|rule| matches(rule)) {
+        Some(rule) => Matched::Allow(rule),
+        None => Matched::Default {
+            allowed: allow.is_empty(),
+        },
+    }
+}
+
+/// Network in CIDR notation, e.g. `2001:db8::/64` or `10.0.0.0/8`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpNet {
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl IpNet {
+    fn max_prefix_len(&self) -> u8 {
+        match self.addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        }
+    }
+
+    fn mask(&self) -> u128 {
+        let host_bits = u32::from(self.max_prefix_len() - self.prefix_len);
+        u128::MAX.checked_shl(host_bits).unwrap_or(0)
+    }
+
+    /// Whether `ip` is inside the network. IPv4-mapped IPv6 addresses, as a dual-stack
+    /// listener sees IPv4 clients, count as the IPv4 address.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let canonical = self.to_canonical();
+        let (net, ip, mask) = match (canonical.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => (
+                u128::from(u32::from(net)),
+                u128::from(u32::from(ip)),
+                u128::from(canonical.mask() as u32),
+            ),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                (u128::from(net), u128::from(ip), canonical.mask())
+            }
+            _ => return false,
+        };
+        net & mask == ip & mask
+    }
+
+    /// The network as IPv4 when it's written as IPv4-mapped IPv6, like
+    /// `::ffff:10.0.0.0/104`, since the addresses it's matched against are.
+    fn to_canonical(self) -> IpNet {
+        match self.addr {
+            IpAddr::V6(addr) if self.prefix_len >= 96 => match addr.to_ipv4_mapped() {
+                Some(addr) => IpNet {
+                    addr: IpAddr::V4(addr),
+                    prefix_len: self.prefix_len - 96,
+                },
+                None => self,
+            },
+            _ => self,
+        }
+    }
+
+    /// Random address inside the network, keeping the prefix bits and randomizing the rest.
+    pub fn random_addr(&self) -> IpAddr {
+        let random = rand::thread_rng().gen::<u128>();
+
+        match self.addr {
+            IpAddr::V4(addr) => {
+                let mask = self.mask() as u32;
+                let bits = (u32::from(addr) & mask) | (random as u32 & !mask);
+                IpAddr::V4(Ipv4Addr::from(bits))
+            }
+            IpAddr::V6(addr) => {
+                let mask = self.mask();
+                let bits = (u128::from(addr) & mask) | (random & !mask);
+                IpAddr::V6(Ipv6Addr::from(bits))
+            }
+        }
+    }
+
+    /// IPv4-embedded IPv6 address (RFC 6052) for a NAT64 prefix of length 32, 40, 48, 56,
+    /// 64 or 96. Bits 64..71 (the "u" octet) are always left zero.
+    pub fn embed_ipv4(&self, ip: Ipv4Addr) -> Option<Ipv6Addr> {
+        let IpAddr::V6(prefix) = self.addr else {
+            return None;
+        };
+        if ![32, 40, 48, 56, 64, 96].contains(&self.prefix_len) {
+            return None;
+        }
+
+        let mut octets = prefix.octets();
+        let mut pos = usize::from(self.prefix_len / 8);
+        for byte in ip.octets() {
+            if pos == 8 {
+                octets[pos] = 0;
+                pos += 1;
+            }
+            octets[pos] = byte;
+            pos += 1;
+        }
+
+        // Suffix after the embedded address must be zero as well
+        for octet in octets.iter_mut().skip(pos) {
+            *octet = 0;
+        }
+        Some(Ipv6Addr::from(octets))
+    }
+}
+
+impl FromStr for IpNet {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = s
+            .trim()
+            .split_once('/')
+            .ok_or_else(|| format!("Missing prefix length in '{s}'"))?;
+
+        let addr = addr
+            .parse::<IpAddr>()
+            .map_err(|e| format!("Invalid address in '{s}': {e}"))?;
+        let prefix_len = prefix_len
+            .parse::<u8>()
+            .map_err(|e| format!("Invalid prefix length in '{s}': {e}"))?;
+
+        let net = IpNet { addr, prefix_len };
+        if prefix_len > net.max_prefix_len() {
+            return Err(format!("Prefix length out of range in '{s}'"));
+        }
+        Ok(net)
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+/// Inclusive range of local ports, e.g. `40000-60000`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PortRange {
+    pub start: u16,
+    pub end: u16,
+}
+
+impl PortRange {
+    pub fn random_port(&self) -> u16 {
+        rand::thread_rng().gen_range(self.start..=self.end)
+    }
+}
+
+impl FromStr for PortRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .trim()
+            .split_once('-')
+            .ok_or_else(|| format!("Expected 'start-end', got '{s}'"))?;
+
+        let start = start
+            .trim()
+            .parse::<u16>()
+            .map_err(|e| format!("Invalid start port in '{s}': {e}"))?;
+        let end = end
+            .trim()
+            .parse::<u16>()
+            .map_err(|e| format!("Invalid end port in '{s}': {e}"))?;
+
+        if start == 0 || start > end {
+            return Err(format!("Invalid port range '{s}'"));
+        }
+        Ok(PortRange { start, end })
+    }
+}
+
+impl fmt::Display for PortRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.start == self.end {
+            true => write!(f, "{}", self.start),
+            false => write!(f, "{}-{}", self.start, self.end),
+        }
+    }
+}
+
+/// Comma-separated ports and port ranges, e.g. `443, 8443, 5000-5100`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PortList(pub Vec<PortRange>);
+
+impl PortList {
+    /// Range of the list `port` is in.
+    pub fn find(&self, port: u16) -> Option<&PortRange> {
+        self.0
+            .iter()
+            .find(|range| (range.start..=range.end).contains(&port))
+    }
+}
+
+impl fmt::Display for PortList {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let ranges = self
+            .0
+            .iter()
+            .map(PortRange::to_string)
+            .collect::<Vec<String>>();
+        write!(f, "{}", ranges.join(", "))
+    }
+}
+
+impl FromStr for PortList {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| match item.contains('-') {
+                true => item.parse::<PortRange>(),
+                false => match item.parse::<u16>() {
+                    Ok(port) if port > 0 => Ok(PortRange {
+                        start: port,
+                        end: port,
+                    }),
+                    _ => Err(format!("Invalid port '{item}'")),
+                },
+            })
+            .collect::<Result<Vec<PortRange>, String>>()
+            .map(PortList)
+    }
+}
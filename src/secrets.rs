@@ -0,0 +1,518 @@
+use crate::age;
+use crate::https::{read_certs, read_private_key};
+use crate::json::{self, object, Value};
+use crate::options::Opt;
+use crate::utils::formatted_time;
+
+use std::env;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::os::fd::AsRawFd;
+use std::str::FromStr;
+use std::sync::{Arc, OnceLock, RwLock};
+use std::time::Duration;
+
+use base64::{engine::general_purpose::STANDARD as b64, Engine};
+use chrono::Utc;
+use hyper::header::AUTHORIZATION;
+use hyper::{Body, Client, Method, Request, Uri};
+use hyper_tls::HttpsConnector;
+use log::{info, warn};
+use ring::hmac;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::{self, CertifiedKey};
+use sha2::{Digest, Sha256};
+use tokio::time::{sleep, timeout};
+
+const FETCH_TIMEOUT: Duration = Duration::from_secs(10);
+
+static SECRETS: OnceLock<RwLock<Arc<Secrets>>> = OnceLock::new();
+static PASSPHRASE: OnceLock<String> = OnceLock::new();
+
+/// Secret manager entry holding the proxy's secrets as a JSON object with the keys
+/// `auth`, `token`, `cert` and `pkey`, named after the flags they replace.
+#[derive(Debug, Clone)]
+pub enum SecretSource {
+    /// HashiCorp Vault KV v2 secret, `vault://<mount>/<path>`
+    Vault { mount: String, path: String },
+    /// AWS Secrets Manager secret, `aws-sm://<secret id>`
+    AwsSecretsManager { secret_id: String },
+    /// Google Cloud Secret Manager secret, `gcp-sm://projects/<project>/secrets/<secret>`
+    GcpSecretManager { name: String },
+    /// Local file, usually age encrypted, `file://<path>`
+    File { path: String },
+}
+
+impl FromStr for SecretSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (scheme, location) = s
+            .trim()
+            .split_once("://")
+            .ok_or("Expected vault://, aws-sm://, gcp-sm:// or file://")?;
+        if location.is_empty() {
+            return Err("Missing secret location".to_string());
+        }
+
+        match scheme {
+            "vault" => {
+                let (mount, path) = location
+                    .split_once('/')
+                    .filter(|(mount, path)| !mount.is_empty() && !path.is_empty())
+                    .ok_or("Expected vault://<mount>/<path>")?;
+                Ok(SecretSource::Vault {
+                    mount: mount.to_string(),
+                    path: path.trim_end_matches('/').to_string(),
+                })
+            }
+            "aws-sm" => Ok(SecretSource::AwsSecretsManager {
+                secret_id: location.to_string(),
+            }),
+            "gcp-sm" if location.starts_with("projects/") => Ok(SecretSource::GcpSecretManager {
+                name: location.trim_end_matches('/').to_string(),
+            }),
+            "gcp-sm" => Err("Expected gcp-sm://projects/<project>/secrets/<secret>".to_string()),
+            "file" => Ok(SecretSource::File {
+                path: location.to_string(),
+            }),
+            _ => Err(format!("Unknown secret manager {scheme}")),
+        }
+    }
+}
+
+impl fmt::Display for SecretSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SecretSource::Vault { mount, path } => write!(f, "vault://{mount}/{path}"),
+            SecretSource::AwsSecretsManager { secret_id } => write!(f, "aws-sm://{secret_id}"),
+            SecretSource::GcpSecretManager { name } => write!(f, "gcp-sm://{name}"),
+            SecretSource::File { path } => write!(f, "file://{path}"),
+        }
+    }
+}
+
+/// Secrets as last fetched. What the secret doesn't set comes from flags and files.
+#[derive(Default)]
+pub struct Secrets {
+    pub credentials: Option<Vec<String>>,
+    pub token: Option<String>,
+    pub certified_key: Option<Arc<CertifiedKey>>,
+    // The secret as fetched, to tell whether a refresh changed anything
+    fetched: String,
+}
+
+impl Secrets {
+    fn from_json(value: &Value) -> Result<Secrets, String> {
+        let credentials = match value.get("auth") {
+            None | Some(Value::Null) => None,
+            Some(Value::String(credentials)) => Some(
+                credentials
+                    .split(',')
+                    .map(|credentials| credentials.trim().to_string())
+                    .filter(|credentials| !credentials.is_empty())
+                    .collect(),
+            ),
+            Some(Value::Array(credentials)) => Some(
+                credentials
+                    .iter()
+                    .map(|credentials| credentials.as_str().map(str::to_string))
+                    .collect::<Option<Vec<String>>>()
+                    .ok_or("auth must only contain login:password strings")?,
+            ),
+            Some(_) => return Err("auth must be a string or an array".to_string()),
+        };
+
+        let string = |key: &str| match value.get(key) {
+            None | Some(Value::Null) => Ok(None),
+            Some(Value::String(s)) => Ok(Some(s.clone())),
+            Some(_) => Err(format!("{key} must be a string")),
+        };
+
+        let certified_key = match (string("cert")?, string("pkey")?) {
+            (Some(cert), Some(pkey)) => {
+                let certs = read_certs(&mut cert.as_bytes()).map_err(|e| format!("cert: {e}"))?;
+                let key =
+                    read_private_key(&mut pkey.as_bytes()).map_err(|e| format!("pkey: {e}"))?;
+                Some(certified_key(certs, key)?)
+            }
+            (None, None) => None,
+            _ => return Err("cert and pkey must be set together".to_string()),
+        };
+
+        Ok(Secrets {
+            credentials,
+            token: string("token")?,
+            certified_key,
+            fetched: value.to_string(),
+        })
+    }
+}
+
+pub fn certified_key(
+    certs: Vec<rustls::Certificate>,
+    key: rustls::PrivateKey,
+) -> Result<Arc<CertifiedKey>, String> {
+    let signing_key = sign::any_supported_type(&key).map_err(|e| e.to_string())?;
+    Ok(Arc::new(CertifiedKey::new(certs, signing_key)))
+}
+
+/// Whether secrets come from a secret manager.
+pub fn enabled() -> bool {
+    Opt::global().secrets_source.is_some()
+}
+
+/// Secrets as last fetched, empty without `--secrets-source`.
+pub fn current() -> Arc<Secrets> {
+    SECRETS
+        .get()
+        .map(|secrets| secrets.read().unwrap().clone())
+        .unwrap_or_default()
+}
+
+/// Fetch the secrets at startup. Unlike a refresh, failing here is fatal.
+pub async fn init() -> Result<(), String> {
+    let Some(source) = &Opt::global().secrets_source else {
+        return Ok(());
+    };
+
+    let secrets = fetch(source).await?;
+    info!("Fetched secrets from {source}");
+    SECRETS
+        .set(RwLock::new(Arc::new(secrets)))
+        .map_err(|_| "Secrets are already fetched".to_string())
+}
+
+/// Fetch the secrets again every `--secrets-refresh` seconds. A failed refresh keeps
+/// the secrets that were fetched last.
+pub async fn refresh_periodically() {
+    let options = Opt::global();
+    let Some(source) = &options.secrets_source else {
+        return;
+    };
+    if options.secrets_refresh == 0 {
+        return;
+    }
+
+    loop {
+        sleep(Duration::from_secs(options.secrets_refresh)).await;
+
+        let time = formatted_time();
+        match fetch(source).await {
+            Ok(secrets) => {
+                let Some(current) = SECRETS.get() else {
+                    return;
+                };
+                let mut current = current.write().unwrap();
+                if current.fetched != secrets.fetched {
+                    *current = Arc::new(secrets);
+                    info!("[{time}] Secrets from {source} changed, now in use");
+                }
+            }
+            Err(e) => warn!("[{time}] Failed to refresh secrets from {source}: {e}"),
+        }
+    }
+}
+
+async fn fetch(source: &SecretSource) -> Result<Secrets, String> {
+    let value = match source {
+        // No timeout for files, a passphrase may have to be typed in
+        SecretSource::File { path } => read_file(path)?,
+        source => match timeout(FETCH_TIMEOUT, fetch_json(source)).await {
+            Ok(value) => value?,
+            Err(_) => return Err("Secret manager timed out".to_string()),
+        },
+    };
+    Secrets::from_json(&value)
+}
+
+async fn fetch_json(source: &SecretSource) -> Result<Value, String> {
+    match source {
+        SecretSource::Vault { mount, path } => fetch_vault(mount, path).await,
+        SecretSource::AwsSecretsManager { secret_id } => fetch_aws(secret_id).await,
+        SecretSource::GcpSecretManager { name } => fetch_gcp(name).await,
+        SecretSource::File { path } => read_file(path),
+    }
+}
+
+/// Secrets file, decrypted with `--secrets-identity` or a passphrase when it is age encrypted.
+fn read_file(path: &str) -> Result<Value, String> {
+    let data = std::fs::read(path).map_err(|e| format!("{path}: {e}"))?;
+    if !age::is_encrypted(&data) {
+        return json::parse(&String::from_utf8(data).map_err(|e| e.to_string())?);
+    }
+
+    let identities = match &Opt::global().secrets_identity {
+        Some(identity_file) => {
+            let text = std::fs::read_to_string(identity_file)
+                .map_err(|e| format!("{identity_file}: {e}"))?;
+            age::parse_identities(&text).map_err(|e| format!("{identity_file}: {e}"))?
+        }
+        None => Vec::new(),
+    };
+
+    let plaintext = age::decrypt(&data, &identities, &passphrase)?;
+    json::parse(&String::from_utf8(plaintext).map_err(|e| e.to_string())?)
+}
+
+/// Passphrase of the secrets file, asked for once on the terminal and kept for refreshes.
+fn passphrase() -> Result<String, String> {
+    if let Some(passphrase) = PASSPHRASE.get() {
+        return Ok(passphrase.clone());
+    }
+    let passphrase = prompt_hidden("Passphrase for the secrets file: ")
+        .map_err(|e| format!("Can't ask for the secrets passphrase: {e}"))?;
+    Ok(PASSPHRASE.get_or_init(|| passphrase).clone())
+}
+
+fn prompt_hidden(prompt: &str) -> std::io::Result<String> {
+    let mut tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+    let fd = tty.as_raw_fd();
+
+    let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+    if unsafe { libc::tcgetattr(fd, &mut termios) } != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    let original = termios;
+    termios.c_lflag &= !libc::ECHO;
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) };
+
+    let result = (|| {
+        tty.write_all(prompt.as_bytes())?;
+        let mut line = String::new();
+        BufReader::new(&tty).read_line(&mut line)?;
+        Ok(line.trim_end_matches(['\r', '\n']).to_string())
+    })();
+
+    unsafe { libc::tcsetattr(fd, libc::TCSANOW, &original) };
+    tty.write_all(b"\n")?;
+    result
+}
+
+/// Vault address and token come from `VAULT_ADDR` and `VAULT_TOKEN` (or `~/.vault-token`),
+/// as for the Vault CLI.
+async fn fetch_vault(mount: &str, path: &str) -> Result<Value, String> {
+    let addr = env::var("VAULT_ADDR").unwrap_or_else(|_| "http://127.0.0.1:8200".to_string());
+    let token = match env::var("VAULT_TOKEN") {
+        Ok(token) => token,
+        Err(_) => {
+            let home = env::var("HOME").map_err(|_| "VAULT_TOKEN is not set")?;
+            std::fs::read_to_string(format!("{home}/.vault-token"))
+                .map_err(|_| "VAULT_TOKEN is not set and ~/.vault-token can't be read")?
+        }
+    };
+
+    let mut request = Request::builder()
+        .method(Method::GET)
+        .uri(format!(
+            "{}/v1/{mount}/data/{path}",
+            addr.trim_end_matches('/')
+        ))
+        .header("x-vault-token", token.trim());
+    if let Ok(namespace) = env::var("VAULT_NAMESPACE") {
+        request = request.header("x-vault-namespace", namespace);
+    }
+    let request = request.body(Body::empty()).map_err(|e| e.to_string())?;
+
+    let response = json::parse(&send(request).await?)?;
+    response
+        .get("data")
+        .and_then(|data| data.get("data"))
+        .cloned()
+        .ok_or_else(|| "Vault response without data, is it a KV v2 secret?".to_string())
+}
+
+/// Credentials and region come from the standard `AWS_*` environment variables.
+async fn fetch_aws(secret_id: &str) -> Result<Value, String> {
+    let var = |name: &str| env::var(name).map_err(|_| format!("{name} is not set"));
+    let access_key = var("AWS_ACCESS_KEY_ID")?;
+    let secret_key = var("AWS_SECRET_ACCESS_KEY")?;
+    let session_token = env::var("AWS_SESSION_TOKEN").ok();
+    let region = var("AWS_REGION").or_else(|_| var("AWS_DEFAULT_REGION"))?;
+
+    let endpoint = env::var("AWS_ENDPOINT_URL")
+        .unwrap_or_else(|_| format!("https://secretsmanager.{region}.amazonaws.com"));
+    let endpoint = endpoint.parse::<Uri>().map_err(|e| e.to_string())?;
+    let host = endpoint
+        .authority()
+        .ok_or("AWS endpoint without host")?
+        .to_string();
+
+    let body = object([("SecretId", secret_id.into())]).to_string();
+    let amz_date = Utc::now().format("%Y%m%dT%H%M%SZ").to_string();
+
+    // Signed headers, sorted by name as SigV4 requires
+    let mut headers = vec![
+        ("content-type", "application/x-amz-json-1.1".to_string()),
+        ("host", host),
+        ("x-amz-date", amz_date.clone()),
+    ];
+    if let Some(session_token) = session_token {
+        headers.push(("x-amz-security-token", session_token));
+    }
+    headers.push(("x-amz-target", "secretsmanager.GetSecretValue".to_string()));
+
+    let authorization = sigv4_authorization(
+        "POST",
+        &headers,
+        body.as_bytes(),
+        &access_key,
+        &secret_key,
+        &region,
+        "secretsmanager",
+        &amz_date,
+    );
+
+    let mut request = Request::builder()
+        .method(Method::POST)
+        .uri(&endpoint)
+        .header(AUTHORIZATION, authorization);
+    for (name, value) in &headers {
+        request = request.header(*name, value);
+    }
+    let request = request.body(Body::from(body)).map_err(|e| e.to_string())?;
+
+    let response = json::parse(&send(request).await?)?;
+    let secret = response
+        .get("SecretString")
+        .and_then(Value::as_str)
+        .ok_or("AWS secret without SecretString")?;
+    json::parse(secret)
+}
+
+/// AWS Signature Version 4 `Authorization` header for a request to `/` without a query.
+/// `headers` are the signed headers, lowercase and sorted by name.
+#[allow(clippy::too_many_arguments)]
+fn sigv4_authorization(
+    method: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+    access_key: &str,
+    secret_key: &str,
+    region: &str,
+    service: &str,
+    amz_date: &str,
+) -> String {
+    let date = &amz_date[..8];
+    let scope = format!("{date}/{region}/{service}/aws4_request");
+
+    let canonical_headers = headers
+        .iter()
+        .map(|(name, value)| format!("{name}:{}\n", value.trim()))
+        .collect::<String>();
+    let signed_headers = headers
+        .iter()
+        .map(|(name, _)| *name)
+        .collect::<Vec<&str>>()
+        .join(";");
+    let canonical_request = format!(
+        "{method}\n/\n\n{canonical_headers}\n{signed_headers}\n{}",
+        hex(&Sha256::digest(body))
+    );
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+        hex(&Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let hmac_sha256 = |key: &[u8], data: &str| {
+        hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data.as_bytes())
+            .as_ref()
+            .to_vec()
+    };
+    let mut signing_key = format!("AWS4{secret_key}").into_bytes();
+    for part in [date, region, service, "aws4_request"] {
+        signing_key = hmac_sha256(&signing_key, part);
+    }
+    let signature = hex(&hmac_sha256(&signing_key, &string_to_sign));
+
+    format!(
+        "AWS4-HMAC-SHA256 Credential={access_key}/{scope}, SignedHeaders={signed_headers}, Signature={signature}"
+    )
+}
+
+/// The access token comes from `GOOGLE_OAUTH_ACCESS_TOKEN` or, on Google Cloud, the
+/// metadata server's default service account.
+async fn fetch_gcp(name: &str) -> Result<Value, String> {
+    let token = match env::var("GOOGLE_OAUTH_ACCESS_TOKEN") {
+        Ok(token) => token,
+        Err(_) => gcp_metadata_token().await?,
+    };
+
+    let version = if name.contains("/versions/") {
+        name.to_string()
+    } else {
+        format!("{name}/versions/latest")
+    };
+    let request = Request::builder()
+        .method(Method::GET)
+        .uri(format!(
+            "https://secretmanager.googleapis.com/v1/{version}:access"
+        ))
+        .header(AUTHORIZATION, format!("Bearer {}", token.trim()))
+        .body(Body::empty())
+        .map_err(|e| e.to_string())?;
+
+    let response = json::parse(&send(request).await?)?;
+    let data = response
+        .get("payload")
+        .and_then(|payload| payload.get("data"))
+        .and_then(Value::as_str)
+        .ok_or("Google Cloud secret without payload")?;
+    let data = b64.decode(data).map_err(|e| e.to_string())?;
+    json::parse(&String::from_utf8(data).map_err(|e| e.to_string())?)
+}
+
+async fn gcp_metadata_token() -> Result<String, String> {
+    let host =
+        env::var("GCE_METADATA_HOST").unwrap_or_else(|_| "metadata.google.internal".to_string());
+    let request = Request::builder()
+        .method(Method::GET)
+        .uri(format!(
+            "http://{host}/computeMetadata/v1/instance/service-accounts/default/token"
+        ))
+        .header("metadata-flavor", "Google")
+        .body(Body::empty())
+        .map_err(|e| e.to_string())?;
+
+    let response = json::parse(&send(request).await?)?;
+    response
+        .get("access_token")
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .ok_or_else(|| "Metadata server answered without an access token".to_string())
+}
+
+async fn send(request: Request<Body>) -> Result<String, String> {
+    let client = Client::builder().build::<_, Body>(HttpsConnector::new());
+    let response = client.request(request).await.map_err(|e| e.to_string())?;
+    let status = response.status();
+    let body = hyper::body::to_bytes(response.into_body())
+        .await
+        .map_err(|e| e.to_string())?;
+    let body = String::from_utf8_lossy(&body).into_owned();
+
+    if !status.is_success() {
+        return Err(format!("Secret manager answered {status}: {}", body.trim()));
+    }
+    Ok(body)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Serves the certificate from the secrets, so a refreshed one is used for new
+/// connections. Falls back to `--cert`/`--pkey` while the secret has none.
+pub struct CertResolver {
+    pub fallback: Option<Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        current()
+            .certified_key
+            .clone()
+            .or_else(|| self.fallback.clone())
+    }
+}
@@ -0,0 +1,147 @@
+use crate::digest;
+use crate::signed_token;
+
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use base64::{engine::general_purpose::STANDARD as b64, Engine};
+use hyper::Uri;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Notify;
+use tokio::time::timeout;
+
+// How long each of the requests may take, connecting to the target included
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Longest response head read before giving up on finding its end
+const MAX_HEAD: usize = 16 * 1024;
+
+static LISTENING: Notify = Notify::const_new();
+
+/// Mark the main HTTP listener as bound, so the self-test can start.
+pub fn listening() {
+    LISTENING.notify_one();
+}
+
+/// How the self-test gets past the listener's checks: the first `--auth` credentials and
+/// the `--token`, when they are set. With `--auth-scheme digest` the credentials are
+/// signed for each request, with a nonce the listener takes as its own.
+pub struct Identity<'a> {
+    pub credentials: Option<&'a str>,
+    pub secret_token: &'a str,
+}
+
+impl Identity<'_> {
+    fn headers(&self, method: &str, uri: &str) -> String {
+        let mut headers = String::new();
+        if let Some(credentials) = self.credentials {
+            let authorization = match digest::enabled() {
+                true => digest::authorization(credentials, method, uri),
+                false => format!("Basic {}", b64.encode(credentials)),
+            };
+            headers.push_str(&format!("Proxy-Authorization: {authorization}\r\n"));
+        }
+        if !self.secret_token.is_empty() {
+            let token = signed_token::header_value(self.secret_token);
+            headers.push_str(&format!("x-http-secret-token: {token}\r\n"));
+        }
+        headers
+    }
+}
+
+/// Once the HTTP listener is bound, send a CONNECT and a plain request for `target` (an
+/// `http://` URL) through it at `addr`, the way a client would. Errors tell which of the
+/// two broke and how, e.g. a 502 from a bad egress address or a missing route.
+pub async fn run(addr: SocketAddr, target: &str, identity: &Identity<'_>) -> Result<(), String> {
+    LISTENING.notified().await;
+
+    let uri = target
+        .parse::<Uri>()
+        .map_err(|e| format!("invalid target {target}: {e}"))?;
+    let host = uri.host().unwrap_or_default();
+    let authority = format!("{host}:{}", uri.port_u16().unwrap_or(80));
+    let path = uri.path_and_query().map_or("/", |path| path.as_str());
+    let headers = identity.headers("CONNECT", &authority);
+    let connect = format!("CONNECT {authority} HTTP/1.1\r\nHost: {authority}\r\n{headers}\r\n");
+    let inner = format!("HEAD {path} HTTP/1.1\r\nHost: {host}\r\nConnection: close\r\n\r\n");
+    let status = within(tunnel(addr, &connect, &inner))
+        .await
+        .map_err(|e| format!("CONNECT {authority} through {addr} failed: {e}"))?;
+    if status != 200 {
+        return Err(format!(
+            "CONNECT {authority} through {addr} was answered with {status}"
+        ));
+    }
+
+    let headers = identity.headers("HEAD", target);
+    let plain =
+        format!("HEAD {target} HTTP/1.1\r\nHost: {host}\r\n{headers}Connection: close\r\n\r\n");
+    let status = within(request(addr, &plain))
+        .await
+        .map_err(|e| format!("plain request for {target} through {addr} failed: {e}"))?;
+    // The target may well answer 4xx itself, only the proxy's refusals and failures count
+    if status == 407 || status >= 500 {
+        return Err(format!(
+            "plain request for {target} through {addr} was answered with {status}"
+        ));
+    }
+
+    Ok(())
+}
+
+async fn within<T>(future: impl Future<Output = io::Result<T>>) -> io::Result<T> {
+    timeout(REQUEST_TIMEOUT, future)
+        .await
+        .unwrap_or_else(|_| Err(io::Error::new(ErrorKind::TimedOut, "timed out")))
+}
+
+/// Open a tunnel with `connect` and send `inner` through it. The status of the CONNECT,
+/// and when it's 200, an error unless the target answers `inner` with an HTTP response.
+async fn tunnel(addr: SocketAddr, connect: &str, inner: &str) -> io::Result<u16> {
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(connect.as_bytes()).await?;
+    let status = read_status(&mut stream).await?;
+    if status != 200 {
+        return Ok(status);
+    }
+
+    stream.write_all(inner.as_bytes()).await?;
+    read_status(&mut stream).await?;
+    Ok(status)
+}
+
+async fn request(addr: SocketAddr, request: &str) -> io::Result<u16> {
+    let mut stream = TcpStream::connect(addr).await?;
+    stream.write_all(request.as_bytes()).await?;
+    read_status(&mut stream).await
+}
+
+/// Read a response head and return its status code.
+async fn read_status(stream: &mut TcpStream) -> io::Result<u16> {
+    let mut head = Vec::new();
+    let mut byte = [0; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                "response head too long",
+            ));
+        }
+        if stream.read(&mut byte).await? == 0 {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "the connection closed without a response",
+            ));
+        }
+        head.push(byte[0]);
+    }
+
+    let head = String::from_utf8_lossy(&head);
+    head.strip_prefix("HTTP/1.")
+        .and_then(|line| line.get(2..5))
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "not an HTTP response"))
+}
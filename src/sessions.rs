@@ -0,0 +1,227 @@
+use crate::auth;
+use crate::options::Opt;
+use crate::users::UserStore;
+use crate::utils::to_sha256;
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+/// Header a session token is issued in and presented back with.
+pub const SESSION_HEADER: &str = "x-proxerver-session";
+
+const TOKEN_LEN: usize = 32;
+
+// Expired sessions are swept out at most this often, not on every request
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+static SESSIONS: OnceLock<Mutex<Sessions>> = OnceLock::new();
+
+/// Where the login of a session was verified, checked again each time it's resumed so
+/// an account taken away ends its sessions too.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// A user of `--users-file`, who must still be there and active.
+    Users,
+    /// A `login:password` pair of the listener, by its SHA-256, which must still be listed.
+    Credentials(String),
+    /// An external provider, which must not have lost the account since.
+    Provider(&'static str),
+    /// A Kerberos ticket, good until the settings are reloaded.
+    Kerberos,
+}
+
+impl Source {
+    /// The pair of `credentials_allowed` whose login is `login`, `None` if there is none.
+    pub fn credentials(login: &str, credentials_allowed: &[String]) -> Option<Source> {
+        credentials_allowed
+            .iter()
+            .find(|credentials| credentials.split_once(':').map(|(login, _)| login) == Some(login))
+            .map(|credentials| Source::Credentials(to_sha256(credentials)))
+    }
+
+    fn is_valid(&self, login: &str, credentials_allowed: &[String]) -> bool {
+        match self {
+            Source::Users => UserStore::global()
+                .and_then(|store| store.get(login))
+                .is_some_and(|user| user.is_active()),
+            // The pair must still be listed, and be the one of this login
+            Source::Credentials(pair) => credentials_allowed.iter().any(|credentials| {
+                credentials.split_once(':').map(|(login, _)| login) == Some(login)
+                    && to_sha256(credentials) == *pair
+            }),
+            Source::Provider(name) => auth::may_know(name, login),
+            Source::Kerberos => true,
+        }
+    }
+}
+
+/// Authenticated client that may skip sending credentials until the session expires.
+/// Sessions are bound to the client's IP and the tenant they were issued for.
+#[derive(Debug)]
+struct Session {
+    login: String,
+    source: Source,
+    client_ip: IpAddr,
+    tenant: Option<String>,
+    created: Instant,
+    last_used: Instant,
+}
+
+impl Session {
+    fn is_expired(&self, now: Instant) -> bool {
+        let options = Opt::global();
+        now.duration_since(self.last_used) > Duration::from_secs(options.session_idle_timeout)
+            || now.duration_since(self.created) > Duration::from_secs(options.session_max_age)
+    }
+
+    fn client(&self) -> Client {
+        (self.login.clone(), self.client_ip, self.tenant.clone())
+    }
+}
+
+// A client holds one session at a time, found again when it sends credentials anew
+type Client = (String, IpAddr, Option<String>);
+
+struct Sessions {
+    by_token: HashMap<String, Session>,
+    by_client: HashMap<Client, String>,
+    swept: Instant,
+}
+
+impl Sessions {
+    fn remove(&mut self, token: &str) {
+        if let Some(session) = self.by_token.remove(token) {
+            self.by_client.remove(&session.client());
+        }
+    }
+
+    fn sweep(&mut self, now: Instant) {
+        if now.duration_since(self.swept) < SWEEP_INTERVAL {
+            return;
+        }
+        self.swept = now;
+        self.by_token.retain(|_, session| !session.is_expired(now));
+        let by_token = &self.by_token;
+        self.by_client
+            .retain(|_, token| by_token.contains_key(token));
+    }
+}
+
+fn sessions() -> &'static Mutex<Sessions> {
+    SESSIONS.get_or_init(|| {
+        Mutex::new(Sessions {
+            by_token: HashMap::new(),
+            by_client: HashMap::new(),
+            swept: Instant::now(),
+        })
+    })
+}
+
+/// Session for a client that just authenticated with credentials, the one it already
+/// has if that's still good. Returns the token to hand to the client, `None` when
+/// sessions are off or `login` doesn't authenticate at `source`, with the listener's
+/// `credentials_allowed` for pairs.
+pub fn issue(
+    login: &str,
+    source: Source,
+    client_ip: IpAddr,
+    tenant: Option<&str>,
+    credentials_allowed: &[String],
+) -> Option<String> {
+    if !Opt::global().sessions || !source.is_valid(login, credentials_allowed) {
+        return None;
+    }
+
+    let now = Instant::now();
+    let mut sessions = sessions().lock().unwrap();
+    sessions.sweep(now);
+
+    let client = (login.to_string(), client_ip, tenant.map(str::to_string));
+    if let Some(token) = sessions.by_client.get(&client).cloned() {
+        match sessions.by_token.get_mut(&token) {
+            Some(session) if !session.is_expired(now) && session.source == source => {
+                session.last_used = now;
+                return Some(token);
+            }
+            _ => sessions.remove(&token),
+        }
+    }
+
+    let token = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(TOKEN_LEN)
+        .map(char::from)
+        .collect::<String>();
+    sessions.by_client.insert(client, token.clone());
+    sessions.by_token.insert(
+        token.clone(),
+        Session {
+            login: login.to_string(),
+            source,
+            client_ip,
+            tenant: tenant.map(str::to_string),
+            created: now,
+            last_used: now,
+        },
+    );
+
+    Some(token)
+}
+
+/// Login of the session `token` stands for, if it is still valid for this client
+/// and tenant and the login still authenticates where it did, with the listener's
+/// `credentials_allowed` for pairs. Using a session keeps it from idling out.
+pub fn resume(
+    token: &str,
+    client_ip: IpAddr,
+    tenant: Option<&str>,
+    credentials_allowed: &[String],
+) -> Option<String> {
+    if !Opt::global().sessions {
+        return None;
+    }
+
+    let now = Instant::now();
+    let token = token.trim();
+    let mut sessions = sessions().lock().unwrap();
+    let session = sessions.by_token.get(token)?;
+
+    if session.client_ip != client_ip || session.tenant.as_deref() != tenant {
+        return None;
+    }
+    if session.is_expired(now) || !session.source.is_valid(&session.login, credentials_allowed) {
+        sessions.remove(token);
+        return None;
+    }
+
+    let session = sessions.by_token.get_mut(token)?;
+    session.last_used = now;
+    Some(session.login.clone())
+}
+
+/// End every session of `login`. Returns how many there were.
+pub fn revoke(login: &str) -> usize {
+    let mut sessions = sessions().lock().unwrap();
+    let before = sessions.by_token.len();
+    sessions
+        .by_token
+        .retain(|_, session| session.login != login);
+    sessions
+        .by_client
+        .retain(|(client_login, _, _), _| client_login != login);
+    before - sessions.by_token.len()
+}
+
+/// End all sessions, when the settings they were verified against are reloaded.
+pub fn revoke_all() -> usize {
+    let mut sessions = sessions().lock().unwrap();
+    let count = sessions.by_token.len();
+    sessions.by_token.clear();
+    sessions.by_client.clear();
+    count
+}
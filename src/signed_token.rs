@@ -0,0 +1,103 @@
+use crate::options::Opt;
+use crate::utils::to_sha256;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use ring::hmac;
+
+// How long the header values of the self-test and `explain` stay good
+const OWN_TOKEN_LIFETIME: u64 = 60;
+
+/// What an `x-http(s)-secret-token` header is, for a secret token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenHeader {
+    /// The SHA-256 of the token, good forever.
+    Static,
+    /// The static value while `--signed-token-only` refuses it.
+    StaticRefused,
+    /// `expiry:hmac` signed with the token, not expired.
+    Signed,
+    /// Signed with the token but expired, even allowing for clock skew.
+    Expired,
+    /// Signed with the token but expiring further ahead than `--token-max-lifetime`.
+    TooLong,
+    /// Not made from the token.
+    Invalid,
+}
+
+/// Signed header value of `secret_token` expiring at `expiry`, in seconds since the
+/// epoch: `expiry:hex(HMAC-SHA256(token, expiry))`.
+pub fn sign(secret_token: &str, expiry: u64) -> String {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret_token.trim().as_bytes());
+    let tag = hmac::sign(&key, expiry.to_string().as_bytes());
+    let hex: String = tag
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect();
+    format!("{expiry}:{hex}")
+}
+
+/// Signed header value of `secret_token` good for `lifetime` seconds from now.
+pub fn sign_for(secret_token: &str, lifetime: u64) -> String {
+    sign(secret_token, now() + lifetime)
+}
+
+/// Header value the proxy sends through itself, signed when `--signed-token-only` is set.
+pub fn header_value(secret_token: &str) -> String {
+    match Opt::global().signed_token_only {
+        true => sign_for(secret_token, OWN_TOKEN_LIFETIME),
+        false => to_sha256(secret_token.trim()),
+    }
+}
+
+/// What `header` is for `secret_token`, now.
+pub fn verify(secret_token: &str, header: &str) -> TokenHeader {
+    let options = Opt::global();
+    let header = header.trim();
+    if header == to_sha256(secret_token.trim()) {
+        return match options.signed_token_only {
+            true => TokenHeader::StaticRefused,
+            false => TokenHeader::Static,
+        };
+    }
+
+    let Some((expiry, hex)) = header.split_once(':') else {
+        return TokenHeader::Invalid;
+    };
+    let (Ok(expiry_secs), Some(tag)) = (expiry.parse::<u64>(), decode_hex(hex)) else {
+        return TokenHeader::Invalid;
+    };
+    let key = hmac::Key::new(hmac::HMAC_SHA256, secret_token.trim().as_bytes());
+    if hmac::verify(&key, expiry.as_bytes(), &tag).is_err() {
+        return TokenHeader::Invalid;
+    }
+
+    // Clients' clocks may be a little off either way
+    let now = now();
+    let skew = options.token_clock_skew;
+    if expiry_secs.saturating_add(skew) < now {
+        TokenHeader::Expired
+    } else if expiry_secs > now + options.token_max_lifetime + skew {
+        TokenHeader::TooLong
+    } else {
+        TokenHeader::Signed
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
@@ -0,0 +1,214 @@
+//! Socket options only some systems have: binding to an interface, firewall marks, TCP
+//! Fast Open and clamping the MSS. Whether this system takes each of them is found out
+//! once, on a throwaway socket, so the options needing one are refused or turned off at
+//! startup with the reason, instead of failing on every connection.
+
+use std::fmt;
+use std::io;
+use std::sync::OnceLock;
+
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+
+static PROBED: OnceLock<Vec<(Capability, Result<(), String>)>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Capability {
+    /// Listening on an interface, SO_BINDTODEVICE on Linux and IP_BOUND_IF on macOS.
+    BindDevice,
+    /// Firewall marks (SO_MARK), Linux only.
+    Mark,
+    /// TCP Fast Open on listeners and outbound connections, Linux only.
+    FastOpen,
+    /// Clamping the MSS (TCP_MAXSEG), on Unix systems.
+    Mss,
+}
+
+const CAPABILITIES: [Capability; 4] = [
+    Capability::BindDevice,
+    Capability::Mark,
+    Capability::FastOpen,
+    Capability::Mss,
+];
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Capability::BindDevice => "binding to an interface",
+            Capability::Mark => "firewall marks",
+            Capability::FastOpen => "TCP Fast Open",
+            Capability::Mss => "MSS clamping",
+        })
+    }
+}
+
+/// Whether this system and the privileges of the process allow `capability`, or why not.
+pub fn check(capability: Capability) -> Result<(), String> {
+    PROBED
+        .get_or_init(|| {
+            CAPABILITIES
+                .into_iter()
+                .map(|capability| (capability, probe(capability)))
+                .collect()
+        })
+        .iter()
+        .find(|(probed, _)| *probed == capability)
+        .map_or(Ok(()), |(_, support)| support.clone())
+}
+
+pub fn supported(capability: Capability) -> bool {
+    check(capability).is_ok()
+}
+
+/// Set the option `capability` stands for on a fresh socket, where it changes nothing.
+fn probe(capability: Capability) -> Result<(), String> {
+    let socket = Socket::new(Domain::IPV4, Type::STREAM, Some(Protocol::TCP))
+        .map_err(|e| format!("can't open a socket to probe for {capability}: {e}"))?;
+    let socket = SockRef::from(&socket);
+    let result = match capability {
+        Capability::BindDevice => probe_bind_device(socket),
+        Capability::Mark => set_mark(socket, 0),
+        Capability::FastOpen => fast_open_connect(socket),
+        Capability::Mss => probe_mss(socket),
+    };
+
+    result.map_err(|e| match e.kind() {
+        io::ErrorKind::Unsupported => e.to_string(),
+        io::ErrorKind::PermissionDenied if capability == Capability::Mark => {
+            "SO_MARK needs the CAP_NET_ADMIN capability".to_string()
+        }
+        _ => format!("the system refused {capability}: {e}"),
+    })
+}
+
+// Every option is there on Linux
+#[cfg_attr(target_os = "linux", allow(dead_code))]
+fn unsupported(option: &str, systems: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{option} is only available on {systems}"),
+    )
+}
+
+/// Have the socket only take or send traffic through `interface`. `ipv6` is the family of
+/// the socket, macOS binds each one with an option of its own.
+pub fn bind_device(socket: SockRef<'_>, interface: &str, ipv6: bool) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        let _ = ipv6;
+        socket.bind_device(Some(interface.as_bytes()))
+    }
+
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    {
+        let name = std::ffi::CString::new(interface)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Invalid interface"))?;
+        // SAFETY: the name is a valid C string living through the call
+        let index = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        let index = std::num::NonZeroU32::new(index).ok_or_else(io::Error::last_os_error)?;
+        match ipv6 {
+            true => socket.bind_device_by_index_v6(Some(index)),
+            false => socket.bind_device_by_index_v4(Some(index)),
+        }
+    }
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "ios")))]
+    {
+        let _ = (socket, ipv6);
+        Err(unsupported(
+            &format!("Binding to interface {interface}"),
+            "Linux and macOS",
+        ))
+    }
+}
+
+fn probe_bind_device(socket: SockRef<'_>) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    return socket.device().map(drop);
+
+    #[cfg(any(target_os = "macos", target_os = "ios"))]
+    return socket.device_index_v4().map(drop);
+
+    #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "ios")))]
+    {
+        let _ = socket;
+        Err(unsupported("Binding to an interface", "Linux and macOS"))
+    }
+}
+
+/// Mark the socket's packets for `ip rule` and the firewall.
+pub fn set_mark(socket: SockRef<'_>, mark: u32) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    return socket.set_mark(mark);
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (socket, mark);
+        Err(unsupported("SO_MARK", "Linux"))
+    }
+}
+
+/// Clamp the MSS the socket announces in its SYN.
+pub fn set_mss(socket: SockRef<'_>, mss: u32) -> io::Result<()> {
+    #[cfg(unix)]
+    return socket.set_mss(mss);
+
+    #[cfg(not(unix))]
+    {
+        let _ = (socket, mss);
+        Err(unsupported("TCP_MAXSEG", "Unix systems"))
+    }
+}
+
+fn probe_mss(socket: SockRef<'_>) -> io::Result<()> {
+    #[cfg(unix)]
+    return socket.mss().map(drop);
+
+    #[cfg(not(unix))]
+    {
+        let _ = socket;
+        Err(unsupported("TCP_MAXSEG", "Unix systems"))
+    }
+}
+
+/// Accept data in the SYN, with up to `queue` connections waiting for their handshake.
+pub fn fast_open_listen(socket: SockRef<'_>, queue: i32) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    return set_tcp_option(socket, libc::TCP_FASTOPEN, queue);
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (socket, queue);
+        Err(unsupported("TCP Fast Open", "Linux"))
+    }
+}
+
+/// Send the first data written to the socket in its SYN.
+pub fn fast_open_connect(socket: SockRef<'_>) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    return set_tcp_option(socket, libc::TCP_FASTOPEN_CONNECT, 1);
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = socket;
+        Err(unsupported("TCP Fast Open", "Linux"))
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_tcp_option(socket: SockRef<'_>, option: libc::c_int, value: libc::c_int) -> io::Result<()> {
+    use std::os::fd::AsRawFd;
+
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::IPPROTO_TCP,
+            option,
+            &value as *const libc::c_int as *const libc::c_void,
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    match result {
+        0 => Ok(()),
+        _ => Err(io::Error::last_os_error()),
+    }
+}
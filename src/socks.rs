@@ -0,0 +1,312 @@
+use crate::alerts::record_failed_login;
+use crate::auth;
+use crate::bans;
+use crate::credentials::allowed_login;
+use crate::limiter::{self, is_queue_full};
+use crate::listener::{self, Listen};
+use crate::options::Opt;
+use crate::outbound::connect_target;
+use crate::policy::{
+    self, check_connect_port, check_host, check_maintenance, check_quota, check_user_connect_port,
+    check_user_host, Decision,
+};
+use crate::reload;
+use crate::secrets;
+use crate::socks5::{
+    read_methods, read_password, read_request, ATYP_IPV4, COMMAND_CONNECT, METHOD_NONE,
+    METHOD_PASSWORD, METHOD_UNACCEPTABLE, PASSWORD_VERSION, VERSION,
+};
+use crate::stats;
+use crate::throttle::{self, ThrottledStream};
+use crate::tunnel;
+use crate::usage::{self, Direction, MeteredStream};
+use crate::users::UserStore;
+use crate::utils::{client_label, loggable, unverified_label};
+use crate::warmup;
+
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::Ordering;
+
+use base64::{engine::general_purpose::STANDARD as b64, Engine};
+use log::warn;
+use tokio::io::AsyncWriteExt;
+use tokio::net::{TcpListener, TcpStream};
+
+// Reply codes from RFC 1928
+const REPLY_SUCCEEDED: u8 = 0x00;
+const REPLY_FAILURE: u8 = 0x01;
+const REPLY_NOT_ALLOWED: u8 = 0x02;
+const REPLY_NETWORK_UNREACHABLE: u8 = 0x03;
+const REPLY_HOST_UNREACHABLE: u8 = 0x04;
+const REPLY_CONNECTION_REFUSED: u8 = 0x05;
+const REPLY_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+const REPLY_ADDRESS_NOT_SUPPORTED: u8 = 0x08;
+
+/// Accept SOCKS5 clients on `listen`. Only CONNECT is supported, with
+/// username/password authentication (RFC 1929) against the same credentials, users and
+/// providers as the HTTP proxy, and targets are checked against the allowed hosts and
+/// CONNECT ports.
+pub async fn start_proxy(
+    listen: Listen,
+    allowed_credentials: Vec<String>,
+    allowed_hosts: Vec<String>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let server_ip = listen.local_ip();
+    let listener = TcpListener::from_std(listener::bind(listen, "SOCKS5 server").await?)?;
+
+    loop {
+        let (stream, addr) = listener.accept().await?;
+        if !policy::admit_client(addr) {
+            continue;
+        }
+        let Some(slot) = limiter::acquire_client(addr) else {
+            continue;
+        };
+        if let Err(e) = listener::set_client_keepalive(&stream) {
+            warn!("Failed to enable keepalives for {addr}: {e}");
+        }
+
+        // Settings reloaded on SIGHUP replace the flags, and credentials from a secret
+        // manager replace both, changing on refresh
+        let reloaded = reload::current();
+        let (allowed_credentials, allowed_hosts) = match &reloaded {
+            Some(reloaded) => (&reloaded.allowed_credentials, &reloaded.allowed_hosts),
+            None => (&allowed_credentials, &allowed_hosts),
+        };
+        let secrets = secrets::current();
+        let allowed_credentials = secrets
+            .credentials
+            .clone()
+            .unwrap_or_else(|| allowed_credentials.clone());
+        let allowed_hosts = allowed_hosts.clone();
+
+        // SOCKS5 clients can't send the secret token, behind one they need credentials
+        let secret_token = match (&secrets.token, &reloaded) {
+            (Some(token), _) => token.clone(),
+            (None, Some(reloaded)) => reloaded.secret_token.clone(),
+            (None, None) => Opt::global().token.clone().unwrap_or_default(),
+        };
+        let token_protected = !secret_token.trim().is_empty();
+
+        tokio::spawn(async move {
+            let _open = stats::OpenConnection::open();
+            let _slot = slot;
+            stats::SOCKS_REQUESTS.fetch_add(1, Ordering::Relaxed);
+            let result = handle(
+                stream,
+                addr,
+                server_ip,
+                &allowed_credentials,
+                &allowed_hosts,
+                token_protected,
+            )
+            .await;
+            if let Err(e) = result {
+                warn!("SOCKS5 client {addr} failed: {e}");
+            }
+        });
+    }
+}
+
+async fn handle(
+    mut stream: TcpStream,
+    addr: SocketAddr,
+    server_ip: IpAddr,
+    allowed_credentials: &[String],
+    allowed_hosts: &[String],
+    token_protected: bool,
+) -> io::Result<()> {
+    let methods = read_methods(&mut stream).await?;
+
+    let auth_required = !allowed_credentials.is_empty()
+        || UserStore::global().is_some()
+        || auth::enabled()
+        || token_protected;
+    let method = match auth_required {
+        true => METHOD_PASSWORD,
+        false => METHOD_NONE,
+    };
+    if !methods.contains(&method) {
+        let client = match auth_required {
+            true => unverified_label(addr),
+            false => client_label(addr, None),
+        };
+        Decision::deny("auth:missing").log(&client, "-");
+        stream.write_all(&[VERSION, METHOD_UNACCEPTABLE]).await?;
+        return Ok(());
+    }
+    stream.write_all(&[VERSION, method]).await?;
+
+    let mut client = client_label(addr, None);
+    let mut login = None;
+    if auth_required {
+        // Checked like a Proxy-Authorization header, so every credential source applies
+        let (username, password) = read_password(&mut stream).await?;
+        let header = format!("Basic {}", b64.encode(format!("{username}:{password}")));
+
+        // Basic credentials end the login at the first ':', one in the username would
+        // make it another account's
+        let (decision, verified) = match username.contains(':') {
+            true => (Decision::deny("auth:default"), None),
+            false => authenticate(&header, allowed_credentials).await,
+        };
+        decision.log(&unverified_label(addr), "-");
+        let Some(verified) = verified.filter(|_| decision.is_allowed()) else {
+            record_failed_login(&header, allowed_credentials, addr.ip());
+            bans::record_failure(addr.ip());
+            stream.write_all(&[PASSWORD_VERSION, 1]).await?;
+            return Ok(());
+        };
+        stream.write_all(&[PASSWORD_VERSION, 0]).await?;
+        client = client_label(addr, Some(&verified));
+        login = Some(verified);
+    } else {
+        Decision::allow("auth:default").log(&client, "-");
+    }
+
+    let (command, address) = read_request(&mut stream).await?;
+    let Some((host, port)) = address else {
+        return reply(&mut stream, REPLY_ADDRESS_NOT_SUPPORTED).await;
+    };
+    let target = match host.contains(':') {
+        true => format!("[{host}]:{port}"),
+        false => format!("{host}:{port}"),
+    };
+    if command != COMMAND_CONNECT {
+        Decision::deny("socks:command").log(&client, &target);
+        return reply(&mut stream, REPLY_COMMAND_NOT_SUPPORTED).await;
+    }
+
+    // There is no page to show SOCKS5 clients, only a general failure
+    let decision = check_maintenance();
+    decision.log(&client, &target);
+    if !decision.is_allowed() {
+        return reply(&mut stream, REPLY_FAILURE).await;
+    }
+
+    let decision = check_host(&host, allowed_hosts);
+    decision.log(&client, &target);
+    if !decision.is_allowed() {
+        return reply(&mut stream, REPLY_NOT_ALLOWED).await;
+    }
+
+    // Every SOCKS5 request is a tunnel, held to the ports CONNECT may open
+    let decision = check_connect_port(&host, port);
+    decision.log(&client, &target);
+    if !decision.is_allowed() {
+        return reply(&mut stream, REPLY_NOT_ALLOWED).await;
+    }
+
+    // Accounts of --users-file can be held to their own hosts, ports and quota
+    if let Some(user) = login
+        .as_deref()
+        .and_then(|login| UserStore::global()?.get(login))
+    {
+        let decision = check_user_host(&user, &host);
+        decision.log(&client, &target);
+        if !decision.is_allowed() {
+            return reply(&mut stream, REPLY_NOT_ALLOWED).await;
+        }
+        let decision = check_user_connect_port(&user, &host, port);
+        decision.log(&client, &target);
+        if !decision.is_allowed() {
+            return reply(&mut stream, REPLY_NOT_ALLOWED).await;
+        }
+        let decision = check_quota(&user, &host);
+        decision.log(&client, &target);
+        if !decision.is_allowed() {
+            return reply(&mut stream, REPLY_NOT_ALLOWED).await;
+        }
+    }
+
+    // SOCKS5 has no way to say when to retry, only a general failure
+    if limiter::take_request(login.as_deref(), addr.ip()).is_err() {
+        return reply(&mut stream, REPLY_FAILURE).await;
+    }
+
+    // Users still warming up are held to the --warm-up caps
+    let warm_up = login.as_deref().and_then(warmup::limits);
+    let _guard = match &warm_up {
+        Some(limits) => match limits.acquire_connection() {
+            Some(guard) => Some(guard),
+            None => {
+                warn!(
+                    "Connection limit reached ({} active), rejecting client={client}",
+                    limits.connections()
+                );
+                return reply(&mut stream, REPLY_FAILURE).await;
+            }
+        },
+        None => None,
+    };
+
+    let server = match connect_target(&target, Some(server_ip), &client).await {
+        Ok(server) => server,
+        Err(e) => {
+            warn!("Failed to connect to {}: {e}", loggable(&target, &client));
+            return reply(&mut stream, reply_code(&e)).await;
+        }
+    };
+    let bandwidths = warm_up
+        .and_then(|limits| limits.bandwidth.clone())
+        .into_iter()
+        .chain(throttle::user_bandwidth(login.as_deref(), addr.ip()))
+        .chain(throttle::connection_bandwidth().map(|bandwidth| bandwidth.0));
+    let server = ThrottledStream::new(server, bandwidths);
+    let server = MeteredStream::new(
+        server,
+        usage::meter_for(login.as_deref(), None),
+        Direction::Down,
+    );
+
+    reply(&mut stream, REPLY_SUCCEEDED).await?;
+    tunnel::relay(stream, server, &[], &target, &client).await;
+    Ok(())
+}
+
+/// Decide on a `Basic` header built from the RFC 1929 username and password, with the
+/// login of the account that matched when allowed.
+async fn authenticate(header: &str, allowed_credentials: &[String]) -> (Decision, Option<String>) {
+    if let Some(user) = UserStore::global().and_then(|store| store.authenticate(header)) {
+        (
+            Decision::allow(format!("auth:users/{}", user.login)),
+            Some(user.login),
+        )
+    } else if let Some(login) = allowed_login(header, allowed_credentials) {
+        (
+            Decision::allow(format!("auth:credentials/{login}")),
+            Some(login),
+        )
+    } else {
+        match auth::authenticate(header).await {
+            Some((provider, login)) => (
+                Decision::allow(format!("auth:{}", provider.to_lowercase())),
+                Some(login),
+            ),
+            None => (Decision::deny("auth:default"), None),
+        }
+    }
+}
+
+/// Reply to the request. The bound address isn't worth giving away, clients only need
+/// it for BIND.
+async fn reply(stream: &mut TcpStream, code: u8) -> io::Result<()> {
+    stream
+        .write_all(&[VERSION, code, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await
+}
+
+fn reply_code(e: &io::Error) -> u8 {
+    if is_queue_full(e) {
+        return REPLY_FAILURE;
+    }
+    match e.kind() {
+        ErrorKind::ConnectionRefused => REPLY_CONNECTION_REFUSED,
+        ErrorKind::NetworkUnreachable => REPLY_NETWORK_UNREACHABLE,
+        ErrorKind::HostUnreachable | ErrorKind::NotFound | ErrorKind::TimedOut => {
+            REPLY_HOST_UNREACHABLE
+        }
+        _ => REPLY_FAILURE,
+    }
+}
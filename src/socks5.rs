@@ -0,0 +1,94 @@
+//! What SOCKS5 clients send, RFC 1928 and the username/password method of RFC 1929.
+//! Kept free of the listener's state, the fuzz targets read it from any byte stream.
+
+use std::io::{self, ErrorKind};
+use std::net::{Ipv4Addr, Ipv6Addr};
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+pub const VERSION: u8 = 5;
+pub const METHOD_NONE: u8 = 0x00;
+pub const METHOD_PASSWORD: u8 = 0x02;
+pub const METHOD_UNACCEPTABLE: u8 = 0xff;
+pub const PASSWORD_VERSION: u8 = 1;
+pub const COMMAND_CONNECT: u8 = 1;
+pub const ATYP_IPV4: u8 = 1;
+pub const ATYP_DOMAIN: u8 = 3;
+pub const ATYP_IPV6: u8 = 4;
+
+/// Authentication methods the client offers in its greeting.
+pub async fn read_methods<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Vec<u8>> {
+    let [version, count] = read_bytes::<2, _>(stream).await?;
+    if version != VERSION {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Not a SOCKS5 client (version {version})"),
+        ));
+    }
+    let mut methods = vec![0; count as usize];
+    stream.read_exact(&mut methods).await?;
+    Ok(methods)
+}
+
+/// Username and password, bytes that aren't UTF-8 replaced.
+pub async fn read_password<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<(String, String)> {
+    let [version, username_len] = read_bytes::<2, _>(stream).await?;
+    if version != PASSWORD_VERSION {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!("Bad authentication version {version}"),
+        ));
+    }
+    let mut username = vec![0; username_len as usize];
+    stream.read_exact(&mut username).await?;
+
+    let [password_len] = read_bytes::<1, _>(stream).await?;
+    let mut password = vec![0; password_len as usize];
+    stream.read_exact(&mut password).await?;
+
+    Ok((
+        String::from_utf8_lossy(&username).into_owned(),
+        String::from_utf8_lossy(&password).into_owned(),
+    ))
+}
+
+/// Command and target of the request, no target for an unknown address type.
+pub async fn read_request<R: AsyncRead + Unpin>(
+    stream: &mut R,
+) -> io::Result<(u8, Option<(String, u16)>)> {
+    let [version, command, _, atyp] = read_bytes::<4, _>(stream).await?;
+    if version != VERSION {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "Bad request version",
+        ));
+    }
+    Ok((command, read_address(stream, atyp).await?))
+}
+
+/// Host and port of the request, `None` for an unknown address type.
+async fn read_address<R: AsyncRead + Unpin>(
+    stream: &mut R,
+    atyp: u8,
+) -> io::Result<Option<(String, u16)>> {
+    let host = match atyp {
+        ATYP_IPV4 => Ipv4Addr::from(read_bytes::<4, _>(stream).await?).to_string(),
+        ATYP_IPV6 => Ipv6Addr::from(read_bytes::<16, _>(stream).await?).to_string(),
+        ATYP_DOMAIN => {
+            let [len] = read_bytes::<1, _>(stream).await?;
+            let mut domain = vec![0; len as usize];
+            stream.read_exact(&mut domain).await?;
+            String::from_utf8(domain)
+                .map_err(|_| io::Error::new(ErrorKind::InvalidData, "Domain is not UTF-8"))?
+        }
+        _ => return Ok(None),
+    };
+    let port = u16::from_be_bytes(read_bytes::<2, _>(stream).await?);
+    Ok(Some((host, port)))
+}
+
+async fn read_bytes<const N: usize, R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<[u8; N]> {
+    let mut bytes = [0; N];
+    stream.read_exact(&mut bytes).await?;
+    Ok(bytes)
+}
@@ -0,0 +1,108 @@
+use crate::json::{object, Value};
+use crate::tunnel;
+use crate::users::UserStore;
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+/// Upstream connections that failed because no local port could be bound.
+pub static PORT_EXHAUSTED: AtomicU64 = AtomicU64::new(0);
+
+/// Upstream connections refused because too many were already queued for the host.
+pub static CONNECT_QUEUE_FULL: AtomicU64 = AtomicU64::new(0);
+
+/// Client connections refused by `--max-connections` or `--max-connections-per-ip`.
+pub static CONNECTIONS_LIMITED: AtomicU64 = AtomicU64::new(0);
+
+/// Requests refused by `--rate-limit`.
+pub static REQUESTS_RATE_LIMITED: AtomicU64 = AtomicU64::new(0);
+
+/// Lookups and connects failed straight away because the same one failed a moment ago.
+pub static NEGATIVE_CACHE_HITS: AtomicU64 = AtomicU64::new(0);
+
+/// Requests received by the HTTP listeners.
+pub static HTTP_REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+/// Requests received by the HTTPS listener.
+pub static HTTPS_REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+/// Requests received by the SOCKS5 listener.
+pub static SOCKS_REQUESTS: AtomicU64 = AtomicU64::new(0);
+
+/// Client connections open right now, on all listeners.
+pub static OPEN_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Bytes relayed by tunnels from clients to targets, open tunnels included.
+pub static TUNNEL_BYTES_UP: AtomicU64 = AtomicU64::new(0);
+
+/// Bytes relayed by tunnels from targets to clients, open tunnels included.
+pub static TUNNEL_BYTES_DOWN: AtomicU64 = AtomicU64::new(0);
+
+static DENIED: OnceLock<Mutex<BTreeMap<String, u64>>> = OnceLock::new();
+
+/// Client connection counted in [`OPEN_CONNECTIONS`] for as long as it is kept.
+pub struct OpenConnection(());
+
+impl OpenConnection {
+    pub fn open() -> OpenConnection {
+        OPEN_CONNECTIONS.fetch_add(1, Ordering::Relaxed);
+        OpenConnection(())
+    }
+}
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        OPEN_CONNECTIONS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Count a request denied by `rule`, under the check the rule belongs to, e.g. `auth` for
+/// `acme/auth:default`.
+pub fn count_denied(rule: &str) {
+    let check = rule.split(':').next().unwrap_or(rule);
+    let check = check.rsplit('/').next().unwrap_or(check);
+    *DENIED
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap()
+        .entry(check.to_string())
+        .or_default() += 1;
+}
+
+/// Requests denied so far, by check.
+pub fn denied() -> BTreeMap<String, u64> {
+    DENIED
+        .get()
+        .map(|denied| denied.lock().unwrap().clone())
+        .unwrap_or_default()
+}
+
+/// Live counters for the admin API.
+pub fn to_json() -> Value {
+    let users = UserStore::global().map(|store| store.list().len() as u64);
+    object([
+        ("active_tunnels", (tunnel::active_count() as u64).into()),
+        ("users", users.into()),
+        (
+            "port_exhausted",
+            PORT_EXHAUSTED.load(Ordering::Relaxed).into(),
+        ),
+        (
+            "connect_queue_full",
+            CONNECT_QUEUE_FULL.load(Ordering::Relaxed).into(),
+        ),
+        (
+            "connections_limited",
+            CONNECTIONS_LIMITED.load(Ordering::Relaxed).into(),
+        ),
+        (
+            "requests_rate_limited",
+            REQUESTS_RATE_LIMITED.load(Ordering::Relaxed).into(),
+        ),
+        (
+            "negative_cache_hits",
+            NEGATIVE_CACHE_HITS.load(Ordering::Relaxed).into(),
+        ),
+    ])
+}
@@ -0,0 +1,247 @@
+use crate::listener::{self, Listen, ListenOn};
+use crate::nameserver;
+use crate::options::Opt;
+use crate::reload;
+
+use std::env;
+use std::ffi::CString;
+use std::io;
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::process::{exit, Child, Command, ExitStatus};
+
+use futures_util::future::select_all;
+
+/// Descriptor the listening socket is passed to a worker on, right after stdio.
+pub const LISTEN_FD: RawFd = 3;
+
+/// Environment variable naming the server whose listener a worker inherited.
+pub const LISTENER_ENV: &str = "PROXERVER_LISTENER";
+
+/// Value of `--worker` for the worker running the `--dns-zone` server.
+pub const DNS_WORKER: &str = "dns";
+
+/// A listener the supervisor binds and hands to a worker of its own.
+pub struct Worker {
+    /// Value of `--worker`, which server the worker runs
+    pub name: String,
+    /// Name the server's listener is bound under, see [`listener::bind`]
+    pub server_name: String,
+    pub listen: Listen,
+    pub transport: Transport,
+}
+
+/// Kind of socket a worker's listener is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transport {
+    Tcp,
+    /// Bound with [`listener::bind_udp`]
+    Udp,
+}
+
+/// The servers `--isolate-listeners` runs in workers of their own, where `listen_on`
+/// resolves a listener's address like for the servers themselves.
+pub fn workers(listen_on: impl Fn(&Option<ListenOn>, u16) -> Listen) -> Vec<Worker> {
+    let options = Opt::global();
+    let mut workers = Vec::new();
+
+    if !options.no_http_server {
+        workers.push(Worker {
+            name: "http".to_string(),
+            server_name: "HTTP server".to_string(),
+            listen: listen_on(&options.http_listen, options.http_port.unwrap_or(58080)),
+            transport: Transport::Tcp,
+        });
+    }
+    if !options.no_https_server {
+        workers.push(Worker {
+            name: "https".to_string(),
+            server_name: "HTTPS server".to_string(),
+            listen: listen_on(&options.https_listen, options.https_port.unwrap_or(443)),
+            transport: Transport::Tcp,
+        });
+    }
+    if let Some(port) = options.socks_port {
+        workers.push(Worker {
+            name: "socks".to_string(),
+            server_name: "SOCKS5 server".to_string(),
+            listen: listen_on(&options.socks_listen, port),
+            transport: Transport::Tcp,
+        });
+    }
+    for tenant in &options.tenant {
+        // Tenants without a port of their own are served by the HTTP worker
+        let Some(port) = tenant.port else {
+            continue;
+        };
+        workers.push(Worker {
+            name: tenant_worker(&tenant.name),
+            server_name: format!("HTTP server: {}", tenant.name),
+            listen: listen_on(&options.http_listen, port),
+            transport: Transport::Tcp,
+        });
+    }
+    // The DNS server keeps no state, so it can answer from a worker of its own too
+    if options.dns_zone.is_some() {
+        workers.push(Worker {
+            name: DNS_WORKER.to_string(),
+            server_name: nameserver::SERVER_NAME.to_string(),
+            listen: Listen::from(options.dns_listen),
+            transport: Transport::Udp,
+        });
+    }
+
+    workers
+}
+
+/// Value of `--worker` for the worker running the listener of tenant `name`.
+pub fn tenant_worker(name: &str) -> String {
+    format!("tenant:{name}")
+}
+
+/// Bind every listener, then run each server in a worker process of its own that gets
+/// only its listening socket, as `--worker-user` if given. The supervisor keeps no
+/// connections itself: it passes SIGHUP on to the workers and, once one of them exits,
+/// stops the others and exits with its status, so the service manager restarts them all.
+pub async fn run(workers: Vec<Worker>) -> ! {
+    let options = Opt::global();
+    let user = options
+        .worker_user
+        .as_deref()
+        .map(|name| match lookup_user(name) {
+            Some(user) => user,
+            None => {
+                eprintln!("Error: --worker-user {name} doesn't exist");
+                exit(1);
+            }
+        });
+
+    let mut children = Vec::new();
+    for Worker {
+        name,
+        server_name,
+        listen,
+        transport,
+    } in workers
+    {
+        let socket = match transport {
+            Transport::Tcp => listener::bind(listen, &server_name)
+                .await
+                .map(OwnedFd::from),
+            Transport::Udp => listener::bind_udp(listen.addr, &server_name).map(OwnedFd::from),
+        };
+        let listener = match socket {
+            Ok(listener) => listener,
+            Err(e) => {
+                eprintln!("Error starting {server_name}: {e}");
+                exit(1);
+            }
+        };
+
+        match spawn(&name, &server_name, listener.as_raw_fd(), user) {
+            Ok(child) => {
+                println!(
+                    "\n\x1B[34m\x1B[1mRunning {} in worker process {}\x1B[0m",
+                    server_name,
+                    child.id()
+                );
+                children.push((server_name, child));
+            }
+            Err(e) => {
+                eprintln!("Error starting a worker for {server_name}: {e}");
+                stop(&children);
+                exit(1);
+            }
+        }
+        // The worker has its own copy of the socket
+        drop(listener);
+    }
+
+    let pids = children
+        .iter()
+        .map(|(_, child)| child.id())
+        .collect::<Vec<u32>>();
+    tokio::spawn(reload::forward_hangup(pids.clone()));
+
+    let waits = children.into_iter().map(|(server_name, mut child)| {
+        tokio::task::spawn_blocking(move || (server_name, child.wait()))
+    });
+    let (finished, _, _) = select_all(waits).await;
+    let (server_name, status) = finished.expect("worker wait task panicked");
+
+    for pid in pids {
+        unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+    }
+    match status {
+        Ok(status) => {
+            eprintln!("Error: the worker for {server_name} exited ({status}), stopping the others");
+            exit(exit_code(status));
+        }
+        Err(e) => {
+            eprintln!("Error: lost the worker for {server_name}: {e}, stopping the others");
+            exit(1);
+        }
+    }
+}
+
+/// Start the proxy again with the same arguments plus `--worker`, `fd` as its
+/// [`LISTEN_FD`]. Workers die with the supervisor.
+fn spawn(name: &str, server_name: &str, fd: RawFd, user: Option<(u32, u32)>) -> io::Result<Child> {
+    let mut command = Command::new(env::current_exe()?);
+    command
+        .args(env::args_os().skip(1))
+        .args(["--worker", name])
+        .env(LISTENER_ENV, server_name);
+    if let Some((uid, gid)) = user {
+        // Supplementary groups are dropped along with root
+        command.uid(uid).gid(gid);
+    }
+
+    // SAFETY: only async-signal-safe calls between fork and exec
+    unsafe {
+        command.pre_exec(move || {
+            if fd == LISTEN_FD {
+                // dup2 onto itself would keep close-on-exec set
+                if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                    return Err(io::Error::last_os_error());
+                }
+            } else if libc::dup2(fd, LISTEN_FD) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+
+            #[cfg(target_os = "linux")]
+            if libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM) == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            Ok(())
+        });
+    }
+    command.spawn()
+}
+
+fn stop(children: &[(String, Child)]) {
+    for (_, child) in children {
+        unsafe { libc::kill(child.id() as libc::pid_t, libc::SIGTERM) };
+    }
+}
+
+/// User and group ID of the account `name`.
+fn lookup_user(name: &str) -> Option<(u32, u32)> {
+    let c_name = CString::new(name).ok()?;
+    let passwd = unsafe { libc::getpwnam(c_name.as_ptr()) };
+    if passwd.is_null() {
+        return None;
+    }
+    let passwd = unsafe { &*passwd };
+    Some((passwd.pw_uid, passwd.pw_gid))
+}
+
+/// Exit code to pass on for a worker's status, 128 plus the signal for a killed one like
+/// shells report it.
+fn exit_code(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    }
+}
@@ -0,0 +1,243 @@
+use crate::hostmatch;
+use crate::options::Opt;
+use crate::throttle::Bandwidth;
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+
+static LIMITS: OnceLock<HashMap<String, Arc<TenantLimits>>> = OnceLock::new();
+
+/// Logical proxy instance sharing the process with others. A tenant either gets its own
+/// HTTP listener (`port`) or is picked on the main listener by its secret token.
+#[derive(Debug, Clone)]
+pub struct Tenant {
+    pub name: String,
+    pub port: Option<u16>,
+    pub allowed_credentials: Vec<String>,
+    pub allowed_hosts: Vec<String>,
+    pub secret_token: String,
+    pub max_connections: Option<usize>,
+    pub bandwidth: Option<u64>,
+    pub max_body_memory: Option<usize>,
+}
+
+impl FromStr for Tenant {
+    type Err = String;
+
+    /// Parse `name=acme;port=8001;auth=login:password,login2:password2;hosts=*.acme.com;token=secret`,
+    /// optionally with `max_conn=100;bandwidth=10M;max_body=64M` resource caps.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tenant = Tenant {
+            name: String::new(),
+            port: None,
+            allowed_credentials: Vec::new(),
+            allowed_hosts: Vec::new(),
+            secret_token: String::new(),
+            max_connections: None,
+            bandwidth: None,
+            max_body_memory: None,
+        };
+
+        for field in s
+            .split(';')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+        {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key=value, got '{field}'"))?;
+            let value = value.trim();
+
+            match key.trim() {
+                "name" => tenant.name = value.to_string(),
+                "port" => {
+                    tenant.port = Some(
+                        value
+                            .parse::<u16>()
+                            .map_err(|e| format!("Invalid tenant port '{value}': {e}"))?,
+                    )
+                }
+                "auth" => tenant.allowed_credentials = split_list(value),
+                "hosts" => {
+                    tenant.allowed_hosts = split_list(value);
+                    for pattern in &tenant.allowed_hosts {
+                        hostmatch::check(pattern)?;
+                    }
+                }
+                "token" => tenant.secret_token = value.to_string(),
+                "max_conn" => {
+                    tenant.max_connections = Some(
+                        value
+                            .parse::<usize>()
+                            .map_err(|e| format!("Invalid tenant max_conn '{value}': {e}"))?,
+                    )
+                }
+                "bandwidth" => tenant.bandwidth = Some(parse_bytes(value)?),
+                "max_body" => tenant.max_body_memory = Some(parse_bytes(value)? as usize),
+                key => return Err(format!("Unknown tenant setting '{key}'")),
+            }
+        }
+
+        if tenant.name.is_empty() {
+            return Err("Tenant name is required".to_string());
+        }
+        if tenant.port.is_none() && tenant.secret_token.is_empty() {
+            return Err(format!(
+                "Tenant '{}' needs its own port or a token to be identified by",
+                tenant.name
+            ));
+        }
+        Ok(tenant)
+    }
+}
+
+/// Runtime state enforcing a tenant's resource caps, shared by all its connections
+/// whichever listener they arrive on. Warming-up users get the same kind of caps.
+#[derive(Debug)]
+pub struct TenantLimits {
+    max_connections: Option<usize>,
+    connections: AtomicUsize,
+    pub bandwidth: Option<Arc<Bandwidth>>,
+    max_body_memory: Option<usize>,
+    body_memory: AtomicUsize,
+}
+
+impl TenantLimits {
+    fn new(tenant: &Tenant) -> Self {
+        TenantLimits {
+            max_body_memory: tenant.max_body_memory,
+            ..TenantLimits::capped(tenant.max_connections, tenant.bandwidth)
+        }
+    }
+
+    /// Connection and bandwidth caps without body buffering.
+    pub fn capped(max_connections: Option<usize>, bandwidth: Option<u64>) -> Self {
+        TenantLimits {
+            max_connections,
+            connections: AtomicUsize::new(0),
+            bandwidth: bandwidth.map(|rate| Arc::new(Bandwidth::new(rate))),
+            max_body_memory: None,
+            body_memory: AtomicUsize::new(0),
+        }
+    }
+
+    /// Count a connection against the cap. `None` when the tenant is at its limit.
+    pub fn acquire_connection(self: &Arc<Self>) -> Option<ConnectionGuard> {
+        reserve(&self.connections, 1, self.max_connections)?;
+        Some(ConnectionGuard(self.clone()))
+    }
+
+    /// Memory cap for the tenant's buffered bodies. Bodies are only buffered when set.
+    pub fn max_body_memory(&self) -> Option<usize> {
+        self.max_body_memory
+    }
+
+    /// Reserve `bytes` of the tenant's body buffer memory. `None` when that would
+    /// take the tenant over its cap.
+    pub fn reserve_body_memory(self: &Arc<Self>, bytes: usize) -> Option<MemoryReservation> {
+        reserve(&self.body_memory, bytes, self.max_body_memory)?;
+        Some(MemoryReservation {
+            limits: self.clone(),
+            bytes,
+        })
+    }
+
+    pub fn connections(&self) -> usize {
+        self.connections.load(Ordering::Relaxed)
+    }
+
+    pub fn max_connections(&self) -> Option<usize> {
+        self.max_connections
+    }
+}
+
+fn reserve(counter: &AtomicUsize, amount: usize, limit: Option<usize>) -> Option<()> {
+    counter
+        .fetch_update(Ordering::AcqRel, Ordering::Acquire, |current| {
+            let next = current.checked_add(amount)?;
+            match limit {
+                Some(limit) if next > limit => None,
+                _ => Some(next),
+            }
+        })
+        .ok()
+        .map(|_| ())
+}
+
+/// Keeps a connection counted until dropped.
+#[derive(Debug)]
+pub struct ConnectionGuard(Arc<TenantLimits>);
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.0.connections.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+/// Body buffer memory that stays reserved until dropped.
+#[derive(Debug)]
+pub struct MemoryReservation {
+    limits: Arc<TenantLimits>,
+    bytes: usize,
+}
+
+impl MemoryReservation {
+    /// Grow the reservation by `bytes`, failing when the tenant's cap would be exceeded.
+    pub fn grow(&mut self, bytes: usize) -> bool {
+        let limits = &self.limits;
+        if reserve(&limits.body_memory, bytes, limits.max_body_memory).is_none() {
+            return false;
+        }
+        self.bytes += bytes;
+        true
+    }
+}
+
+impl Drop for MemoryReservation {
+    fn drop(&mut self) {
+        self.limits
+            .body_memory
+            .fetch_sub(self.bytes, Ordering::AcqRel);
+    }
+}
+
+/// Resource limits of the named tenant from `--tenant`.
+pub fn limits(name: &str) -> Option<Arc<TenantLimits>> {
+    LIMITS
+        .get_or_init(|| {
+            Opt::global()
+                .tenant
+                .iter()
+                .map(|tenant| (tenant.name.clone(), Arc::new(TenantLimits::new(tenant))))
+                .collect()
+        })
+        .get(name)
+        .cloned()
+}
+
+/// Parse a byte count with an optional `K`, `M` or `G` suffix (powers of 1024).
+pub fn parse_bytes(value: &str) -> Result<u64, String> {
+    let (digits, multiplier) = match value.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&value[..value.len() - 1], 1 << 10),
+        Some('M') => (&value[..value.len() - 1], 1 << 20),
+        Some('G') => (&value[..value.len() - 1], 1 << 30),
+        _ => (value, 1),
+    };
+
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .filter(|n| *n > 0)
+        .ok_or_else(|| format!("Invalid byte size '{value}'"))
+}
+
+fn split_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
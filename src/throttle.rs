@@ -0,0 +1,231 @@
+use crate::options::Opt;
+use crate::tenant::parse_bytes;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::task::{ready, Context, Poll};
+use std::time::{Duration, Instant};
+
+use hyper::body::HttpBody;
+use hyper::Body;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::{sleep, Sleep};
+
+static USERS: OnceLock<Mutex<HashMap<String, Weak<Bandwidth>>>> = OnceLock::new();
+
+/// Token bucket shared by every stream it throttles. Bytes are charged after they have
+/// been transferred, so the bucket can go into debt and the next transfer waits it out.
+#[derive(Debug)]
+pub struct Bandwidth {
+    rate: u64,
+    state: Mutex<BucketState>,
+}
+
+#[derive(Debug)]
+struct BucketState {
+    available: f64,
+    updated: Instant,
+}
+
+impl Bandwidth {
+    /// `rate` is in bytes per second, up to one second worth of traffic can burst.
+    pub fn new(rate: u64) -> Self {
+        Bandwidth {
+            rate,
+            state: Mutex::new(BucketState {
+                available: rate as f64,
+                updated: Instant::now(),
+            }),
+        }
+    }
+
+    /// Charge `bytes` and return how long to wait before transferring more.
+    pub fn take(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(state.updated).as_secs_f64() * self.rate as f64;
+
+        state.available = (state.available + refill).min(self.rate as f64) - bytes as f64;
+        state.updated = now;
+
+        if state.available >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.available / self.rate as f64)
+        }
+    }
+
+    pub async fn consume(&self, bytes: usize) {
+        let wait = self.take(bytes);
+        if !wait.is_zero() {
+            sleep(wait).await;
+        }
+    }
+}
+
+/// Bytes per second, like `10M` or `10MBps` (powers of 1024).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRate(pub u64);
+
+impl FromStr for ByteRate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let bytes = ["Bps", "B/s", "/s"]
+            .iter()
+            .find_map(|unit| s.strip_suffix(unit))
+            .unwrap_or(s);
+        parse_bytes(bytes).map(ByteRate)
+    }
+}
+
+impl fmt::Display for ByteRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}Bps", self.0)
+    }
+}
+
+/// `--bandwidth-per-connection` bucket of a client connection, kept in the extensions of
+/// the requests on it.
+#[derive(Debug, Clone)]
+pub struct ConnectionBandwidth(pub Arc<Bandwidth>);
+
+/// A fresh `--bandwidth-per-connection` bucket for a client connection just accepted.
+pub fn connection_bandwidth() -> Option<ConnectionBandwidth> {
+    let rate = Opt::global().bandwidth_per_connection?;
+    Some(ConnectionBandwidth(Arc::new(Bandwidth::new(rate.0))))
+}
+
+/// `--bandwidth-per-user` bucket of `login`, or of the client address `ip` for clients
+/// without credentials. It's shared by all their transfers while any of them runs.
+pub fn user_bandwidth(login: Option<&str>, ip: IpAddr) -> Option<Arc<Bandwidth>> {
+    let rate = Opt::global().bandwidth_per_user?;
+    let key = match login {
+        Some(login) => format!("user={login}"),
+        None => format!("ip={}", ip.to_canonical()),
+    };
+
+    let mut users = USERS.get_or_init(Default::default).lock().unwrap();
+    if let Some(bandwidth) = users.get(&key).and_then(Weak::upgrade) {
+        return Some(bandwidth);
+    }
+    users.retain(|_, bandwidth| bandwidth.strong_count() > 0);
+    let bandwidth = Arc::new(Bandwidth::new(rate.0));
+    users.insert(key, Arc::downgrade(&bandwidth));
+    Some(bandwidth)
+}
+
+/// Stream whose reads and writes both draw from every one of its shared `Bandwidth`s, or
+/// pass straight through when there are none.
+pub struct ThrottledStream<S> {
+    inner: S,
+    bandwidths: Vec<Arc<Bandwidth>>,
+    delay: Option<Pin<Box<Sleep>>>,
+}
+
+impl<S> ThrottledStream<S> {
+    pub fn new(inner: S, bandwidths: impl IntoIterator<Item = Arc<Bandwidth>>) -> Self {
+        ThrottledStream {
+            inner,
+            bandwidths: bandwidths.into_iter().collect(),
+            delay: None,
+        }
+    }
+
+    fn poll_delay(&mut self, cx: &mut Context<'_>) -> Poll<()> {
+        if let Some(delay) = &mut self.delay {
+            ready!(delay.as_mut().poll(cx));
+            self.delay = None;
+        }
+        Poll::Ready(())
+    }
+
+    /// Charge `bytes` to every bucket, waiting for the one that's furthest in debt.
+    fn charge(&mut self, bytes: usize) {
+        let wait = self
+            .bandwidths
+            .iter()
+            .map(|bandwidth| bandwidth.take(bytes))
+            .max()
+            .unwrap_or_default();
+        if !wait.is_zero() {
+            self.delay = Some(Box::pin(sleep(wait)));
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for ThrottledStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        ready!(this.poll_delay(cx));
+
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.charge(buf.filled().len() - before);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for ThrottledStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        ready!(this.poll_delay(cx));
+
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.charge(written);
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Pass a body through a task that paces it with every one of `bandwidths`. `guard` is
+/// kept alive until the body has been fully sent, so whatever it accounts for covers the transfer.
+pub fn relay_body<G: Send + 'static>(
+    mut body: Body,
+    bandwidths: Vec<Arc<Bandwidth>>,
+    guard: G,
+) -> Body {
+    let (mut sender, relayed) = Body::channel();
+
+    tokio::spawn(async move {
+        let _guard = guard;
+        while let Some(chunk) = body.data().await {
+            let Ok(chunk) = chunk else {
+                sender.abort();
+                return;
+            };
+            for bandwidth in &bandwidths {
+                bandwidth.consume(chunk.len()).await;
+            }
+            if sender.send_data(chunk).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    relayed
+}
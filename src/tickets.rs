@@ -0,0 +1,241 @@
+use crate::options::Opt;
+use crate::utils::formatted_time;
+
+use std::fs;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use log::{info, warn};
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use rand::Rng;
+use rustls::server::ProducesTickets;
+
+// A key is a 16-byte name tickets carry to find it again, and a 32-byte AES-256-GCM key
+const NAME_LEN: usize = 16;
+const SECRET_LEN: usize = 32;
+const KEY_LEN: usize = NAME_LEN + SECRET_LEN;
+const IV_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+static KEY_FILES: OnceLock<Mutex<Vec<Key>>> = OnceLock::new();
+
+struct Key {
+    name: [u8; NAME_LEN],
+    secret: [u8; SECRET_LEN],
+}
+
+impl Key {
+    fn generate() -> Key {
+        let mut rng = rand::thread_rng();
+        Key {
+            name: rng.gen(),
+            secret: rng.gen(),
+        }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Key> {
+        if bytes.len() != KEY_LEN {
+            return None;
+        }
+        let (name, secret) = bytes.split_at(NAME_LEN);
+        Some(Key {
+            name: name.try_into().ok()?,
+            secret: secret.try_into().ok()?,
+        })
+    }
+
+    /// The ticket: key name, IV, then `plain` encrypted with the name authenticated too.
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let iv = rand::thread_rng().gen::<[u8; IV_LEN]>();
+        let mut tag = [0; TAG_LEN];
+        let cipher = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.secret,
+            Some(&iv),
+            &self.name,
+            plain,
+            &mut tag,
+        )
+        .ok()?;
+
+        let mut ticket = Vec::with_capacity(NAME_LEN + IV_LEN + cipher.len() + TAG_LEN);
+        ticket.extend_from_slice(&self.name);
+        ticket.extend_from_slice(&iv);
+        ticket.extend_from_slice(&cipher);
+        ticket.extend_from_slice(&tag);
+        Some(ticket)
+    }
+
+    fn decrypt(&self, ticket: &[u8]) -> Option<Vec<u8>> {
+        let rest = ticket.strip_prefix(&self.name[..])?;
+        if rest.len() < IV_LEN + TAG_LEN {
+            return None;
+        }
+        let (iv, rest) = rest.split_at(IV_LEN);
+        let (cipher, tag) = rest.split_at(rest.len() - TAG_LEN);
+        decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.secret,
+            Some(iv),
+            &self.name,
+            cipher,
+            tag,
+        )
+        .ok()
+    }
+}
+
+// Keys past their use are wiped, so a later memory disclosure can't decrypt the tickets
+// they made and the sessions those resumed
+impl Drop for Key {
+    fn drop(&mut self) {
+        for byte in self.secret.iter_mut() {
+            // SAFETY: a valid, aligned reference to the byte
+            unsafe { std::ptr::write_volatile(byte, 0) };
+        }
+    }
+}
+
+/// Whether the HTTPS server issues session tickets, with keys of its own or from files.
+pub fn enabled() -> bool {
+    let options = Opt::global();
+    options.tls_session_tickets || !options.tls_ticket_key.is_empty()
+}
+
+/// Session ticket keys for the HTTPS server, `None` without `--tls-session-tickets` or
+/// `--tls-ticket-key`. Key files that can't be read are an error.
+pub fn ticketer() -> Result<Option<Arc<dyn ProducesTickets>>, String> {
+    if !enabled() {
+        return Ok(None);
+    }
+    let options = Opt::global();
+    let rotation = Duration::from_secs(options.tls_ticket_rotation);
+
+    if options.tls_ticket_key.is_empty() {
+        return Ok(Some(Arc::new(RotatingKeys {
+            rotation,
+            keys: Mutex::new(Rotation {
+                current: Key::generate(),
+                previous: None,
+                rotated: Instant::now(),
+            }),
+        })));
+    }
+
+    let keys = read_key_files(&options.tls_ticket_key)?;
+    info!(
+        "Loaded {} TLS session ticket keys from --tls-ticket-key",
+        keys.len()
+    );
+    KEY_FILES
+        .set(Mutex::new(keys))
+        .map_err(|_| "TLS session ticket keys are already loaded".to_string())?;
+    Ok(Some(Arc::new(FileKeys { rotation })))
+}
+
+/// Read the `--tls-ticket-key` files again, on SIGHUP. Files that can't be read leave
+/// the keys as they were.
+pub fn reload() {
+    let Some(keys) = KEY_FILES.get() else {
+        return;
+    };
+    let time = formatted_time();
+    match read_key_files(&Opt::global().tls_ticket_key) {
+        Ok(loaded) => {
+            info!("[{time}] Reloaded {} TLS session ticket keys", loaded.len());
+            *keys.lock().unwrap() = loaded;
+        }
+        Err(e) => warn!("[{time}] Reloading the TLS session ticket keys failed, keeping them: {e}"),
+    }
+}
+
+fn read_key_files(paths: &[String]) -> Result<Vec<Key>, String> {
+    paths
+        .iter()
+        .map(|path| {
+            let bytes = fs::read(path).map_err(|e| format!("{path}: {e}"))?;
+            Key::from_bytes(&bytes).ok_or_else(|| {
+                format!(
+                    "{path}: a ticket key is {KEY_LEN} bytes, e.g. from `openssl rand {KEY_LEN}`"
+                )
+            })
+        })
+        .collect()
+}
+
+struct Rotation {
+    current: Key,
+    previous: Option<Key>,
+    rotated: Instant,
+}
+
+/// Keys of this process only: a new one every `rotation`, the one before kept to decrypt
+/// tickets for one more period and then erased, so a ticket is good for at most two.
+struct RotatingKeys {
+    rotation: Duration,
+    keys: Mutex<Rotation>,
+}
+
+impl RotatingKeys {
+    fn rotated(&self) -> std::sync::MutexGuard<'_, Rotation> {
+        let mut keys = self.keys.lock().unwrap();
+        let elapsed = keys.rotated.elapsed();
+        if elapsed >= self.rotation * 2 {
+            // Idle for long enough that the previous key is past its use too
+            keys.previous = None;
+            keys.current = Key::generate();
+            keys.rotated = Instant::now();
+        } else if elapsed >= self.rotation {
+            keys.previous = Some(std::mem::replace(&mut keys.current, Key::generate()));
+            keys.rotated += self.rotation;
+        }
+        keys
+    }
+}
+
+impl ProducesTickets for RotatingKeys {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.rotation.as_secs().try_into().unwrap_or(u32::MAX)
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        self.rotated().current.encrypt(plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        let keys = self.rotated();
+        keys.current
+            .decrypt(cipher)
+            .or_else(|| keys.previous.as_ref()?.decrypt(cipher))
+    }
+}
+
+/// Keys of `--tls-ticket-key`, the same on every server given the same files: the first
+/// encrypts, the others only decrypt. They're rotated by replacing the files.
+struct FileKeys {
+    rotation: Duration,
+}
+
+impl ProducesTickets for FileKeys {
+    fn enabled(&self) -> bool {
+        true
+    }
+
+    fn lifetime(&self) -> u32 {
+        self.rotation.as_secs().try_into().unwrap_or(u32::MAX)
+    }
+
+    fn encrypt(&self, plain: &[u8]) -> Option<Vec<u8>> {
+        let keys = KEY_FILES.get()?.lock().unwrap();
+        keys.first()?.encrypt(plain)
+    }
+
+    fn decrypt(&self, cipher: &[u8]) -> Option<Vec<u8>> {
+        let keys = KEY_FILES.get()?.lock().unwrap();
+        keys.iter().find_map(|key| key.decrypt(cipher))
+    }
+}
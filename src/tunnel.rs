@@ -0,0 +1,313 @@
+use crate::access::{self, Access};
+use crate::json::{object, Value};
+use crate::metrics::TUNNEL_DURATIONS;
+use crate::options::Opt;
+use crate::stats;
+use crate::utils::{formatted_time, loggable};
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::future::pending;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use log::info;
+use tokio::io::{split, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::Notify;
+use tokio::time::sleep;
+
+const BUFFER_LEN: usize = 16 * 1024;
+
+static ACTIVE: OnceLock<Mutex<BTreeMap<u64, Arc<Tunnel>>>> = OnceLock::new();
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+// Totals over the tunnels closed so far
+static CLOSED: [AtomicU64; CloseCause::ALL.len()] =
+    [const { AtomicU64::new(0) }; CloseCause::ALL.len()];
+static BYTES_UP: AtomicU64 = AtomicU64::new(0);
+static BYTES_DOWN: AtomicU64 = AtomicU64::new(0);
+
+/// Why a tunnel ended. With both sides closing, the one that closed first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseCause {
+    ClientEof,
+    UpstreamEof,
+    IdleTimeout,
+    AdminKill,
+    Error,
+}
+
+impl CloseCause {
+    const ALL: [CloseCause; 5] = [
+        CloseCause::ClientEof,
+        CloseCause::UpstreamEof,
+        CloseCause::IdleTimeout,
+        CloseCause::AdminKill,
+        CloseCause::Error,
+    ];
+}
+
+impl fmt::Display for CloseCause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloseCause::ClientEof => write!(f, "client_eof"),
+            CloseCause::UpstreamEof => write!(f, "upstream_eof"),
+            CloseCause::IdleTimeout => write!(f, "idle_timeout"),
+            CloseCause::AdminKill => write!(f, "admin_kill"),
+            CloseCause::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// Open CONNECT tunnel, listed and killed through the admin API.
+struct Tunnel {
+    id: u64,
+    target: String,
+    client: String,
+    opened_at: DateTime<Utc>,
+    opened: Instant,
+    /// Client to target
+    bytes_up: AtomicU64,
+    /// Target to client
+    bytes_down: AtomicU64,
+    last_activity: Mutex<Instant>,
+    first_eof: Mutex<Option<CloseCause>>,
+    kill: Notify,
+}
+
+impl Tunnel {
+    fn open(target: &str, client: &str) -> Arc<Tunnel> {
+        let tunnel = Arc::new(Tunnel {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            target: loggable(target, client).to_string(),
+            client: client.to_string(),
+            opened_at: Utc::now(),
+            opened: Instant::now(),
+            bytes_up: AtomicU64::new(0),
+            bytes_down: AtomicU64::new(0),
+            last_activity: Mutex::new(Instant::now()),
+            first_eof: Mutex::new(None),
+            kill: Notify::new(),
+        });
+        active().lock().unwrap().insert(tunnel.id, tunnel.clone());
+        tunnel
+    }
+
+    /// Count `bytes` on the tunnel's `counter` and the process wide `total`.
+    fn transferred(&self, counter: &AtomicU64, total: &AtomicU64, bytes: usize) {
+        counter.fetch_add(bytes as u64, Ordering::Relaxed);
+        total.fetch_add(bytes as u64, Ordering::Relaxed);
+        *self.last_activity.lock().unwrap() = Instant::now();
+    }
+
+    fn eof(&self, cause: CloseCause) {
+        self.first_eof.lock().unwrap().get_or_insert(cause);
+    }
+
+    /// Resolves once the tunnel has been idle for `--tunnel-idle-timeout`.
+    async fn idle(&self) {
+        let timeout = Opt::global().tunnel_idle_timeout;
+        if timeout == 0 {
+            return pending().await;
+        }
+        let timeout = Duration::from_secs(timeout);
+
+        loop {
+            let idle_for = self.last_activity.lock().unwrap().elapsed();
+            if idle_for >= timeout {
+                return;
+            }
+            sleep(timeout - idle_for).await;
+        }
+    }
+
+    fn close(&self, cause: CloseCause, error: Option<io::Error>) {
+        active().lock().unwrap().remove(&self.id);
+
+        let up = self.bytes_up.load(Ordering::Relaxed);
+        let down = self.bytes_down.load(Ordering::Relaxed);
+        CLOSED[cause as usize].fetch_add(1, Ordering::Relaxed);
+        BYTES_UP.fetch_add(up, Ordering::Relaxed);
+        BYTES_DOWN.fetch_add(down, Ordering::Relaxed);
+        TUNNEL_DURATIONS.observe(self.opened.elapsed());
+
+        let time = formatted_time();
+        let error = error.map(|e| format!(" ({e})")).unwrap_or_default();
+        info!(
+            "[{time}] Tunnel {} closed: cause={cause}{error} up={up} down={down} duration={:.1}s client={}",
+            self.target,
+            self.opened.elapsed().as_secs_f64(),
+            self.client
+        );
+        access::record(&Access {
+            status: 200,
+            bytes_up: up,
+            bytes_down: down,
+            started: self.opened,
+            ..Access::new(&self.client, "CONNECT", &self.target)
+        });
+    }
+
+    fn to_json(&self) -> Value {
+        object([
+            ("id", self.id.into()),
+            ("target", self.target.as_str().into()),
+            ("client", self.client.as_str().into()),
+            ("opened_at", self.opened_at.to_rfc3339().into()),
+            ("bytes_up", self.bytes_up.load(Ordering::Relaxed).into()),
+            ("bytes_down", self.bytes_down.load(Ordering::Relaxed).into()),
+            (
+                "idle_seconds",
+                self.last_activity
+                    .lock()
+                    .unwrap()
+                    .elapsed()
+                    .as_secs()
+                    .into(),
+            ),
+        ])
+    }
+}
+
+fn active() -> &'static Mutex<BTreeMap<u64, Arc<Tunnel>>> {
+    ACTIVE.get_or_init(Default::default)
+}
+
+/// Relay a tunnel between the client and the target, then log how much it carried and
+/// why it ended. `early_data` is what the client sent before the tunnel was set up.
+/// A side that stops sending only has its direction shut down, the other keeps flowing
+/// until it is done too. `client_label` identifies who opened it in the log line.
+pub async fn relay<C, S>(
+    client: C,
+    mut server: S,
+    early_data: &[u8],
+    target: &str,
+    client_label: &str,
+) where
+    C: AsyncRead + AsyncWrite + Unpin,
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let tunnel = Tunnel::open(target, client_label);
+    if !early_data.is_empty() {
+        if let Err(e) = server.write_all(early_data).await {
+            tunnel.close(CloseCause::Error, Some(e));
+            return;
+        }
+        tunnel.transferred(&tunnel.bytes_up, &stats::TUNNEL_BYTES_UP, early_data.len());
+    }
+
+    let (client_read, client_write) = split(client);
+    let (server_read, server_write) = split(server);
+
+    let copy = async {
+        tokio::try_join!(
+            pipe(
+                client_read,
+                server_write,
+                &tunnel,
+                &tunnel.bytes_up,
+                &stats::TUNNEL_BYTES_UP,
+                CloseCause::ClientEof
+            ),
+            pipe(
+                server_read,
+                client_write,
+                &tunnel,
+                &tunnel.bytes_down,
+                &stats::TUNNEL_BYTES_DOWN,
+                CloseCause::UpstreamEof
+            ),
+        )
+    };
+
+    let (cause, error) = tokio::select! {
+        result = copy => match result {
+            Ok(_) => (tunnel.first_eof.lock().unwrap().unwrap_or(CloseCause::ClientEof), None),
+            Err(e) => (CloseCause::Error, Some(e)),
+        },
+        _ = tunnel.idle() => (CloseCause::IdleTimeout, None),
+        _ = tunnel.kill.notified() => (CloseCause::AdminKill, None),
+    };
+
+    tunnel.close(cause, error);
+}
+
+/// Copy one direction until its reader is done, then shut the writer down.
+async fn pipe<R, W>(
+    mut reader: R,
+    mut writer: W,
+    tunnel: &Tunnel,
+    counter: &AtomicU64,
+    total: &AtomicU64,
+    eof: CloseCause,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buffer = vec![0; BUFFER_LEN];
+    loop {
+        let read = match reader.read(&mut buffer).await {
+            // TLS clients often close the connection without a close_notify
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => 0,
+            result => result?,
+        };
+        if read == 0 {
+            tunnel.eof(eof);
+            // The other side may have closed the connection completely already
+            return match writer.shutdown().await {
+                Err(e) if e.kind() == io::ErrorKind::NotConnected => Ok(()),
+                result => result,
+            };
+        }
+
+        writer.write_all(&buffer[..read]).await?;
+        writer.flush().await?;
+        tunnel.transferred(counter, total, read);
+    }
+}
+
+/// Open tunnels and the totals over the closed ones, for the admin API.
+pub fn to_json() -> Value {
+    let tunnels = active()
+        .lock()
+        .unwrap()
+        .values()
+        .map(|tunnel| tunnel.to_json())
+        .collect::<Vec<Value>>();
+    let closed = CloseCause::ALL
+        .iter()
+        .map(|cause| {
+            (
+                cause.to_string(),
+                CLOSED[*cause as usize].load(Ordering::Relaxed).into(),
+            )
+        })
+        .collect();
+
+    object([
+        ("tunnels", Value::Array(tunnels)),
+        ("closed", Value::Object(closed)),
+        ("bytes_up", BYTES_UP.load(Ordering::Relaxed).into()),
+        ("bytes_down", BYTES_DOWN.load(Ordering::Relaxed).into()),
+    ])
+}
+
+/// Number of open tunnels.
+pub fn active_count() -> usize {
+    active().lock().unwrap().len()
+}
+
+/// Close an open tunnel. Returns whether there was one with that ID.
+pub fn kill(id: u64) -> bool {
+    match active().lock().unwrap().get(&id) {
+        Some(tunnel) => {
+            tunnel.kill.notify_one();
+            true
+        }
+        None => false,
+    }
+}
@@ -0,0 +1,438 @@
+use crate::json::{self, object, Value};
+use crate::options::Opt;
+use crate::users::User;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind};
+use std::path::Path;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{ready, Context, Poll};
+use std::time::Duration;
+
+use chrono::{Datelike, NaiveDate, Utc};
+use hyper::body::HttpBody;
+use hyper::Body;
+use log::warn;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::sleep;
+
+// How often changed counters are written to the --usage-file
+const SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+static METERS: OnceLock<Mutex<BTreeMap<String, Arc<Meter>>>> = OnceLock::new();
+static CHANGED: AtomicBool = AtomicBool::new(false);
+
+/// What a user's quota is counted over, calendar days and months in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QuotaPeriod {
+    Daily,
+    Monthly,
+}
+
+impl FromStr for QuotaPeriod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "daily" => Ok(QuotaPeriod::Daily),
+            "monthly" => Ok(QuotaPeriod::Monthly),
+            _ => Err(format!(
+                "Unknown quota period '{s}', expected daily or monthly"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for QuotaPeriod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QuotaPeriod::Daily => write!(f, "daily"),
+            QuotaPeriod::Monthly => write!(f, "monthly"),
+        }
+    }
+}
+
+/// Which way the bytes a metered stream reads go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Client to target
+    Up,
+    /// Target to client
+    Down,
+}
+
+/// Bytes a login transferred since it was first seen, and in the current day and month.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Counters {
+    pub up: u64,
+    pub down: u64,
+    pub day: Option<NaiveDate>,
+    pub day_bytes: u64,
+    /// First day of the month
+    pub month: Option<NaiveDate>,
+    pub month_bytes: u64,
+}
+
+impl Counters {
+    fn add(&mut self, direction: Direction, bytes: u64, today: NaiveDate) {
+        match direction {
+            Direction::Up => self.up += bytes,
+            Direction::Down => self.down += bytes,
+        }
+        if self.day != Some(today) {
+            self.day = Some(today);
+            self.day_bytes = 0;
+        }
+        self.day_bytes += bytes;
+        let month = first_of_month(today);
+        if self.month != Some(month) {
+            self.month = Some(month);
+            self.month_bytes = 0;
+        }
+        self.month_bytes += bytes;
+    }
+
+    /// Bytes counted against a quota over `period`, or against one without a period.
+    pub fn used(&self, period: Option<QuotaPeriod>) -> u64 {
+        let today = Utc::now().date_naive();
+        match period {
+            None => self.up + self.down,
+            Some(QuotaPeriod::Daily) if self.day == Some(today) => self.day_bytes,
+            Some(QuotaPeriod::Monthly) if self.month == Some(first_of_month(today)) => {
+                self.month_bytes
+            }
+            Some(_) => 0,
+        }
+    }
+
+    pub fn to_json(self) -> Value {
+        object([
+            ("up", self.up.into()),
+            ("down", self.down.into()),
+            ("day", self.day.map(|day| day.to_string()).into()),
+            ("day_bytes", self.used(Some(QuotaPeriod::Daily)).into()),
+            ("month", self.month.map(|month| month.to_string()).into()),
+            ("month_bytes", self.used(Some(QuotaPeriod::Monthly)).into()),
+        ])
+    }
+
+    fn from_json(record: &Value) -> Option<Counters> {
+        let number = |key: &str| record.get(key).and_then(Value::as_u64).unwrap_or(0);
+        let date = |key: &str| match record.get(key) {
+            None | Some(Value::Null) => Some(None),
+            Some(value) => value.as_str()?.parse::<NaiveDate>().ok().map(Some),
+        };
+        Some(Counters {
+            up: number("up"),
+            down: number("down"),
+            day: date("day")?,
+            day_bytes: number("day_bytes"),
+            month: date("month")?,
+            month_bytes: number("month_bytes"),
+        })
+    }
+}
+
+fn first_of_month(day: NaiveDate) -> NaiveDate {
+    day.with_day(1).unwrap_or(day)
+}
+
+/// Traffic counters of one login, shared by all its transfers.
+#[derive(Debug, Default)]
+pub struct Meter(Mutex<Counters>);
+
+impl Meter {
+    pub fn add(&self, direction: Direction, bytes: usize) {
+        if bytes == 0 {
+            return;
+        }
+        let today = Utc::now().date_naive();
+        self.0.lock().unwrap().add(direction, bytes as u64, today);
+        CHANGED.store(true, Ordering::Relaxed);
+    }
+}
+
+fn meters() -> &'static Mutex<BTreeMap<String, Arc<Meter>>> {
+    METERS.get_or_init(Default::default)
+}
+
+/// Meter of `login`, kept from its first transfer on.
+pub fn meter(login: &str) -> Arc<Meter> {
+    meters()
+        .lock()
+        .unwrap()
+        .entry(login.to_string())
+        .or_default()
+        .clone()
+}
+
+/// Meter of an authenticated client, none for anonymous ones. `login` is the one its
+/// credentials matched, never the one a header claims, or a client could bill its
+/// traffic to anyone. Logins of tenants are counted apart from the main listener's, as
+/// `{tenant}/{login}`.
+pub fn meter_for(login: Option<&str>, tenant: Option<&str>) -> Option<Arc<Meter>> {
+    let login = login?;
+    Some(match tenant {
+        Some(tenant) => meter(&format!("{tenant}/{login}")),
+        None => meter(login),
+    })
+}
+
+/// What `login` transferred, nothing for a login that never did.
+pub fn counters(login: &str) -> Counters {
+    meters()
+        .lock()
+        .unwrap()
+        .get(login)
+        .map(|meter| *meter.0.lock().unwrap())
+        .unwrap_or_default()
+}
+
+fn snapshot() -> BTreeMap<String, Counters> {
+    meters()
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(login, meter)| (login.clone(), *meter.0.lock().unwrap()))
+        .collect()
+}
+
+/// Body of the 403 refusing a user who used up their quota.
+pub fn quota_message(user: &User) -> String {
+    let used = counters(&user.login).used(user.quota_period);
+    let period = match user.quota_period {
+        Some(QuotaPeriod::Daily) => " today",
+        Some(QuotaPeriod::Monthly) => " this month",
+        None => "",
+    };
+    format!(
+        "Traffic quota exceeded: {used} of {} bytes used{period}\n",
+        user.quota.unwrap_or_default()
+    )
+}
+
+/// Counters of every login, for the admin API.
+pub fn to_json() -> Value {
+    let usage = snapshot()
+        .into_iter()
+        .map(|(login, counters)| (login, counters.to_json()))
+        .collect();
+    object([("usage", Value::Object(usage))])
+}
+
+/// Load the `--usage-file`, so counting goes on where the last run stopped and a broken
+/// file is reported at startup.
+pub fn init() -> io::Result<()> {
+    let Some(path) = path() else {
+        return Ok(());
+    };
+    let meters = load(&path)?
+        .into_iter()
+        .map(|(login, counters)| (login, Arc::new(Meter(Mutex::new(counters)))))
+        .collect();
+    *self::meters().lock().unwrap() = meters;
+    Ok(())
+}
+
+/// The `--usage-file`. Workers of `--isolate-listeners` count apart, each in its own file.
+fn path() -> Option<String> {
+    let options = Opt::global();
+    let path = options.usage_file.as_ref()?;
+    Some(match &options.worker {
+        Some(worker) => format!("{path}.{worker}"),
+        None => path.clone(),
+    })
+}
+
+fn load(path: &str) -> io::Result<BTreeMap<String, Counters>> {
+    let invalid = |message: String| io::Error::new(ErrorKind::InvalidData, message);
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(e),
+    };
+
+    let Value::Object(records) = json::parse(&content).map_err(invalid)? else {
+        return Err(invalid("Expected an object of logins".to_string()));
+    };
+    records
+        .iter()
+        .map(|(login, record)| {
+            Counters::from_json(record)
+                .map(|counters| (login.clone(), counters))
+                .ok_or_else(|| invalid(format!("Invalid usage of {login}")))
+        })
+        .collect()
+}
+
+/// Write the counters to a temporary file and move it over the old one, like the users.
+fn save(path: &str, counters: &BTreeMap<String, Counters>) -> io::Result<()> {
+    let records = Value::Object(
+        counters
+            .iter()
+            .map(|(login, counters)| (login.clone(), counters.to_json()))
+            .collect(),
+    );
+    let tmp_path = format!("{path}.tmp");
+    fs::write(&tmp_path, format!("{records}\n"))?;
+    fs::rename(&tmp_path, path)
+}
+
+/// Write changed counters to the `--usage-file` every minute. What was transferred
+/// since the last write is lost if the proxy is killed.
+pub async fn save_periodically() {
+    let Some(path) = path() else {
+        return;
+    };
+
+    loop {
+        sleep(SAVE_INTERVAL).await;
+        if !CHANGED.swap(false, Ordering::Relaxed) {
+            continue;
+        }
+        if let Err(e) = save(&path, &snapshot()) {
+            CHANGED.store(true, Ordering::Relaxed);
+            warn!("Failed to save usage to {path}: {e}");
+        }
+    }
+}
+
+/// Forget what `login` transferred, in memory and in the `--usage-file`. Returns whether
+/// there was anything.
+pub fn purge(login: &str) -> io::Result<bool> {
+    let removed = meters().lock().unwrap().remove(login).is_some();
+    if let Some(path) = path().filter(|_| removed) {
+        save(&path, &snapshot())?;
+    }
+    Ok(removed)
+}
+
+/// Remove `login` from the `--usage-file` of a proxy that isn't running, and from the
+/// files of its `--isolate-listeners` workers. Returns the files it was in.
+pub fn purge_files(login: &str) -> io::Result<Vec<String>> {
+    let Some(path) = Opt::global().usage_file.clone() else {
+        return Ok(Vec::new());
+    };
+
+    let file = Path::new(&path);
+    let prefix = format!(
+        "{}.",
+        file.file_name().unwrap_or_default().to_string_lossy()
+    );
+    let mut paths = vec![path.clone()];
+    let dir = match file.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    if let Ok(entries) = fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with(&prefix) && !name.ends_with(".tmp") {
+                paths.push(file.with_file_name(name).to_string_lossy().into_owned());
+            }
+        }
+    }
+
+    let mut purged = Vec::new();
+    for path in paths {
+        let mut counters = load(&path)?;
+        if counters.remove(login).is_some() {
+            save(&path, &counters)?;
+            purged.push(path);
+        }
+    }
+    Ok(purged)
+}
+
+/// Stream counting what passes through it on a login's meter, or passing it straight
+/// through without one. `reads` is the way the bytes it reads go, writes go the other way.
+pub struct MeteredStream<S> {
+    inner: S,
+    meter: Option<Arc<Meter>>,
+    reads: Direction,
+}
+
+impl<S> MeteredStream<S> {
+    pub fn new(inner: S, meter: Option<Arc<Meter>>, reads: Direction) -> Self {
+        MeteredStream {
+            inner,
+            meter,
+            reads,
+        }
+    }
+
+    fn count(&self, read: bool, bytes: usize) {
+        let Some(meter) = &self.meter else {
+            return;
+        };
+        let direction = match (read, self.reads) {
+            (true, direction) => direction,
+            (false, Direction::Up) => Direction::Down,
+            (false, Direction::Down) => Direction::Up,
+        };
+        meter.add(direction, bytes);
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MeteredStream<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        this.count(true, buf.filled().len() - before);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MeteredStream<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, buf))?;
+        this.count(false, written);
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// Count a body going `direction` on `meter` as it's sent, or leave it be without one.
+pub fn meter_body(mut body: Body, meter: Option<Arc<Meter>>, direction: Direction) -> Body {
+    let Some(meter) = meter else {
+        return body;
+    };
+    let (mut sender, metered) = Body::channel();
+
+    tokio::spawn(async move {
+        while let Some(chunk) = body.data().await {
+            let Ok(chunk) = chunk else {
+                sender.abort();
+                return;
+            };
+            meter.add(direction, chunk.len());
+            if sender.send_data(chunk).await.is_err() {
+                return;
+            }
+        }
+    });
+
+    metered
+}
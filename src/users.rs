@@ -0,0 +1,569 @@
+use crate::argon2::Argon2Hash;
+use crate::credentials::basic_credentials;
+use crate::hostmatch;
+use crate::json::{self, object, Value};
+use crate::options::Opt;
+use crate::rules::PortList;
+use crate::usage::QuotaPeriod;
+use crate::utils::{to_sha256, RequestRate};
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::PathBuf;
+use std::sync::{Mutex, OnceLock, RwLock};
+
+use chrono::{DateTime, Utc};
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+static STORE: OnceLock<Option<UserStore>> = OnceLock::new();
+
+const GENERATED_PASSWORD_LEN: usize = 24;
+
+/// Proxy account managed through the admin API.
+#[derive(Debug, Clone)]
+pub struct User {
+    pub login: String,
+    password_hash: String,
+    /// Traffic allowance in bytes, unlimited when `None`.
+    pub quota: Option<u64>,
+    /// What the quota is counted over, all time when `None`.
+    pub quota_period: Option<QuotaPeriod>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub scopes: Vec<String>,
+    /// Host patterns the user may reach on top of `--allowed-hosts`, any when empty.
+    pub hosts: Vec<String>,
+    pub deny_hosts: Vec<String>,
+    /// Replaces `--rate-limit` for this user.
+    pub rate_limit: Option<RequestRate>,
+    /// CONNECT ports the user may open tunnels to, on top of `--allow-connect-ports`.
+    pub connect_ports: Option<PortList>,
+    pub disabled: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+impl User {
+    pub fn is_active(&self) -> bool {
+        !self.disabled && self.expires_at.map(|at| at > Utc::now()).unwrap_or(true)
+    }
+
+    fn verify_password(&self, password: &str) -> bool {
+        match self.password_hash.split('$').collect::<Vec<&str>>()[..] {
+            // Salted SHA-256 of users added before Argon2, until their password changes
+            ["sha256", salt, hash] => {
+                let computed = to_sha256(&format!("{salt}{password}"));
+                computed.len() == hash.len()
+                    && openssl::memcmp::eq(computed.as_bytes(), hash.as_bytes())
+            }
+            _ => self
+                .password_hash
+                .parse::<Argon2Hash>()
+                .is_ok_and(|hash| hash.verify(password.as_bytes())),
+        }
+    }
+
+    fn set_password(&mut self, password: &str) {
+        self.password_hash = Argon2Hash::new(password.as_bytes()).to_string();
+    }
+
+    /// What the admin API shows about a user: everything except the password hash.
+    pub fn to_json(&self) -> Value {
+        object([
+            ("login", self.login.as_str().into()),
+            ("quota", self.quota.into()),
+            (
+                "quota_period",
+                self.quota_period.map(|period| period.to_string()).into(),
+            ),
+            (
+                "expires_at",
+                self.expires_at.map(|at| at.to_rfc3339()).into(),
+            ),
+            ("scopes", self.scopes.clone().into()),
+            ("hosts", self.hosts.clone().into()),
+            ("deny_hosts", self.deny_hosts.clone().into()),
+            (
+                "rate_limit",
+                self.rate_limit.map(|rate| rate.to_string()).into(),
+            ),
+            (
+                "connect_ports",
+                self.connect_ports
+                    .as_ref()
+                    .map(|ports| ports.to_string())
+                    .into(),
+            ),
+            ("disabled", self.disabled.into()),
+            ("active", self.is_active().into()),
+            ("created_at", self.created_at.to_rfc3339().into()),
+        ])
+    }
+
+    fn to_record(&self) -> Value {
+        let mut record = self.to_json();
+        if let Value::Object(fields) = &mut record {
+            fields.remove("active");
+            fields.insert(
+                "password_hash".to_string(),
+                self.password_hash.as_str().into(),
+            );
+        }
+        record
+    }
+
+    fn from_record(record: &Value) -> Result<User, String> {
+        let login = record
+            .get("login")
+            .and_then(Value::as_str)
+            .ok_or("User record without login")?;
+        let field = |key: &str| record.get(key).filter(|value| !value.is_null());
+
+        Ok(User {
+            login: login.to_string(),
+            password_hash: field("password_hash")
+                .and_then(Value::as_str)
+                .ok_or_else(|| format!("User {login} has no password hash"))?
+                .to_string(),
+            quota: field("quota").and_then(Value::as_u64),
+            quota_period: field("quota_period")
+                .map(parse_quota_period)
+                .transpose()
+                .map_err(|e| format!("User {login}: {e}"))?,
+            expires_at: field("expires_at")
+                .map(|value| parse_time(value).map_err(|e| format!("User {login}: {e}")))
+                .transpose()?,
+            scopes: field("scopes")
+                .map(parse_scopes)
+                .transpose()?
+                .unwrap_or_default(),
+            hosts: field("hosts")
+                .map(|value| parse_hosts("hosts", value))
+                .transpose()
+                .map_err(|e| format!("User {login}: {e}"))?
+                .unwrap_or_default(),
+            deny_hosts: field("deny_hosts")
+                .map(|value| parse_hosts("deny_hosts", value))
+                .transpose()
+                .map_err(|e| format!("User {login}: {e}"))?
+                .unwrap_or_default(),
+            rate_limit: field("rate_limit")
+                .map(parse_rate)
+                .transpose()
+                .map_err(|e| format!("User {login}: {e}"))?,
+            connect_ports: field("connect_ports")
+                .map(parse_ports)
+                .transpose()
+                .map_err(|e| format!("User {login}: {e}"))?,
+            disabled: field("disabled").and_then(Value::as_bool).unwrap_or(false),
+            created_at: field("created_at")
+                .map(parse_time)
+                .transpose()?
+                .unwrap_or_else(Utc::now),
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum UserError {
+    NotFound,
+    AlreadyExists,
+    Invalid(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for UserError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UserError::NotFound => write!(f, "User not found"),
+            UserError::AlreadyExists => write!(f, "User already exists"),
+            UserError::Invalid(message) => write!(f, "{message}"),
+            UserError::Io(e) => write!(f, "Failed to save users: {e}"),
+        }
+    }
+}
+
+/// Users kept in memory and persisted to `--users-file` on every change.
+#[derive(Debug)]
+pub struct UserStore {
+    path: PathBuf,
+    users: RwLock<BTreeMap<String, User>>,
+    // Logins whose password checked out, against the hash it was checked against, so
+    // Argon2 only runs again after a wrong password or a new hash
+    verified: Mutex<HashMap<String, String>>,
+}
+
+impl UserStore {
+    /// The store configured with `--users-file`, loaded on first use.
+    pub fn global() -> Option<&'static UserStore> {
+        STORE
+            .get_or_init(|| {
+                let path = Opt::global().users_file.as_ref()?;
+                match UserStore::load(PathBuf::from(path)) {
+                    Ok(store) => Some(store),
+                    Err(e) => {
+                        eprintln!("Error: failed to load users from {path}: {e}");
+                        std::process::exit(1);
+                    }
+                }
+            })
+            .as_ref()
+    }
+
+    fn load(path: PathBuf) -> Result<UserStore, String> {
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => "[]".to_string(),
+            Err(e) => return Err(e.to_string()),
+        };
+
+        let users = json::parse(&content)?
+            .as_array()
+            .ok_or("Expected an array of users")?
+            .iter()
+            .map(|record| User::from_record(record).map(|user| (user.login.clone(), user)))
+            .collect::<Result<BTreeMap<String, User>, String>>()?;
+
+        Ok(UserStore {
+            path,
+            users: RwLock::new(users),
+            verified: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Read the users file again, for changes made to it by hand. Returns the number of users.
+    pub fn reload(&self) -> Result<usize, String> {
+        let reloaded = UserStore::load(self.path.clone())?
+            .users
+            .into_inner()
+            .unwrap();
+        let count = reloaded.len();
+        *self.users.write().unwrap() = reloaded;
+        Ok(count)
+    }
+
+    /// Write all users to a temporary file only the proxy's user can read and move it
+    /// over the old one, so a crash never leaves a half-written file behind.
+    fn save(&self, users: &BTreeMap<String, User>) -> Result<(), UserError> {
+        let records = Value::Array(users.values().map(User::to_record).collect());
+        let tmp_path = self.path.with_extension("tmp");
+
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)
+            .map_err(UserError::Io)?;
+        file.write_all(format!("{records}\n").as_bytes())
+            .map_err(UserError::Io)?;
+        // A file left over from before keeps its mode, which `mode` only sets on creation
+        fs::set_permissions(&tmp_path, fs::Permissions::from_mode(0o600)).map_err(UserError::Io)?;
+        fs::rename(&tmp_path, &self.path).map_err(UserError::Io)
+    }
+
+    pub fn list(&self) -> Vec<User> {
+        self.users.read().unwrap().values().cloned().collect()
+    }
+
+    pub fn get(&self, login: &str) -> Option<User> {
+        self.users.read().unwrap().get(login).cloned()
+    }
+
+    /// Create a user from an admin API request. Returns the user and its password,
+    /// which is generated unless the request sets one.
+    pub fn create(&self, request: &Value) -> Result<(User, String), UserError> {
+        let login = request
+            .get("login")
+            .and_then(Value::as_str)
+            .ok_or_else(|| UserError::Invalid("login is required".to_string()))?;
+        validate_login(login)?;
+
+        let password = match request.get("password").and_then(Value::as_str) {
+            Some(password) => password.to_string(),
+            None => generate_password(),
+        };
+
+        let mut user = User {
+            login: login.to_string(),
+            password_hash: String::new(),
+            quota: None,
+            quota_period: None,
+            expires_at: None,
+            scopes: Vec::new(),
+            hosts: Vec::new(),
+            deny_hosts: Vec::new(),
+            rate_limit: None,
+            connect_ports: None,
+            disabled: false,
+            created_at: Utc::now(),
+        };
+        user.set_password(&password);
+        apply_update(&mut user, request)?;
+
+        let mut users = self.users.write().unwrap();
+        if users.contains_key(login) {
+            return Err(UserError::AlreadyExists);
+        }
+        users.insert(login.to_string(), user.clone());
+        if let Err(e) = self.save(&users) {
+            users.remove(login);
+            return Err(e);
+        }
+
+        Ok((user, password))
+    }
+
+    /// Apply the fields present in an admin API request to an existing user.
+    pub fn update(&self, login: &str, request: &Value) -> Result<User, UserError> {
+        let mut users = self.users.write().unwrap();
+        let current = users.get(login).ok_or(UserError::NotFound)?;
+
+        let mut user = current.clone();
+        apply_update(&mut user, request)?;
+        if let Some(password) = request.get("password").and_then(Value::as_str) {
+            user.set_password(password);
+        }
+
+        let previous = users.insert(login.to_string(), user.clone());
+        if let Err(e) = self.save(&users) {
+            if let Some(previous) = previous {
+                users.insert(login.to_string(), previous);
+            }
+            return Err(e);
+        }
+        Ok(user)
+    }
+
+    pub fn delete(&self, login: &str) -> Result<(), UserError> {
+        let mut users = self.users.write().unwrap();
+        let removed = users.remove(login).ok_or(UserError::NotFound)?;
+
+        if let Err(e) = self.save(&users) {
+            users.insert(login.to_string(), removed);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Check a `Basic` Proxy-Authorization header against the active users.
+    pub fn authenticate(&self, credentials_header: &str) -> Option<User> {
+        let (login, password) = basic_credentials(credentials_header)?;
+
+        let user = self.get(&login).filter(User::is_active)?;
+        let checked = to_sha256(&format!("{}:{password}", user.password_hash));
+        let cached = self.verified.lock().unwrap().get(&login).cloned();
+        if cached.is_some_and(|cached| openssl::memcmp::eq(cached.as_bytes(), checked.as_bytes())) {
+            return Some(user);
+        }
+
+        // Hashed without the lock, other logins needn't wait for this one
+        if !user.verify_password(&password) {
+            return None;
+        }
+        self.verified.lock().unwrap().insert(login, checked);
+        Some(user)
+    }
+}
+
+fn apply_update(user: &mut User, request: &Value) -> Result<(), UserError> {
+    let invalid = |message: &str| UserError::Invalid(message.to_string());
+
+    if let Some(quota) = request.get("quota") {
+        user.quota = match quota {
+            Value::Null => None,
+            quota => Some(
+                quota
+                    .as_u64()
+                    .ok_or_else(|| invalid("quota must be a number of bytes or null"))?,
+            ),
+        };
+    }
+    if let Some(quota_period) = request.get("quota_period") {
+        user.quota_period = match quota_period {
+            Value::Null => None,
+            quota_period => Some(parse_quota_period(quota_period).map_err(UserError::Invalid)?),
+        };
+    }
+    if let Some(expires_at) = request.get("expires_at") {
+        user.expires_at = match expires_at {
+            Value::Null => None,
+            expires_at => Some(parse_time(expires_at).map_err(UserError::Invalid)?),
+        };
+    }
+    if let Some(scopes) = request.get("scopes") {
+        user.scopes = parse_scopes(scopes).map_err(UserError::Invalid)?;
+    }
+    if let Some(hosts) = request.get("hosts") {
+        user.hosts = match hosts {
+            Value::Null => Vec::new(),
+            hosts => parse_hosts("hosts", hosts).map_err(UserError::Invalid)?,
+        };
+    }
+    if let Some(deny_hosts) = request.get("deny_hosts") {
+        user.deny_hosts = match deny_hosts {
+            Value::Null => Vec::new(),
+            deny_hosts => parse_hosts("deny_hosts", deny_hosts).map_err(UserError::Invalid)?,
+        };
+    }
+    if let Some(rate_limit) = request.get("rate_limit") {
+        user.rate_limit = match rate_limit {
+            Value::Null => None,
+            rate_limit => Some(parse_rate(rate_limit).map_err(UserError::Invalid)?),
+        };
+    }
+    if let Some(connect_ports) = request.get("connect_ports") {
+        user.connect_ports = match connect_ports {
+            Value::Null => None,
+            connect_ports => Some(parse_ports(connect_ports).map_err(UserError::Invalid)?),
+        };
+    }
+    if let Some(disabled) = request.get("disabled") {
+        user.disabled = disabled
+            .as_bool()
+            .ok_or_else(|| invalid("disabled must be true or false"))?;
+    }
+    if let Some(password) = request.get("password") {
+        match password.as_str() {
+            Some(password) if !password.is_empty() => check_password_policy(password)?,
+            _ => return Err(invalid("password must be a non-empty string")),
+        }
+    }
+    Ok(())
+}
+
+fn validate_login(login: &str) -> Result<(), UserError> {
+    let valid = !login.is_empty()
+        && login.len() <= 64
+        && login
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '-' | '@'));
+
+    if !valid {
+        return Err(UserError::Invalid(
+            "login must be 1-64 characters of letters, digits and . _ - @".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+fn parse_time(value: &Value) -> Result<DateTime<Utc>, String> {
+    let value = value
+        .as_str()
+        .ok_or("Timestamps must be RFC 3339 strings")?;
+    DateTime::parse_from_rfc3339(value)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|e| format!("Invalid timestamp '{value}': {e}"))
+}
+
+fn parse_scopes(value: &Value) -> Result<Vec<String>, String> {
+    value
+        .as_array()
+        .and_then(|scopes| {
+            scopes
+                .iter()
+                .map(|scope| scope.as_str().map(str::to_string))
+                .collect::<Option<Vec<String>>>()
+        })
+        .ok_or_else(|| "scopes must be an array of strings".to_string())
+}
+
+/// Host patterns of a user's `hosts` or `deny_hosts`, regular expressions checked.
+fn parse_hosts(key: &str, value: &Value) -> Result<Vec<String>, String> {
+    let hosts = value
+        .as_array()
+        .and_then(|hosts| {
+            hosts
+                .iter()
+                .map(|host| host.as_str().map(str::to_string))
+                .collect::<Option<Vec<String>>>()
+        })
+        .ok_or_else(|| format!("{key} must be an array of host patterns"))?;
+    for pattern in &hosts {
+        hostmatch::check(pattern)?;
+    }
+    Ok(hosts)
+}
+
+fn parse_quota_period(value: &Value) -> Result<QuotaPeriod, String> {
+    value
+        .as_str()
+        .ok_or("quota_period must be 'daily', 'monthly' or null")?
+        .parse()
+}
+
+fn parse_rate(value: &Value) -> Result<RequestRate, String> {
+    value
+        .as_str()
+        .ok_or("rate_limit must be a string like '100/60s' or null")?
+        .parse()
+}
+
+fn parse_ports(value: &Value) -> Result<PortList, String> {
+    let ports = value
+        .as_str()
+        .ok_or("connect_ports must be a string like '443, 8443' or null")?
+        .parse::<PortList>()?;
+    match ports.0.is_empty() {
+        true => Err("connect_ports must list at least one port".to_string()),
+        false => Ok(ports),
+    }
+}
+
+/// Strong random password for a new user, about 142 bits of entropy.
+pub fn generate_password() -> String {
+    random_string(GENERATED_PASSWORD_LEN)
+}
+
+/// Refuse passwords below `--min-password-entropy`.
+pub fn check_password_policy(password: &str) -> Result<(), UserError> {
+    let min_entropy = Opt::global().min_password_entropy;
+    let entropy = password_entropy(password);
+
+    if entropy < min_entropy as f64 {
+        return Err(UserError::Invalid(format!(
+            "Password too weak: about {entropy:.0} bits of entropy, at least {min_entropy} required. Use a longer password mixing letters, digits and symbols"
+        )));
+    }
+    Ok(())
+}
+
+/// Rough entropy estimate: length times the bits per character of the character classes
+/// used. Repeated characters count little, so `aaaaaaaaaaaa` doesn't pass for strong.
+pub fn password_entropy(password: &str) -> f64 {
+    let mut pool = 0u32;
+    if password.chars().any(|c| c.is_ascii_lowercase()) {
+        pool += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_uppercase()) {
+        pool += 26;
+    }
+    if password.chars().any(|c| c.is_ascii_digit()) {
+        pool += 10;
+    }
+    if password
+        .chars()
+        .any(|c| c.is_ascii_punctuation() || c == ' ')
+    {
+        pool += 33;
+    }
+    if !password.is_ascii() {
+        pool += 100;
+    }
+
+    let length = password.chars().count();
+    let unique = password.chars().collect::<HashSet<char>>().len();
+    let effective_length = length.min(unique * 2);
+
+    effective_length as f64 * (pool.max(1) as f64).log2()
+}
+
+fn random_string(len: usize) -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(len)
+        .map(char::from)
+        .collect()
+}
@@ -1,16 +1,50 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use crate::options::Opt;
+
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::process::Command;
+use std::str::FromStr;
 
-use base64::{engine::general_purpose::STANDARD as b64, Engine};
-use chrono::Local;
+use chrono::format::{Item, StrftimeItems};
+use chrono::{DateTime, FixedOffset, Local, SecondsFormat, Utc};
 use hyper::{header::PROXY_AUTHENTICATE, Body, Response, StatusCode};
-use rand::Rng;
 use sha2::{Digest, Sha256};
-use wildmatch::WildMatch;
 
-pub fn get_rand_ipv4_socket_addr(server_ip_addr: IpAddr) -> SocketAddr {
-    let mut rng = rand::thread_rng();
-    SocketAddr::new(server_ip_addr, rng.gen::<u16>())
+/// `Retry-After`, and with a `limit` the `RateLimit-*` headers, for a request refused by a
+/// limit or quota, so well-behaved clients back off for `--retry-after` seconds.
+pub fn rate_limit_headers(limit: Option<usize>) -> Vec<(&'static str, String)> {
+    retry_headers(limit, Opt::global().retry_after)
+}
+
+/// Headers of [`rate_limit_headers`] telling clients to wait `retry_after` seconds.
+pub fn retry_headers(limit: Option<usize>, retry_after: u64) -> Vec<(&'static str, String)> {
+    let retry_after = retry_after.to_string();
+    let mut headers = vec![("Retry-After", retry_after.clone())];
+    if let Some(limit) = limit {
+        headers.push(("RateLimit-Limit", limit.to_string()));
+        headers.push(("RateLimit-Remaining", "0".to_string()));
+        headers.push(("RateLimit-Reset", retry_after));
+    }
+    headers
+}
+
+/// Empty response refusing a request because of a limit, see [`rate_limit_headers`].
+pub fn rate_limited(status: StatusCode, limit: Option<usize>) -> Response<Body> {
+    limited_response(status, rate_limit_headers(limit))
+}
+
+/// Empty response refusing a request with the headers of [`retry_headers`].
+pub fn limited_response(
+    status: StatusCode,
+    headers: Vec<(&'static str, String)>,
+) -> Response<Body> {
+    let mut builder = Response::builder().status(status);
+    for (name, value) in headers {
+        builder = builder.header(name, value);
+    }
+    builder.body(Body::empty()).unwrap()
 }
 
 pub fn require_basic_auth() -> Response<Body> {
@@ -34,24 +68,78 @@ pub fn create_basic_auth_response() -> Vec<u8> {
     response.into_bytes()
 }
 
-pub fn is_host_allowed(req_host: &str, allowed_hosts: &[String]) -> bool {
-    for host in allowed_hosts {
-        if WildMatch::new(host.as_str()).matches(req_host) {
-            return true;
-        }
-    }
-    false
+/// Label identifying a client in logs: its address and the login it authenticated with.
+pub fn client_label(addr: SocketAddr, login: Option<&str>) -> String {
+    format!("{addr} user={}", login.unwrap_or("-"))
 }
 
-pub fn is_credentials_allowed(credentials_header: &str, credentials_allowed: &[String]) -> bool {
-    for credentials in credentials_allowed {
-        let credentials_allowed = b64.encode(credentials);
+/// Label of a client that has yet to prove who it is. Headers name whoever the client
+/// likes, so until its credentials are checked the label names no one.
+pub fn unverified_label(addr: SocketAddr) -> String {
+    format!("{addr} user={UNVERIFIED}")
+}
 
-        if credentials_header.contains(&credentials_allowed) {
-            return true;
-        }
+/// Login in the label of a client whose credentials aren't checked yet.
+pub const UNVERIFIED: &str = "?";
+
+/// Login in a client label, `-` when the client didn't authenticate.
+pub fn label_user(client: &str) -> &str {
+    client
+        .split_whitespace()
+        .find_map(|field| field.strip_prefix("user="))
+        .unwrap_or("-")
+}
+
+/// Address of the client in a client label, IPv4-mapped addresses as IPv4.
+pub fn label_ip(client: &str) -> Option<IpAddr> {
+    let addr = client
+        .split_whitespace()
+        .next()?
+        .parse::<SocketAddr>()
+        .ok()?;
+    Some(addr.ip().to_canonical())
+}
+
+/// `target` as it may be logged about `client`. Destinations of `--no-log` users are
+/// kept out of logs and analytics, only their byte counts are.
+pub fn loggable<'a>(target: &'a str, client: &str) -> &'a str {
+    if is_no_log(client) {
+        "redacted"
+    } else {
+        target
     }
-    false
+}
+
+pub fn is_no_log(client: &str) -> bool {
+    let no_log = &Opt::global().no_log;
+    match label_user(client) {
+        // Any client not known yet may turn out to be one of them
+        UNVERIFIED => !no_log.is_empty(),
+        user => no_log.iter().any(|login| login == user),
+    }
+}
+
+/// Request headers for the debug log. Credentials and cookies are masked unless logging at
+/// trace level.
+pub fn redacted_headers<'a>(
+    headers: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> Vec<(&'a str, &'a str)> {
+    const SENSITIVE: [&str; 4] = [
+        "authorization",
+        "proxy-authorization",
+        "cookie",
+        "set-cookie",
+    ];
+    let reveal = log::log_enabled!(log::Level::Trace);
+    headers
+        .into_iter()
+        .map(|(name, value)| {
+            match !reveal && SENSITIVE.iter().any(|s| name.eq_ignore_ascii_case(s)) {
+                true => (name, "[redacted]"),
+                false => (name, value),
+            }
+        })
+        .collect()
 }
 
 pub async fn get_server_ip() -> IpAddr {
@@ -90,7 +178,179 @@ pub fn to_sha256(input: &str) -> String {
     format!("{:x}", result)
 }
 
+/// Addresses configured on the host's interfaces, with the interface names.
+pub fn interface_addrs() -> Vec<(String, IpAddr)> {
+    let mut addrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut addrs) } != 0 {
+        return Vec::new();
+    }
+
+    let mut interface_addrs = Vec::new();
+    let mut cursor = addrs;
+    while !cursor.is_null() {
+        let entry = unsafe { &*cursor };
+        if !entry.ifa_addr.is_null() {
+            if let Some(ip) = unsafe { sockaddr_ip(entry.ifa_addr) } {
+                let name = unsafe { CStr::from_ptr(entry.ifa_name) };
+                interface_addrs.push((name.to_string_lossy().into_owned(), ip));
+            }
+        }
+        cursor = entry.ifa_next;
+    }
+
+    unsafe { libc::freeifaddrs(addrs) };
+    interface_addrs
+}
+
+/// # Safety
+/// `addr` must point to a valid `sockaddr` of the size its family says.
+unsafe fn sockaddr_ip(addr: *const libc::sockaddr) -> Option<IpAddr> {
+    match i32::from((*addr).sa_family) {
+        libc::AF_INET => {
+            let addr = &*(addr as *const libc::sockaddr_in);
+            Some(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr)).into())
+        }
+        libc::AF_INET6 => {
+            let addr = &*(addr as *const libc::sockaddr_in6);
+            Some(Ipv6Addr::from(addr.sin6_addr.s6_addr).into())
+        }
+        _ => None,
+    }
+}
+
+/// Value of `--log-time-format` for RFC 3339 timestamps.
+pub const RFC3339_FORMAT: &str = "rfc3339";
+
+/// Time zone the proxy's logs are written in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogTimezone {
+    Local,
+    Utc,
+}
+
+impl FromStr for LogTimezone {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "local" => Ok(LogTimezone::Local),
+            "utc" => Ok(LogTimezone::Utc),
+            _ => Err(format!("Unknown time zone '{s}', expected local or utc")),
+        }
+    }
+}
+
+impl fmt::Display for LogTimezone {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogTimezone::Local => write!(f, "local"),
+            LogTimezone::Utc => write!(f, "utc"),
+        }
+    }
+}
+
+/// Current time for log lines, in `--log-timezone` and `--log-time-format`.
 pub fn formatted_time() -> String {
-    let now = Local::now();
-    now.format("%Y-%m-%d %H:%M:%S").to_string()
+    format_time(Utc::now())
+}
+
+/// `time` like in log lines, in `--log-timezone` and `--log-time-format`.
+pub fn format_time(time: DateTime<Utc>) -> String {
+    let time = log_time(time);
+    match Opt::global().log_time_format.as_str() {
+        RFC3339_FORMAT => time.to_rfc3339_opts(SecondsFormat::Secs, true),
+        format => time.format(format).to_string(),
+    }
+}
+
+/// `time` in `--log-timezone`, for logs whose layout is fixed, like the access log.
+pub fn log_time(time: DateTime<Utc>) -> DateTime<FixedOffset> {
+    match Opt::global().log_timezone {
+        LogTimezone::Local => time.with_timezone(&Local).fixed_offset(),
+        LogTimezone::Utc => time.fixed_offset(),
+    }
+}
+
+/// Check a `--log-time-format`, chrono would panic on a bad one when writing a log line.
+pub fn check_time_format(format: &str) -> Result<(), String> {
+    if format == RFC3339_FORMAT || !StrftimeItems::new(format).any(|item| item == Item::Error) {
+        return Ok(());
+    }
+    Err(format!("Invalid time format '{format}'"))
+}
+
+/// Requests allowed per period, e.g. `100/60s`. The period is in seconds, or with an `s`,
+/// `m` or `h` suffix, and a bare unit like `10/s` means one of it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RequestRate {
+    pub requests: u32,
+    pub seconds: u64,
+}
+
+impl FromStr for RequestRate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid rate '{s}', expected requests/period like '100/60s'");
+        let (requests, period) = s.trim().split_once('/').ok_or_else(invalid)?;
+        let requests = requests.trim().parse::<u32>().map_err(|_| invalid())?;
+
+        let period = period.trim();
+        let (count, unit) = match period.strip_suffix(['s', 'm', 'h']) {
+            Some(count) => (count, &period[count.len()..]),
+            None => (period, "s"),
+        };
+        let count = match count {
+            "" => 1,
+            count => count.parse::<u64>().map_err(|_| invalid())?,
+        };
+        let seconds = match unit {
+            "m" => count * 60,
+            "h" => count * 3600,
+            _ => count,
+        };
+        if requests == 0 || seconds == 0 {
+            return Err(format!("Rate '{s}' must allow requests over some time"));
+        }
+        Ok(RequestRate { requests, seconds })
+    }
+}
+
+impl fmt::Display for RequestRate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}s", self.requests, self.seconds)
+    }
+}
+
+/// Decode `%XX` escapes of a URL path or query component.
+pub fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Parameters of a URL query string, decoded. Later duplicates win.
+pub fn parse_query(query: Option<&str>) -> BTreeMap<String, String> {
+    query
+        .unwrap_or_default()
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .filter_map(|pair| {
+            let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+            let key = percent_decode(&key.replace('+', " "))?;
+            let value = percent_decode(&value.replace('+', " "))?;
+            Some((key, value))
+        })
+        .collect()
 }
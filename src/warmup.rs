@@ -0,0 +1,90 @@
+use crate::options::Opt;
+use crate::tenant::{parse_bytes, TenantLimits};
+use crate::users::UserStore;
+
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use chrono::{Duration, Utc};
+
+static LIMITS: OnceLock<Mutex<HashMap<String, Arc<TenantLimits>>>> = OnceLock::new();
+
+/// Caps for users during their first hours after creation, so a freshly leaked trial
+/// account can't do much before anyone notices.
+#[derive(Debug, Clone)]
+pub struct WarmUp {
+    pub hours: u64,
+    pub max_connections: Option<usize>,
+    pub bandwidth: Option<u64>,
+}
+
+impl FromStr for WarmUp {
+    type Err = String;
+
+    /// Parse `hours=24;max_conn=4;bandwidth=256K`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut warm_up = WarmUp {
+            hours: 0,
+            max_connections: None,
+            bandwidth: None,
+        };
+
+        for field in s
+            .split(';')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+        {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| format!("Expected key=value, got '{field}'"))?;
+            let value = value.trim();
+
+            match key.trim() {
+                "hours" => {
+                    warm_up.hours = value
+                        .parse::<u64>()
+                        .map_err(|e| format!("Invalid warm-up hours '{value}': {e}"))?
+                }
+                "max_conn" => {
+                    warm_up.max_connections = Some(
+                        value
+                            .parse::<usize>()
+                            .map_err(|e| format!("Invalid warm-up max_conn '{value}': {e}"))?,
+                    )
+                }
+                "bandwidth" => warm_up.bandwidth = Some(parse_bytes(value)?),
+                key => return Err(format!("Unknown warm-up setting '{key}'")),
+            }
+        }
+
+        if warm_up.hours == 0 {
+            return Err("Warm-up hours are required".to_string());
+        }
+        if warm_up.max_connections.is_none() && warm_up.bandwidth.is_none() {
+            return Err("Warm-up needs max_conn or bandwidth to limit".to_string());
+        }
+        Ok(warm_up)
+    }
+}
+
+/// Caps shared by all connections of `login` while it is warming up, `None` for
+/// users past their warm-up and for credentials that aren't managed users.
+pub fn limits(login: &str) -> Option<Arc<TenantLimits>> {
+    let warm_up = Opt::global().warm_up.as_ref()?;
+    let user = UserStore::global()?.get(login)?;
+
+    let mut limits = LIMITS.get_or_init(Default::default).lock().unwrap();
+    if Utc::now() - user.created_at >= Duration::hours(warm_up.hours as i64) {
+        limits.remove(login);
+        return None;
+    }
+
+    let limits = limits.entry(login.to_string()).or_insert_with(|| {
+        Arc::new(TenantLimits::capped(
+            warm_up.max_connections,
+            warm_up.bandwidth,
+        ))
+    });
+    Some(limits.clone())
+}
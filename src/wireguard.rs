@@ -0,0 +1,388 @@
+//! Embedded WireGuard client. With `--wireguard`, tunnels leave through the configured peer:
+//! boringtun runs the handshake and transport over a UDP socket and the TCP connections
+//! inside the tunnel are made by [`netstack`](crate::netstack), so neither root nor a tun
+//! device is needed.
+
+use crate::netstack::{self, Stack};
+use crate::options::Opt;
+use crate::outbound::set_fwmark;
+use crate::rules::IpNet;
+
+use std::future::Future;
+use std::io::{self, ErrorKind};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use base64::{engine::general_purpose::STANDARD as b64, Engine};
+use boringtun::noise::errors::WireGuardError;
+use boringtun::noise::{Tunn, TunnResult};
+use boringtun::x25519::{PublicKey, StaticSecret};
+use hyper::client::connect::{Connected, Connection};
+use hyper::service::Service;
+use hyper::Uri;
+use log::{info, warn};
+use rand::Rng;
+use socket2::SockRef;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{lookup_host, UdpSocket};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::time::interval;
+
+const MESSAGE_RESPONSE: u8 = 2;
+const MESSAGE_COOKIE_REPLY: u8 = 3;
+// Large enough for any datagram, and for any packet of the stack with the transport header
+const BUFFER_LEN: usize = 64 * 1024;
+// How often boringtun wants its timers updated, for handshakes, rekeys and keepalives
+const TIMER_TICK: Duration = Duration::from_millis(250);
+
+static DEVICE: OnceLock<Arc<Device>> = OnceLock::new();
+
+/// `--wireguard` configuration, the `[Interface]` and single `[Peer]` of a wg-quick file.
+/// `AllowedIPs` and the other wg-quick keys are ignored, all tunnels go to the peer.
+pub struct Config {
+    private_key: [u8; 32],
+    peer_public_key: [u8; 32],
+    preshared_key: Option<[u8; 32]>,
+    address: Ipv4Addr,
+    endpoint: String,
+    persistent_keepalive: Option<u16>,
+}
+
+impl Config {
+    pub fn parse(text: &str) -> Result<Config, String> {
+        let mut section = String::new();
+        let mut peers = 0;
+        let mut private_key = None;
+        let mut address = None;
+        let mut peer_public_key = None;
+        let mut preshared_key = None;
+        let mut endpoint = None;
+        let mut persistent_keepalive = None;
+
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default().trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') {
+                section = line.to_ascii_lowercase();
+                if section == "[peer]" {
+                    peers += 1;
+                }
+                continue;
+            }
+
+            let (key, value) = line
+                .split_once('=')
+                .ok_or_else(|| format!("Expected 'Key = Value', got '{line}'"))?;
+            let value = value.trim();
+            match (section.as_str(), key.trim().to_ascii_lowercase().as_str()) {
+                ("[interface]", "privatekey") => private_key = Some(decode_key(value)?),
+                ("[interface]", "address") => {
+                    // The tunnel addresses carry only IPv4 connections
+                    address = value
+                        .split(',')
+                        .map(|net| net.parse::<IpNet>())
+                        .collect::<Result<Vec<IpNet>, String>>()?
+                        .into_iter()
+                        .find_map(|net| match net.addr {
+                            IpAddr::V4(addr) => Some(addr),
+                            IpAddr::V6(_) => None,
+                        });
+                }
+                ("[peer]", "publickey") => peer_public_key = Some(decode_key(value)?),
+                ("[peer]", "presharedkey") => preshared_key = Some(decode_key(value)?),
+                ("[peer]", "endpoint") => endpoint = Some(value.to_string()),
+                ("[peer]", "persistentkeepalive") if value != "off" => {
+                    let seconds = value
+                        .parse::<u16>()
+                        .map_err(|e| format!("Invalid PersistentKeepalive '{value}': {e}"))?;
+                    persistent_keepalive = (seconds > 0).then_some(seconds);
+                }
+                _ => {}
+            }
+        }
+
+        if peers != 1 {
+            return Err(format!("Expected exactly one [Peer], found {peers}"));
+        }
+        Ok(Config {
+            private_key: private_key.ok_or("Missing PrivateKey in [Interface]")?,
+            peer_public_key: peer_public_key.ok_or("Missing PublicKey in [Peer]")?,
+            preshared_key,
+            address: address.ok_or("Missing an IPv4 Address in [Interface]")?,
+            endpoint: endpoint.ok_or("Missing Endpoint in [Peer]")?,
+            persistent_keepalive,
+        })
+    }
+}
+
+fn decode_key(value: &str) -> Result<[u8; 32], String> {
+    b64.decode(value)
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| format!("Invalid key '{value}', expected 32 bytes of base64"))
+}
+
+/// Start the WireGuard egress if `--wireguard` is set.
+pub async fn init() -> Result<(), String> {
+    let Some(path) = &Opt::global().wireguard else {
+        return Ok(());
+    };
+
+    let text = std::fs::read_to_string(path).map_err(|e| format!("{path}: {e}"))?;
+    let config = Config::parse(&text).map_err(|e| format!("{path}: {e}"))?;
+
+    let peer = lookup_host(&config.endpoint)
+        .await
+        .map_err(|e| format!("Cannot resolve {}: {e}", config.endpoint))?
+        .next()
+        .ok_or_else(|| format!("Cannot resolve {}", config.endpoint))?;
+    let bind_addr: SocketAddr = match peer {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(bind_addr)
+        .await
+        .map_err(|e| e.to_string())?;
+    // Like wg's own fwmark, so the encrypted packets can be routed around the tunnel
+    set_fwmark(SockRef::from(&socket), Opt::global().fwmark).map_err(|e| e.to_string())?;
+    socket.connect(peer).await.map_err(|e| e.to_string())?;
+
+    let tunnel = Tunn::new(
+        StaticSecret::from(config.private_key),
+        PublicKey::from(config.peer_public_key),
+        config.preshared_key,
+        config.persistent_keepalive,
+        // Only the low 24 bits are ours, boringtun numbers the sessions in the rest
+        rand::thread_rng().gen_range(0..1 << 24),
+        None,
+    )
+    .map_err(|e| format!("{path}: {e}"))?;
+
+    let (outbound, packets) = unbounded_channel();
+    let device = Arc::new(Device {
+        stack: Stack::new(config.address, outbound),
+        peer,
+        socket,
+        tunnel: Mutex::new(tunnel),
+    });
+    DEVICE
+        .set(device.clone())
+        .map_err(|_| "WireGuard egress already started".to_string())?;
+
+    info!(
+        "WireGuard egress through {peer} from {}",
+        device.stack.address()
+    );
+    tokio::spawn(device.clone().receive());
+    tokio::spawn(device.send(packets));
+    Ok(())
+}
+
+/// The WireGuard egress, when `--wireguard` is set.
+pub fn device() -> Option<&'static Arc<Device>> {
+    DEVICE.get()
+}
+
+pub struct Device {
+    peer: SocketAddr,
+    socket: UdpSocket,
+    stack: Arc<Stack>,
+    /// Handshakes, sessions and their timers; the packets of the stack wait in it while
+    /// there is no session
+    tunnel: Mutex<Tunn>,
+}
+
+impl Device {
+    pub fn peer(&self) -> SocketAddr {
+        self.peer
+    }
+
+    /// Connect to the first reachable of `addrs` through the tunnel. Returns the stream,
+    /// the address it is connected to and the tunnel address it is from.
+    pub async fn connect(
+        &self,
+        addrs: &[SocketAddr],
+    ) -> io::Result<(WireGuardStream, SocketAddr, SocketAddr)> {
+        let mut last_error = None;
+        for addr in addrs {
+            let SocketAddr::V4(remote) = addr else {
+                continue;
+            };
+            match self.stack.connect(*remote).await {
+                Ok((stream, local)) => return Ok((WireGuardStream(stream), *addr, local.into())),
+                Err(e) => last_error = Some(e),
+            }
+        }
+
+        Err(last_error.unwrap_or_else(|| {
+            io::Error::new(
+                ErrorKind::NotFound,
+                "No IPv4 address, the WireGuard egress only reaches IPv4 destinations",
+            )
+        }))
+    }
+
+    async fn receive(self: Arc<Self>) {
+        let mut buffer = vec![0; BUFFER_LEN];
+        let mut out = vec![0; BUFFER_LEN];
+        loop {
+            let n = match self.socket.recv(&mut buffer).await {
+                Ok(n) => n,
+                // ICMP errors for earlier packets show up here, the peer may be back later
+                Err(e) => {
+                    warn!("WireGuard receive from {} failed: {e}", self.peer);
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+
+            let message = &buffer[..n];
+            let mut replies = Vec::new();
+            {
+                let mut tunnel = self.tunnel.lock().unwrap();
+                match tunnel.decapsulate(None, message, &mut out) {
+                    TunnResult::WriteToNetwork(reply) => {
+                        if message.first() == Some(&MESSAGE_RESPONSE) {
+                            info!("WireGuard handshake with {} completed", self.peer);
+                        }
+                        replies.push(reply.to_vec());
+                        // What waited for the session goes out now
+                        while let TunnResult::WriteToNetwork(queued) =
+                            tunnel.decapsulate(None, &[], &mut out)
+                        {
+                            replies.push(queued.to_vec());
+                        }
+                    }
+                    TunnResult::WriteToTunnelV4(packet, _) => self.stack.deliver(packet),
+                    // The cookie is kept for the next handshake
+                    TunnResult::Done if message.first() == Some(&MESSAGE_COOKIE_REPLY) => info!(
+                        "WireGuard peer {} is under load, retrying the handshake later",
+                        self.peer
+                    ),
+                    // Replays, packets from old sessions and anything forged
+                    TunnResult::Done | TunnResult::WriteToTunnelV6(..) | TunnResult::Err(_) => {}
+                }
+            }
+
+            for reply in replies {
+                if let Err(e) = self.socket.send(&reply).await {
+                    warn!("WireGuard send to {} failed: {e}", self.peer);
+                }
+            }
+        }
+    }
+
+    /// Encrypt and send the packets of the stack, and whatever the timers of the tunnel
+    /// call for: handshakes, rekeys and keepalives.
+    async fn send(self: Arc<Self>, mut packets: UnboundedReceiver<Vec<u8>>) {
+        let mut out = vec![0; BUFFER_LEN];
+        let mut timers = interval(TIMER_TICK);
+        loop {
+            let message = {
+                let packet = tokio::select! {
+                    packet = packets.recv() => match packet {
+                        Some(packet) => Some(packet),
+                        None => return,
+                    },
+                    _ = timers.tick() => None,
+                };
+
+                let mut tunnel = self.tunnel.lock().unwrap();
+                let result = match &packet {
+                    Some(packet) => tunnel.encapsulate(packet, &mut out),
+                    None => tunnel.update_timers(&mut out),
+                };
+                match result {
+                    TunnResult::WriteToNetwork(message) => message.to_vec(),
+                    // Without a session for that long, the peer is tried again with the
+                    // next packet
+                    TunnResult::Err(WireGuardError::ConnectionExpired) => continue,
+                    TunnResult::Err(e) => {
+                        warn!("WireGuard tunnel to {} failed: {e:?}", self.peer);
+                        continue;
+                    }
+                    _ => continue,
+                }
+            };
+
+            if let Err(e) = self.socket.send(&message).await {
+                warn!("WireGuard send to {} failed: {e}", self.peer);
+            }
+        }
+    }
+}
+
+/// Connection through the WireGuard egress.
+pub struct WireGuardStream(netstack::TcpStream);
+
+impl AsyncRead for WireGuardStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for WireGuardStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.0).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.0).poll_shutdown(cx)
+    }
+}
+
+impl Connection for WireGuardStream {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+/// hyper connector sending direct requests through the WireGuard egress, to the already
+/// resolved and validated addresses.
+#[derive(Debug, Clone)]
+pub struct WireGuardConnector {
+    addrs: Vec<SocketAddr>,
+}
+
+impl WireGuardConnector {
+    pub fn new(addrs: Vec<SocketAddr>) -> Self {
+        WireGuardConnector { addrs }
+    }
+}
+
+impl Service<Uri> for WireGuardConnector {
+    type Response = WireGuardStream;
+    type Error = io::Error;
+    type Future = Pin<Box<dyn Future<Output = io::Result<WireGuardStream>> + Send>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _dst: Uri) -> Self::Future {
+        let addrs = self.addrs.clone();
+        Box::pin(async move {
+            let device = device()
+                .ok_or_else(|| io::Error::new(ErrorKind::NotConnected, "No WireGuard egress"))?;
+            let (stream, _, _) = device.connect(&addrs).await?;
+            Ok(stream)
+        })
+    }
+}
@@ -0,0 +1,49 @@
+//! Hashes the Argon2 reference implementation writes still verify, so `--auth-file` and
+//! users files keep working with whatever wrote their hashes.
+
+#[path = "../src/argon2.rs"]
+#[allow(dead_code)]
+mod argon2;
+
+use argon2::Argon2Hash;
+
+// From the reference implementation's tests, "password" with the salt "somesalt"
+const VERSION_10: &str =
+    "$argon2i$m=65536,t=2,p=1$c29tZXNhbHQ$9sTbSlTio3Biev89thdrlKKiCaYsjjYVJxGAL3swxpQ";
+const VERSION_13: &str =
+    "$argon2i$v=19$m=65536,t=2,p=1$c29tZXNhbHQ$wWKIMhR9lyDFvRz9YTZweHKfbftvj+qf+YFY4NeBbtA";
+
+#[test]
+fn verifies_reference_hashes() {
+    for encoded in [VERSION_10, VERSION_13] {
+        let hash = encoded.parse::<Argon2Hash>().unwrap();
+        assert!(hash.verify(b"password"), "{encoded}");
+        assert!(!hash.verify(b"passw0rd"), "{encoded}");
+    }
+}
+
+#[test]
+fn hashes_new_passwords_with_argon2id() {
+    let hash = Argon2Hash::new(b"wonderland");
+    let encoded = hash.to_string();
+    assert!(
+        encoded.starts_with("$argon2id$v=19$m=19456,t=2,p=1$"),
+        "{encoded}"
+    );
+    let parsed = encoded.parse::<Argon2Hash>().unwrap();
+    assert!(parsed.verify(b"wonderland"));
+    assert!(!parsed.verify(b"wonderlan"));
+}
+
+#[test]
+fn refuses_hashes_asking_for_too_much() {
+    for encoded in [
+        "$argon2id$v=19$m=8388608,t=2,p=1$c29tZXNhbHQ$wWKIMhR9lyDFvRz9YTZweHKfbftvj+qf+YFY4NeBbtA",
+        "$argon2id$v=19$m=65536,t=1000,p=1$c29tZXNhbHQ$wWKIMhR9lyDFvRz9YTZweHKfbftvj+qf+YFY4NeBbtA",
+        "$argon2id$v=19$m=65536,t=2,p=1$c29tZXNhbHQ",
+        "$argon2id$v=18$m=65536,t=2,p=1$c29tZXNhbHQ$wWKIMhR9lyDFvRz9YTZweHKfbftvj+qf+YFY4NeBbtA",
+        "$scrypt$ln=16,r=8,p=1$c29tZXNhbHQ$wWKIMhR9lyDFvRz9YTZweHKfbftvj+qf+YFY4NeBbtA",
+    ] {
+        assert!(encoded.parse::<Argon2Hash>().is_err(), "{encoded}");
+    }
+}
@@ -0,0 +1,1531 @@
+//! End-to-end tests of the data path: the proxy binary is started on ephemeral loopback
+//! ports and driven over real sockets against stub origins, checking what clients get
+//! back, what the proxy logs and what its metrics count.
+
+use std::env;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{Ipv4Addr, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::process::{self, Child, Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use base64::{engine::general_purpose::STANDARD as b64, Engine};
+use openssl::asn1::Asn1Time;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::PKey;
+use openssl::ssl::{SslConnector, SslMethod, SslStream, SslVerifyMode};
+use openssl::x509::{X509NameBuilder, X509};
+use ring::hmac;
+use sha2::{Digest, Sha256};
+
+// How long the proxy gets to start listening, and a log line to show up
+const STARTUP_TIMEOUT: Duration = Duration::from_secs(15);
+const LOG_TIMEOUT: Duration = Duration::from_secs(5);
+
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+const ADMIN_TOKEN: &str = "e2e-admin-token";
+
+/// A running proxy, killed when dropped.
+struct Proxerver {
+    child: Child,
+    http_port: u16,
+    socks_port: u16,
+    admin_port: u16,
+    // Zero unless started with the HTTPS server
+    https_port: u16,
+    logs: Arc<Mutex<String>>,
+}
+
+impl Proxerver {
+    /// Start the proxy on loopback with its HTTP, SOCKS5 and admin listeners, allowed to
+    /// reach the stub origins on loopback too, plus `args`.
+    fn start(args: &[&str]) -> Proxerver {
+        Proxerver::launch(&[&["--allow-private-destinations"], args].concat())
+    }
+
+    /// Start the proxy like [`Proxerver::start`], but with only the destinations it
+    /// allows by default.
+    fn launch(args: &[&str]) -> Proxerver {
+        Proxerver::spawn(&[&["--no-https-server"], args].concat(), 0)
+    }
+
+    /// Start the proxy like [`Proxerver::start`], with the HTTPS server too, on a
+    /// self-signed certificate.
+    fn start_https(args: &[&str]) -> Proxerver {
+        let https_port = free_port();
+        let (cert, key) = self_signed_certificate(https_port);
+        let tls = [
+            "--https-listen",
+            "127.0.0.1",
+            "--https-port",
+            &https_port.to_string(),
+            "--cert",
+            &cert,
+            "--pkey",
+            &key,
+            "--allow-private-destinations",
+        ]
+        .map(String::from);
+        let tls: Vec<&str> = tls.iter().map(String::as_str).collect();
+        Proxerver::spawn(&[&tls, args].concat(), https_port)
+    }
+
+    fn spawn(args: &[&str], https_port: u16) -> Proxerver {
+        let (http_port, socks_port, admin_port) = (free_port(), free_port(), free_port());
+        let mut child = Command::new(env!("CARGO_BIN_EXE_proxerver"))
+            .args(["--http-listen", "127.0.0.1", "--http-port"])
+            .arg(http_port.to_string())
+            .args(["--socks-listen", "127.0.0.1", "--socks-port"])
+            .arg(socks_port.to_string())
+            .arg("--admin-listen")
+            .arg(format!("127.0.0.1:{admin_port}"))
+            .arg("--admin-token")
+            .arg(format!("read:{ADMIN_TOKEN}"))
+            .args(args)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .expect("failed to start proxerver");
+
+        let logs = Arc::new(Mutex::new(String::new()));
+        collect(child.stdout.take().unwrap(), logs.clone());
+        collect(child.stderr.take().unwrap(), logs.clone());
+
+        let proxy = Proxerver {
+            child,
+            http_port,
+            socks_port,
+            admin_port,
+            https_port,
+            logs,
+        };
+        proxy.wait_for_ports();
+        proxy
+    }
+
+    fn wait_for_ports(&self) {
+        let started = Instant::now();
+        let ports = [
+            self.http_port,
+            self.socks_port,
+            self.admin_port,
+            self.https_port,
+        ];
+        for port in ports.into_iter().filter(|&port| port != 0) {
+            while TcpStream::connect(local(port)).is_err() {
+                assert!(
+                    started.elapsed() < STARTUP_TIMEOUT,
+                    "proxerver didn't listen on {port}:\n{}",
+                    self.logs()
+                );
+                thread::sleep(Duration::from_millis(50));
+            }
+        }
+    }
+
+    fn logs(&self) -> String {
+        self.logs.lock().unwrap().clone()
+    }
+
+    /// Wait for a log line containing `needle`, failing with the logs if none comes.
+    fn expect_log(&self, needle: &str) {
+        let started = Instant::now();
+        while !self.logs().contains(needle) {
+            assert!(
+                started.elapsed() < LOG_TIMEOUT,
+                "no log line with '{needle}' in:\n{}",
+                self.logs()
+            );
+            thread::sleep(Duration::from_millis(20));
+        }
+    }
+
+    /// Prometheus metrics from the admin API.
+    fn metrics(&self) -> String {
+        let response = send(
+            self.admin_port,
+            &format!(
+                "GET /metrics HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {ADMIN_TOKEN}\r\nConnection: close\r\n\r\n"
+            ),
+        );
+        assert_eq!(response.status, 200, "{}", response.body);
+        response.body
+    }
+
+    /// Value of the metric sample `name`, labels included, zero if it isn't there yet.
+    fn metric(&self, name: &str) -> u64 {
+        self.metrics()
+            .lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .map_or(0, |value| value.trim().parse().unwrap())
+    }
+}
+
+impl Drop for Proxerver {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+fn collect(output: impl Read + Send + 'static, logs: Arc<Mutex<String>>) {
+    thread::spawn(move || {
+        for line in BufReader::new(output).lines() {
+            let Ok(line) = line else {
+                return;
+            };
+            let mut logs = logs.lock().unwrap();
+            logs.push_str(&line);
+            logs.push('\n');
+        }
+    });
+}
+
+/// Origin server answering every request with its method, path and body length, so
+/// tests can tell the request arrived unchanged. `/bytes/<n>` gets `n` bytes instead.
+struct Origin {
+    port: u16,
+}
+
+impl Origin {
+    fn start() -> Origin {
+        let listener = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                thread::spawn(move || serve(stream));
+            }
+        });
+        Origin { port }
+    }
+
+    fn authority(&self) -> String {
+        format!("127.0.0.1:{}", self.port)
+    }
+}
+
+fn serve(mut stream: TcpStream) {
+    stream.set_read_timeout(Some(IO_TIMEOUT)).unwrap();
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    // Keep answering on the connection, tunnels send several requests over one
+    while let Ok(Some(head)) = read_head(&mut reader) {
+        let request_line = head.lines().next().unwrap_or_default().to_string();
+        let mut parts = request_line.split_whitespace();
+        let (method, path) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+
+        let body = match is_chunked(&head) {
+            true => read_chunked(&mut reader),
+            false => {
+                let mut body = vec![0; content_length(&head)];
+                reader.read_exact(&mut body).map(|()| body)
+            }
+        };
+        let Ok(body) = body else {
+            return;
+        };
+        let text = match path.strip_prefix("/bytes/").and_then(|n| n.parse().ok()) {
+            Some(n) => "x".repeat(n),
+            None => format!("{method} {path} {}", body.len()),
+        };
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nX-Origin: stub\r\n\r\n{text}",
+            text.len()
+        );
+        if stream.write_all(response.as_bytes()).is_err() {
+            return;
+        }
+    }
+}
+
+fn is_chunked(head: &str) -> bool {
+    head.lines().skip(1).any(|line| {
+        line.split_once(':').is_some_and(|(name, value)| {
+            name.eq_ignore_ascii_case("transfer-encoding") && value.trim().ends_with("chunked")
+        })
+    })
+}
+
+/// Body of a chunked request, its trailers skipped.
+fn read_chunked(reader: &mut impl BufRead) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let size = line.split(';').next().unwrap_or_default().trim();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if size == 0 {
+            break;
+        }
+        let start = body.len();
+        body.resize(start + size + 2, 0);
+        reader.read_exact(&mut body[start..])?;
+        body.truncate(start + size);
+    }
+    // The trailers end like a head
+    read_head(reader)?;
+    Ok(body)
+}
+
+/// A response as the client saw it.
+#[derive(Debug)]
+struct Response {
+    status: u16,
+    head: String,
+    body: String,
+}
+
+impl Response {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.head.lines().skip(1).find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    }
+}
+
+/// Send a raw request to `port` and read the response to the end of its body.
+fn send(port: u16, request: &str) -> Response {
+    let mut stream = connect(port);
+    stream.write_all(request.as_bytes()).unwrap();
+    read_response(&mut BufReader::new(stream))
+}
+
+fn connect(port: u16) -> TcpStream {
+    let stream = TcpStream::connect(local(port)).unwrap();
+    stream.set_read_timeout(Some(IO_TIMEOUT)).unwrap();
+    stream
+}
+
+fn read_response(reader: &mut impl BufRead) -> Response {
+    let head = read_head(reader)
+        .unwrap()
+        .expect("connection closed before a response");
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .unwrap_or_else(|| panic!("bad status line in {head:?}"));
+
+    let mut body = vec![0; content_length(&head)];
+    reader.read_exact(&mut body).unwrap();
+    Response {
+        status,
+        head,
+        body: String::from_utf8_lossy(&body).into_owned(),
+    }
+}
+
+/// Head of a request or response up to the empty line, `None` at the end of the stream.
+fn read_head(reader: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut head = String::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        if line == "\r\n" {
+            return Ok(Some(head));
+        }
+        head.push_str(&line);
+    }
+}
+
+fn content_length(head: &str) -> usize {
+    head.lines()
+        .find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.eq_ignore_ascii_case("content-length")
+                .then(|| value.trim().parse().ok())?
+        })
+        .unwrap_or(0)
+}
+
+fn free_port() -> u16 {
+    TcpListener::bind((Ipv4Addr::LOCALHOST, 0))
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+fn local(port: u16) -> SocketAddr {
+    SocketAddr::from((Ipv4Addr::LOCALHOST, port))
+}
+
+/// Certificate and key files of a P-256 certificate for localhost, named after `port`
+/// so concurrent tests don't share them.
+fn self_signed_certificate(port: u16) -> (String, String) {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+    let mut name = X509NameBuilder::new().unwrap();
+    name.append_entry_by_text("CN", "localhost").unwrap();
+    let name = name.build();
+
+    let mut cert = X509::builder().unwrap();
+    cert.set_version(2).unwrap();
+    cert.set_subject_name(&name).unwrap();
+    cert.set_issuer_name(&name).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    cert.sign(&key, MessageDigest::sha256()).unwrap();
+
+    let path = |name: &str| {
+        let path = env::temp_dir().join(format!("proxerver-e2e-{port}.{name}"));
+        path.to_string_lossy().into_owned()
+    };
+    let (cert_path, key_path) = (path("crt"), path("key"));
+    fs::write(&cert_path, cert.build().to_pem().unwrap()).unwrap();
+    fs::write(&key_path, key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    (cert_path, key_path)
+}
+
+/// TLS connection to the HTTPS server on `port`, offering the ALPN protocol `alpn`
+/// unless empty. The certificate isn't checked, it's the test's own.
+fn connect_tls(port: u16, alpn: &[u8]) -> SslStream<TcpStream> {
+    let mut connector = SslConnector::builder(SslMethod::tls_client()).unwrap();
+    connector.set_verify(SslVerifyMode::NONE);
+    if !alpn.is_empty() {
+        let protocols = [&[alpn.len() as u8], alpn].concat();
+        connector.set_alpn_protos(&protocols).unwrap();
+    }
+    connector
+        .build()
+        .connect("localhost", connect(port))
+        .unwrap()
+}
+
+fn basic(credentials: &str) -> String {
+    format!("Basic {}", b64.encode(credentials))
+}
+
+/// Proxy-Authorization header answering a SHA-256 Digest challenge for a GET of `uri`.
+fn digest(credentials: &str, nonce: &str, uri: &str, nc: u32) -> String {
+    let (login, password) = credentials.split_once(':').unwrap();
+    let hex = |input: String| format!("{:x}", Sha256::digest(input));
+    let ha1 = hex(format!("{login}:proxerver:{password}"));
+    let ha2 = hex(format!("GET:{uri}"));
+    let response = hex(format!("{ha1}:{nonce}:{nc:08x}:c0ffee:auth:{ha2}"));
+    format!(
+        "Proxy-Authorization: Digest username=\"{login}\", realm=\"proxerver\", nonce=\"{nonce}\", uri=\"{uri}\", algorithm=SHA-256, qop=auth, nc={nc:08x}, cnonce=\"c0ffee\", response=\"{response}\"\r\n"
+    )
+}
+
+fn get(origin: &Origin, path: &str, headers: &str) -> String {
+    let authority = origin.authority();
+    format!(
+        "GET http://{authority}{path} HTTP/1.1\r\nHost: {authority}\r\n{headers}Connection: close\r\n\r\n"
+    )
+}
+
+/// Open a CONNECT tunnel to `target`, returning the response and, when it was
+/// established, the connection to go on over.
+fn open_tunnel(port: u16, target: &str, headers: &str) -> (Response, BufReader<TcpStream>) {
+    let mut stream = connect(port);
+    let request = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n{headers}\r\n");
+    stream.write_all(request.as_bytes()).unwrap();
+    let mut reader = BufReader::new(stream);
+    let response = read_response(&mut reader);
+    (response, reader)
+}
+
+#[test]
+fn forwards_plain_requests() {
+    let origin = Origin::start();
+    let proxy = Proxerver::start(&[]);
+
+    let response = send(proxy.http_port, &get(&origin, "/hello?x=1", ""));
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, "GET /hello?x=1 0");
+    assert_eq!(response.header("x-origin"), Some("stub"));
+
+    // The body goes through unchanged
+    let authority = origin.authority();
+    let request = format!(
+        "POST http://{authority}/upload HTTP/1.1\r\nHost: {authority}\r\nContent-Length: 11\r\nConnection: close\r\n\r\nhello world"
+    );
+    let response = send(proxy.http_port, &request);
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, "POST /upload 11");
+
+    assert!(proxy.metric("proxerver_requests_total{listener=\"http\"}") >= 2);
+}
+
+#[test]
+fn relays_connect_tunnels() {
+    let origin = Origin::start();
+    let proxy = Proxerver::start(&["--allow-connect-ports", &origin.port.to_string()]);
+
+    let (response, mut tunnel) = open_tunnel(proxy.http_port, &origin.authority(), "");
+    assert_eq!(response.status, 200);
+
+    // Several requests over the one tunnel
+    for path in ["/first", "/second"] {
+        let request = format!("GET {path} HTTP/1.1\r\nHost: origin\r\n\r\n");
+        tunnel.get_mut().write_all(request.as_bytes()).unwrap();
+        let response = read_response(&mut tunnel);
+        assert_eq!(response.status, 200);
+        assert_eq!(response.body, format!("GET {path} 0"));
+    }
+    assert_eq!(proxy.metric("proxerver_active_tunnels"), 1);
+
+    drop(tunnel);
+    let started = Instant::now();
+    while proxy.metric("proxerver_active_tunnels") > 0 {
+        assert!(started.elapsed() < LOG_TIMEOUT, "tunnel stayed open");
+        thread::sleep(Duration::from_millis(50));
+    }
+    assert!(proxy.metric("proxerver_tunnel_bytes_total{direction=\"up\"}") > 0);
+    assert!(proxy.metric("proxerver_tunnel_bytes_total{direction=\"down\"}") > 0);
+}
+
+#[test]
+fn runs_with_optional_socket_options() {
+    // Where the system lacks Fast Open or MSS clamping, they're turned off with a warning
+    let origin = Origin::start();
+    let proxy = Proxerver::start(&["--tcp-fast-open", "--outbound-mss", "1360"]);
+
+    let response = send(proxy.http_port, &get(&origin, "/", ""));
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, "GET / 0");
+}
+
+#[test]
+fn refuses_connect_to_ports_not_allowed() {
+    let origin = Origin::start();
+    let proxy = Proxerver::start(&["--allow-connect-ports", "443"]);
+
+    let (response, _) = open_tunnel(proxy.http_port, &origin.authority(), "");
+    assert_eq!(response.status, 403);
+    proxy.expect_log("Policy deny rule=connect-ports:default");
+}
+
+#[test]
+fn requires_credentials() {
+    let origin = Origin::start();
+    let port = origin.port.to_string();
+    let proxy = Proxerver::start(&["--auth", "alice:wonderland", "--allow-connect-ports", &port]);
+
+    let response = send(proxy.http_port, &get(&origin, "/", ""));
+    assert_eq!(response.status, 407);
+    assert!(response
+        .header("proxy-authenticate")
+        .is_some_and(|value| value.starts_with("Basic")));
+    proxy.expect_log("Policy deny rule=auth:missing");
+
+    let wrong = format!("Proxy-Authorization: {}\r\n", basic("alice:looking-glass"));
+    let response = send(proxy.http_port, &get(&origin, "/", &wrong));
+    assert_eq!(response.status, 407);
+    proxy.expect_log("Policy deny rule=auth:default");
+
+    let right = format!("Proxy-Authorization: {}\r\n", basic("alice:wonderland"));
+    let response = send(proxy.http_port, &get(&origin, "/", &right));
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, "GET / 0");
+
+    // Tunnels are held to the same credentials
+    let (response, _) = open_tunnel(proxy.http_port, &origin.authority(), &wrong);
+    assert_eq!(response.status, 407);
+    let (response, _) = open_tunnel(proxy.http_port, &origin.authority(), &right);
+    assert_eq!(response.status, 200);
+
+    assert!(proxy.metric("proxerver_denied_total{check=\"auth\"}") >= 3);
+}
+
+#[test]
+fn holds_clients_to_the_login_that_matched() {
+    let origin = Origin::start();
+    let proxy = Proxerver::start_https(&["--auth", "alice:wonderland", "--rate-limit", "2/60s"]);
+    let authority = origin.authority();
+    let request = |credentials: &str| {
+        format!(
+            "GET http://{authority}/ HTTP/1.1\r\nHost: {authority}\r\nProxy-Authorization: {}\r\nConnection: close\r\n\r\n",
+            basic(credentials)
+        )
+    };
+    let send_https = |request: &str| {
+        let mut stream = connect_tls(proxy.https_port, &[]);
+        stream.write_all(request.as_bytes()).unwrap();
+        read_response(&mut BufReader::new(stream))
+    };
+
+    // Alice's password behind another login is no login at all, on either listener
+    for spoofed in [
+        "zz:alice:wonderland",
+        "alice:wonderland:",
+        ":alice:wonderland",
+    ] {
+        assert_eq!(send(proxy.http_port, &request(spoofed)).status, 407);
+        assert_eq!(send_https(&request(spoofed)).status, 407);
+    }
+    proxy.expect_log("Policy deny rule=auth:default");
+
+    // So alice can't make up logins to get fresh --rate-limit buckets
+    assert_eq!(
+        send(proxy.http_port, &request("alice:wonderland")).status,
+        200
+    );
+    assert_eq!(send_https(&request("alice:wonderland")).status, 200);
+    assert_eq!(
+        send(proxy.http_port, &request("alice:wonderland")).status,
+        429
+    );
+    assert_eq!(
+        send(proxy.http_port, &request("x:alice:wonderland")).status,
+        407
+    );
+    assert_eq!(send_https(&request("alice:wonderland")).status, 429);
+}
+
+#[test]
+fn authenticates_from_the_auth_file() {
+    // Made with OpenSSL's Argon2: `openssl kdf -kdfopt pass:wonderland ... ARGON2ID`
+    let path = env::temp_dir().join(format!("proxerver-e2e-htpasswd-{}", process::id()));
+    fs::write(
+        &path,
+        "# accounts\nalice:$argon2id$v=19$m=256,t=2,p=2$cmFiYml0LWhvbGUtc2FsdA$2Q6Oz0C4B8d6fGP9AlWUznfh6KLigqIobYOzXEVEvws\n",
+    )
+    .unwrap();
+    let origin = Origin::start();
+    let proxy = Proxerver::start(&["--auth-file", path.to_str().unwrap()]);
+
+    let wrong = format!("Proxy-Authorization: {}\r\n", basic("alice:looking-glass"));
+    let response = send(proxy.http_port, &get(&origin, "/", &wrong));
+    assert_eq!(response.status, 407);
+
+    let right = format!("Proxy-Authorization: {}\r\n", basic("alice:wonderland"));
+    let response = send(proxy.http_port, &get(&origin, "/", &right));
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, "GET / 0");
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn hashes_passwords_for_the_auth_file() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_proxerver"))
+        .args(["hash-password", "--login", "bob"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .expect("failed to start proxerver");
+    child.stdin.take().unwrap().write_all(b"builder\n").unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    let line = String::from_utf8(output.stdout).unwrap();
+    assert!(line.starts_with("bob:$argon2id$v=19$"), "{line}");
+
+    let path = env::temp_dir().join(format!("proxerver-e2e-hashed-{}", process::id()));
+    fs::write(&path, line).unwrap();
+    let origin = Origin::start();
+    let proxy = Proxerver::start(&["--auth-file", path.to_str().unwrap()]);
+
+    let right = format!("Proxy-Authorization: {}\r\n", basic("bob:builder"));
+    let response = send(proxy.http_port, &get(&origin, "/", &right));
+    assert_eq!(response.status, 200);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn authenticates_users_from_the_users_file() {
+    use std::os::unix::fs::PermissionsExt;
+
+    // A user hashed the way users were before Argon2, which must keep working
+    let path = env::temp_dir().join(format!("proxerver-e2e-users-{}.json", process::id()));
+    let legacy = format!("sha256$salt${:x}", Sha256::digest("saltbuilder"));
+    let record = format!(
+        "[{{\"login\":\"bob\",\"password_hash\":\"{legacy}\",\"created_at\":\"2024-01-01T00:00:00Z\"}}]"
+    );
+    fs::write(&path, record).unwrap();
+
+    let status = Command::new(env!("CARGO_BIN_EXE_proxerver"))
+        .args([
+            "user",
+            "add",
+            "amy",
+            "--password",
+            "Correct-Horse-Battery-9",
+        ])
+        .arg("--users-file")
+        .arg(&path)
+        .stdout(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(status.success());
+    let mode = fs::metadata(&path).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+    let users = fs::read_to_string(&path).unwrap();
+    assert!(
+        users.contains("\"password_hash\":\"$argon2id$v=19$"),
+        "{users}"
+    );
+
+    let origin = Origin::start();
+    let proxy = Proxerver::start(&["--users-file", path.to_str().unwrap()]);
+    for (credentials, status) in [
+        ("amy:Correct-Horse-Battery-9", 200),
+        ("bob:builder", 200),
+        ("amy:Correct-Horse-Battery-8", 407),
+        ("bob:builde", 407),
+    ] {
+        let header = format!("Proxy-Authorization: {}\r\n", basic(credentials));
+        let response = send(proxy.http_port, &get(&origin, "/", &header));
+        assert_eq!(response.status, status, "{credentials}");
+    }
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn authenticates_with_digest() {
+    let origin = Origin::start();
+    let proxy = Proxerver::start(&["--auth", "alice:wonderland", "--auth-scheme", "digest"]);
+    let uri = format!("http://{}/", origin.authority());
+
+    let response = send(proxy.http_port, &get(&origin, "/", ""));
+    assert_eq!(response.status, 407);
+    let challenge = response.header("Proxy-Authenticate").unwrap();
+    assert!(challenge.contains("algorithm=SHA-256"), "{challenge}");
+    let nonce = challenge
+        .split("nonce=\"")
+        .nth(1)
+        .and_then(|rest| rest.split('"').next())
+        .unwrap();
+
+    // Basic credentials are no longer taken, even right ones
+    let header = format!("Proxy-Authorization: {}\r\n", basic("alice:wonderland"));
+    let response = send(proxy.http_port, &get(&origin, "/", &header));
+    assert_eq!(response.status, 407);
+    proxy.expect_log("Policy deny rule=auth:scheme");
+
+    let header = digest("alice:looking-glass", nonce, &uri, 1);
+    let response = send(proxy.http_port, &get(&origin, "/", &header));
+    assert_eq!(response.status, 407);
+
+    let header = digest("alice:wonderland", nonce, &uri, 1);
+    let response = send(proxy.http_port, &get(&origin, "/", &header));
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, "GET / 0");
+
+    // A response seen on the wire can't be sent again
+    let response = send(proxy.http_port, &get(&origin, "/", &header));
+    assert_eq!(response.status, 407);
+    let header = digest("alice:wonderland", nonce, &uri, 2);
+    let response = send(proxy.http_port, &get(&origin, "/", &header));
+    assert_eq!(response.status, 200);
+}
+
+#[test]
+fn ends_sessions_when_credentials_change() {
+    let path = env::temp_dir().join(format!("proxerver-e2e-sessions-{}.toml", process::id()));
+    fs::write(&path, "auth = [\"alice:wonderland\"]\n").unwrap();
+    let origin = Origin::start();
+    let proxy = Proxerver::start(&["--sessions", "--config", path.to_str().unwrap()]);
+
+    // A client sending its credentials again keeps the session it has
+    let header = format!("Proxy-Authorization: {}\r\n", basic("alice:wonderland"));
+    let response = send(proxy.http_port, &get(&origin, "/", &header));
+    assert_eq!(response.status, 200);
+    let token = response.header("x-proxerver-session").unwrap().to_string();
+    let response = send(proxy.http_port, &get(&origin, "/", &header));
+    assert_eq!(response.header("x-proxerver-session"), Some(token.as_str()));
+
+    let session = format!("x-proxerver-session: {token}\r\n");
+    let response = send(proxy.http_port, &get(&origin, "/", &session));
+    assert_eq!(response.status, 200);
+
+    fs::write(&path, "auth = [\"alice:looking-glass\"]\n").unwrap();
+    let hangup = Command::new("kill")
+        .args(["-HUP", &proxy.child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(hangup.success());
+    proxy.expect_log("Reloaded 1 credentials");
+
+    let response = send(proxy.http_port, &get(&origin, "/", &session));
+    assert_eq!(response.status, 407);
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn refuses_scheduling_what_a_reload_leaves_alone() {
+    let path = env::temp_dir().join(format!("proxerver-e2e-scheduled-{}.toml", process::id()));
+    fs::write(
+        &path,
+        "hosts = [\"*.example.com\"]\n\n[scheduled.\"2020-01-01T00:00:00+00:00\"]\nhosts = [\"*.example.org\"]\ndeny_hosts = [\"*.example.net\"]\n",
+    )
+    .unwrap();
+
+    // The denied hosts would only change at the next restart, not at their time
+    let output = Command::new(env!("CARGO_BIN_EXE_proxerver"))
+        .args(["--no-https-server", "--config", path.to_str().unwrap()])
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("'deny_hosts' scheduled for 2020-01-01T00:00:00+00:00 can't change"),
+        "{stderr}"
+    );
+
+    fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn answers_dns_from_a_worker_of_its_own() {
+    let (http_port, dns_port) = (free_port(), free_port());
+    let mut child = Command::new(env!("CARGO_BIN_EXE_proxerver"))
+        .args(["--no-https-server", "--isolate-listeners"])
+        .args(["--http-listen", "127.0.0.1", "--http-port"])
+        .arg(http_port.to_string())
+        .args(["--dns-zone", "proxy.test", "--dns-answer", "203.0.113.10"])
+        .arg("--dns-listen")
+        .arg(format!("127.0.0.1:{dns_port}"))
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("failed to start proxerver");
+    let logs = Arc::new(Mutex::new(String::new()));
+    collect(child.stdout.take().unwrap(), logs.clone());
+    collect(child.stderr.take().unwrap(), logs.clone());
+    let proxy = Proxerver {
+        child,
+        http_port,
+        socks_port: 0,
+        admin_port: 0,
+        https_port: 0,
+        logs,
+    };
+    proxy.wait_for_ports();
+    proxy.expect_log("Running DNS server in worker process");
+
+    // An A query for the zone, asked again until the worker is up
+    let mut query = vec![0x12, 0x34, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0];
+    for label in ["proxy", "test"] {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.extend_from_slice(&[0, 0, 1, 0, 1]);
+    let socket = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_millis(200)))
+        .unwrap();
+    let started = Instant::now();
+    let mut answer = [0; 512];
+    let n = loop {
+        socket.send_to(&query, local(dns_port)).unwrap();
+        if let Ok((n, _)) = socket.recv_from(&mut answer) {
+            break n;
+        }
+        assert!(
+            started.elapsed() < STARTUP_TIMEOUT,
+            "no DNS answer:\n{}",
+            proxy.logs()
+        );
+    };
+    assert_eq!(answer[..2], [0x12, 0x34]);
+    assert_eq!(answer[3] & 0x0f, 0);
+    assert_eq!(answer[n - 4..n], [203, 0, 113, 10]);
+}
+
+#[test]
+fn issues_sessions_only_to_the_login_that_matched() {
+    let origin = Origin::start();
+    let port = origin.port.to_string();
+    let proxy = Proxerver::start_https(&[
+        "--sessions",
+        "--auth",
+        "alice:wonderland,bo:builder",
+        "--allow-connect-ports",
+        &port,
+    ]);
+    let authority = origin.authority();
+    let request = |method: &str, target: &str, credentials: &str| {
+        format!(
+            "{method} {target} HTTP/1.1\r\nHost: {authority}\r\nProxy-Authorization: {}\r\n\r\n",
+            basic(credentials)
+        )
+    };
+    // Sessions come with plain requests on the HTTP listener, with tunnels on HTTPS
+    let session = |credentials: &str| {
+        let get = request("GET", &format!("http://{authority}/"), credentials);
+        let http = send(proxy.http_port, &get);
+
+        let mut stream = connect_tls(proxy.https_port, &[]);
+        let connect = request("CONNECT", &authority, credentials);
+        stream.write_all(connect.as_bytes()).unwrap();
+        let https = read_response(&mut BufReader::new(stream));
+        [http, https]
+    };
+
+    // Alice's password can't get a session recorded as bo's
+    for response in session("bo:alice:wonderland") {
+        assert_eq!(response.status, 407);
+        assert_eq!(response.header("x-proxerver-session"), None);
+    }
+
+    for response in session("bo:builder") {
+        assert_eq!(response.status, 200);
+        assert!(response.header("x-proxerver-session").is_some());
+    }
+}
+
+#[test]
+fn requires_the_secret_token() {
+    // SOCKS5 clients can't send the token, so it doesn't go without credentials
+    let status = Command::new(env!("CARGO_BIN_EXE_proxerver"))
+        .args([
+            "--no-https-server",
+            "--socks-port",
+            "0",
+            "--token",
+            "s3cret",
+        ])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(!status.success());
+
+    let origin = Origin::start();
+    let proxy = Proxerver::start(&["--token", "s3cret", "--auth", "bob:builder"]);
+    let credentials = format!("Proxy-Authorization: {}\r\n", basic("bob:builder"));
+
+    let response = send(proxy.http_port, &get(&origin, "/", ""));
+    assert_eq!(response.status, 400);
+    proxy.expect_log("Policy deny rule=token:missing");
+
+    let response = send(
+        proxy.http_port,
+        &get(&origin, "/", "x-http-secret-token: s3cret\r\n"),
+    );
+    assert_eq!(response.status, 400);
+    proxy.expect_log("Policy deny rule=token:invalid");
+
+    // Clients send the token's SHA-256
+    let hash = format!("{:x}", Sha256::digest("s3cret"));
+    let header = format!("x-http-secret-token: {hash}\r\n{credentials}");
+    let response = send(proxy.http_port, &get(&origin, "/", &header));
+    assert_eq!(response.status, 200);
+}
+
+#[test]
+fn accepts_signed_tokens_only() {
+    let output = Command::new(env!("CARGO_BIN_EXE_proxerver"))
+        .args(["gen-token", "--token", "s3cret", "--expires-in", "600"])
+        .output()
+        .expect("failed to run proxerver");
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let signed = stdout
+        .lines()
+        .find_map(|line| line.strip_prefix("Signed header value: "))
+        .unwrap();
+
+    let origin = Origin::start();
+    let proxy = Proxerver::start(&[
+        "--token",
+        "s3cret",
+        "--signed-token-only",
+        "--auth",
+        "bob:builder",
+    ]);
+
+    let credentials = format!("Proxy-Authorization: {}\r\n", basic("bob:builder"));
+    let header = format!("x-http-secret-token: {signed}\r\n{credentials}");
+    let response = send(proxy.http_port, &get(&origin, "/", &header));
+    assert_eq!(response.status, 200);
+
+    // The static SHA-256 is good forever once leaked, so it's refused
+    let hash = format!("{:x}", Sha256::digest("s3cret"));
+    let header = format!("x-http-secret-token: {hash}\r\n");
+    let response = send(proxy.http_port, &get(&origin, "/", &header));
+    assert_eq!(response.status, 400);
+    proxy.expect_log("Policy deny rule=token:unsigned");
+
+    // Signed right, but long expired
+    let key = hmac::Key::new(hmac::HMAC_SHA256, b"s3cret");
+    let tag = hmac::sign(&key, b"1700000000");
+    let hex: String = tag.as_ref().iter().map(|b| format!("{b:02x}")).collect();
+    let header = format!("x-http-secret-token: 1700000000:{hex}\r\n");
+    let response = send(proxy.http_port, &get(&origin, "/", &header));
+    assert_eq!(response.status, 400);
+    proxy.expect_log("Policy deny rule=token:expired");
+}
+
+#[test]
+fn hides_client_credentials_from_the_parent_cache() {
+    let parent_port = parent_cache();
+    let proxy = Proxerver::start(&[
+        "--auth",
+        "bob:builder",
+        "--sessions",
+        "--parent-cache",
+        &format!("127.0.0.1:{parent_port}"),
+    ]);
+    let headers = format!(
+        "Proxy-Authorization: {}\r\nx-proxerver-session: abc\r\nKeep-Alive: timeout=5\r\nX-Hop: 1\r\nConnection: X-Hop\r\nAccept: text/html\r\n",
+        basic("bob:builder")
+    );
+    let request =
+        format!("GET http://cached.example/page HTTP/1.1\r\nHost: cached.example\r\n{headers}\r\n");
+    let response = send(proxy.http_port, &request);
+    assert_eq!(response.status, 200);
+
+    let sent = response.body.to_ascii_lowercase();
+    assert!(sent.contains("accept: text/html"), "{sent}");
+    for name in [
+        "proxy-authorization",
+        "x-proxerver-session",
+        "keep-alive",
+        "x-hop",
+    ] {
+        assert!(
+            !sent.contains(name),
+            "{name} reached the parent cache:\n{sent}"
+        );
+    }
+}
+
+#[test]
+fn meters_parent_cache_responses() {
+    let parent_port = parent_cache();
+    let proxy = Proxerver::start(&[
+        "--auth",
+        "bob:builder",
+        "--parent-cache",
+        &format!("127.0.0.1:{parent_port}"),
+    ]);
+    let headers = format!("Proxy-Authorization: {}\r\n", basic("bob:builder"));
+    let request =
+        format!("GET http://cached.example/page HTTP/1.1\r\nHost: cached.example\r\n{headers}Connection: close\r\n\r\n");
+    let response = send(proxy.http_port, &request);
+    assert_eq!(response.status, 200);
+
+    // Counted on the login like a response of the destination
+    let usage = send(
+        proxy.admin_port,
+        &format!(
+            "GET /v1/usage/bob HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {ADMIN_TOKEN}\r\nConnection: close\r\n\r\n"
+        ),
+    );
+    assert_eq!(usage.status, 200, "{}", usage.body);
+    let down = format!("\"down\":{}", response.body.len());
+    assert!(
+        usage.body.replace(' ', "").contains(&down),
+        "{}",
+        usage.body
+    );
+}
+
+#[test]
+fn meters_only_the_login_that_matched() {
+    let origin = Origin::start();
+    let port = origin.port.to_string();
+    let proxy = Proxerver::start_https(&[
+        "--auth",
+        "alice:wonderland,bo:builder",
+        "--allow-connect-ports",
+        &port,
+    ]);
+    let authority = origin.authority();
+    let usage = || {
+        let response = send(
+            proxy.admin_port,
+            &format!(
+                "GET /v1/usage HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {ADMIN_TOKEN}\r\nConnection: close\r\n\r\n"
+            ),
+        );
+        assert_eq!(response.status, 200, "{}", response.body);
+        response.body.replace(char::is_whitespace, "")
+    };
+    let request = |credentials: &str| {
+        format!(
+            "GET http://{authority}/bytes/1000 HTTP/1.1\r\nHost: {authority}\r\nProxy-Authorization: {}\r\nConnection: close\r\n\r\n",
+            basic(credentials)
+        )
+    };
+
+    // Bo knowing alice's password can't bill her traffic to bo, nor to a made-up login
+    for spoofed in ["bo:alice:wonderland", "zz:alice:wonderland"] {
+        assert_eq!(send(proxy.http_port, &request(spoofed)).status, 407);
+        let mut stream = connect_tls(proxy.https_port, &[]);
+        stream.write_all(request(spoofed).as_bytes()).unwrap();
+        assert_eq!(read_response(&mut BufReader::new(stream)).status, 407);
+    }
+    let mut stream = connect(proxy.socks_port);
+    let mut reply = [0; 2];
+    stream.write_all(&[5, 1, 2]).unwrap();
+    stream.read_exact(&mut reply).unwrap();
+    stream
+        .write_all(&socks_password("zz:alice", "wonderland"))
+        .unwrap();
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [1, 1]);
+    assert_eq!(usage(), "{\"usage\":{}}");
+
+    let response = send(proxy.http_port, &request("alice:wonderland"));
+    assert_eq!(response.status, 200);
+    let usage = usage();
+    assert!(usage.contains("\"alice\":{"), "{usage}");
+    assert!(!usage.contains("\"bo\""), "{usage}");
+}
+
+#[test]
+fn journals_destinations_only_once_the_client_is_known() {
+    let origin = Origin::start();
+    let proxy = Proxerver::start_https(&[
+        "--auth",
+        "alice:wonderland",
+        "--no-log",
+        "alice",
+        "--deny-hosts",
+        "*.secret.example",
+    ]);
+    let authority = origin.authority();
+    let journal = || {
+        let response = send(
+            proxy.admin_port,
+            &format!(
+                "GET /v1/log HTTP/1.1\r\nHost: localhost\r\nAuthorization: Bearer {ADMIN_TOKEN}\r\nConnection: close\r\n\r\n"
+            ),
+        );
+        assert_eq!(response.status, 200, "{}", response.body);
+        response.body.replace(char::is_whitespace, "")
+    };
+    let request = |host: &str, credentials: &str| {
+        format!(
+            "GET http://{host}/ HTTP/1.1\r\nHost: {host}\r\nProxy-Authorization: {}\r\nConnection: close\r\n\r\n",
+            basic(credentials)
+        )
+    };
+
+    // The host is refused before the credentials are looked at, so neither alice nor
+    // whoever claims to be her is on record with it
+    for credentials in ["alice:wonderland", "alice:guess"] {
+        let request = request("www.secret.example", credentials);
+        assert_eq!(send(proxy.http_port, &request).status, 400);
+        let mut stream = connect_tls(proxy.https_port, &[]);
+        stream.write_all(request.as_bytes()).unwrap();
+        assert_eq!(read_response(&mut BufReader::new(stream)).status, 400);
+    }
+    let entries = journal();
+    assert!(entries.contains("\"user\":\"?\""), "{entries}");
+    assert!(!entries.contains("\"user\":\"alice\""), "{entries}");
+    assert!(!entries.contains("www.secret.example"), "{entries}");
+    assert!(
+        !proxy.logs().contains("www.secret.example"),
+        "{}",
+        proxy.logs()
+    );
+
+    // Once she is known, her destinations are kept out as a --no-log user's
+    let response = send(proxy.http_port, &request(&authority, "alice:wonderland"));
+    assert_eq!(response.status, 200);
+    let entries = journal();
+    assert!(entries.contains("\"user\":\"alice\""), "{entries}");
+    assert!(!entries.contains(&authority), "{entries}");
+}
+
+/// A parent cache answering every request with the head it was sent, on the port returned.
+fn parent_cache() -> u16 {
+    let parent = TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap();
+    let port = parent.local_addr().unwrap().port();
+    thread::spawn(move || {
+        for mut stream in parent.incoming().flatten() {
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let head = read_head(&mut reader).unwrap().unwrap_or_default();
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{head}",
+                head.len()
+            );
+            let _ = stream.write_all(response.as_bytes());
+        }
+    });
+    port
+}
+
+#[test]
+fn refuses_ambiguous_framing() {
+    let origin = Origin::start();
+    let proxy = Proxerver::start_https(&[]);
+    let authority = origin.authority();
+
+    // Requests the proxy and the origin could each end somewhere else
+    let smuggled = [
+        (
+            "HTTP/1.1",
+            "Content-Length: 5\r\nTransfer-Encoding: chunked\r\n",
+            "te-and-length",
+        ),
+        (
+            "HTTP/1.1",
+            "Content-Length: 5\r\nContent-Length: 6\r\n",
+            "content-length",
+        ),
+        ("HTTP/1.1", "Content-Length: 5, 6\r\n", "content-length"),
+        ("HTTP/1.1", "Content-Length: +5\r\n", "content-length"),
+        (
+            "HTTP/1.1",
+            "Transfer-Encoding: chunked, identity\r\n",
+            "transfer-encoding",
+        ),
+        (
+            "HTTP/1.1",
+            "Transfer-Encoding: xchunked\r\n",
+            "transfer-encoding",
+        ),
+        (
+            "HTTP/1.1",
+            "Transfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n",
+            "transfer-encoding",
+        ),
+        ("HTTP/1.0", "Transfer-Encoding: chunked\r\n", "te-http10"),
+    ];
+    for (version, headers, rule) in smuggled {
+        let request = format!(
+            "POST http://{authority}/smuggled {version}\r\nHost: {authority}\r\n{headers}Connection: close\r\n\r\n0\r\n\r\nGET /hidden HTTP/1.1\r\nHost: {authority}\r\n\r\n"
+        );
+
+        // The HTTPS server parses requests itself, so it's held to every rule
+        let denied = proxy.metric(FRAMING_DENIED);
+        let mut stream = connect_tls(proxy.https_port, &[]);
+        stream.write_all(request.as_bytes()).unwrap();
+        let response = read_response(&mut BufReader::new(stream));
+        assert_eq!(response.status, 400, "HTTPS {version} {headers:?}");
+        assert_eq!(proxy.metric(FRAMING_DENIED), denied + 1);
+        proxy.expect_log(&format!("Policy deny rule=framing:{rule}"));
+
+        // hyper turns some away before the check, the HTTP server answers 400 either way
+        let response = send(proxy.http_port, &request);
+        assert_eq!(response.status, 400, "HTTP {version} {headers:?}");
+    }
+}
+
+const FRAMING_DENIED: &str = "proxerver_denied_total{check=\"framing\"}";
+
+#[test]
+fn streams_large_bodies_over_https() {
+    const SIZE: usize = 64 * 1024 * 1024;
+    let origin = Origin::start();
+    let proxy = Proxerver::start_https(&[]);
+    let authority = origin.authority();
+
+    // Up: the body is passed on as it's sent, the origin counts it
+    let mut stream = connect_tls(proxy.https_port, &[]);
+    let head = format!(
+        "POST http://{authority}/upload HTTP/1.1\r\nHost: {authority}\r\nContent-Length: {SIZE}\r\nConnection: close\r\n\r\n"
+    );
+    stream.write_all(head.as_bytes()).unwrap();
+    let chunk = vec![b'u'; 64 * 1024];
+    for _ in 0..SIZE / chunk.len() {
+        stream.write_all(&chunk).unwrap();
+    }
+    let response = read_response(&mut BufReader::new(stream));
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, format!("POST /upload {SIZE}"));
+
+    // Down: all of it arrives, in the length announced
+    let mut stream = connect_tls(proxy.https_port, &[]);
+    let request = format!(
+        "GET http://{authority}/bytes/{SIZE} HTTP/1.1\r\nHost: {authority}\r\nConnection: close\r\n\r\n"
+    );
+    stream.write_all(request.as_bytes()).unwrap();
+    let response = read_response(&mut BufReader::new(stream));
+    assert_eq!(response.status, 200);
+    assert_eq!(
+        response.header("content-length"),
+        Some(SIZE.to_string().as_str())
+    );
+    assert!(response.body.len() == SIZE && response.body.bytes().all(|byte| byte == b'x'));
+
+    // Chunked: passed on as the chunks come, extensions and trailers aside
+    let mut stream = connect_tls(proxy.https_port, &[]);
+    let head = format!(
+        "POST http://{authority}/upload HTTP/1.1\r\nHost: {authority}\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n"
+    );
+    stream.write_all(head.as_bytes()).unwrap();
+    for _ in 0..SIZE / chunk.len() {
+        stream
+            .write_all(format!("{:x};ext=1\r\n", chunk.len()).as_bytes())
+            .unwrap();
+        stream.write_all(&chunk).unwrap();
+        stream.write_all(b"\r\n").unwrap();
+    }
+    stream.write_all(b"0\r\nX-Trailer: 1\r\n\r\n").unwrap();
+    let response = read_response(&mut BufReader::new(stream));
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, format!("POST /upload {SIZE}"));
+
+    // A size the server could read differently cuts the body off
+    let mut stream = connect_tls(proxy.https_port, &[]);
+    let request = format!(
+        "POST http://{authority}/upload HTTP/1.1\r\nHost: {authority}\r\nTransfer-Encoding: chunked\r\nConnection: close\r\n\r\n+5\r\nhello\r\n0\r\n\r\n"
+    );
+    stream.write_all(request.as_bytes()).unwrap();
+    let response = read_response(&mut BufReader::new(stream));
+    assert_ne!(response.status, 200);
+
+    // No body was held in memory whole
+    let status = fs::read_to_string(format!("/proc/{}/status", proxy.child.id())).unwrap();
+    let peak_kib: usize = status
+        .lines()
+        .find_map(|line| line.strip_prefix("VmHWM:"))
+        .and_then(|peak| peak.trim().trim_end_matches(" kB").parse().ok())
+        .unwrap();
+    assert!(peak_kib * 1024 < SIZE, "proxerver peaked at {peak_kib} KiB");
+}
+
+#[test]
+fn limits_hosts() {
+    let origin = Origin::start();
+    let proxy = Proxerver::start(&["--hosts", "allowed.example.com"]);
+
+    let response = send(proxy.http_port, &get(&origin, "/", ""));
+    assert_eq!(response.status, 400);
+    proxy.expect_log("Policy deny rule=hosts:default");
+    assert!(proxy.metric("proxerver_denied_total{check=\"hosts\"}") >= 1);
+}
+
+#[test]
+fn refuses_private_destinations_by_default() {
+    let origin = Origin::start();
+    let port = origin.port.to_string();
+    let proxy = Proxerver::launch(&["--allow-connect-ports", &port]);
+
+    let response = send(proxy.http_port, &get(&origin, "/", ""));
+    assert_eq!(response.status, 502);
+    let (response, _) = open_tunnel(proxy.http_port, &origin.authority(), "");
+    assert_eq!(response.status, 502);
+    proxy.expect_log("Policy deny rule=destination:private");
+    assert!(proxy.metric("proxerver_denied_total{check=\"destination\"}") >= 2);
+}
+
+#[test]
+fn relays_socks5_with_password() {
+    let origin = Origin::start();
+    let port = origin.port.to_string();
+    let proxy = Proxerver::start(&["--auth", "bob:builder", "--allow-connect-ports", &port]);
+
+    // Wrong password first, the server answers with a failure status
+    let mut stream = connect(proxy.socks_port);
+    stream.write_all(&[5, 1, 2]).unwrap();
+    let mut reply = [0; 2];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [5, 2]);
+    stream.write_all(&socks_password("bob", "wrong")).unwrap();
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [1, 1]);
+
+    // Ports CONNECT may not open are refused over SOCKS5 too
+    let mut stream = socks_login(proxy.socks_port, "bob", "builder");
+    stream
+        .write_all(&[5, 1, 0, 1, 127, 0, 0, 1, 0, 25])
+        .unwrap();
+    let mut reply = [0; 10];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[..2], [5, 2]);
+    proxy.expect_log("Policy deny rule=connect-ports:default");
+
+    let mut stream = socks_login(proxy.socks_port, "bob", "builder");
+    let mut request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+    request.extend(origin.port.to_be_bytes());
+    stream.write_all(&request).unwrap();
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[..2], [5, 0]);
+
+    stream
+        .write_all(b"GET /over-socks HTTP/1.1\r\nHost: origin\r\n\r\n")
+        .unwrap();
+    let response = read_response(&mut BufReader::new(stream));
+    assert_eq!(response.status, 200);
+    assert_eq!(response.body, "GET /over-socks 0");
+    assert!(proxy.metric("proxerver_requests_total{listener=\"socks\"}") >= 2);
+}
+
+#[test]
+fn holds_socks5_clients_to_the_account_that_matched() {
+    let origin = Origin::start();
+    let port = origin.port.to_string();
+    let proxy = Proxerver::start(&[
+        "--auth",
+        "alice:wonderland,bo:b:c",
+        "--rate-limit",
+        "1/60s",
+        "--allow-connect-ports",
+        &port,
+    ]);
+    let mut connect_request = vec![5, 1, 0, 1, 127, 0, 0, 1];
+    connect_request.extend(origin.port.to_be_bytes());
+    let login = |username: &str, password: &str| {
+        let mut stream = connect(proxy.socks_port);
+        let mut reply = [0; 2];
+        stream.write_all(&[5, 1, 2]).unwrap();
+        stream.read_exact(&mut reply).unwrap();
+        stream
+            .write_all(&socks_password(username, password))
+            .unwrap();
+        stream.read_exact(&mut reply).unwrap();
+        (reply, stream)
+    };
+
+    // A ':' in the username would move the login of the Basic header it is checked as
+    assert_eq!(login("zz:alice", "wonderland").0, [1, 1]);
+    assert_eq!(login("bo:b", "c").0, [1, 1]);
+    proxy.expect_log("Policy deny rule=auth:default");
+
+    let (reply, mut stream) = login("bo", "b:c");
+    assert_eq!(reply, [1, 0]);
+    stream.write_all(&connect_request).unwrap();
+    let mut reply = [0; 10];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[..2], [5, 0]);
+
+    // Bo's one request a minute is taken, under bo's login and no other
+    let (reply, mut stream) = login("bo", "b:c");
+    assert_eq!(reply, [1, 0]);
+    stream.write_all(&connect_request).unwrap();
+    let mut reply = [0; 10];
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply[..2], [5, 1]);
+}
+
+/// A SOCKS5 connection past the greeting and the username/password exchange.
+fn socks_login(port: u16, username: &str, password: &str) -> TcpStream {
+    let mut stream = connect(port);
+    let mut reply = [0; 2];
+    stream.write_all(&[5, 1, 2]).unwrap();
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [5, 2]);
+    stream
+        .write_all(&socks_password(username, password))
+        .unwrap();
+    stream.read_exact(&mut reply).unwrap();
+    assert_eq!(reply, [1, 0]);
+    stream
+}
+
+fn socks_password(username: &str, password: &str) -> Vec<u8> {
+    let mut message = vec![1, username.len() as u8];
+    message.extend(username.as_bytes());
+    message.push(password.len() as u8);
+    message.extend(password.as_bytes());
+    message
+}
+
+#[test]
+fn multiplexes_streams_over_one_connection() {
+    let origin = Origin::start();
+    let port = origin.port.to_string();
+    // The connection and two streams, one stream more is over the limit
+    let proxy = Proxerver::start_https(&[
+        "--multiplex",
+        "--allow-connect-ports",
+        &port,
+        "--max-connections-per-ip",
+        "3",
+    ]);
+
+    let mut stream = connect_tls(proxy.https_port, b"proxerver-yamux/1");
+    assert_eq!(
+        stream.ssl().selected_alpn_protocol(),
+        Some(&b"proxerver-yamux/1"[..])
+    );
+
+    let target = origin.authority();
+    let connect = format!("CONNECT {target} HTTP/1.1\r\nHost: {target}\r\n\r\n");
+    for id in [1, 3] {
+        let open = yamux_frame(YAMUX_DATA, YAMUX_SYN, id, connect.as_bytes());
+        stream.write_all(&open).unwrap();
+    }
+    stream
+        .write_all(&yamux_frame(YAMUX_WINDOW_UPDATE, YAMUX_SYN, 5, &[]))
+        .unwrap();
+    stream.write_all(&yamux_ping(YAMUX_SYN, 42)).unwrap();
+
+    let mut received: [Vec<u8>; 2] = Default::default();
+    let (mut pong, mut refused) = (false, false);
+    let tunnels_open = |received: &[Vec<u8>; 2]| {
+        received
+            .iter()
+            .all(|data| data.starts_with(b"HTTP/1.1 200"))
+    };
+    while !(pong && refused && tunnels_open(&received)) {
+        let (kind, flags, id, length, payload) = read_yamux_frame(&mut stream);
+        match (kind, id) {
+            (YAMUX_PING, 0) => pong = flags & YAMUX_ACK != 0 && length == 42,
+            (_, 5) => refused = flags & YAMUX_RST != 0,
+            (YAMUX_DATA, 1 | 3) => received[id as usize / 2].extend(payload),
+            _ => {}
+        }
+    }
+    proxy.expect_log("Connection limit reached (3 from 127.0.0.1)");
+
+    // Each stream is a tunnel of its own
+    for (id, path) in [(1, "/one"), (3, "/three")] {
+        let request = format!("GET {path} HTTP/1.1\r\nHost: origin\r\n\r\n");
+        stream
+            .write_all(&yamux_frame(YAMUX_DATA, 0, id, request.as_bytes()))
+            .unwrap();
+        let expected = format!("GET {path} 0");
+        let data = &mut received[id as usize / 2];
+        data.clear();
+        while !data.ends_with(expected.as_bytes()) {
+            let (kind, _, frame_id, _, payload) = read_yamux_frame(&mut stream);
+            if kind == YAMUX_DATA && frame_id == id {
+                data.extend(payload);
+            }
+        }
+    }
+}
+
+const YAMUX_DATA: u8 = 0;
+const YAMUX_WINDOW_UPDATE: u8 = 1;
+const YAMUX_PING: u8 = 2;
+
+const YAMUX_SYN: u16 = 1;
+const YAMUX_ACK: u16 = 2;
+const YAMUX_RST: u16 = 8;
+
+/// A yamux frame of `kind` on stream `id`, with `payload` for data and an empty window
+/// update otherwise.
+fn yamux_frame(kind: u8, flags: u16, id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = vec![0, kind];
+    frame.extend(flags.to_be_bytes());
+    frame.extend(id.to_be_bytes());
+    frame.extend((payload.len() as u32).to_be_bytes());
+    frame.extend(payload);
+    frame
+}
+
+fn yamux_ping(flags: u16, opaque: u32) -> Vec<u8> {
+    let mut frame = yamux_frame(YAMUX_PING, flags, 0, &[]);
+    frame[8..].copy_from_slice(&opaque.to_be_bytes());
+    frame
+}
+
+/// Kind, flags, stream ID, length and, for data, payload of the next frame.
+fn read_yamux_frame(reader: &mut impl Read) -> (u8, u16, u32, u32, Vec<u8>) {
+    let mut header = [0; 12];
+    reader.read_exact(&mut header).unwrap();
+    assert_eq!(header[0], 0, "unknown yamux version");
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    let id = u32::from_be_bytes(header[4..8].try_into().unwrap());
+    let length = u32::from_be_bytes(header[8..12].try_into().unwrap());
+    let mut payload = Vec::new();
+    if header[1] == YAMUX_DATA {
+        payload.resize(length as usize, 0);
+        reader.read_exact(&mut payload).unwrap();
+    }
+    (header[1], flags, id, length, payload)
+}
@@ -0,0 +1,120 @@
+//! The access rule invariants the `rules` fuzz target checks, held on its corpus and on
+//! generated rules, so a plain `cargo test` catches a change to their semantics too.
+
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::Path;
+
+use proptest::collection::vec;
+use proptest::prelude::*;
+use proptest::sample::select;
+
+#[path = "../src/hostmatch.rs"]
+#[allow(dead_code)]
+mod hostmatch;
+#[path = "../fuzz/invariants/rules.rs"]
+mod invariants;
+#[path = "../src/rules.rs"]
+#[allow(dead_code)]
+mod rules;
+
+// Inputs generated per run; a failure is shrunk and saved to tests/rules.proptest-regressions
+const GENERATED_INPUTS: u32 = 20_000;
+
+const SUBJECTS: &[&str] = &[
+    "api.internal.example.com",
+    "vault.internal.example.com",
+    "example.com",
+    "",
+    "10.1.2.3",
+    "10.200.0.1",
+    "192.0.2.7",
+    "::ffff:10.1.2.3",
+    "2001:db8::1",
+    "::",
+    "443",
+    "22",
+    "65535",
+];
+
+const RULES: &[&str] = &[
+    "*",
+    "*.internal.example.com",
+    "api.*",
+    "vault.*",
+    "*.example.com",
+    "example.com",
+    "re:api[0-9]*\\.internal\\.example\\.com",
+    "re:^(vault|api)\\.",
+    "re:[",
+    "10.0.0.0/8",
+    "10.1.0.0/16",
+    "10.1.2.3",
+    "10.1.2.3/32",
+    "0.0.0.0/0",
+    "192.0.2.0/24",
+    "::ffff:10.1.2.3/128",
+    "::ffff:10.0.0.0/104",
+    "::ffff:0.0.0.0/96",
+    "2001:db8::/32",
+    "::/0",
+    "10.0.0.0/33",
+    "443",
+    "443, 8443, 5000-5100",
+    "1-65535",
+    "22",
+    "5100-5000",
+    "0",
+    "80,,443",
+    " 443 ",
+];
+
+#[test]
+fn hold_on_the_fuzz_corpus() {
+    let corpus = Path::new(env!("CARGO_MANIFEST_DIR")).join("fuzz/corpus/rules");
+    let mut checked = 0;
+    for entry in fs::read_dir(&corpus).unwrap() {
+        let input = fs::read(entry.unwrap().path()).unwrap();
+        // The fuzz target only sees inputs that are UTF-8 too
+        if let Ok(input) = std::str::from_utf8(&input) {
+            invariants::check(input);
+            checked += 1;
+        }
+    }
+    assert!(checked > 0, "no inputs in {}", corpus.display());
+}
+
+/// A host, address or port: the known ones the rules are written against, or any other.
+fn subject() -> impl Strategy<Value = String> {
+    prop_oneof![
+        3 => select(SUBJECTS).prop_map(String::from),
+        1 => "[a-z0-9-]{1,8}(\\.[a-z0-9-]{1,8}){0,3}",
+        1 => any::<IpAddr>().prop_map(|ip| ip.to_string()),
+        1 => any::<u16>().prop_map(|port| port.to_string()),
+    ]
+}
+
+/// A rule of any kind, valid or not: the known ones, host patterns, networks and port
+/// ranges.
+fn rule() -> impl Strategy<Value = String> {
+    prop_oneof![
+        3 => select(RULES).prop_map(String::from),
+        1 => "(\\*\\.)?[a-z0-9*-]{1,8}(\\.[a-z0-9*-]{1,8}){0,3}",
+        1 => (any::<Ipv4Addr>(), 0..=33u8).prop_map(|(addr, len)| format!("{addr}/{len}")),
+        1 => (any::<u16>(), any::<u16>()).prop_map(|(start, end)| format!("{start}-{end}")),
+    ]
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(GENERATED_INPUTS))]
+
+    #[test]
+    fn hold_on_generated_rules(subject in subject(), rules in vec((any::<bool>(), rule()), 0..6)) {
+        let mut input = subject;
+        for (deny, rule) in rules {
+            let deny = if deny { "!" } else { "" };
+            input.push_str(&format!("\n{deny}{rule}"));
+        }
+        invariants::check(&input);
+    }
+}
@@ -0,0 +1,200 @@
+//! The TCP stack of the WireGuard egress against a scripted peer, which checks the IP
+//! packets that would go into the tunnel and answers with packets of its own. The tunnel
+//! itself is boringtun's and has its own tests.
+
+use std::net::{Ipv4Addr, SocketAddrV4};
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tokio::time::timeout;
+
+#[path = "../src/netstack.rs"]
+#[allow(dead_code)]
+mod netstack;
+
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+const LOCAL_ADDRESS: Ipv4Addr = Ipv4Addr::new(10, 8, 0, 2);
+const MSS: u16 = 1380;
+const SYN: u8 = 0x02;
+const ACK: u8 = 0x10;
+const FIN: u8 = 0x01;
+
+fn unhex(hex: &str) -> Vec<u8> {
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+/// Internet checksum (RFC 1071) of `bytes`, 0 over a header with a correct checksum.
+fn checksum(bytes: &[u8]) -> u16 {
+    let mut sum = bytes
+        .chunks(2)
+        .map(|pair| u16::from_be_bytes([pair[0], pair.get(1).copied().unwrap_or(0)]) as u32)
+        .sum::<u32>();
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+fn pseudo_header(packet: &[u8]) -> Vec<u8> {
+    let mut header = packet[12..20].to_vec();
+    header.extend([0, 6]);
+    header.extend(((packet.len() - 20) as u16).to_be_bytes());
+    header.extend(&packet[20..]);
+    header
+}
+
+#[test]
+fn checksum_matches_a_known_ipv4_header() {
+    let header = unhex("45000073000040004011b861c0a80001c0a800c7");
+    assert_eq!(checksum(&header), 0);
+    let mut zeroed = header.clone();
+    zeroed[10..12].copy_from_slice(&[0, 0]);
+    assert_eq!(checksum(&zeroed), 0xb861);
+}
+
+/// A TCP segment as the scripted peer sees it.
+struct Segment {
+    source: SocketAddrV4,
+    destination: SocketAddrV4,
+    seq: u32,
+    ack: u32,
+    flags: u8,
+    mss: Option<u16>,
+    payload: Vec<u8>,
+}
+
+async fn next_segment(packets: &mut UnboundedReceiver<Vec<u8>>) -> Segment {
+    let packet = timeout(IO_TIMEOUT, packets.recv()).await.unwrap().unwrap();
+    assert_eq!(packet[0], 0x45, "IPv4 without options");
+    assert_eq!(
+        u16::from_be_bytes([packet[2], packet[3]]) as usize,
+        packet.len()
+    );
+    assert_eq!(packet[6], 0x40, "don't fragment");
+    assert_eq!(packet[8], 64, "TTL");
+    assert_eq!(packet[9], 6, "TCP");
+    assert_eq!(checksum(&packet[..20]), 0, "IPv4 header checksum");
+    assert_eq!(checksum(&pseudo_header(&packet)), 0, "TCP checksum");
+
+    let ip = |at: usize| Ipv4Addr::new(packet[at], packet[at + 1], packet[at + 2], packet[at + 3]);
+    let word = |at: usize| u32::from_be_bytes(packet[at..at + 4].try_into().unwrap());
+    let tcp = &packet[20..];
+    let data_offset = (tcp[12] >> 4) as usize * 4;
+    Segment {
+        source: SocketAddrV4::new(ip(12), u16::from_be_bytes([tcp[0], tcp[1]])),
+        destination: SocketAddrV4::new(ip(16), u16::from_be_bytes([tcp[2], tcp[3]])),
+        seq: word(24),
+        ack: word(28),
+        flags: tcp[13],
+        mss: mss(&tcp[20..data_offset]),
+        payload: tcp[data_offset..].to_vec(),
+    }
+}
+
+/// The MSS among the TCP `options`.
+fn mss(mut options: &[u8]) -> Option<u16> {
+    loop {
+        match options {
+            [] | [0, ..] => return None,
+            [1, rest @ ..] => options = rest,
+            [2, 4, high, low, ..] => return Some(u16::from_be_bytes([*high, *low])),
+            [_, len, ..] if *len >= 2 && options.len() >= *len as usize => {
+                options = &options[*len as usize..]
+            }
+            _ => panic!("malformed TCP options {options:?}"),
+        }
+    }
+}
+
+/// The peer's answer to `to`, with the checksums filled in.
+fn reply(to: &Segment, seq: u32, ack: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+    let total_len = 40 + payload.len();
+    let mut packet = vec![0x45, 0];
+    packet.extend((total_len as u16).to_be_bytes());
+    packet.extend([0, 0, 0x40, 0, 64, 6, 0, 0]);
+    packet.extend(to.destination.ip().octets());
+    packet.extend(to.source.ip().octets());
+    let header_checksum = checksum(&packet);
+    packet[10..12].copy_from_slice(&header_checksum.to_be_bytes());
+
+    packet.extend(to.destination.port().to_be_bytes());
+    packet.extend(to.source.port().to_be_bytes());
+    packet.extend(seq.to_be_bytes());
+    packet.extend(ack.to_be_bytes());
+    packet.extend([5 << 4, flags, 0xff, 0xff, 0, 0, 0, 0]);
+    packet.extend(payload);
+    let tcp_checksum = checksum(&pseudo_header(&packet));
+    packet[36..38].copy_from_slice(&tcp_checksum.to_be_bytes());
+    packet
+}
+
+#[tokio::test]
+async fn netstack_talks_tcp_to_a_scripted_peer() {
+    let (outbound, mut packets) = unbounded_channel();
+    let stack = netstack::Stack::new(LOCAL_ADDRESS, outbound);
+    let remote = SocketAddrV4::new(Ipv4Addr::new(192, 0, 2, 1), 80);
+    let connecting = tokio::spawn({
+        let stack = stack.clone();
+        async move { stack.connect(remote).await }
+    });
+
+    let syn = next_segment(&mut packets).await;
+    assert_eq!(syn.flags, SYN);
+    assert_eq!(syn.destination, remote);
+    assert_eq!(*syn.source.ip(), LOCAL_ADDRESS);
+    assert_eq!(syn.mss, Some(MSS));
+
+    // A SYN-ACK with a bad checksum is dropped, the connection waits for the real one
+    let peer_iss = 7000;
+    let mut corrupted = reply(&syn, peer_iss, syn.seq.wrapping_add(1), SYN | ACK, &[]);
+    corrupted[27] ^= 1;
+    stack.deliver(&corrupted);
+    stack.deliver(&reply(
+        &syn,
+        peer_iss,
+        syn.seq.wrapping_add(1),
+        SYN | ACK,
+        &[],
+    ));
+    let (mut stream, local) = timeout(IO_TIMEOUT, connecting)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(local, syn.source);
+
+    let ack = next_segment(&mut packets).await;
+    assert_eq!(ack.flags, ACK);
+    assert_eq!(ack.seq, syn.seq.wrapping_add(1));
+    assert_eq!(ack.ack, peer_iss + 1);
+
+    stream.write_all(b"hello").await.unwrap();
+    let data = next_segment(&mut packets).await;
+    assert_eq!(data.seq, syn.seq.wrapping_add(1));
+    assert_eq!(data.payload, b"hello");
+
+    stack.deliver(&reply(
+        &syn,
+        peer_iss + 1,
+        data.seq.wrapping_add(5),
+        ACK,
+        b"world",
+    ));
+    let ack = next_segment(&mut packets).await;
+    assert_eq!(ack.ack, peer_iss + 1 + 5);
+    let mut received = [0; 5];
+    timeout(IO_TIMEOUT, stream.read_exact(&mut received))
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(&received, b"world");
+
+    stream.shutdown().await.unwrap();
+    let fin = next_segment(&mut packets).await;
+    assert_eq!(fin.flags & FIN, FIN);
+    assert_eq!(fin.seq, syn.seq.wrapping_add(6));
+}